@@ -0,0 +1,20 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use ethtrie::{keccak256, HashDB, MemoryDB, NodeCodec, PatriciaTrie, RlpNodeCodec};
+
+// Decodes arbitrary bytes as a node, then reads a trie whose root they are, which
+// must fail with an error rather than panic on malformed nodes.
+fuzz_target!(|data: &[u8]| {
+    let _ = RlpNodeCodec::decode(data);
+
+    let mut memdb = MemoryDB::new(true);
+    let root = keccak256(data);
+    memdb.insert(root, data.to_vec());
+    if let Ok(trie) = PatriciaTrie::from(&memdb, root) {
+        let _ = trie.get(data);
+        let _ = trie.contains(data);
+        let _ = trie.get_proof(data);
+        trie.iter().for_each(drop);
+    }
+});
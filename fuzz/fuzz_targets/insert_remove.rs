@@ -0,0 +1,59 @@
+#![no_main]
+use std::collections::BTreeMap;
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+
+use ethtrie::{MemoryDB, PatriciaTrie, PatriciaTrieMut};
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    Insert(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+    Root,
+}
+
+// Applies the operations to a trie and to a `BTreeMap`, which must agree at every
+// step. Roots must not change without updates, nor depend on the updates that led
+// to the entries.
+fuzz_target!(|ops: Vec<Op>| {
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    let mut oracle = BTreeMap::new();
+    for op in ops {
+        match op {
+            Op::Insert(key, value) => {
+                trie.insert(&key, value.clone()).unwrap();
+                // An empty value removes the key.
+                if value.is_empty() {
+                    oracle.remove(&key);
+                } else {
+                    oracle.insert(key.clone(), value);
+                }
+                assert_eq!(trie.get(&key).unwrap(), oracle.get(&key).cloned());
+            }
+            Op::Remove(key) => {
+                assert_eq!(trie.remove(&key).unwrap(), oracle.remove(&key).is_some());
+                assert_eq!(trie.get(&key).unwrap(), None);
+            }
+            Op::Root => {
+                let root = trie.root().unwrap();
+                assert_eq!(trie.root().unwrap(), root);
+            }
+        }
+    }
+    for (key, value) in oracle.iter() {
+        assert_eq!(trie.get(key).unwrap().as_ref(), Some(value));
+    }
+    let root = trie.root().unwrap();
+
+    let mut fresh_db = MemoryDB::new(true);
+    let mut fresh = PatriciaTrieMut::new(&mut fresh_db);
+    for (key, value) in oracle.iter() {
+        fresh.insert(key, value.clone()).unwrap();
+    }
+    assert_eq!(fresh.root().unwrap(), root);
+
+    let trie = PatriciaTrie::from(&memdb, root).unwrap();
+    assert_eq!(trie.iter().collect::<BTreeMap<_, _>>(), oracle);
+});
@@ -0,0 +1,32 @@
+#![no_main]
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+
+use ethtrie::{MemoryDB, PatriciaTrieMut};
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+    absent: Vec<Vec<u8>>,
+}
+
+// Proves each key of a trie and some absent keys, checking that every proof
+// verifies to the value the trie holds and that a truncated proof does not verify.
+fuzz_target!(|input: Input| {
+    let Input { entries, absent } = input;
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    for (key, value) in entries.iter() {
+        trie.insert(key, value.clone()).unwrap();
+    }
+    let root = trie.root().unwrap();
+
+    for key in entries.iter().map(|(key, _)| key).chain(absent.iter()) {
+        let proof = trie.get_proof(key).unwrap();
+        let value = trie.verify_proof(root, key, proof.clone()).unwrap();
+        assert_eq!(value, trie.get(key).unwrap());
+        if let Some((_, truncated)) = proof.split_last() {
+            assert!(trie.verify_proof(root, key, truncated.to_vec()).is_err());
+        }
+    }
+});
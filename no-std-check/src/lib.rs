@@ -0,0 +1,23 @@
+//! Fails to build if `ethtrie` or one of its dependencies links `std`: the panic
+//! handler below would then be defined twice.
+
+#![no_std]
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use ethtrie::{MemoryDB, PatriciaTrieMut, TrieError, H256};
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+pub fn root(entries: &[(Vec<u8>, Vec<u8>)]) -> Result<H256, TrieError> {
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    for (key, value) in entries {
+        trie.insert(key, value.clone())?;
+    }
+    trie.root()
+}
@@ -0,0 +1,101 @@
+extern crate alloc;
+use alloc::vec::Vec;
+use std::thread::{self, JoinHandle};
+
+use crate::codec::NodeCodec;
+use crate::db::HashDB;
+use crate::encoder::Encoder;
+use crate::hasher::Hasher;
+use crate::hook::DbAccess;
+use crate::node::RawNodeOrHash;
+use crate::trie::PatriciaTrieMut;
+use crate::H256;
+
+/// The root of a trie state committed on a background thread by
+/// `commit_in_background`.
+#[derive(Debug)]
+pub struct PendingRoot(Pending);
+
+#[derive(Debug)]
+enum Pending {
+    // The state was committed already.
+    Ready(H256),
+    Running(JoinHandle<H256>),
+}
+
+impl PendingRoot {
+    /// Returns whether the nodes are written, so that `wait` returns at once.
+    pub fn is_finished(&self) -> bool {
+        match &self.0 {
+            Pending::Ready(_) => true,
+            Pending::Running(handle) => handle.is_finished(),
+        }
+    }
+
+    /// Waits for the nodes to be written and returns the root hash.
+    pub fn wait(self) -> H256 {
+        match self.0 {
+            Pending::Ready(root) => root,
+            Pending::Running(handle) => handle
+                .join()
+                .unwrap_or_else(|e| std::panic::resume_unwind(e)),
+        }
+    }
+}
+
+impl<'db, D: HashDB, H: Hasher + 'static, C: NodeCodec + 'static> PatriciaTrieMut<'db, D, H, C> {
+    /// Seals the current state of the trie and commits it on a background thread,
+    /// which encodes and hashes its changed nodes and writes them to `db` in one
+    /// batch.
+    ///
+    /// `db` must be a handle to the storage of the trie, such as a clone of a
+    /// `SharedMemoryDB`. The trie can keep changing while the commit runs, and the
+    /// sealed state is not affected. Nothing is pruned: the nodes the trie passed
+    /// through are removed by its next commit, which should only happen once the
+    /// root is known. That commit hashes the nodes it kept from the sealed state again.
+    pub fn commit_in_background<S: HashDB + Send + 'static>(&mut self, mut db: S) -> PendingRoot {
+        // Nodes fetched from a provider are written by the trie itself.
+        self.write_cache();
+        if let Some(root) = self.root_hash {
+            return PendingRoot(Pending::Ready(root));
+        }
+
+        let root = self.root.clone();
+        let retain = self.retained_levels;
+        let hook = self.hook.clone();
+        let handle = thread::spawn(move || {
+            #[cfg(feature = "metrics")]
+            let started = std::time::Instant::now();
+            let mut encoder = Encoder::<H, C>::new(retain);
+            let (encoded, _) = encoder.encode_node(&root);
+            let (nodes, _) = encoder.finish();
+            let (mut keys, mut values): (Vec<H256>, Vec<Vec<u8>>) = nodes.unzip();
+            let root_hash = match encoded {
+                RawNodeOrHash::Node(raw) => {
+                    let hash = H::hash(&raw);
+                    keys.push(hash);
+                    values.push(raw);
+                    hash
+                }
+                RawNodeOrHash::Hash(hash) => hash,
+            };
+
+            if let Some(hook) = hook {
+                for (hash, value) in keys.iter().zip(values.iter()) {
+                    (hook.0)(&DbAccess::Insert {
+                        hash: *hash,
+                        len: value.len(),
+                    });
+                }
+            }
+            #[cfg(feature = "metrics")]
+            let written = keys.iter().filter(|key| !db.contains(key)).count();
+            db.insert_batch(keys, values);
+            db.commit_root(&root_hash);
+            #[cfg(feature = "metrics")]
+            crate::metrics::metrics().record_commit(started, written);
+            root_hash
+        });
+        PendingRoot(Pending::Running(handle))
+    }
+}
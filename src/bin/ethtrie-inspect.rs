@@ -0,0 +1,357 @@
+//! Inspects a trie stored in a snapshot container written by `write_snapshot`.
+//!
+//! ```sh
+//! ethtrie-inspect <snapshot> [--root <hash>] <command>
+//! ```
+//!
+//! The trie is the one the snapshot was written for, unless `--root` picks another
+//! one stored in it. Keys and hashes are given in hex, and paths as one hex digit
+//! per nibble. The commands are:
+//!
+//! - `get <key>`: prints the value of a key and the proof of it;
+//! - `stats`: prints the number of nodes of each kind, their size and the depth of
+//!   the trie;
+//! - `children [path]`: prints the node at a path, the root by default, and its
+//!   children;
+//! - `verify`: checks that every node of the trie is stored, matches its hash and
+//!   decodes, printing the ones that do not.
+
+use std::fs::File;
+use std::io::Read;
+use std::process::exit;
+
+use ethtrie::{
+    ChildReference, DecodedNode, HashDB, Hasher, KeccakHasher, MemoryDB, NodeCodec, NodeKind,
+    NodeReference, PatriciaTrie, RlpNodeCodec, SnapshotReader, H256,
+};
+
+const USAGE: &str = "usage: ethtrie-inspect <snapshot> [--root <hash>] <command>
+
+commands:
+    get <key>          print the value of a key and its proof
+    stats              print the number of nodes of each kind and the depth
+    children [path]    print the node at a path, in nibbles, and its children
+    verify             check that every node is stored, matches its hash and decodes";
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(true) => {}
+        Ok(false) => exit(1),
+        Err(Error::Usage) => {
+            eprintln!("{}", USAGE);
+            exit(2);
+        }
+        Err(Error::Failed(message)) => {
+            eprintln!("error: {}", message);
+            exit(1);
+        }
+    }
+}
+
+enum Error {
+    Usage,
+    Failed(String),
+}
+
+impl From<ethtrie::TrieError> for Error {
+    fn from(e: ethtrie::TrieError) -> Self {
+        Error::Failed(e.to_string())
+    }
+}
+
+// Runs the command of `args`, returning whether the trie passed it.
+fn run(args: &[String]) -> Result<bool, Error> {
+    let (path, args) = args.split_first().ok_or(Error::Usage)?;
+    let (root, args) = match args {
+        [flag, root, args @ ..] if flag == "--root" => (Some(parse_hash(root)?), args),
+        _ => (None, args),
+    };
+    let (db, snapshot_root) = open(path)?;
+    let root = root.unwrap_or(snapshot_root);
+
+    match args {
+        [command, key] if command == "get" => get(&db, root, &parse_hex(key)?),
+        [command] if command == "stats" => {
+            let walk = Walk::run(&db, root);
+            println!("root: {:?}", root);
+            println!("stored nodes: {} ({} bytes)", walk.stored, walk.bytes);
+            println!("inline nodes: {}", walk.inline);
+            println!("branches: {}", walk.branches);
+            println!("extensions: {}", walk.extensions);
+            println!("leaves: {}", walk.leaves);
+            println!("values: {}", walk.values);
+            println!("depth: {}", walk.depth);
+            println!("problems: {}", walk.problems.len());
+            Ok(walk.problems.is_empty())
+        }
+        [command] if command == "children" => children(&db, root, &[]),
+        [command, path] if command == "children" => children(&db, root, &parse_nibbles(path)?),
+        [command] if command == "verify" => {
+            let walk = Walk::run(&db, root);
+            for problem in walk.problems.iter() {
+                println!("{}", problem);
+            }
+            println!(
+                "{} stored nodes checked, {} problems",
+                walk.stored,
+                walk.problems.len()
+            );
+            Ok(walk.problems.is_empty())
+        }
+        _ => Err(Error::Usage),
+    }
+}
+
+// Reads the snapshot container at `path` into a db, returning it with the root of
+// the container. An incomplete container is still read, so that `verify` can tell
+// which nodes it lacks.
+fn open(path: &str) -> Result<(MemoryDB, H256), Error> {
+    let mut data = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut data))
+        .map_err(|e| Error::Failed(format!("cannot read {}: {}", path, e)))?;
+
+    let mut db = MemoryDB::new(true);
+    let mut reader = SnapshotReader::new(&mut db);
+    let fed = reader.feed(&data);
+    let root = reader.root();
+    let finished = fed.and_then(|_| reader.finish());
+    let root = match (finished, root) {
+        (Ok(root), _) => root,
+        (Err(e), Some(root)) => {
+            eprintln!("warning: snapshot {} is incomplete: {}", path, e);
+            root
+        }
+        (Err(e), None) => return Err(e.into()),
+    };
+    Ok((db, root))
+}
+
+fn get(db: &MemoryDB, root: H256, key: &[u8]) -> Result<bool, Error> {
+    let trie = PatriciaTrie::from(db, root)?;
+    match trie.get(key)? {
+        Some(value) => println!("value: 0x{}", to_hex(&value)),
+        None => println!("value: none"),
+    }
+    println!("proof:");
+    for node in trie.get_proof(key)? {
+        println!("    0x{}", to_hex(&node));
+    }
+    Ok(true)
+}
+
+fn children(db: &MemoryDB, root: H256, path: &[u8]) -> Result<bool, Error> {
+    let info = match PatriciaTrie::from(db, root)?.node_at_path(path)? {
+        Some(info) => info,
+        None => {
+            println!("no node at path {}", nibbles_to_hex(path));
+            return Ok(false);
+        }
+    };
+    let kind = match info.kind {
+        NodeKind::Empty => "empty",
+        NodeKind::Leaf => "leaf",
+        NodeKind::Extension => "extension",
+        NodeKind::Branch => "branch",
+    };
+    println!("path: {}", nibbles_to_hex(path));
+    println!(
+        "node: {} ({}, {} bytes)",
+        kind,
+        describe(&info.reference),
+        info.encoded.len()
+    );
+    println!("encoded: 0x{}", to_hex(&info.encoded));
+    if info.kind == NodeKind::Leaf {
+        println!("key nibbles: {}", to_hex_digits(&info.partial));
+    }
+    match info.value {
+        Some(value) => println!("value: 0x{}", to_hex(&value)),
+        None => println!("value: none"),
+    }
+    if !info.children.is_empty() {
+        println!("children:");
+    }
+    for child in info.children.iter() {
+        println!(
+            "    {}: {}",
+            to_hex_digits(&child.nibbles),
+            describe(&child.reference)
+        );
+    }
+    Ok(true)
+}
+
+fn describe(reference: &NodeReference) -> String {
+    match reference {
+        NodeReference::Hash(hash) => format!("stored {:?}", hash),
+        NodeReference::Inline => String::from("inline"),
+    }
+}
+
+fn load(db: &MemoryDB, hash: &H256) -> Result<Vec<u8>, Error> {
+    match db.get(hash) {
+        Some(data) => Ok(data),
+        // The root of the empty trie may not be stored.
+        None if *hash == KeccakHasher::hash(RlpNodeCodec::EMPTY_NODE) => {
+            Ok(RlpNodeCodec::EMPTY_NODE.to_vec())
+        }
+        None => Err(Error::Failed(format!("missing node {:?}", hash))),
+    }
+}
+
+// The nodes of a trie, counted by a walk from its root, and the problems found on
+// the way.
+#[derive(Default)]
+struct Walk {
+    stored: usize,
+    bytes: usize,
+    inline: usize,
+    branches: usize,
+    extensions: usize,
+    leaves: usize,
+    values: usize,
+    depth: usize,
+    problems: Vec<String>,
+}
+
+impl Walk {
+    fn run(db: &MemoryDB, root: H256) -> Self {
+        let mut walk = Walk::default();
+        walk.stored_node(db, root, &mut Vec::new());
+        walk
+    }
+
+    fn stored_node(&mut self, db: &MemoryDB, hash: H256, path: &mut Vec<u8>) {
+        let data = match load(db, &hash) {
+            Ok(data) => data,
+            Err(_) => {
+                self.problem(path, format!("missing node {:?}", hash));
+                return;
+            }
+        };
+        self.stored += 1;
+        self.bytes += data.len();
+        if KeccakHasher::hash(&data) != hash {
+            self.problem(
+                path,
+                format!("node stored under {:?} has another hash", hash),
+            );
+        }
+        self.node(db, &data, path);
+    }
+
+    fn node(&mut self, db: &MemoryDB, data: &[u8], path: &mut Vec<u8>) {
+        let node = match RlpNodeCodec::decode(data) {
+            Ok(node) => node,
+            Err(e) => {
+                self.problem(path, format!("undecodable node: {}", e));
+                return;
+            }
+        };
+        self.depth = self.depth.max(path.len());
+        match node {
+            DecodedNode::Empty => {}
+            DecodedNode::Leaf(..) => {
+                self.leaves += 1;
+                self.values += 1;
+            }
+            DecodedNode::Extension(ext, child) => {
+                self.extensions += 1;
+                let len = path.len();
+                path.extend((0..ext.len()).map(|i| ext.at(i)));
+                self.child(db, &child, path);
+                path.truncate(len);
+            }
+            DecodedNode::Branch(children, value) => {
+                self.branches += 1;
+                if value.is_some() {
+                    self.values += 1;
+                }
+                for (i, child) in children.iter().enumerate() {
+                    path.push(i as u8);
+                    self.child(db, child, path);
+                    path.pop();
+                }
+            }
+        }
+    }
+
+    fn child(&mut self, db: &MemoryDB, child: &ChildReference, path: &mut Vec<u8>) {
+        match child {
+            ChildReference::Empty => {}
+            ChildReference::Hash(digest) => match digest_hash(digest) {
+                Ok(hash) => self.stored_node(db, hash, path),
+                Err(_) => self.problem(path, String::from("invalid digest")),
+            },
+            ChildReference::Inline(data) => {
+                self.inline += 1;
+                self.node(db, data, path);
+            }
+        }
+    }
+
+    fn problem(&mut self, path: &[u8], problem: String) {
+        self.problems
+            .push(format!("at path {}: {}", nibbles_to_hex(path), problem));
+    }
+}
+
+fn digest_hash(digest: &[u8]) -> Result<H256, Error> {
+    if digest.len() != KeccakHasher::LENGTH {
+        return Err(ethtrie::TrieError::InvalidData.into());
+    }
+    Ok(H256::from_slice(digest))
+}
+
+fn parse_hash(s: &str) -> Result<H256, Error> {
+    let data = parse_hex(s)?;
+    if data.len() != 32 {
+        return Err(Error::Failed(format!("{} is not a 32-byte hash", s)));
+    }
+    Ok(H256::from_slice(&data))
+}
+
+fn parse_hex(s: &str) -> Result<Vec<u8>, Error> {
+    let nibbles = parse_nibbles(s)?;
+    if nibbles.len() % 2 == 1 {
+        return Err(Error::Failed(format!("{} has an odd number of digits", s)));
+    }
+    Ok(nibbles
+        .chunks(2)
+        .map(|pair| pair[0] << 4 | pair[1])
+        .collect())
+}
+
+// Reads one nibble per hex digit, after an optional `0x`.
+fn parse_nibbles(s: &str) -> Result<Vec<u8>, Error> {
+    let digits = s.strip_prefix("0x").unwrap_or(s);
+    digits
+        .chars()
+        .map(|c| {
+            c.to_digit(16)
+                .map(|nibble| nibble as u8)
+                .ok_or_else(|| Error::Failed(format!("{} is not hex", s)))
+        })
+        .collect()
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Writes a path of nibbles, one hex digit each.
+fn nibbles_to_hex(path: &[u8]) -> String {
+    if path.is_empty() {
+        return String::from("(root)");
+    }
+    to_hex_digits(path)
+}
+
+fn to_hex_digits(nibbles: &[u8]) -> String {
+    nibbles
+        .iter()
+        .map(|nibble| format!("{:x}", nibble))
+        .collect()
+}
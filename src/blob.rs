@@ -0,0 +1,94 @@
+extern crate alloc;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::errors::TrieError;
+use crate::hasher::{digest_bytes, digest_from_slice, H256Map, Hasher};
+use crate::shared::Shared;
+use crate::trie::TrieResult;
+use crate::H256;
+
+/// A store for the values tries keep out of their nodes, keyed by the hash of the
+/// value, see `with_blob_store`.
+///
+/// Tries take stores that are `Send + Sync` and share them with their clones and
+/// forks, so stores write through `&self`.
+pub trait BlobStore {
+    /// Returns the value stored under `hash`, if any.
+    fn get(&self, hash: &H256) -> Option<Vec<u8>>;
+
+    /// Stores `blob` under its hash. The same blob may be inserted again.
+    fn insert(&self, hash: H256, blob: Vec<u8>);
+}
+
+/// A `BlobStore` in memory. Clones share the same blobs.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBlobStore {
+    blobs: Shared<H256Map<Vec<u8>>>,
+}
+
+impl MemoryBlobStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.blobs.with((), |blobs, ()| blobs.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl BlobStore for MemoryBlobStore {
+    fn get(&self, hash: &H256) -> Option<Vec<u8>> {
+        self.blobs
+            .with(hash, |blobs, hash| blobs.get(hash).cloned())
+    }
+
+    fn insert(&self, hash: H256, blob: Vec<u8>) {
+        self.blobs
+            .with((hash, blob), |blobs, (hash, blob)| blobs.insert(hash, blob));
+    }
+}
+
+// The blob store of a trie, shared by its clones and forks, with the length above
+// which values are kept in it.
+//
+// Values moved to the store are replaced by their hash in the trie. So that a value
+// read from the trie can be told from a hash, values exactly as long as a hash are
+// always moved to the store, and only they are read back from it.
+#[derive(Clone)]
+pub(crate) struct SharedBlobs {
+    pub(crate) store: Arc<dyn BlobStore + Send + Sync>,
+    pub(crate) threshold: usize,
+}
+
+impl SharedBlobs {
+    // Returns what the trie holds for `value`, moving it to the store if needed.
+    pub(crate) fn put<H: Hasher>(&self, value: Vec<u8>) -> Vec<u8> {
+        if value.len() <= self.threshold && value.len() != H::LENGTH {
+            return value;
+        }
+        let hash = H::hash(&value);
+        self.store.insert(hash, value);
+        digest_bytes::<H>(&hash).to_vec()
+    }
+
+    // Returns the value the trie holds `held` for.
+    pub(crate) fn resolve<H: Hasher>(&self, held: Vec<u8>) -> TrieResult<Vec<u8>> {
+        if held.len() != H::LENGTH {
+            return Ok(held);
+        }
+        let hash = digest_from_slice(&held);
+        self.store.get(&hash).ok_or(TrieError::MissingBlob(hash))
+    }
+}
+
+impl fmt::Debug for SharedBlobs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BlobStore(threshold: {})", self.threshold)
+    }
+}
@@ -0,0 +1,216 @@
+extern crate alloc;
+
+use core::marker::PhantomData;
+
+use crate::codec::{DecodeLimits, NodeCodec, RlpNodeCodec};
+use crate::db::{HashDB, MemoryDB};
+use crate::hasher::{Hasher, KeccakHasher};
+use crate::trie::{
+    EmptyValues, PatriciaTrie, PatriciaTrieMut, TrieResult, DEFAULT_NODE_CACHE_CAPACITY,
+};
+use crate::H256;
+
+/// Options for opening tries, gathered before the db and root are known, so the
+/// same configuration opens every trie of an application.
+///
+/// Options that only apply to writes, the memory limit, write caching, pruning,
+/// empty values and shadow verification, are ignored by the read-only tries `build`
+/// and `open` return.
+#[derive(Debug)]
+pub struct TrieBuilder<H: Hasher = KeccakHasher, C: NodeCodec = RlpNodeCodec> {
+    node_cache_capacity: usize,
+    memory_limit: Option<usize>,
+    cache_writes: bool,
+    prune: bool,
+    empty_values: EmptyValues,
+    lenient: bool,
+    limits: DecodeLimits,
+    shadow: bool,
+    marker: PhantomData<fn() -> (H, C)>,
+}
+
+impl<H: Hasher, C: NodeCodec> Clone for TrieBuilder<H, C> {
+    fn clone(&self) -> Self {
+        Self {
+            node_cache_capacity: self.node_cache_capacity,
+            memory_limit: self.memory_limit,
+            cache_writes: self.cache_writes,
+            prune: self.prune,
+            empty_values: self.empty_values,
+            lenient: self.lenient,
+            limits: self.limits,
+            shadow: self.shadow,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl Default for TrieBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TrieBuilder {
+    /// Returns a builder with the defaults of `PatriciaTrie::new`.
+    pub fn new() -> Self {
+        Self {
+            node_cache_capacity: DEFAULT_NODE_CACHE_CAPACITY,
+            memory_limit: None,
+            cache_writes: false,
+            prune: true,
+            empty_values: EmptyValues::Remove,
+            lenient: false,
+            limits: DecodeLimits::default(),
+            shadow: false,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl PatriciaTrie<'static, MemoryDB> {
+    /// Returns a builder configuring a trie before opening it.
+    pub fn builder() -> TrieBuilder {
+        TrieBuilder::new()
+    }
+}
+
+impl PatriciaTrieMut<'static, MemoryDB> {
+    /// Returns a builder configuring a trie before opening it.
+    pub fn builder() -> TrieBuilder {
+        TrieBuilder::new()
+    }
+}
+
+impl<H: Hasher, C: NodeCodec> TrieBuilder<H, C> {
+    /// Sets how many decoded nodes are kept in memory, `0` disables the cache.
+    pub fn with_node_cache_capacity(mut self, capacity: usize) -> Self {
+        self.node_cache_capacity = capacity;
+        self
+    }
+
+    /// See `PatriciaTrieMut::with_memory_limit`.
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// See `PatriciaTrieMut::with_write_caching`.
+    pub fn with_write_caching(mut self, enabled: bool) -> Self {
+        self.cache_writes = enabled;
+        self
+    }
+
+    /// Hashes the nodes with `H2` and encodes them with `C2`.
+    pub fn with_hasher<H2: Hasher, C2: NodeCodec>(self) -> TrieBuilder<H2, C2> {
+        TrieBuilder {
+            node_cache_capacity: self.node_cache_capacity,
+            memory_limit: self.memory_limit,
+            cache_writes: self.cache_writes,
+            prune: self.prune,
+            empty_values: self.empty_values,
+            lenient: self.lenient,
+            limits: self.limits,
+            shadow: self.shadow,
+            marker: PhantomData,
+        }
+    }
+
+    /// See `PatriciaTrieMut::with_pruning`.
+    pub fn with_pruning(mut self, prune: bool) -> Self {
+        self.prune = prune;
+        self
+    }
+
+    /// See `PatriciaTrieMut::with_empty_values`.
+    pub fn with_empty_values(mut self, empty_values: EmptyValues) -> Self {
+        self.empty_values = empty_values;
+        self
+    }
+
+    /// See `PatriciaTrie::with_lenient_mode`.
+    pub fn with_lenient_mode(mut self) -> Self {
+        self.lenient = true;
+        self
+    }
+
+    /// See `PatriciaTrie::with_decode_limits`. The root opened is bound by them too.
+    pub fn with_decode_limits(mut self, limits: DecodeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// See `PatriciaTrieMut::with_shadow_verification`.
+    pub fn with_shadow_verification(mut self) -> Self {
+        self.shadow = true;
+        self
+    }
+
+    /// Creates an empty read-only trie.
+    pub fn build<'db, D: HashDB>(&self, db: &'db D) -> PatriciaTrie<'db, D, H, C> {
+        self.configure(PatriciaTrie::new_with_hasher(db))
+    }
+
+    /// Opens the read-only trie at `root`.
+    pub fn open<'db, D: HashDB>(
+        &self,
+        db: &'db D,
+        root: H256,
+    ) -> TrieResult<PatriciaTrie<'db, D, H, C>> {
+        let mut trie = self.build(db);
+        trie.root = trie.load_root(&root)?;
+        Ok(trie)
+    }
+
+    /// Creates an empty trie.
+    pub fn build_mut<'db, D: HashDB>(&self, db: &'db mut D) -> PatriciaTrieMut<'db, D, H, C> {
+        self.configure_mut(PatriciaTrieMut::new_with_hasher(db))
+    }
+
+    /// Opens the trie at `root`.
+    pub fn open_mut<'db, D: HashDB>(
+        &self,
+        db: &'db mut D,
+        root: H256,
+    ) -> TrieResult<PatriciaTrieMut<'db, D, H, C>> {
+        let mut trie = self.build_mut(db);
+        trie.open_at(root)?;
+        Ok(trie)
+    }
+
+    fn configure<'db, D: HashDB>(
+        &self,
+        trie: PatriciaTrie<'db, D, H, C>,
+    ) -> PatriciaTrie<'db, D, H, C> {
+        let trie = trie
+            .with_node_cache_capacity(self.node_cache_capacity)
+            .with_decode_limits(self.limits);
+        if self.lenient {
+            trie.with_lenient_mode()
+        } else {
+            trie
+        }
+    }
+
+    fn configure_mut<'db, D: HashDB>(
+        &self,
+        trie: PatriciaTrieMut<'db, D, H, C>,
+    ) -> PatriciaTrieMut<'db, D, H, C> {
+        let mut trie = trie
+            .with_node_cache_capacity(self.node_cache_capacity)
+            .with_decode_limits(self.limits)
+            .with_pruning(self.prune)
+            .with_empty_values(self.empty_values)
+            .with_write_caching(self.cache_writes);
+        if let Some(bytes) = self.memory_limit {
+            trie = trie.with_memory_limit(bytes);
+        }
+        if self.lenient {
+            trie = trie.with_lenient_mode();
+        }
+        if self.shadow {
+            trie = trie.with_shadow_verification();
+        }
+        trie
+    }
+}
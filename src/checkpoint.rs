@@ -0,0 +1,64 @@
+extern crate alloc;
+
+use crate::codec::NodeCodec;
+use crate::db::HashDB;
+use crate::hasher::{H256Set, Hasher};
+use crate::node::Node;
+use crate::trie::PatriciaTrieMut;
+use crate::H256;
+
+// The in-memory state of a trie when a checkpoint was opened. Nodes are never
+// modified in place once shared, so keeping the root keeps the whole state.
+#[derive(Debug)]
+pub(crate) struct Checkpoint {
+    root: Node,
+    root_hash: Option<H256>,
+    passing_keys: H256Set,
+    pending_bytes: usize,
+}
+
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> PatriciaTrieMut<'db, D, H, C> {
+    /// Opens a checkpoint, nested in the ones already open, that the changes made
+    /// from now on can be rolled back to, as the call frames of a transaction revert
+    /// their own changes.
+    ///
+    /// Checkpoints only cover the in-memory state: the trie cannot be committed to
+    /// the db until all of them are closed, and `root` fails with
+    /// `TrieError::OpenCheckpoints` meanwhile. Nodes a memory limit made the trie
+    /// write to the db while a checkpoint was open stay there once it is rolled
+    /// back.
+    pub fn begin(&mut self) {
+        self.checkpoints.push(Checkpoint {
+            root: self.root.clone(),
+            root_hash: self.root_hash,
+            passing_keys: self.passing_keys.clone(),
+            pending_bytes: self.pending_bytes,
+        });
+    }
+
+    /// Closes the innermost checkpoint and keeps its changes, which its parent, if
+    /// any, now rolls back along with its own. Returns false if no checkpoint is
+    /// open. Nothing is written to the db, which `root` does.
+    pub fn commit_checkpoint(&mut self) -> bool {
+        self.checkpoints.pop().is_some()
+    }
+
+    /// Closes the innermost checkpoint and discards the changes made since it was
+    /// opened. Returns false if no checkpoint is open.
+    pub fn rollback(&mut self) -> bool {
+        let checkpoint = match self.checkpoints.pop() {
+            Some(checkpoint) => checkpoint,
+            None => return false,
+        };
+        self.root = checkpoint.root;
+        self.root_hash = checkpoint.root_hash;
+        self.passing_keys = checkpoint.passing_keys;
+        self.pending_bytes = checkpoint.pending_bytes;
+        true
+    }
+
+    /// Returns the number of open checkpoints.
+    pub fn checkpoint_depth(&self) -> usize {
+        self.checkpoints.len()
+    }
+}
@@ -0,0 +1,93 @@
+extern crate alloc;
+use alloc::vec::Vec;
+
+use ethereum_types::Address;
+
+use crate::db::HashDB;
+use crate::errors::TrieError;
+use crate::state::{Account, StateTrie};
+use crate::trie::TrieResult;
+use crate::{keccak256, H256};
+
+/// The code of contracts, keyed by its keccak hash as the `code_hash` of their
+/// accounts refers to it.
+///
+/// The code must be kept in a db of its own, not in the db of the state trie: code
+/// is not reachable from any root, so `collect_garbage`, the releases of an
+/// `ArchiveDB` or `RefCountedDB` and the pruning of the trie would delete it there.
+/// The empty code is never stored, and is always found.
+pub struct CodeStore<D: HashDB> {
+    db: D,
+}
+
+impl<D: HashDB> CodeStore<D> {
+    pub fn new(db: D) -> Self {
+        Self { db }
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.db
+    }
+
+    pub fn into_inner(self) -> D {
+        self.db
+    }
+
+    /// Stores `code` and returns its hash.
+    pub fn insert(&mut self, code: Vec<u8>) -> H256 {
+        let hash = keccak256(&code);
+        if !code.is_empty() {
+            self.db.insert(hash, code);
+        }
+        hash
+    }
+
+    /// Returns the code with the hash `code_hash`, if it is stored.
+    pub fn get(&self, code_hash: &H256) -> Option<Vec<u8>> {
+        if *code_hash == empty_code_hash() {
+            return Some(Vec::new());
+        }
+        self.db.get(code_hash)
+    }
+
+    pub fn contains(&self, code_hash: &H256) -> bool {
+        *code_hash == empty_code_hash() || self.db.contains(code_hash)
+    }
+}
+
+impl<'db, D: HashDB> StateTrie<'db, D> {
+    /// Returns the code of the account at `address` from `codes`, or `None` if the
+    /// account does not exist. Fails with `TrieError::MissingBlob` if `codes` lacks
+    /// the code the account refers to.
+    pub fn get_code<S: HashDB>(
+        &self,
+        address: &Address,
+        codes: &CodeStore<S>,
+    ) -> TrieResult<Option<Vec<u8>>> {
+        match self.get_account(address)? {
+            Some(account) => codes
+                .get(&account.code_hash)
+                .map(Some)
+                .ok_or(TrieError::MissingBlob(account.code_hash)),
+            None => Ok(None),
+        }
+    }
+
+    /// Stores `code` in `codes` and sets its hash on the account at `address`,
+    /// creating the account if it does not exist. Returns the hash of the code.
+    pub fn set_code<S: HashDB>(
+        &mut self,
+        address: &Address,
+        code: Vec<u8>,
+        codes: &mut CodeStore<S>,
+    ) -> TrieResult<H256> {
+        let mut account = self.get_account(address)?.unwrap_or_default();
+        account.code_hash = codes.insert(code);
+        self.set_account(address, &account)?;
+        Ok(account.code_hash)
+    }
+}
+
+fn empty_code_hash() -> H256 {
+    Account::default().code_hash
+}
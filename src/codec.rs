@@ -0,0 +1,436 @@
+extern crate alloc;
+use alloc::vec::Vec;
+
+use rlp::{Prototype, Rlp};
+
+use crate::errors::TrieError;
+use crate::hasher::{decode_digest, Hasher};
+use crate::trie::TrieResult;
+use crate::H256;
+
+/// How an encoded node refers to one of its children.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChildReference<'a> {
+    Empty,
+    /// The digest of a child stored by hash.
+    Hash(&'a [u8]),
+    /// The encoding of a child smaller than a digest, embedded in its parent.
+    Inline(&'a [u8]),
+}
+
+/// The nibbles of a node path, packed two per byte with the high nibble first and
+/// starting at nibble `start` of `data`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PackedPath<'a> {
+    pub data: &'a [u8],
+    pub start: usize,
+}
+
+impl PackedPath<'_> {
+    pub fn len(&self) -> usize {
+        self.data.len() * 2 - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn at(&self, i: usize) -> u8 {
+        let i = self.start + i;
+        let byte = self.data[i / 2];
+        if i & 1 == 0 {
+            byte >> 4
+        } else {
+            byte & 0x0f
+        }
+    }
+}
+
+/// A node as read by a `NodeCodec`, borrowing from the encoded data.
+// Decoded nodes only live on the stack while being read, so branches are not boxed.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodedNode<'a> {
+    Empty,
+    Leaf(PackedPath<'a>, &'a [u8]),
+    Extension(PackedPath<'a>, ChildReference<'a>),
+    Branch([ChildReference<'a>; 16], Option<&'a [u8]>),
+}
+
+impl DecodedNode<'_> {
+    // Returns the digests of the children the node refers to by hash. Children
+    // embedded in their parent are smaller than a digest, so they cannot refer to
+    // other nodes by hash.
+    pub(crate) fn hashed_children<H: Hasher>(&self) -> TrieResult<Vec<H256>> {
+        let children = match self {
+            DecodedNode::Extension(_, child) => core::slice::from_ref(child),
+            DecodedNode::Branch(children, _) => &children[..],
+            DecodedNode::Empty | DecodedNode::Leaf(..) => &[],
+        };
+        let mut hashes = Vec::new();
+        for child in children {
+            if let ChildReference::Hash(digest) = child {
+                hashes.push(decode_digest::<H>(digest)?);
+            }
+        }
+        Ok(hashes)
+    }
+}
+
+/// Bounds on the nodes a trie decodes, so that nodes from an untrusted source, such
+/// as a peer during sync, cannot make it allocate or recurse without limit. Nothing
+/// is bounded by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// Longest path a leaf or an extension may hold, in nibbles.
+    pub max_path_len: usize,
+    /// Largest encoded node, in bytes.
+    pub max_node_size: usize,
+    /// Deepest nesting of nodes embedded in their parent, and most nodes in a proof.
+    pub max_depth: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self {
+            max_path_len: usize::MAX,
+            max_node_size: usize::MAX,
+            max_depth: usize::MAX,
+        }
+    }
+}
+
+impl DecodeLimits {
+    // Checks `node`, decoded from `size` bytes at `depth` below the node read from
+    // the db or the proof.
+    pub(crate) fn check(&self, node: &DecodedNode, size: usize, depth: usize) -> TrieResult<()> {
+        let path_len = match node {
+            DecodedNode::Leaf(path, _) | DecodedNode::Extension(path, _) => path.len(),
+            _ => 0,
+        };
+        if size > self.max_node_size || depth > self.max_depth || path_len > self.max_path_len {
+            return Err(TrieError::LimitExceeded);
+        }
+        Ok(())
+    }
+}
+
+/// The serialization of trie nodes, RLP by default.
+///
+/// Paths are given to the encoders one nibble per byte, without the leaf
+/// terminator. The encoders append to `out` and push to `hashes` the offset in
+/// `out` of the digest of each `Hash` child, in the order of the children: digests
+/// of new children are filled in there once known, so the encoding of a `Hash`
+/// child must not depend on the digest's value.
+pub trait NodeCodec {
+    /// Encoding of the empty node, the root of an empty trie.
+    const EMPTY_NODE: &'static [u8];
+
+    fn encode_leaf(path: &[u8], value: &[u8], out: &mut Vec<u8>);
+
+    fn encode_extension(
+        path: &[u8],
+        child: ChildReference<'_>,
+        out: &mut Vec<u8>,
+        hashes: &mut Vec<usize>,
+    );
+
+    fn encode_branch(
+        children: &[ChildReference<'_>; 16],
+        value: Option<&[u8]>,
+        out: &mut Vec<u8>,
+        hashes: &mut Vec<usize>,
+    );
+
+    fn decode(data: &[u8]) -> Result<DecodedNode<'_>, TrieError>;
+}
+
+/// The RLP encoding of Ethereum tries, with hex-prefix encoded paths.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RlpNodeCodec;
+
+impl NodeCodec for RlpNodeCodec {
+    const EMPTY_NODE: &'static [u8] = &[0x80];
+
+    fn encode_leaf(path: &[u8], value: &[u8], out: &mut Vec<u8>) {
+        append_header(out, compact_len(path) + string_len(value), 0xc0);
+        append_compact(out, path, true);
+        append_string(out, value);
+    }
+
+    fn encode_extension(
+        path: &[u8],
+        child: ChildReference<'_>,
+        out: &mut Vec<u8>,
+        hashes: &mut Vec<usize>,
+    ) {
+        append_header(out, compact_len(path) + child_len(&child), 0xc0);
+        append_compact(out, path, false);
+        append_child(out, child, hashes);
+    }
+
+    fn encode_branch(
+        children: &[ChildReference<'_>; 16],
+        value: Option<&[u8]>,
+        out: &mut Vec<u8>,
+        hashes: &mut Vec<usize>,
+    ) {
+        let children_len: usize = children.iter().map(child_len).sum();
+        append_header(out, children_len + value.map_or(1, string_len), 0xc0);
+        for child in children.iter() {
+            append_child(out, *child, hashes);
+        }
+        append_string(out, value.unwrap_or_default());
+    }
+
+    /// Only accepts the canonical encoding of a node, as produced by the `encode_`
+    /// functions, failing with `TrieError::NonCanonicalNode` otherwise. Nodes the
+    /// trie never leaves, such as a branch with a single child, are rejected too.
+    fn decode(data: &[u8]) -> Result<DecodedNode<'_>, TrieError> {
+        let r = Rlp::new(data);
+        if r.payload_info()?.total() != data.len() {
+            return Err(TrieError::NonCanonicalNode);
+        }
+        match r.prototype()? {
+            Prototype::Data(0) => Ok(DecodedNode::Empty),
+            Prototype::List(2) => {
+                let (path, is_leaf) = decode_compact(string(&r.at(0)?)?)?;
+                if is_leaf {
+                    // Empty values are never stored.
+                    let value = string(&r.at(1)?)?;
+                    if value.is_empty() {
+                        return Err(TrieError::NonCanonicalNode);
+                    }
+                    Ok(DecodedNode::Leaf(path, value))
+                } else {
+                    // The trie merges an extension with an empty path, or above an
+                    // empty node, a leaf or an extension, into the nodes around it.
+                    let child = decode_child(&r.at(1)?)?;
+                    let merged = match child {
+                        ChildReference::Empty => true,
+                        ChildReference::Inline(data) => Rlp::new(data).item_count()? == 2,
+                        ChildReference::Hash(_) => false,
+                    };
+                    if path.is_empty() || merged {
+                        return Err(TrieError::NonCanonicalNode);
+                    }
+                    Ok(DecodedNode::Extension(path, child))
+                }
+            }
+            Prototype::List(17) => {
+                let mut children = [ChildReference::Empty; 16];
+                for (child, item) in children.iter_mut().zip(r.iter()) {
+                    *child = decode_child(&item)?;
+                }
+                // The last element is a value node.
+                let value = string(&r.at(16)?)?;
+                let value = if value.is_empty() { None } else { Some(value) };
+                // A branch with a single child or value is a leaf or an extension.
+                let used = children
+                    .iter()
+                    .filter(|child| !matches!(child, ChildReference::Empty))
+                    .count();
+                if used + usize::from(value.is_some()) < 2 {
+                    return Err(TrieError::NonCanonicalNode);
+                }
+                Ok(DecodedNode::Branch(children, value))
+            }
+            _ => Err(TrieError::InvalidData),
+        }
+    }
+}
+
+fn decode_child<'a>(r: &Rlp<'a>) -> TrieResult<ChildReference<'a>> {
+    if r.is_list() {
+        return Ok(ChildReference::Inline(r.as_raw()));
+    }
+    let data = string(r)?;
+    if data.is_empty() {
+        Ok(ChildReference::Empty)
+    } else {
+        Ok(ChildReference::Hash(data))
+    }
+}
+
+// Reads an RLP string, which must not be a single byte below 0x80 behind a header:
+// such bytes encode as themselves. Longer headers than needed are already rejected
+// by `rlp`.
+fn string<'a>(r: &Rlp<'a>) -> TrieResult<&'a [u8]> {
+    if !r.is_data() {
+        return Err(TrieError::InvalidData);
+    }
+    let data = r.data()?;
+    if r.as_raw()[0] == 0x81 && data[0] < 0x80 {
+        return Err(TrieError::NonCanonicalNode);
+    }
+    Ok(data)
+}
+
+// Reads a hex-prefix encoded path, returning it with whether it is a leaf's.
+pub(crate) fn decode_compact(compact: &[u8]) -> TrieResult<(PackedPath<'_>, bool)> {
+    // node type    path length    |    prefix    hexchar
+    // --------------------------------------------------
+    // extension    even           |    0000      0x0
+    // extension    odd            |    0001      0x1
+    // leaf         even           |    0010      0x2
+    // leaf         odd            |    0011      0x3
+    let first = match compact.first() {
+        Some(byte) => *byte,
+        None => return Err(TrieError::InvalidCompactEncoding),
+    };
+    let (start, is_leaf) = match first >> 4 {
+        0x0 => (2, false),
+        0x1 => (1, false),
+        0x2 => (2, true),
+        0x3 => (1, true),
+        _ => return Err(TrieError::InvalidCompactEncoding),
+    };
+    // Even paths pad the flag with a zero nibble, anything else would give the
+    // same path a second encoding.
+    if start == 2 && first & 0x0f != 0 {
+        return Err(TrieError::InvalidCompactEncoding);
+    }
+    Ok((
+        PackedPath {
+            data: compact,
+            start,
+        },
+        is_leaf,
+    ))
+}
+
+// Returns the length of the hex-prefix encoding of `path` as an RLP string.
+fn compact_len(path: &[u8]) -> usize {
+    let len = 1 + path.len() / 2;
+    if len == 1 {
+        // The flag byte is always below 0x80 and encodes as itself.
+        1
+    } else {
+        header_len(len) + len
+    }
+}
+
+fn append_compact(out: &mut Vec<u8>, path: &[u8], is_leaf: bool) {
+    let len = 1 + path.len() / 2;
+    if len > 1 {
+        append_header(out, len, 0x80);
+    }
+    write_compact(out, path, is_leaf);
+}
+
+// Writes the hex-prefix encoding of `path`, without an RLP header.
+pub(crate) fn write_compact(out: &mut Vec<u8>, path: &[u8], is_leaf: bool) {
+    let mut flag = if is_leaf { 0x20 } else { 0x00 };
+    let mut rest = path;
+    if path.len() & 1 == 1 {
+        flag += 0x10 + path[0];
+        rest = &path[1..];
+    }
+    out.push(flag);
+    out.extend(rest.chunks_exact(2).map(|pair| (pair[0] << 4) | pair[1]));
+}
+
+fn child_len(child: &ChildReference<'_>) -> usize {
+    match child {
+        ChildReference::Empty => 1,
+        ChildReference::Hash(digest) => string_len(digest),
+        ChildReference::Inline(data) => data.len(),
+    }
+}
+
+fn append_child(out: &mut Vec<u8>, child: ChildReference<'_>, hashes: &mut Vec<usize>) {
+    match child {
+        ChildReference::Empty => out.push(0x80),
+        ChildReference::Hash(digest) => {
+            append_string(out, digest);
+            hashes.push(out.len() - digest.len());
+        }
+        ChildReference::Inline(data) => out.extend_from_slice(data),
+    }
+}
+
+fn string_len(data: &[u8]) -> usize {
+    if data.len() == 1 && data[0] < 0x80 {
+        1
+    } else {
+        header_len(data.len()) + data.len()
+    }
+}
+
+fn append_string(out: &mut Vec<u8>, data: &[u8]) {
+    if data.len() != 1 || data[0] >= 0x80 {
+        append_header(out, data.len(), 0x80);
+    }
+    out.extend_from_slice(data);
+}
+
+fn header_len(len: usize) -> usize {
+    if len <= 55 {
+        1
+    } else {
+        1 + 8 - (len as u64).leading_zeros() as usize / 8
+    }
+}
+
+// Appends the RLP header of a string (`offset` 0x80) or list (0xc0) of `len` bytes.
+fn append_header(out: &mut Vec<u8>, len: usize, offset: u8) {
+    if len <= 55 {
+        out.push(offset + len as u8);
+        return;
+    }
+    let bytes = (len as u64).to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count();
+    out.push(offset + 55 + (8 - skip) as u8);
+    out.extend_from_slice(&bytes[skip..]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn test_compact_paths() {
+        let path = [6, 11, 6, 5, 7, 9, 3, 1];
+        let mut out = Vec::new();
+        RlpNodeCodec::encode_leaf(&path, b"value", &mut out);
+        assert_eq!(
+            out,
+            rlp::encode_list::<Vec<u8>, _>(&[
+                vec![0x20, 0x6b, 0x65, 0x79, 0x31],
+                b"value".to_vec()
+            ])
+        );
+        match RlpNodeCodec::decode(&out).unwrap() {
+            DecodedNode::Leaf(packed, value) => {
+                assert_eq!(
+                    (0..packed.len()).map(|i| packed.at(i)).collect::<Vec<_>>(),
+                    path
+                );
+                assert_eq!(value, b"value");
+            }
+            node => panic!("unexpected node {:?}", node),
+        }
+
+        let mut out = Vec::new();
+        let mut hashes = Vec::new();
+        let digest = [0xaa; 32];
+        RlpNodeCodec::encode_extension(
+            &path[1..],
+            ChildReference::Hash(&digest),
+            &mut out,
+            &mut hashes,
+        );
+        assert_eq!(&out[hashes[0]..], &digest[..]);
+        match RlpNodeCodec::decode(&out).unwrap() {
+            DecodedNode::Extension(packed, child) => {
+                assert_eq!(
+                    (0..packed.len()).map(|i| packed.at(i)).collect::<Vec<_>>(),
+                    &path[1..]
+                );
+                assert_eq!(child, ChildReference::Hash(&digest));
+            }
+            node => panic!("unexpected node {:?}", node),
+        }
+    }
+}
@@ -0,0 +1,310 @@
+extern crate alloc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::codec::{NodeCodec, RlpNodeCodec};
+use crate::db::HashDB;
+use crate::encoder::Encoder;
+use crate::errors::TrieError;
+use crate::hasher::{Hasher, KeccakHasher};
+use crate::inspect::{NodeKind, NodeReference};
+use crate::nibbles::Nibbles;
+use crate::node::Node;
+use crate::trie::{PatriciaTrie, PatriciaTrieMut, TrieResult};
+use crate::H256;
+
+// A node on the path of the cursor, with the hash it was loaded from the db under
+// and the number of nibbles of the path leading to it.
+#[derive(Clone)]
+struct Frame {
+    node: Node,
+    stored: Option<H256>,
+    start: usize,
+}
+
+/// A cursor over the nodes of a trie, moving to nibble paths, up and down the
+/// trie and from one value to the next or previous one in key order.
+///
+/// The cursor always rests on a node, the root when it is created, and reports
+/// its path, hash and encoding. Nodes stored by hash are read from the db as the
+/// cursor reaches them. Steps that find nothing to move to, or fail to read a
+/// node, leave the cursor where it was.
+pub struct TrieCursor<'db, D: HashDB, H: Hasher = KeccakHasher, C: NodeCodec = RlpNodeCodec> {
+    trie: PatriciaTrie<'db, D, H, C>,
+    // The nodes from the root to the current one, which is last.
+    stack: Vec<Frame>,
+    // The nibbles leading to the current node.
+    path: Vec<u8>,
+}
+
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> PatriciaTrie<'db, D, H, C> {
+    /// Returns a cursor resting on the root of the trie.
+    pub fn cursor(&self) -> TrieResult<TrieCursor<'db, D, H, C>> {
+        let trie = self.clone();
+        let root = trie.resolve(self.root.clone(), 0)?;
+        Ok(TrieCursor {
+            trie,
+            stack: vec![root],
+            path: Vec::new(),
+        })
+    }
+
+    // Reads a node stored by hash from the db.
+    fn resolve(&self, node: Node, start: usize) -> TrieResult<Frame> {
+        let (node, stored) = match node {
+            Node::Hash(hash) => (self.recover_from_db(&hash)?, Some(hash)),
+            node => (node, None),
+        };
+        Ok(Frame {
+            node,
+            stored,
+            start,
+        })
+    }
+}
+
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> PatriciaTrieMut<'db, D, H, C> {
+    /// Returns a cursor resting on the root of the trie, including uncommitted
+    /// changes.
+    pub fn cursor(&self) -> TrieResult<TrieCursor<'_, D, H, C>> {
+        self.trie_ref().cursor()
+    }
+}
+
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> TrieCursor<'db, D, H, C> {
+    /// Returns the nibbles leading to the current node, one per byte.
+    pub fn path(&self) -> &[u8] {
+        &self.path
+    }
+
+    /// Returns how many nodes the current one is below the root.
+    pub fn depth(&self) -> usize {
+        self.stack.len() - 1
+    }
+
+    pub fn kind(&self) -> NodeKind {
+        match self.node() {
+            Node::Empty | Node::Hash(_) => NodeKind::Empty,
+            Node::Leaf(_) => NodeKind::Leaf,
+            Node::Extension(_) => NodeKind::Extension,
+            Node::Branch(_) => NodeKind::Branch,
+        }
+    }
+
+    /// Returns the hash the current node is referred to by, or `None` if it is
+    /// embedded in its parent. Nodes not committed yet are hashed as they would be
+    /// committed.
+    pub fn hash(&self) -> Option<H256> {
+        let frame = self.top();
+        if let Some(hash) = frame.stored.or_else(|| frame.node.cached_hash()) {
+            return Some(hash);
+        }
+        // The root is stored by hash whatever its size.
+        if self.stack.len() == 1 {
+            return Some(H::hash(&self.encoded()));
+        }
+        match self.trie.reference_to(&frame.node) {
+            NodeReference::Hash(hash) => Some(hash),
+            NodeReference::Inline => None,
+        }
+    }
+
+    /// Returns the encoding of the current node, with its children stored by hash
+    /// referred to by their hashes.
+    pub fn encoded(&self) -> Vec<u8> {
+        Encoder::<H, C>::new(0).encode_raw(self.node())
+    }
+
+    /// Returns the value held by the current node, a leaf or a branch with one.
+    pub fn value(&self) -> Option<&[u8]> {
+        match self.node() {
+            Node::Leaf(leaf) => Some(&leaf.value),
+            Node::Branch(branch) => branch.value.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Returns the key of the value held by the current node, if any.
+    pub fn key(&self) -> Option<Vec<u8>> {
+        let mut key = Nibbles::from_hex(&self.path);
+        match self.node() {
+            Node::Leaf(leaf) => key.extend(&leaf.key),
+            Node::Branch(branch) if branch.value.is_some() => {}
+            _ => return None,
+        }
+        Some(key.encode_raw().0)
+    }
+
+    /// Moves to the node at the nibble `path`, one nibble per byte, and returns
+    /// true if there is one. Otherwise the cursor rests on the deepest node whose
+    /// path is a prefix of `path`.
+    pub fn seek(&mut self, path: &[u8]) -> TrieResult<bool> {
+        if path.iter().any(|nibble| *nibble > 0x0f) {
+            return Err(TrieError::InvalidData);
+        }
+        self.stack.truncate(1);
+        self.path.clear();
+        while self.path.len() < path.len() {
+            let rest = &path[self.path.len()..];
+            // An extension is only entered if the path runs through its prefix.
+            if let Node::Extension(ext) = self.node() {
+                let prefix = &ext.prefix;
+                if prefix.len() > rest.len()
+                    || (0..prefix.len()).any(|i| prefix.at(i) != usize::from(rest[i]))
+                {
+                    return Ok(false);
+                }
+            }
+            if !self.descend(rest[0])? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Moves to the child of the current node whose path goes on with `nibble`:
+    /// that child of a branch, or the child of an extension whose prefix starts
+    /// with it. Returns false if there is none.
+    pub fn descend(&mut self, nibble: u8) -> TrieResult<bool> {
+        let (child, nibbles) = match self.node() {
+            Node::Branch(branch) if nibble < 16 => {
+                (branch.children[usize::from(nibble)].clone(), vec![nibble])
+            }
+            Node::Extension(ext) if ext.prefix.at(0) == usize::from(nibble) => {
+                let prefix = (0..ext.prefix.len()).map(|i| ext.prefix.at(i) as u8);
+                (ext.node.clone(), prefix.collect())
+            }
+            _ => return Ok(false),
+        };
+        if let Node::Empty = child {
+            return Ok(false);
+        }
+        let frame = self.trie.resolve(child, self.path.len() + nibbles.len())?;
+        self.path.extend(nibbles);
+        self.stack.push(frame);
+        Ok(true)
+    }
+
+    /// Moves to the parent of the current node. Returns false at the root.
+    pub fn ascend(&mut self) -> bool {
+        if self.stack.len() == 1 {
+            return false;
+        }
+        self.stack.pop();
+        self.path.truncate(self.top().start);
+        true
+    }
+
+    /// Moves to the next node holding a value in key order, a leaf or a branch with
+    /// one. Returns false if there is none.
+    pub fn next_leaf(&mut self) -> TrieResult<bool> {
+        self.try_move(|cursor| {
+            if cursor.first_below()? {
+                return Ok(true);
+            }
+            while cursor.depth() > 0 {
+                let index = cursor.index_in_branch();
+                cursor.ascend();
+                for nibble in index.map_or(16, |index| index + 1)..16 {
+                    if cursor.descend(nibble)? {
+                        return Ok(cursor.value().is_some() || cursor.first_below()?);
+                    }
+                }
+            }
+            Ok(false)
+        })
+    }
+
+    /// Moves to the previous node holding a value in key order, a leaf or a branch
+    /// with one. Returns false if there is none.
+    pub fn prev_leaf(&mut self) -> TrieResult<bool> {
+        self.try_move(|cursor| {
+            while cursor.depth() > 0 {
+                let index = cursor.index_in_branch();
+                cursor.ascend();
+                for nibble in (0..index.unwrap_or(0)).rev() {
+                    if cursor.descend(nibble)? {
+                        return cursor.last_at_or_below();
+                    }
+                }
+                // A branch comes before its children.
+                if cursor.value().is_some() {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        })
+    }
+
+    // Runs a move, putting the cursor back unless it succeeds.
+    fn try_move(&mut self, f: impl FnOnce(&mut Self) -> TrieResult<bool>) -> TrieResult<bool> {
+        let stack = self.stack.clone();
+        let path = self.path.clone();
+        let result = f(self);
+        if !matches!(result, Ok(true)) {
+            self.stack = stack;
+            self.path = path;
+        }
+        result
+    }
+
+    // Moves to the first node holding a value below the current one.
+    fn first_below(&mut self) -> TrieResult<bool> {
+        loop {
+            let moved = match self.node() {
+                Node::Branch(branch) => match branch.children.iter().position(is_present) {
+                    Some(nibble) => self.descend(nibble as u8)?,
+                    None => false,
+                },
+                Node::Extension(ext) => self.descend(ext.prefix.at(0) as u8)?,
+                _ => false,
+            };
+            if !moved {
+                return Ok(false);
+            }
+            if self.value().is_some() {
+                return Ok(true);
+            }
+        }
+    }
+
+    // Moves to the last node holding a value at or below the current one.
+    fn last_at_or_below(&mut self) -> TrieResult<bool> {
+        loop {
+            let moved = match self.node() {
+                Node::Branch(branch) => match branch.children.iter().rposition(is_present) {
+                    Some(nibble) => self.descend(nibble as u8)?,
+                    None => false,
+                },
+                Node::Extension(ext) => self.descend(ext.prefix.at(0) as u8)?,
+                _ => false,
+            };
+            if !moved {
+                return Ok(self.value().is_some());
+            }
+        }
+    }
+
+    // Returns the index of the current node in its parent, if that is a branch.
+    fn index_in_branch(&self) -> Option<u8> {
+        let parent = &self.stack[self.stack.len().checked_sub(2)?];
+        match parent.node {
+            Node::Branch(_) => Some(self.path[parent.start]),
+            _ => None,
+        }
+    }
+
+    fn top(&self) -> &Frame {
+        self.stack
+            .last()
+            .expect("the cursor always rests on a node")
+    }
+
+    fn node(&self) -> &Node {
+        &self.top().node
+    }
+}
+
+fn is_present(node: &Node) -> bool {
+    !matches!(node, Node::Empty)
+}
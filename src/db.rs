@@ -1,7 +1,10 @@
 extern crate alloc;
 use alloc::vec::Vec;
 use ethereum_types::H256;
-use hashbrown::HashMap;
+#[cfg(feature = "std")]
+use std::sync::{Arc, PoisonError, RwLock};
+
+use crate::hasher::H256Map;
 
 /// "DB" defines the "trait" of trie and database interaction.
 /// You should first write the data to the cache and write the data
@@ -31,22 +34,33 @@ pub trait HashDB {
         }
     }
 
+    /// Called by a commit once the nodes of the new `root` are inserted, before the
+    /// nodes it replaced are removed.
+    fn commit_root(&mut self, _root: &H256) {}
+
     /// Flush data to the DB from the cache.
     fn flush(&mut self);
 }
 
+/// A "DB" that is able to enumerate every node key it stores.
+/// Required by routines that need to find nodes which are no longer referenced.
+pub trait IterableHashDB: HashDB {
+    /// Returns the keys of all nodes in the database.
+    fn keys(&self) -> Vec<H256>;
+}
+
 #[derive(Default, Debug)]
 pub struct MemoryDB {
     // If "light" is true, the data is deleted from the database at the time of submission.
     light: bool,
-    storage: HashMap<H256, Vec<u8>>,
+    storage: H256Map<Vec<u8>>,
 }
 
 impl MemoryDB {
     pub fn new(light: bool) -> Self {
         MemoryDB {
             light,
-            storage: HashMap::new(),
+            storage: H256Map::default(),
         }
     }
 }
@@ -72,3 +86,68 @@ impl HashDB for MemoryDB {
 
     fn flush(&mut self) {}
 }
+
+impl IterableHashDB for MemoryDB {
+    fn keys(&self) -> Vec<H256> {
+        self.storage.keys().copied().collect()
+    }
+}
+
+/// A `MemoryDB` behind a lock, whose clones share its nodes, so that tries on
+/// several threads can use the same db.
+#[cfg(feature = "std")]
+#[derive(Default, Debug, Clone)]
+pub struct SharedMemoryDB(Arc<RwLock<MemoryDB>>);
+
+#[cfg(feature = "std")]
+impl SharedMemoryDB {
+    pub fn new(light: bool) -> Self {
+        SharedMemoryDB(Arc::new(RwLock::new(MemoryDB::new(light))))
+    }
+
+    // Calls `f` with the db locked for reading. A thread that panicked holding the
+    // lock cannot have left a map update half done, so poisoning is ignored.
+    fn read<R>(&self, f: impl FnOnce(&MemoryDB) -> R) -> R {
+        f(&self.0.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn write<R>(&self, f: impl FnOnce(&mut MemoryDB) -> R) -> R {
+        f(&mut self.0.write().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+#[cfg(feature = "std")]
+impl HashDB for SharedMemoryDB {
+    fn get(&self, key: &H256) -> Option<Vec<u8>> {
+        self.read(|db| db.get(key))
+    }
+
+    fn contains(&self, key: &H256) -> bool {
+        self.read(|db| db.contains(key))
+    }
+
+    fn insert(&mut self, key: H256, value: Vec<u8>) {
+        self.write(|db| db.insert(key, value))
+    }
+
+    fn remove(&mut self, key: &H256) {
+        self.write(|db| db.remove(key))
+    }
+
+    fn insert_batch(&mut self, keys: Vec<H256>, values: Vec<Vec<u8>>) {
+        self.write(|db| db.insert_batch(keys, values))
+    }
+
+    fn remove_batch(&mut self, keys: &[H256]) {
+        self.write(|db| db.remove_batch(keys))
+    }
+
+    fn flush(&mut self) {}
+}
+
+#[cfg(feature = "std")]
+impl IterableHashDB for SharedMemoryDB {
+    fn keys(&self) -> Vec<H256> {
+        self.read(|db| db.keys())
+    }
+}
@@ -0,0 +1,157 @@
+extern crate alloc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::codec::{NodeCodec, RlpNodeCodec};
+use crate::db::HashDB;
+use crate::hasher::{H256Set, Hasher, KeccakHasher};
+use crate::node::Node;
+use crate::trie::{PatriciaTrie, TrieResult};
+use crate::H256;
+
+/// The nodes stored by hash under one root and not under another, as found by
+/// `diff_nodes`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NodeDiff {
+    /// Nodes under the second root and not under the first, parents first: the
+    /// nodes a peer holding the first root needs to get to the second.
+    pub added: Vec<H256>,
+    /// Nodes under the first root and not under the second, parents first.
+    pub removed: Vec<H256>,
+}
+
+/// Returns the nodes under `root_b` that are not under `root_a`, and the other way
+/// round, both tries being stored in `db`.
+///
+/// Both tries are walked together in the order of the paths of their nodes, and the
+/// subtries they have at the same path with the same hash are skipped, so only the
+/// nodes on the paths that changed are read.
+pub fn diff_nodes<D: HashDB>(db: &D, root_a: H256, root_b: H256) -> TrieResult<NodeDiff> {
+    diff_nodes_with_hasher::<KeccakHasher, RlpNodeCodec, D>(db, root_a, root_b)
+}
+
+/// Same as `diff_nodes`, for tries hashing their nodes with `H` and encoding them
+/// with `C`.
+pub fn diff_nodes_with_hasher<H: Hasher, C: NodeCodec, D: HashDB>(
+    db: &D,
+    root_a: H256,
+    root_b: H256,
+) -> TrieResult<NodeDiff> {
+    let trie = PatriciaTrie::<D, H, C>::new_with_hasher(db);
+    let mut a = Walk::new(root_a);
+    let mut b = Walk::new(root_b);
+    let mut diff = NodeDiff::default();
+    loop {
+        match (a.peek(&trie)?, b.peek(&trie)?) {
+            (None, None) => break,
+            (Some((_, hash)), None) => {
+                diff.removed.push(hash);
+                a.expand(&trie)?;
+            }
+            (None, Some((_, hash))) => {
+                diff.added.push(hash);
+                b.expand(&trie)?;
+            }
+            (Some((path_a, hash_a)), Some((path_b, hash_b))) => {
+                if path_a < path_b {
+                    diff.removed.push(hash_a);
+                    a.expand(&trie)?;
+                } else if path_b < path_a {
+                    diff.added.push(hash_b);
+                    b.expand(&trie)?;
+                } else if hash_a == hash_b {
+                    a.skip();
+                    b.skip();
+                } else {
+                    diff.removed.push(hash_a);
+                    diff.added.push(hash_b);
+                    a.expand(&trie)?;
+                    b.expand(&trie)?;
+                }
+            }
+        }
+    }
+
+    // A node can sit at different paths in both tries, or at several in one.
+    let removed: H256Set = diff.removed.iter().copied().collect();
+    let added: H256Set = diff.added.iter().copied().collect();
+    let mut seen = H256Set::default();
+    diff.added
+        .retain(|hash| !removed.contains(hash) && seen.insert(*hash));
+    seen.clear();
+    diff.removed
+        .retain(|hash| !added.contains(hash) && seen.insert(*hash));
+    Ok(diff)
+}
+
+// A walk through the nodes of a trie in the order of their paths, parents first,
+// stopping at each node stored by hash.
+struct Walk {
+    // Nodes left to visit with their path, the next one last.
+    stack: Vec<(Vec<u8>, Node)>,
+}
+
+impl Walk {
+    fn new(root: H256) -> Self {
+        Walk {
+            stack: vec![(Vec::new(), Node::Hash(root))],
+        }
+    }
+
+    // Returns the path and hash of the next node stored by hash, walking through
+    // the nodes embedded in their parent on the way.
+    fn peek<D: HashDB, H: Hasher, C: NodeCodec>(
+        &mut self,
+        trie: &PatriciaTrie<D, H, C>,
+    ) -> TrieResult<Option<(Vec<u8>, H256)>> {
+        loop {
+            match self.stack.last() {
+                None => return Ok(None),
+                Some((path, Node::Hash(hash))) => return Ok(Some((path.clone(), *hash))),
+                Some(_) => {}
+            }
+            self.expand(trie)?;
+        }
+    }
+
+    // Moves past the next node stored by hash and the nodes below it.
+    fn skip(&mut self) {
+        self.stack.pop();
+    }
+
+    // Replaces the next node with its children, loading it first if it is stored by
+    // hash.
+    fn expand<D: HashDB, H: Hasher, C: NodeCodec>(
+        &mut self,
+        trie: &PatriciaTrie<D, H, C>,
+    ) -> TrieResult<()> {
+        let (path, node) = match self.stack.pop() {
+            Some(next) => next,
+            None => return Ok(()),
+        };
+        let node = match node {
+            // The root of the empty trie may not be stored.
+            Node::Hash(hash) if hash == H::hash(C::EMPTY_NODE) => Node::Empty,
+            Node::Hash(hash) => trie.recover_from_db(&hash)?,
+            node => node,
+        };
+        match node {
+            Node::Branch(branch) => {
+                for (i, child) in branch.children.iter().enumerate().rev() {
+                    if !matches!(child, Node::Empty) {
+                        let mut child_path = path.clone();
+                        child_path.push(i as u8);
+                        self.stack.push((child_path, child.clone()));
+                    }
+                }
+            }
+            Node::Extension(ext) => {
+                let mut child_path = path;
+                child_path.extend_from_slice(ext.prefix.get_data());
+                self.stack.push((child_path, ext.node.clone()));
+            }
+            Node::Empty | Node::Leaf(_) | Node::Hash(_) => {}
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::db::MemoryDB;
+use crate::hasher::keccak256;
+use crate::trie::{PatriciaTrieMut, TrieResult};
+use crate::H256;
+
+/// An operation replayed by `replay`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    /// Inserts a value, an empty one removing the key.
+    Insert(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+    Get(Vec<u8>),
+    Contains(Vec<u8>),
+    Root,
+}
+
+/// The result of an operation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Inserted,
+    Removed(bool),
+    Value(Option<Vec<u8>>),
+    Contains(bool),
+    Root(H256),
+}
+
+/// The first operation on which the trie and the reference disagree.
+#[derive(Debug)]
+pub struct Divergence {
+    /// Position of the operation in the sequence.
+    pub index: usize,
+    pub op: Op,
+    /// The outcome of the reference.
+    pub expected: Outcome,
+    /// The outcome of the trie, which may have failed.
+    pub actual: TrieResult<Outcome>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "operation {} ({:?}) diverged: expected {:?}, got {:?}",
+            self.index, self.op, self.expected, self.actual
+        )
+    }
+}
+
+/// Replays `ops` on a `PatriciaTrieMut` and on a reference made of a `BTreeMap` of
+/// the entries, whose root is computed by the `triehash` crate. Returns the first
+/// operation whose outcome differs.
+pub fn replay(ops: &[Op]) -> Result<(), Box<Divergence>> {
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    let mut entries = BTreeMap::new();
+    for (index, op) in ops.iter().enumerate() {
+        let (expected, actual) = match op {
+            Op::Insert(key, value) => {
+                if value.is_empty() {
+                    entries.remove(key);
+                } else {
+                    entries.insert(key.clone(), value.clone());
+                }
+                let actual = trie.insert(key, value.clone()).map(|_| Outcome::Inserted);
+                (Outcome::Inserted, actual)
+            }
+            Op::Remove(key) => (
+                Outcome::Removed(entries.remove(key).is_some()),
+                trie.remove(key).map(Outcome::Removed),
+            ),
+            Op::Get(key) => (
+                Outcome::Value(entries.get(key).cloned()),
+                trie.get(key).map(Outcome::Value),
+            ),
+            Op::Contains(key) => (
+                Outcome::Contains(entries.contains_key(key)),
+                trie.contains(key).map(Outcome::Contains),
+            ),
+            Op::Root => (
+                Outcome::Root(triehash::trie_root::<ReferenceHasher, _, _, _>(&entries)),
+                trie.root().map(Outcome::Root),
+            ),
+        };
+        if actual.as_ref().ok() != Some(&expected) {
+            return Err(Box::new(Divergence {
+                index,
+                op: op.clone(),
+                expected,
+                actual,
+            }));
+        }
+    }
+    Ok(())
+}
+
+// Keccak-256 as the hasher of the reference.
+struct ReferenceHasher;
+
+impl reference_hash_db::Hasher for ReferenceHasher {
+    type Out = H256;
+    type StdHasher = DigestHasher;
+    const LENGTH: usize = 32;
+
+    fn hash(data: &[u8]) -> H256 {
+        keccak256(data)
+    }
+}
+
+// Required by `reference_hash_db::Hasher`, but triehash never keys maps by digests.
+#[derive(Default)]
+struct DigestHasher(u64);
+
+impl core::hash::Hasher for DigestHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = self.0.rotate_left(8) ^ u64::from(*byte);
+        }
+    }
+}
@@ -0,0 +1,452 @@
+extern crate alloc;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use ethereum_types::H256;
+
+use crate::codec::{ChildReference, NodeCodec};
+use crate::hasher::{digest_bytes, H256Set, Hasher};
+use crate::node::{empty_children, Node, RawNodeOrHash};
+
+// Batches smaller than this are not worth spreading over threads.
+#[cfg(feature = "parallel")]
+const PARALLEL_HASH_MIN: usize = 64;
+
+const PLACEHOLDER: [u8; 32] = [0; 32];
+
+/// Encodes nodes without touching the trie, collecting the encoded nodes that have
+/// to be written to the db and the keys of all nodes the encoded tree refers to.
+///
+/// Nodes are encoded first, leaving zeroed space for the hashes of children that
+/// are not known yet, and hashed afterwards in batches: all nodes of the same height
+/// at once, so their children are hashed already. With the `parallel` feature, large
+/// batches are hashed on several threads.
+///
+/// The encoder also returns the encoded node as it stands once committed: nodes up
+/// to `retain` levels below it are rebuilt with their hash cached, and nodes stored
+/// in the db below them are replaced by their hash.
+pub(crate) struct Encoder<H: Hasher, C: NodeCodec> {
+    retain: usize,
+    // Encoded nodes stored by hash, children before their parents.
+    pending: Vec<Pending>,
+    hashes: Vec<H256>,
+    gen_keys: H256Set,
+    // Buffers kept for reuse by the encodings of embedded nodes.
+    buffers: Vec<Vec<u8>>,
+    // Scratch buffer for the offsets of the children stored by hash.
+    offsets: Vec<usize>,
+    marker: PhantomData<fn() -> (H, C)>,
+}
+
+struct Pending {
+    data: Vec<u8>,
+    holes: Vec<Hole>,
+    height: usize,
+}
+
+// Space left in an encoding for the hash of the pending child in `slot`.
+struct Hole {
+    offset: usize,
+    slot: usize,
+    child: usize,
+}
+
+// How a parent refers to a child node.
+enum ChildRef {
+    Empty,
+    Hash(H256),
+    Pending(usize),
+    // The encoding of a node small enough to be embedded in its parent.
+    Inline(Vec<u8>),
+}
+
+impl<H: Hasher, C: NodeCodec> Encoder<H, C> {
+    pub(crate) fn new(retain: usize) -> Self {
+        Encoder {
+            retain,
+            pending: Vec::new(),
+            hashes: Vec::new(),
+            gen_keys: H256Set::default(),
+            buffers: Vec::new(),
+            offsets: Vec::new(),
+            marker: PhantomData,
+        }
+    }
+
+    /// Encodes `n` as referenced by a parent and returns it as committed.
+    pub(crate) fn encode_node(&mut self, n: &Node) -> (RawNodeOrHash, Node) {
+        if let Node::Empty = n {
+            return (RawNodeOrHash::Node(C::EMPTY_NODE.to_vec()), Node::Empty);
+        }
+        let child = self.child_ref(n);
+        self.hash_pending();
+        match child {
+            ChildRef::Hash(hash) => (RawNodeOrHash::Hash(hash), self.committed(n, None, 0)),
+            ChildRef::Pending(index) => (
+                RawNodeOrHash::Hash(self.hashes[index]),
+                self.committed(n, Some(index), 0),
+            ),
+            ChildRef::Inline(data) => (RawNodeOrHash::Node(data), n.clone()),
+            ChildRef::Empty => unreachable!(),
+        }
+    }
+
+    /// Returns the full encoding of `n`, even if it is stored by hash.
+    pub(crate) fn encode_raw(&mut self, n: &Node) -> Vec<u8> {
+        if let Node::Empty = n {
+            return C::EMPTY_NODE.to_vec();
+        }
+        let mut holes = Vec::new();
+        let mut data = self.encode(n, &mut holes);
+        self.hash_pending();
+
+        for hole in holes {
+            data[hole.offset..hole.offset + H::LENGTH]
+                .copy_from_slice(digest_bytes::<H>(&self.hashes[hole.child]));
+        }
+        data
+    }
+
+    /// Returns the encoded nodes by hash, and the keys of all nodes referenced.
+    pub(crate) fn finish(mut self) -> (impl Iterator<Item = (H256, Vec<u8>)>, H256Set) {
+        self.gen_keys.extend(self.hashes.iter().copied());
+        let nodes = self
+            .hashes
+            .into_iter()
+            .zip(self.pending.into_iter().map(|pending| pending.data));
+        (nodes, self.gen_keys)
+    }
+
+    fn child_ref(&mut self, n: &Node) -> ChildRef {
+        if let Node::Empty = n {
+            return ChildRef::Empty;
+        }
+        // Returns the hash value directly to avoid double counting.
+        if let Node::Hash(hash_node) = n {
+            return ChildRef::Hash(*hash_node);
+        }
+        // Nodes unchanged since they were loaded are already in the db.
+        if let Some(hash) = n.cached_hash() {
+            self.gen_keys.insert(hash);
+            return ChildRef::Hash(hash);
+        }
+
+        let mut holes = Vec::new();
+        let data = self.encode(n, &mut holes);
+        // Nodes smaller than a digest are stored inside their parent. They are too
+        // small to refer to another node by hash.
+        if data.len() < H::LENGTH {
+            return ChildRef::Inline(data);
+        }
+
+        let height = holes
+            .iter()
+            .map(|hole| self.pending[hole.child].height + 1)
+            .max()
+            .unwrap_or(0);
+        self.pending.push(Pending {
+            data,
+            holes,
+            height,
+        });
+        ChildRef::Pending(self.pending.len() - 1)
+    }
+
+    // Encodes `n` into a buffer taken from the pool, recording a hole for each child
+    // whose hash is not known yet.
+    fn encode(&mut self, n: &Node, holes: &mut Vec<Hole>) -> Vec<u8> {
+        let mut out = self.buffers.pop().unwrap_or_default();
+        out.clear();
+        match n {
+            Node::Leaf(leaf) => {
+                let key = leaf.key.get_data();
+                C::encode_leaf(&key[..key.len() - 1], &leaf.value, &mut out);
+            }
+            Node::Branch(branch) => {
+                let children: [ChildRef; 16] =
+                    core::array::from_fn(|slot| self.child_ref(&branch.children[slot]));
+                let references = children.each_ref().map(reference::<H>);
+                self.offsets.clear();
+                C::encode_branch(
+                    &references,
+                    branch.value.as_deref(),
+                    &mut out,
+                    &mut self.offsets,
+                );
+                self.record_holes(children, holes);
+            }
+            Node::Extension(ext) => {
+                let child = self.child_ref(&ext.node);
+                self.offsets.clear();
+                C::encode_extension(
+                    ext.prefix.get_data(),
+                    reference::<H>(&child),
+                    &mut out,
+                    &mut self.offsets,
+                );
+                self.record_holes([child], holes);
+            }
+            Node::Empty | Node::Hash(_) => unreachable!(),
+        }
+        out
+    }
+
+    // Matches the offsets of the children stored by hash, in slot order, with the
+    // children, and puts the buffers of embedded children back in the pool.
+    fn record_holes<const N: usize>(&mut self, children: [ChildRef; N], holes: &mut Vec<Hole>) {
+        let mut offsets = self.offsets.iter();
+        for (slot, child) in IntoIterator::into_iter(children).enumerate() {
+            match child {
+                ChildRef::Hash(_) => {
+                    offsets.next();
+                }
+                ChildRef::Pending(child) => holes.push(Hole {
+                    offset: *offsets.next().expect("codec reported too few hashes"),
+                    slot,
+                    child,
+                }),
+                ChildRef::Inline(data) => self.buffers.push(data),
+                ChildRef::Empty => {}
+            }
+        }
+    }
+
+    // Hashes the pending nodes by increasing height, filling in the hashes of their
+    // children before hashing each batch.
+    fn hash_pending(&mut self) {
+        let done = self.hashes.len();
+        let max_height = match self.pending[done..].iter().map(|p| p.height).max() {
+            Some(height) => height,
+            None => return,
+        };
+        let mut batches: Vec<Vec<usize>> = (0..=max_height).map(|_| Vec::new()).collect();
+        for (index, pending) in self.pending.iter().enumerate().skip(done) {
+            batches[pending.height].push(index);
+        }
+
+        self.hashes.resize(self.pending.len(), H256::zero());
+        for batch in batches {
+            for &index in batch.iter() {
+                let pending = &mut self.pending[index];
+                for hole in pending.holes.iter() {
+                    let hash = self.hashes[hole.child];
+                    pending.data[hole.offset..hole.offset + H::LENGTH]
+                        .copy_from_slice(digest_bytes::<H>(&hash));
+                }
+            }
+            for (index, hash) in batch.iter().zip(self.hash_batch(&batch)) {
+                self.hashes[*index] = hash;
+            }
+        }
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    fn hash_batch(&self, batch: &[usize]) -> Vec<H256> {
+        batch
+            .iter()
+            .map(|index| H::hash(&self.pending[*index].data))
+            .collect()
+    }
+
+    #[cfg(feature = "parallel")]
+    fn hash_batch(&self, batch: &[usize]) -> Vec<H256> {
+        use rayon::prelude::*;
+
+        if batch.len() < PARALLEL_HASH_MIN {
+            return batch
+                .iter()
+                .map(|index| H::hash(&self.pending[*index].data))
+                .collect();
+        }
+        batch
+            .par_iter()
+            .map(|index| H::hash(&self.pending[*index].data))
+            .collect()
+    }
+
+    // Returns `n` as it stands once committed. `pending` is its index among the
+    // pending nodes if it was encoded and stored by hash.
+    fn committed(&self, n: &Node, pending: Option<usize>, depth: usize) -> Node {
+        let index = match pending {
+            Some(index) => index,
+            None => {
+                return match n.cached_hash() {
+                    Some(hash) if depth > self.retain => Node::from_hash(hash),
+                    _ => n.clone(),
+                };
+            }
+        };
+        let hash = self.hashes[index];
+        if depth > self.retain {
+            return Node::from_hash(hash);
+        }
+
+        let holes = &self.pending[index].holes;
+        let child_at = |slot: usize| {
+            holes
+                .iter()
+                .find(|hole| hole.slot == slot)
+                .map(|hole| hole.child)
+        };
+        let rebuilt = match n {
+            Node::Leaf(_) => n.clone(),
+            Node::Branch(branch) => {
+                let mut children = empty_children();
+                for (slot, child) in branch.children.iter().enumerate() {
+                    children[slot] = self.committed(child, child_at(slot), depth + 1);
+                }
+                Node::from_branch(children, branch.value.clone())
+            }
+            Node::Extension(ext) => {
+                let child = self.committed(&ext.node, child_at(0), depth + 1);
+                Node::from_extension(ext.prefix.clone(), child)
+            }
+            Node::Empty | Node::Hash(_) => unreachable!(),
+        };
+        rebuilt.with_hash(hash)
+    }
+}
+
+// The digest a parent refers to a child with, zeroed for a pending child.
+fn reference<H: Hasher>(child: &ChildRef) -> ChildReference<'_> {
+    match child {
+        ChildRef::Empty => ChildReference::Empty,
+        ChildRef::Hash(hash) => ChildReference::Hash(digest_bytes::<H>(hash)),
+        ChildRef::Pending(_) => ChildReference::Hash(&PLACEHOLDER[..H::LENGTH]),
+        ChildRef::Inline(data) => ChildReference::Inline(data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::BTreeMap;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use rlp::RlpStream;
+
+    use crate::db::MemoryDB;
+    use crate::hasher::keccak256;
+    use crate::trie::PatriciaTrieMut;
+
+    // Encodes `n` the straightforward way, recursing into its children and hashing
+    // each as soon as it is encoded, with `rlp` instead of the codec.
+    fn reference_encode(n: &Node) -> Vec<u8> {
+        match n {
+            Node::Empty => rlp::NULL_RLP.to_vec(),
+            Node::Leaf(leaf) => {
+                let mut stream = RlpStream::new_list(2);
+                stream.append(&leaf.key.encode_compact());
+                stream.append(&leaf.value);
+                stream.out().to_vec()
+            }
+            Node::Extension(ext) => {
+                let mut stream = RlpStream::new_list(2);
+                stream.append(&ext.prefix.encode_compact());
+                reference_child(&ext.node, &mut stream);
+                stream.out().to_vec()
+            }
+            Node::Branch(branch) => {
+                let mut stream = RlpStream::new_list(17);
+                for child in branch.children.iter() {
+                    reference_child(child, &mut stream);
+                }
+                match &branch.value {
+                    Some(value) => stream.append(value),
+                    None => stream.append_empty_data(),
+                };
+                stream.out().to_vec()
+            }
+            Node::Hash(_) => panic!("the reference encodes tries held in memory"),
+        }
+    }
+
+    fn reference_child(n: &Node, stream: &mut RlpStream) {
+        if let Node::Empty = n {
+            stream.append_empty_data();
+            return;
+        }
+        let data = reference_encode(n);
+        if data.len() < 32 {
+            stream.append_raw(&data, 1);
+        } else {
+            stream.append(&keccak256(&data).as_bytes());
+        }
+    }
+
+    // Returns the root of a trie built in memory from `entries`.
+    fn reference_root(entries: &BTreeMap<Vec<u8>, Vec<u8>>) -> H256 {
+        let mut memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrieMut::new(&mut memdb);
+        for (key, value) in entries.iter() {
+            trie.insert(key, value.clone()).unwrap();
+        }
+        keccak256(&reference_encode(&trie.root))
+    }
+
+    #[test]
+    fn test_encoder_matches_recursive_encoding() {
+        let mut rng = StdRng::seed_from_u64(3);
+        for _ in 0..30 {
+            let mut memdb = MemoryDB::new(true);
+            let mut trie =
+                PatriciaTrieMut::new(&mut memdb).with_retained_levels(rng.gen_range(0, 3));
+            let mut entries = BTreeMap::new();
+            assert_eq!(trie.root().unwrap(), reference_root(&entries));
+
+            // Each round of changes is committed, so later rounds encode changed
+            // nodes next to children stored by hash or unchanged since loaded.
+            for _ in 0..4 {
+                for _ in 0..rng.gen_range(1, 200) {
+                    // Few short keys, so that keys are prefixes of others, and values
+                    // short enough for nodes to be embedded in their parents.
+                    let key: Vec<u8> = (0..rng.gen_range(1, 5))
+                        .map(|_| rng.gen_range(0, 6))
+                        .collect();
+                    if rng.gen_range(0, 4) == 0 {
+                        trie.remove(&key).unwrap();
+                        entries.remove(&key);
+                    } else {
+                        let value: Vec<u8> = (0..rng.gen_range(1, 50)).map(|_| rng.gen()).collect();
+                        trie.insert(&key, value.clone()).unwrap();
+                        entries.insert(key, value);
+                    }
+                }
+                assert_eq!(trie.root().unwrap(), reference_root(&entries));
+            }
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_parallel_hashing_matches_sequential() {
+        use crate::codec::RlpNodeCodec;
+        use crate::hasher::KeccakHasher;
+        use alloc::vec;
+
+        // Hashed keys spread the leaves over full 16-way branches, with far more
+        // nodes at the lower heights than it takes to hash them on several threads.
+        let mut memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrieMut::new(&mut memdb);
+        for i in 0..4096u32 {
+            let key = keccak256(&i.to_be_bytes());
+            trie.insert(key.as_bytes(), vec![i as u8; 40]).unwrap();
+        }
+
+        let mut encoder = Encoder::<KeccakHasher, RlpNodeCodec>::new(0);
+        let (root, _) = encoder.encode_node(&trie.root);
+        let mut batches = vec![0; encoder.pending.len()];
+        for pending in encoder.pending.iter() {
+            batches[pending.height] += 1;
+        }
+        assert!(batches.iter().any(|len| *len >= PARALLEL_HASH_MIN));
+        for (pending, hash) in encoder.pending.iter().zip(encoder.hashes.iter()) {
+            assert_eq!(*hash, keccak256(&pending.data));
+        }
+
+        let root = match root {
+            RawNodeOrHash::Hash(hash) => hash,
+            RawNodeOrHash::Node(_) => panic!("the root of a large trie is stored by hash"),
+        };
+        assert_eq!(trie.root().unwrap(), root);
+    }
+}
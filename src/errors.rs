@@ -1,12 +1,106 @@
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::fmt;
 use rlp::DecoderError;
 
+use crate::H256;
+
 #[derive(Debug)]
 pub enum TrieError {
     Decoder(DecoderError),
     InvalidData,
     InvalidStateRoot,
     InvalidProof,
+    /// A node on the path was not found in the db, which may be pruned or corrupted.
+    MissingNode(H256),
+    /// A value kept out of the trie, in a `BlobStore`, as the code of an account in a
+    /// `CodeStore` or as the preimage of a key of a secure trie, was not found there.
+    MissingBlob(H256),
+    /// A node path is not a valid hex-prefix encoding: bad flag or padding.
+    InvalidCompactEncoding,
+    /// A node is not in the one encoding the trie gives it, such as RLP with a longer
+    /// header than needed or trailing bytes, so that it would hash differently from
+    /// the node it decodes to.
+    NonCanonicalNode,
+    /// A node is larger or deeper than the `DecodeLimits` of the trie allow.
+    LimitExceeded,
+    /// A snapshot is in a format version this version of the crate cannot read.
+    UnsupportedSnapshotVersion(u16),
+    /// A snapshot was written for tries with another hasher or node codec.
+    SnapshotHasherMismatch,
+    /// Record `n` of a snapshot, counting its header as record 0, does not match its
+    /// checksum.
+    SnapshotChecksumMismatch(usize),
+    /// A root committed with shadow verification differs from the root of the trie
+    /// rebuilt from its entries, first at the subtrie at the nibble `path`, whose top
+    /// node hashes to `committed` instead of `recomputed`.
+    RootMismatch {
+        path: Vec<u8>,
+        committed: H256,
+        recomputed: H256,
+    },
+    /// A commit to the db was refused while `n` checkpoints were open, since it
+    /// would leave nothing for them to roll back to. They stay open.
+    OpenCheckpoints(usize),
+    /// `error` was met reading the node stored under `hash`.
+    InNode {
+        hash: H256,
+        error: Box<TrieError>,
+    },
+    /// `error` was met accessing `key`.
+    AtKey {
+        key: Vec<u8>,
+        error: Box<TrieError>,
+    },
+    /// `error` was met checking the proof item at `index`, which is the number of
+    /// items if one was missing.
+    AtProofItem {
+        index: usize,
+        error: Box<TrieError>,
+    },
+}
+
+impl TrieError {
+    /// Returns the error without the context around it.
+    pub fn root_cause(&self) -> &TrieError {
+        let mut error = self;
+        while let Some(inner) = error.inner() {
+            error = inner;
+        }
+        error
+    }
+
+    pub(crate) fn in_node(self, hash: H256) -> Self {
+        TrieError::InNode {
+            hash,
+            error: Box::new(self),
+        }
+    }
+
+    pub(crate) fn at_key(self, key: &[u8]) -> Self {
+        TrieError::AtKey {
+            key: key.to_vec(),
+            error: Box::new(self),
+        }
+    }
+
+    pub(crate) fn at_proof_item(self, index: usize) -> Self {
+        TrieError::AtProofItem {
+            index,
+            error: Box::new(self),
+        }
+    }
+
+    // The error a context variant wraps.
+    fn inner(&self) -> Option<&TrieError> {
+        match self {
+            TrieError::InNode { error, .. }
+            | TrieError::AtKey { error, .. }
+            | TrieError::AtProofItem { error, .. } => Some(error),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for TrieError {
@@ -16,6 +110,60 @@ impl fmt::Display for TrieError {
             TrieError::InvalidData => write!(f, "trie error: invalid data"),
             TrieError::InvalidStateRoot => write!(f, "trie error: invalid state root"),
             TrieError::InvalidProof => write!(f, "trie error: invalid proof"),
+            TrieError::MissingNode(ref hash) => write!(f, "trie error: missing node {:?}", hash),
+            TrieError::MissingBlob(ref hash) => write!(f, "trie error: missing blob {:?}", hash),
+            TrieError::InvalidCompactEncoding => write!(f, "trie error: invalid compact encoding"),
+            TrieError::NonCanonicalNode => write!(f, "trie error: non-canonical node encoding"),
+            TrieError::LimitExceeded => write!(f, "trie error: decode limit exceeded"),
+            TrieError::UnsupportedSnapshotVersion(version) => {
+                write!(f, "trie error: unsupported snapshot version {}", version)
+            }
+            TrieError::SnapshotHasherMismatch => {
+                write!(f, "trie error: snapshot written with another hasher")
+            }
+            TrieError::SnapshotChecksumMismatch(record) => {
+                write!(
+                    f,
+                    "trie error: checksum mismatch in snapshot record {}",
+                    record
+                )
+            }
+            TrieError::RootMismatch {
+                ref path,
+                ref committed,
+                ref recomputed,
+            } => {
+                write!(f, "trie error: committed root differs at path ")?;
+                path.iter()
+                    .try_for_each(|nibble| write!(f, "{:x}", nibble))?;
+                write!(f, ": {:?} instead of {:?}", committed, recomputed)
+            }
+            TrieError::OpenCheckpoints(n) => {
+                write!(f, "trie error: commit with {} open checkpoints", n)
+            }
+            TrieError::InNode {
+                ref hash,
+                ref error,
+            } => write!(f, "{} in node {:?}", error, hash),
+            TrieError::AtKey { ref key, ref error } => {
+                write!(f, "{} at key 0x", error)?;
+                key.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+            }
+            TrieError::AtProofItem { index, ref error } => {
+                write!(f, "{} at proof item {}", error, index)
+            }
+        }
+    }
+}
+
+// `DecoderError` only implements `Error` with the `std` feature of `rlp`, so it is
+// only given as the source with ours.
+impl core::error::Error for TrieError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "std")]
+            TrieError::Decoder(err) => Some(err),
+            _ => self.inner().map(|error| error as _),
         }
     }
 }
@@ -1,7 +1,72 @@
 extern crate alloc;
-use crate::trie::TrieResult;
+use crate::blob::BlobStore;
+use crate::errors::TrieError;
+use crate::root::trie_root;
+use crate::trie::{TrieResult, TrieTryIterator};
 use crate::{keccak256, HashDB, PatriciaTrie, PatriciaTrieMut, H256};
+use alloc::sync::Arc;
 use alloc::vec::Vec;
+use ethereum_types::Address;
+use rlp::{DecoderError, Rlp, RlpStream};
+
+/// Returns the root of the trie mapping `rlp(index)` to each item, as the
+/// transactions and receipts of a block are stored.
+pub fn ordered_trie_root<I, V>(items: I) -> H256
+where
+    I: IntoIterator<Item = V>,
+    V: AsRef<[u8]>,
+{
+    trie_root(
+        items
+            .into_iter()
+            .enumerate()
+            .map(|(index, item)| (rlp::encode(&index), item)),
+    )
+}
+
+/// A validator withdrawal included in a block (EIP-4895).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Withdrawal {
+    pub index: u64,
+    pub validator_index: u64,
+    pub address: Address,
+    /// Amount in Gwei.
+    pub amount: u64,
+}
+
+impl rlp::Encodable for Withdrawal {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        s.begin_list(4);
+        s.append(&self.index);
+        s.append(&self.validator_index);
+        s.append(&self.address.as_bytes());
+        s.append(&self.amount);
+    }
+}
+
+impl rlp::Decodable for Withdrawal {
+    fn decode(rlp: &Rlp) -> Result<Self, DecoderError> {
+        if rlp.item_count()? != 4 {
+            return Err(DecoderError::RlpIncorrectListLen);
+        }
+        let address = rlp.at(2)?.data()?;
+        if address.len() != 20 {
+            return Err(DecoderError::RlpInvalidLength);
+        }
+        Ok(Withdrawal {
+            index: rlp.val_at(0)?,
+            validator_index: rlp.val_at(1)?,
+            address: Address::from_slice(address),
+            amount: rlp.val_at(3)?,
+        })
+    }
+}
+
+/// Returns the withdrawals root of a block, the `ordered_trie_root` of the RLP
+/// encoded withdrawals.
+pub fn withdrawals_root(withdrawals: &[Withdrawal]) -> H256 {
+    ordered_trie_root(withdrawals.iter().map(rlp::encode))
+}
 
 pub struct TrieDB<'db, D: HashDB> {
     trie: PatriciaTrie<'db, D>,
@@ -13,10 +78,59 @@ pub struct TrieDBMut<'db, D: HashDB> {
 
 pub struct SecTrieDB<'db, D: HashDB> {
     trie: TrieDB<'db, D>,
+    preimages: Option<Preimages>,
 }
 
 pub struct SecTrieDBMut<'db, D: HashDB> {
     trie: TrieDBMut<'db, D>,
+    preimages: Option<Preimages>,
+}
+
+// The store mapping the hashed keys of a secure trie back to the keys.
+type Preimages = Arc<dyn BlobStore + Send + Sync>;
+
+/// An iterator over the keys and values of a trie keyed by hashes, returned by
+/// `iter` on `TrieDB`, `SecTrieDB` and their mutable counterparts.
+///
+/// Like `TrieIterator`, it ends at the first node it fails to read, and so it does
+/// at a key that is not 32 bytes long, which only a corrupt db holds. See
+/// `TrieDBTryIterator` for the error.
+pub struct TrieDBIterator<'a, D: HashDB> {
+    inner: TrieDBTryIterator<'a, D>,
+}
+
+impl<'a, D: HashDB> Iterator for TrieDBIterator<'a, D> {
+    type Item = (H256, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()?.ok()
+    }
+}
+
+/// An iterator over the keys and values of a trie keyed by hashes, returned by
+/// `try_iter` on `TrieDB`, `SecTrieDB` and their mutable counterparts.
+///
+/// Like `TrieTryIterator`, it yields the error it fails to read a node with and
+/// ends, and so it does with `TrieError::InvalidData` at a key that is not 32 bytes
+/// long.
+pub struct TrieDBTryIterator<'a, D: HashDB> {
+    // `None` once the iteration failed.
+    inner: Option<TrieTryIterator<'a, D>>,
+}
+
+impl<'a, D: HashDB> Iterator for TrieDBTryIterator<'a, D> {
+    type Item = TrieResult<(H256, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.inner.as_mut()?.next()?.and_then(|(key, value)| {
+            let key = entry_key(&key).ok_or_else(|| TrieError::InvalidData.at_key(&key))?;
+            Ok((key, value))
+        });
+        if entry.is_err() {
+            self.inner = None;
+        }
+        Some(entry)
+    }
 }
 
 impl<'db, D: HashDB> TrieDB<'db, D> {
@@ -24,10 +138,24 @@ impl<'db, D: HashDB> TrieDB<'db, D> {
         self.trie.hashdb()
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (H256, Vec<u8>)> + '_ {
-        self.trie
-            .iter()
-            .map(|(key, value)| (H256::from_slice(&key), value))
+    pub fn iter(&self) -> TrieDBIterator<'db, D> {
+        TrieDBIterator {
+            inner: self.try_iter(),
+        }
+    }
+
+    /// Same as `iter`, but yields the node it fails to read, or a key that is not
+    /// 32 bytes long, as an error.
+    pub fn try_iter(&self) -> TrieDBTryIterator<'db, D> {
+        TrieDBTryIterator {
+            inner: Some(self.trie.try_iter()),
+        }
+    }
+
+    /// Fails with `TrieError::InvalidData` at the first key that is not 32 bytes
+    /// long, after calling `f` with the entries before it, as `iter` does.
+    pub fn for_each_entry(&self, f: impl FnMut(&H256, &[u8])) -> TrieResult<()> {
+        for_each_hashed_entry(|visit| self.trie.for_each_entry(visit), f)
     }
 
     pub fn new(db: &'db mut D) -> Self {
@@ -47,15 +175,24 @@ impl<'db, D: HashDB> SecTrieDB<'db, D> {
     pub fn new(db: &'db mut D) -> Self {
         Self {
             trie: TrieDB::new(db),
+            preimages: None,
         }
     }
 
     pub fn from(db: &'db D, root: H256) -> TrieResult<Self> {
         Ok(Self {
             trie: TrieDB::from(db, root)?,
+            preimages: None,
         })
     }
 
+    /// Reads the keys the hashed keys of the trie were computed from in `store`,
+    /// where `SecTrieDBMut::with_preimage_store` recorded them.
+    pub fn with_preimage_store(mut self, store: impl BlobStore + Send + Sync + 'static) -> Self {
+        self.preimages = Some(Arc::new(store));
+        self
+    }
+
     pub fn hashdb(&self) -> &D {
         self.trie.hashdb()
     }
@@ -65,15 +202,32 @@ impl<'db, D: HashDB> SecTrieDBMut<'db, D> {
     pub fn new(db: &'db mut D) -> Self {
         Self {
             trie: TrieDBMut::new(db),
+            preimages: None,
         }
     }
 
     pub fn from(db: &'db mut D, root: H256) -> TrieResult<Self> {
         Ok(Self {
             trie: TrieDBMut::from(db, root)?,
+            preimages: None,
         })
     }
 
+    /// Sets whether `root` prunes the replaced nodes, see
+    /// `PatriciaTrieMut::with_pruning`.
+    pub fn with_pruning(mut self, prune: bool) -> Self {
+        self.trie = self.trie.with_pruning(prune);
+        self
+    }
+
+    /// Records in `store` the key each inserted value is keyed by the hash of, so the
+    /// trie can be walked by its original keys, see `iter_preimages`. Preimages are
+    /// kept when their key is removed, as other roots may still hold it.
+    pub fn with_preimage_store(mut self, store: impl BlobStore + Send + Sync + 'static) -> Self {
+        self.preimages = Some(Arc::new(store));
+        self
+    }
+
     pub fn hashdb_mut(&mut self) -> &mut D {
         self.trie.hashdb_mut()
     }
@@ -84,10 +238,35 @@ impl<'db, D: HashDB> SecTrieDBMut<'db, D> {
 }
 
 impl<'db, D: HashDB> TrieDBMut<'db, D> {
-    pub fn iter(&self) -> impl Iterator<Item = (H256, Vec<u8>)> + '_ {
-        self.trie
-            .iter()
-            .map(|(key, value)| (H256::from_slice(&key), value))
+    /// Includes the changes made since the last commit.
+    pub fn iter(&self) -> TrieDBIterator<'_, D> {
+        TrieDBIterator {
+            inner: self.try_iter(),
+        }
+    }
+
+    /// Same as `iter`, but yields the node it fails to read, or a key that is not
+    /// 32 bytes long, as an error.
+    pub fn try_iter(&self) -> TrieDBTryIterator<'_, D> {
+        TrieDBTryIterator {
+            inner: Some(self.trie.try_iter()),
+        }
+    }
+
+    /// Iterates over the trie as of the last commit, see
+    /// `PatriciaTrieMut::iter_committed`.
+    pub fn iter_committed(&self) -> TrieResult<TrieDBIterator<'_, D>> {
+        Ok(TrieDBIterator {
+            inner: TrieDBTryIterator {
+                inner: Some(self.trie.committed()?.try_iter()),
+            },
+        })
+    }
+
+    /// Fails with `TrieError::InvalidData` at the first key that is not 32 bytes
+    /// long, after calling `f` with the entries before it, as `iter` does.
+    pub fn for_each_entry(&self, f: impl FnMut(&H256, &[u8])) -> TrieResult<()> {
+        for_each_hashed_entry(|visit| self.trie.for_each_entry(visit), f)
     }
 
     pub fn new(db: &'db mut D) -> Self {
@@ -101,6 +280,14 @@ impl<'db, D: HashDB> TrieDBMut<'db, D> {
             trie: PatriciaTrieMut::from(db, root)?,
         })
     }
+
+    /// Sets whether `root` prunes the replaced nodes, see
+    /// `PatriciaTrieMut::with_pruning`.
+    pub fn with_pruning(mut self, prune: bool) -> Self {
+        self.trie = self.trie.with_pruning(prune);
+        self
+    }
+
     pub fn hashdb(&self) -> &D {
         self.trie.hashdb()
     }
@@ -185,6 +372,32 @@ impl<'db, D: HashDB> SecTrieDB<'db, D> {
     pub fn trie(&self) -> &TrieDB<'db, D> {
         &self.trie
     }
+
+    /// Returns an iterator over the hashed keys and the values of the trie, see
+    /// `iter_preimages` for the original keys.
+    pub fn iter(&self) -> TrieDBIterator<'db, D> {
+        self.trie.iter()
+    }
+
+    /// Same as `iter`, but yields the node it fails to read as an error, see
+    /// `TrieDB::try_iter`.
+    pub fn try_iter(&self) -> TrieDBTryIterator<'db, D> {
+        self.trie.try_iter()
+    }
+
+    /// Calls `f` with every hashed key and value in the trie, see
+    /// `TrieDB::for_each_entry`.
+    pub fn for_each_entry(&self, f: impl FnMut(&H256, &[u8])) -> TrieResult<()> {
+        self.trie.for_each_entry(f)
+    }
+
+    /// Returns an iterator over the original keys and the values of the trie, in
+    /// the order of their hashes. Yields `TrieError::MissingBlob` with the hashed
+    /// key for the entries whose key is missing from the preimage store, or for
+    /// every entry if there is no store.
+    pub fn iter_preimages(&self) -> impl Iterator<Item = TrieResult<(H256, Vec<u8>)>> + '_ {
+        with_preimages(self.trie.try_iter(), self.preimages.as_deref())
+    }
 }
 
 impl<'db, D: HashDB> SecTrieDBMut<'db, D> {
@@ -198,7 +411,11 @@ impl<'db, D: HashDB> SecTrieDBMut<'db, D> {
     }
 
     pub fn insert(&mut self, key: &H256, value: Vec<u8>) -> TrieResult<()> {
-        self.trie.insert(&keccak256(key.as_bytes()), value)
+        let hashed = keccak256(key.as_bytes());
+        if let Some(store) = &self.preimages {
+            store.insert(hashed, key.as_bytes().to_vec());
+        }
+        self.trie.insert(&hashed, value)
     }
 
     pub fn remove(&mut self, key: &H256) -> TrieResult<bool> {
@@ -216,4 +433,108 @@ impl<'db, D: HashDB> SecTrieDBMut<'db, D> {
     pub fn trie_mut(&mut self) -> &mut TrieDBMut<'db, D> {
         &mut self.trie
     }
+
+    /// Returns an iterator over the hashed keys and the values of the trie, including
+    /// the changes made since the last commit.
+    pub fn iter(&self) -> TrieDBIterator<'_, D> {
+        self.trie.iter()
+    }
+
+    /// Same as `iter`, but yields the node it fails to read as an error, see
+    /// `TrieDB::try_iter`.
+    pub fn try_iter(&self) -> TrieDBTryIterator<'_, D> {
+        self.trie.try_iter()
+    }
+
+    /// Calls `f` with every hashed key and value in the trie, see
+    /// `TrieDB::for_each_entry`.
+    pub fn for_each_entry(&self, f: impl FnMut(&H256, &[u8])) -> TrieResult<()> {
+        self.trie.for_each_entry(f)
+    }
+
+    /// Returns an iterator over the original keys and the values of the trie, see
+    /// `SecTrieDB::iter_preimages`.
+    pub fn iter_preimages(&self) -> impl Iterator<Item = TrieResult<(H256, Vec<u8>)>> + '_ {
+        with_preimages(self.trie.try_iter(), self.preimages.as_deref())
+    }
+}
+
+impl<'db, D: HashDB> IntoIterator for &TrieDB<'db, D> {
+    type Item = (H256, Vec<u8>);
+    type IntoIter = TrieDBIterator<'db, D>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, 'db, D: HashDB> IntoIterator for &'a TrieDBMut<'db, D> {
+    type Item = (H256, Vec<u8>);
+    type IntoIter = TrieDBIterator<'a, D>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'db, D: HashDB> IntoIterator for &SecTrieDB<'db, D> {
+    type Item = (H256, Vec<u8>);
+    type IntoIter = TrieDBIterator<'db, D>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, 'db, D: HashDB> IntoIterator for &'a SecTrieDBMut<'db, D> {
+    type Item = (H256, Vec<u8>);
+    type IntoIter = TrieDBIterator<'a, D>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+// Runs `for_each_entry` on a trie keyed by hashes, calling `f` with its entries up
+// to the first key that is not a hash.
+fn for_each_hashed_entry(
+    for_each_entry: impl FnOnce(&mut dyn FnMut(&[u8], &[u8])) -> TrieResult<()>,
+    mut f: impl FnMut(&H256, &[u8]),
+) -> TrieResult<()> {
+    let mut invalid: Option<Vec<u8>> = None;
+    for_each_entry(&mut |key, value| {
+        if invalid.is_some() {
+            return;
+        }
+        match entry_key(key) {
+            Some(key) => f(&key, value),
+            None => invalid = Some(key.to_vec()),
+        }
+    })?;
+    match invalid {
+        Some(key) => Err(TrieError::InvalidData.at_key(&key)),
+        None => Ok(()),
+    }
+}
+
+// Replaces the hashed keys of `entries` with their preimages.
+fn with_preimages<'a>(
+    entries: impl Iterator<Item = TrieResult<(H256, Vec<u8>)>> + 'a,
+    preimages: Option<&'a (dyn BlobStore + Send + Sync)>,
+) -> impl Iterator<Item = TrieResult<(H256, Vec<u8>)>> + 'a {
+    entries.map(move |entry| {
+        let (hashed, value) = entry?;
+        let key = preimages
+            .and_then(|store| store.get(&hashed))
+            .ok_or(TrieError::MissingBlob(hashed))?;
+        match entry_key(&key) {
+            Some(key) if keccak256(key.as_bytes()) == hashed => Ok((key, value)),
+            _ => Err(TrieError::InvalidData.at_key(hashed.as_bytes())),
+        }
+    })
+}
+
+// Reads the key of an entry of a trie keyed by hashes.
+fn entry_key(key: &[u8]) -> Option<H256> {
+    (key.len() == 32).then(|| H256::from_slice(key))
 }
@@ -0,0 +1,70 @@
+extern crate alloc;
+use alloc::vec::Vec;
+
+use crate::codec::{DecodeLimits, NodeCodec, RlpNodeCodec};
+use crate::db::HashDB;
+use crate::errors::TrieError;
+use crate::hasher::{H256Set, Hasher, KeccakHasher};
+use crate::proof::verify_exclusion;
+use crate::trie::{PatriciaTrie, PatriciaTrieMut, TrieResult};
+use crate::H256;
+
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> PatriciaTrie<'db, D, H, C> {
+    /// Returns one proof that none of `keys` is in the trie, made of the nodes on
+    /// the paths of all of them, each given once, root first.
+    ///
+    /// Keys sharing a prefix share the nodes above the point where their paths
+    /// part, so the proof is smaller than the proofs of the keys one by one. Fails
+    /// with `TrieError::InvalidData` at the first key that is in the trie.
+    pub fn get_exclusion_proof<K: AsRef<[u8]>>(&self, keys: &[K]) -> TrieResult<Vec<Vec<u8>>> {
+        #[cfg(feature = "metrics")]
+        crate::metrics::metrics().record_proof();
+        let mut seen = H256Set::default();
+        let mut proof = Vec::new();
+        for key in keys {
+            let key = key.as_ref();
+            if self.contains(key)? {
+                return Err(TrieError::InvalidData.at_key(key));
+            }
+            for node in self.proof_nodes(key).map_err(|e| e.at_key(key))? {
+                if seen.insert(H::hash(&node)) {
+                    proof.push(node.to_vec());
+                }
+            }
+        }
+        Ok(proof)
+    }
+}
+
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> PatriciaTrieMut<'db, D, H, C> {
+    /// Returns one proof that none of `keys` is in the trie, see
+    /// `PatriciaTrie::get_exclusion_proof`. Uncommitted changes are proven against
+    /// the root the next commit gives.
+    pub fn get_exclusion_proof<K: AsRef<[u8]>>(&self, keys: &[K]) -> TrieResult<Vec<Vec<u8>>> {
+        self.trie_ref().get_exclusion_proof(keys)
+    }
+}
+
+/// Checks a proof returned by `get_exclusion_proof` that none of `keys` is in the
+/// trie at `root`.
+///
+/// The proof must hold the nodes on the paths of all the keys, in any order, and
+/// nothing else. Fails with `TrieError::InvalidProof` at the first key it does not
+/// prove absent, or at the proof item that is off every path.
+pub fn verify_exclusion_proof<K: AsRef<[u8]>>(
+    root: H256,
+    keys: &[K],
+    proof: &[Vec<u8>],
+) -> TrieResult<()> {
+    verify_exclusion_proof_with_hasher::<KeccakHasher, RlpNodeCodec, K>(root, keys, proof)
+}
+
+/// Same as `verify_exclusion_proof`, for a trie hashing its nodes with `H` and
+/// encoding them with `C`.
+pub fn verify_exclusion_proof_with_hasher<H: Hasher, C: NodeCodec, K: AsRef<[u8]>>(
+    root: H256,
+    keys: &[K],
+    proof: &[Vec<u8>],
+) -> TrieResult<()> {
+    verify_exclusion::<H, C, K>(root, keys, proof, &DecodeLimits::default())
+}
@@ -0,0 +1,113 @@
+extern crate alloc;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+
+use crate::codec::{NodeCodec, RlpNodeCodec};
+use crate::db::HashDB;
+use crate::hasher::{H256Set, Hasher, KeccakHasher};
+use crate::nibbles::with_key_path;
+use crate::node::{Node, RawNodeOrHash};
+use crate::shared::Shared;
+use crate::trie::{PatriciaTrie, PatriciaTrieMut, TrieIterator, TrieResult, TrieTryIterator};
+use crate::H256;
+
+/// An in-memory fork of a trie.
+///
+/// The fork shares every node with the trie it was created from and only copies
+/// the nodes on the paths it modifies, so creating one is cheap. Changes made to
+/// the fork are never written to the db; dropping the fork discards them.
+///
+/// Shared nodes are never modified in place, so the trie, its clones and iterators,
+/// and other forks keep reading the state they were created at.
+pub struct TrieFork<'a, D: HashDB, H: Hasher = KeccakHasher, C: NodeCodec = RlpNodeCodec> {
+    trie: PatriciaTrie<'a, D, H, C>,
+}
+
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> PatriciaTrie<'db, D, H, C> {
+    /// Creates a fork sharing all nodes with this trie.
+    pub fn fork(&self) -> TrieFork<'db, D, H, C> {
+        TrieFork {
+            trie: PatriciaTrie {
+                root: self.root.clone(),
+                hashdb: self.hashdb,
+                cache: Shared::default(),
+                gen_keys: Shared::default(),
+                node_cache: self.node_cache.clone(),
+                provider: self.provider.clone(),
+                blobs: self.blobs.clone(),
+                hook: self.hook.clone(),
+                lenient: self.lenient,
+                limits: self.limits,
+                marker: PhantomData,
+            },
+        }
+    }
+}
+
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> PatriciaTrieMut<'db, D, H, C> {
+    /// Creates a fork sharing all nodes, including uncommitted ones, with this trie.
+    pub fn fork(&self) -> TrieFork<'_, D, H, C> {
+        self.trie_ref().fork()
+    }
+}
+
+impl<'a, D: HashDB, H: Hasher, C: NodeCodec> TrieFork<'a, D, H, C> {
+    /// Returns the value for key stored in the fork.
+    pub fn get(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        self.trie.get(key)
+    }
+
+    /// Checks that the key is present in the fork.
+    pub fn contains(&self, key: &[u8]) -> TrieResult<bool> {
+        self.trie.contains(key)
+    }
+
+    pub fn iter(&self) -> TrieIterator<'a, D, H, C> {
+        self.trie.iter()
+    }
+
+    pub fn try_iter(&self) -> TrieTryIterator<'a, D, H, C> {
+        self.trie.try_iter()
+    }
+
+    /// Inserts value into the fork and modifies it if it exists.
+    pub fn insert(&mut self, key: &[u8], value: Vec<u8>) -> TrieResult<()> {
+        if value.is_empty() {
+            self.remove(key)?;
+            return Ok(());
+        }
+        let value = match &self.trie.blobs {
+            Some(blobs) => blobs.put::<H>(value),
+            None => value,
+        };
+        let mut root = core::mem::replace(&mut self.trie.root, Node::Empty);
+        let result = with_key_path(key, |path| {
+            self.trie
+                .insert_at(&mut root, path, value, &mut H256Set::default())
+        });
+        self.trie.root = root;
+        result.map_err(|e| e.at_key(key))
+    }
+
+    /// Removes any existing value for key from the fork.
+    pub fn remove(&mut self, key: &[u8]) -> TrieResult<bool> {
+        let mut root = core::mem::replace(&mut self.trie.root, Node::Empty);
+        let result = with_key_path(key, |path| {
+            self.trie
+                .delete_at(&mut root, path, &mut H256Set::default())
+        });
+        self.trie.root = root;
+        result.map_err(|e| e.at_key(key))
+    }
+
+    /// Computes the root hash of the fork without writing anything to the db.
+    pub fn root_hash(&self) -> H256 {
+        let root_hash = match self.trie.encode_node(self.trie.root.clone()) {
+            RawNodeOrHash::Node(raw) => H::hash(&raw),
+            RawNodeOrHash::Hash(hash) => hash,
+        };
+        self.trie.cache.take();
+        self.trie.gen_keys.take();
+        root_hash
+    }
+}
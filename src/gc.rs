@@ -0,0 +1,32 @@
+extern crate alloc;
+use alloc::vec::Vec;
+
+use crate::db::IterableHashDB;
+use crate::hasher::H256Set;
+use crate::trie::{PatriciaTrie, TrieResult};
+use crate::H256;
+
+/// Deletes every node from the database that is not reachable from one of `live_roots`.
+///
+/// Unlike the pruning done by `PatriciaTrieMut::root`, which only knows about the nodes
+/// a single trie passed through, this is safe when several roots share one database.
+/// Nodes are deleted through `HashDB::remove_batch`, so a backend that ignores removals
+/// (such as a non-light `MemoryDB`) keeps them.
+/// Returns the number of nodes that were deleted, which does not count the nodes such a
+/// backend kept.
+pub fn collect_garbage<D: IterableHashDB>(db: &mut D, live_roots: &[H256]) -> TrieResult<usize> {
+    let mut live = H256Set::default();
+    for root in live_roots {
+        let trie = PatriciaTrie::from(&*db, *root)?;
+        live.insert(*root);
+        trie.reachable_hashes(&mut live)?;
+    }
+
+    let dead: Vec<H256> = db
+        .keys()
+        .into_iter()
+        .filter(|key| !live.contains(key))
+        .collect();
+    db.remove_batch(&dead);
+    Ok(dead.iter().filter(|key| !db.contains(key)).count())
+}
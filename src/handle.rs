@@ -0,0 +1,126 @@
+extern crate alloc;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+
+use crate::codec::{DecodeLimits, NodeCodec, RlpNodeCodec};
+use crate::db::HashDB;
+use crate::errors::TrieError;
+use crate::hasher::{Hasher, KeccakHasher};
+use crate::node::Node;
+use crate::shared::Shared;
+use crate::trie::{
+    new_node_cache, NodeCache, PatriciaTrie, TrieIterator, TrieResult, TrieTryIterator,
+};
+use crate::H256;
+
+/// A read-only view of the trie at a committed root, which can be cloned and sent to
+/// other threads while a writer builds the next root in the same db.
+///
+/// The db is held by value, so it is meant to be a handle to storage shared with the
+/// writer, such as a `SharedMemoryDB`. Clones share their decoded-node cache, and so
+/// do the handles made by `at` for later roots. The nodes of the root must stay in
+/// the db for as long as handles read it, so the writer should not prune them.
+pub struct ReadHandle<D: HashDB, H: Hasher = KeccakHasher, C: NodeCodec = RlpNodeCodec> {
+    db: D,
+    root_hash: H256,
+    root: Node,
+    node_cache: NodeCache,
+    marker: PhantomData<fn() -> (H, C)>,
+}
+
+impl<D: HashDB> ReadHandle<D> {
+    pub fn new(db: D, root: H256) -> TrieResult<Self> {
+        Self::new_with_hasher(db, root)
+    }
+}
+
+impl<D: HashDB, H: Hasher, C: NodeCodec> ReadHandle<D, H, C> {
+    /// Opens the trie at `root` whose nodes were hashed with `H` and encoded with `C`.
+    pub fn new_with_hasher(db: D, root: H256) -> TrieResult<Self> {
+        Self::open(db, root, new_node_cache())
+    }
+
+    /// Returns a handle to the trie at another `root` of the db, such as the one the
+    /// writer just committed, sharing the node cache of this one.
+    pub fn at(&self, root: H256) -> TrieResult<Self>
+    where
+        D: Clone,
+    {
+        Self::open(self.db.clone(), root, self.node_cache.clone())
+    }
+
+    /// Sets how many decoded nodes the handle and its clones keep in memory.
+    pub fn with_node_cache_capacity(self, capacity: usize) -> Self {
+        self.node_cache
+            .with(capacity, |cache, capacity| cache.resize(capacity));
+        self
+    }
+
+    pub fn root(&self) -> H256 {
+        self.root_hash
+    }
+
+    pub fn get(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        self.trie().get(key)
+    }
+
+    pub fn contains(&self, key: &[u8]) -> TrieResult<bool> {
+        self.trie().contains(key)
+    }
+
+    /// Returns the nodes proving the value of `key`, or its absence, under `root`.
+    pub fn get_proof(&self, key: &[u8]) -> TrieResult<Vec<Vec<u8>>> {
+        self.trie().get_proof(key)
+    }
+
+    pub fn iter(&self) -> TrieIterator<'_, D, H, C> {
+        self.trie().iter()
+    }
+
+    pub fn try_iter(&self) -> TrieTryIterator<'_, D, H, C> {
+        self.trie().try_iter()
+    }
+
+    fn open(db: D, root: H256, node_cache: NodeCache) -> TrieResult<Self> {
+        let data = db.get(&root).ok_or(TrieError::InvalidStateRoot)?;
+        let mut handle = Self {
+            db,
+            root_hash: root,
+            root: Node::Empty,
+            node_cache,
+            marker: PhantomData,
+        };
+        handle.root = handle.trie().decode_stored(&root, &data)?;
+        Ok(handle)
+    }
+
+    // Returns a trie reading the root through the shared node cache. Nodes encoded
+    // for proofs are only kept by the trie for the call.
+    fn trie(&self) -> PatriciaTrie<'_, D, H, C> {
+        PatriciaTrie {
+            root: self.root.clone(),
+            hashdb: &self.db,
+            cache: Shared::default(),
+            gen_keys: Shared::default(),
+            node_cache: self.node_cache.clone(),
+            provider: None,
+            blobs: None,
+            hook: None,
+            lenient: false,
+            limits: DecodeLimits::default(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<D: HashDB + Clone, H: Hasher, C: NodeCodec> Clone for ReadHandle<D, H, C> {
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+            root_hash: self.root_hash,
+            root: self.root.clone(),
+            node_cache: self.node_cache.clone(),
+            marker: PhantomData,
+        }
+    }
+}
@@ -1,11 +1,147 @@
+use core::hash::BuildHasherDefault;
+use core::marker::PhantomData;
 use ethereum_types::H256;
-use tiny_keccak::Hasher;
-use tiny_keccak::Keccak;
+use hashbrown::{HashMap, HashSet};
 
+use crate::errors::TrieError;
+use crate::trie::TrieResult;
+
+/// The hash function nodes are stored under and roots are computed with.
+///
+/// Only the length of digests is generic: node references, db keys and roots stay
+/// `H256`s, so digests are at most 32 bytes long. 20-byte digests are supported, but
+/// hashers with longer digests, such as 64-byte ones, are not and fail to compile
+/// once used:
+///
+/// ```compile_fail
+/// use ethtrie::{Hasher, MemoryDB, PatriciaTrieMut, H256};
+///
+/// struct WideHasher;
+///
+/// impl Hasher for WideHasher {
+///     const LENGTH: usize = 64;
+///
+///     fn hash(data: &[u8]) -> H256 {
+///         ethtrie::keccak256(data)
+///     }
+/// }
+///
+/// let mut memdb = MemoryDB::new(true);
+/// let mut trie = PatriciaTrieMut::<_, WideHasher>::new_with_hasher(&mut memdb);
+/// trie.insert(b"key", b"value".to_vec()).unwrap();
+/// trie.root().unwrap();
+/// ```
+pub trait Hasher {
+    /// Length of the digests in bytes, from 2 to 32. Shorter digests are kept in the
+    /// first bytes of an `H256` with the rest zeroed, and are encoded in nodes and
+    /// proofs at their own length. Nodes encoding to fewer bytes than this are
+    /// embedded in their parent.
+    ///
+    /// Building a trie, root or proof with a hasher whose length is out of range
+    /// fails to compile.
+    const LENGTH: usize = 32;
+
+    fn hash(data: &[u8]) -> H256;
+}
+
+// Rejects at compile time the hashers whose digests do not fit an `H256`, once
+// code using them is instantiated.
+struct DigestLength<H>(PhantomData<H>);
+
+impl<H: Hasher> DigestLength<H> {
+    const CHECKED: () = assert!(
+        H::LENGTH >= 2 && H::LENGTH <= 32,
+        "hasher digests must be 2 to 32 bytes long"
+    );
+}
+
+// Checks the digest length of `H`, see `DigestLength`.
+pub(crate) fn check_digest_length<H: Hasher>() {
+    let () = DigestLength::<H>::CHECKED;
+}
+
+// Returns the significant bytes of a digest made by `H`.
+pub(crate) fn digest_bytes<H: Hasher>(hash: &H256) -> &[u8] {
+    check_digest_length::<H>();
+    &hash.as_bytes()[..H::LENGTH]
+}
+
+// Reads a digest of up to 32 bytes, zero padded like the digests made by a `Hasher`.
+pub(crate) fn digest_from_slice(data: &[u8]) -> H256 {
+    let mut hash = H256::zero();
+    hash.0[..data.len()].copy_from_slice(data);
+    hash
+}
+
+// Reads the digest a node refers to a child by, which must be as long as the
+// digests made by `H`.
+pub(crate) fn decode_digest<H: Hasher>(data: &[u8]) -> TrieResult<H256> {
+    check_digest_length::<H>();
+    if data.len() != H::LENGTH {
+        return Err(TrieError::InvalidData);
+    }
+    Ok(digest_from_slice(data))
+}
+
+/// Keccak-256, the hash function of Ethereum tries and the default for all tries.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KeccakHasher;
+
+impl Hasher for KeccakHasher {
+    fn hash(data: &[u8]) -> H256 {
+        keccak256(data)
+    }
+}
+
+// The keccak implementation is picked at compile time: `keccak-asm` takes precedence
+// over `sha3`, and `tiny-keccak` is used when neither feature is enabled.
+
+#[cfg(feature = "keccak-asm")]
 pub fn keccak256(data: &[u8]) -> H256 {
+    use keccak_asm::{Digest, Keccak256};
+
+    H256::from_slice(&<Keccak256 as Digest>::digest(data))
+}
+
+#[cfg(all(feature = "sha3", not(feature = "keccak-asm")))]
+pub fn keccak256(data: &[u8]) -> H256 {
+    use sha3::{Digest, Keccak256};
+
+    H256::from_slice(&<Keccak256 as Digest>::digest(data))
+}
+
+#[cfg(not(any(feature = "sha3", feature = "keccak-asm")))]
+pub fn keccak256(data: &[u8]) -> H256 {
+    use tiny_keccak::{Hasher, Keccak};
+
     let mut hasher = Keccak::v256();
     let mut result = H256::zero();
     hasher.update(data);
     hasher.finalize(result.as_mut());
     result
 }
+
+// Node keys are keccak hashes and already uniformly distributed, so the internal maps
+// keyed by them only need a cheap FxHash-style mix instead of the default hasher.
+#[derive(Default, Clone, Copy)]
+pub(crate) struct KeyHasher(u64);
+
+impl core::hash::Hasher for KeyHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+        for chunk in bytes.chunks(8) {
+            let mut word = [0u8; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.0 = (self.0.rotate_left(5) ^ u64::from_le_bytes(word)).wrapping_mul(SEED);
+        }
+    }
+}
+
+pub(crate) type BuildKeyHasher = BuildHasherDefault<KeyHasher>;
+pub(crate) type H256Map<V> = HashMap<H256, V, BuildKeyHasher>;
+pub(crate) type H256Set = HashSet<H256, BuildKeyHasher>;
@@ -0,0 +1,128 @@
+extern crate alloc;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+
+use crate::codec::{DecodeLimits, NodeCodec, RlpNodeCodec};
+use crate::db::HashDB;
+use crate::errors::TrieError;
+use crate::hasher::{H256Set, Hasher, KeccakHasher};
+use crate::trie::TrieResult;
+use crate::H256;
+
+/// Completes the trie at a target root in a db that misses some of its nodes, such
+/// as after a snap sync or a partial corruption.
+///
+/// The healer walks the trie breadth-first from the root and reports the nodes the
+/// db lacks through `missing`. Once they are fetched, `process` writes them to the
+/// db and the walk goes on below them, until `is_complete`.
+pub struct TrieHealer<'db, D: HashDB, H: Hasher = KeccakHasher, C: NodeCodec = RlpNodeCodec> {
+    db: &'db mut D,
+    root: H256,
+    // Nodes still to be looked for in the db.
+    queue: VecDeque<H256>,
+    // Nodes reported missing and not received yet.
+    requested: H256Set,
+    limits: DecodeLimits,
+    marker: PhantomData<fn() -> (H, C)>,
+}
+
+impl<'db, D: HashDB> TrieHealer<'db, D> {
+    pub fn new(db: &'db mut D, root: H256) -> Self {
+        Self::new_with_hasher(db, root)
+    }
+}
+
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> TrieHealer<'db, D, H, C> {
+    /// Creates a healer for a trie hashing its nodes with `H` and encoding them with `C`.
+    pub fn new_with_hasher(db: &'db mut D, root: H256) -> Self {
+        // The root of the empty trie needs no fetching.
+        if root == H::hash(C::EMPTY_NODE) && !db.contains(&root) {
+            db.insert(root, C::EMPTY_NODE.to_vec());
+        }
+        let mut queue = VecDeque::new();
+        queue.push_back(root);
+        Self {
+            db,
+            root,
+            queue,
+            requested: H256Set::default(),
+            limits: DecodeLimits::default(),
+            marker: PhantomData,
+        }
+    }
+
+    /// Bounds the nodes processed, which are rejected with
+    /// `TrieError::LimitExceeded` beyond them.
+    pub fn with_decode_limits(mut self, limits: DecodeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn root(&self) -> H256 {
+        self.root
+    }
+
+    /// Returns up to `max` nodes missing from the db, walking on through the nodes
+    /// the db has. Nodes already returned are not returned again until `process`
+    /// rejects them.
+    pub fn missing(&mut self, max: usize) -> TrieResult<Vec<H256>> {
+        let mut missing = Vec::new();
+        while missing.len() < max {
+            let hash = match self.queue.pop_front() {
+                Some(hash) => hash,
+                None => break,
+            };
+            match self.db.get(&hash) {
+                Some(data) => self.queue_children(&data)?,
+                None => {
+                    if self.requested.insert(hash) {
+                        missing.push(hash);
+                    }
+                }
+            }
+        }
+        Ok(missing)
+    }
+
+    /// Writes fetched nodes to the db, returning how many were written. Each node
+    /// must be one returned by `missing`, which is then no longer missing.
+    pub fn process(&mut self, nodes: Vec<Vec<u8>>) -> TrieResult<usize> {
+        let mut written = 0;
+        for data in nodes {
+            let hash = H::hash(&data);
+            if !self.requested.contains(&hash) {
+                return Err(TrieError::InvalidData.in_node(hash));
+            }
+            // A node that fails to decode stays missing.
+            self.queue_children(&data).map_err(|e| e.in_node(hash))?;
+            self.requested.remove(&hash);
+            self.db.insert(hash, data);
+            written += 1;
+        }
+        Ok(written)
+    }
+
+    /// Puts the nodes returned by `missing` but not received back in the queue, for
+    /// instance after a request for them failed.
+    pub fn retry(&mut self) {
+        self.queue.extend(self.requested.drain());
+    }
+
+    /// Returns the number of nodes reported missing and not received yet.
+    pub fn pending(&self) -> usize {
+        self.requested.len()
+    }
+
+    /// Returns whether every node of the trie is in the db.
+    pub fn is_complete(&self) -> bool {
+        self.queue.is_empty() && self.requested.is_empty()
+    }
+
+    fn queue_children(&mut self, data: &[u8]) -> TrieResult<()> {
+        let node = C::decode(data)?;
+        self.limits.check(&node, data.len(), 0)?;
+        self.queue.extend(node.hashed_children::<H>()?);
+        Ok(())
+    }
+}
@@ -0,0 +1,26 @@
+extern crate alloc;
+use alloc::sync::Arc;
+use core::fmt;
+
+use crate::H256;
+
+/// An access of a trie to its db, as reported to the hook set with `with_db_hook`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DbAccess {
+    /// A read of the node stored under `hash`, `len` bytes long if the db has it.
+    Get { hash: H256, len: Option<usize> },
+    /// A write of a node `len` bytes long under `hash`.
+    Insert { hash: H256, len: usize },
+    /// A removal of the node stored under `hash`, pruned by a commit.
+    Remove { hash: H256 },
+}
+
+// The db hook of a trie, shared by its clones and forks.
+#[derive(Clone)]
+pub(crate) struct SharedHook(pub(crate) Arc<dyn Fn(&DbAccess) + Send + Sync>);
+
+impl fmt::Debug for SharedHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("DbHook")
+    }
+}
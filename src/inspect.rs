@@ -0,0 +1,440 @@
+extern crate alloc;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use ethereum_types::H256;
+
+use crate::codec::NodeCodec;
+use crate::db::HashDB;
+use crate::encoder::Encoder;
+use crate::errors::TrieError;
+use crate::hasher::Hasher;
+use crate::nibbles::Nibbles;
+use crate::node::Node;
+use crate::trie::{PatriciaTrie, PatriciaTrieMut, TrieResult};
+
+// Number of leading bytes shown for hashes and values in rendered output.
+const SHORT_LEN: usize = 4;
+
+/// The kind of a node, as reported by `node_at_path`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    Empty,
+    Leaf,
+    Extension,
+    Branch,
+}
+
+/// How a node is referred to by its parent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeReference {
+    /// By the hash of its encoding, which is stored in the db under it once
+    /// committed.
+    Hash(H256),
+    /// By its encoding, smaller than a hash, embedded in the parent.
+    Inline,
+}
+
+/// A child of a node reported by `node_at_path`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChildInfo {
+    /// The nibbles leading from the node to the child: its index in a branch, or
+    /// the prefix of an extension.
+    pub nibbles: Vec<u8>,
+    pub reference: NodeReference,
+}
+
+/// A node of a trie, as found at a path by `node_at_path`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub kind: NodeKind,
+    /// The encoding of the node, with its children stored by hash referred to by
+    /// their hashes.
+    pub encoded: Vec<u8>,
+    pub reference: NodeReference,
+    /// The nibbles of the rest of the key of a leaf, or of the prefix of an
+    /// extension.
+    pub partial: Vec<u8>,
+    /// The value of a leaf, or of a branch holding one.
+    pub value: Option<Vec<u8>>,
+    pub children: Vec<ChildInfo>,
+}
+
+struct DotWriter {
+    out: String,
+    next_id: usize,
+}
+
+impl DotWriter {
+    fn node(&mut self, label: &str, style: &str) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.out
+            .push_str(&format!("    n{} [label=\"{}\"{}];\n", id, label, style));
+        id
+    }
+
+    fn edge(&mut self, from: usize, to: usize, label: &str) {
+        self.out.push_str(&format!(
+            "    n{} -> n{} [label=\"{}\"];\n",
+            from, to, label
+        ));
+    }
+}
+
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> PatriciaTrie<'db, D, H, C> {
+    /// Renders the whole trie as a Graphviz DOT graph.
+    ///
+    /// Every node shows its type, the nibbles it consumes and a truncated value;
+    /// nodes loaded from the database also show a truncated hash.
+    pub fn to_dot(&self) -> TrieResult<String> {
+        self.to_dot_at(&[], None)
+    }
+
+    /// Renders the subtree found at the nibble `path` as a Graphviz DOT graph,
+    /// descending at most `max_depth` levels below it.
+    pub fn to_dot_at(&self, path: &[u8], max_depth: Option<usize>) -> TrieResult<String> {
+        let node = self.node_at(self.root.clone(), &Nibbles::from_hex(path))?;
+        let mut dot = DotWriter {
+            out: String::from("digraph trie {\n    node [shape=box, fontname=monospace];\n"),
+            next_id: 0,
+        };
+        self.write_dot(&mut dot, node, None, 0, max_depth)?;
+        dot.out.push_str("}\n");
+        Ok(dot.out)
+    }
+
+    /// Renders the trie as an indented ASCII tree, one node per line, with the
+    /// nibbles each node consumes and truncated values and hashes.
+    pub fn format_tree(&self) -> TrieResult<String> {
+        let mut out = String::new();
+        self.write_tree(&mut out, self.root.clone(), None, "", "")?;
+        Ok(out)
+    }
+
+    /// Returns the node starting at the nibble `path`, one nibble per byte, or `None`
+    /// if no node starts there, for instance because the path ends inside the key
+    /// of a leaf or the prefix of an extension.
+    ///
+    /// Nodes not committed yet are reported as they would be committed.
+    pub fn node_at_path(&self, path: &[u8]) -> TrieResult<Option<NodeInfo>> {
+        if path.iter().any(|nibble| *nibble > 0x0f) {
+            return Err(TrieError::InvalidData);
+        }
+        let mut n = self.root.clone();
+        // The hash `n` was loaded from the db under.
+        let mut hash = None;
+        let mut rest = Nibbles::from_hex(path);
+        loop {
+            if let Node::Hash(stored) = n {
+                hash = Some(stored);
+                n = self.recover_from_db(&stored)?;
+                continue;
+            }
+            if rest.is_empty() {
+                break;
+            }
+            hash = None;
+            n = match n {
+                Node::Branch(branch) => {
+                    let child = branch.children[rest.at(0)].clone();
+                    rest = rest.offset(1);
+                    child
+                }
+                Node::Extension(ext) if rest.common_prefix(&ext.prefix) == ext.prefix.len() => {
+                    rest = rest.offset(ext.prefix.len());
+                    ext.node.clone()
+                }
+                _ => return Ok(None),
+            };
+        }
+
+        let encoded = Encoder::<H, C>::new(0).encode_raw(&n);
+        // The root is stored by hash whatever its size.
+        let reference = match hash.or_else(|| n.cached_hash()) {
+            Some(hash) => NodeReference::Hash(hash),
+            None if path.is_empty() => NodeReference::Hash(H::hash(&encoded)),
+            None => self.reference_to(&n),
+        };
+        let mut info = NodeInfo {
+            kind: NodeKind::Empty,
+            encoded,
+            reference,
+            partial: Vec::new(),
+            value: None,
+            children: Vec::new(),
+        };
+        match n {
+            Node::Empty if !path.is_empty() => return Ok(None),
+            Node::Empty | Node::Hash(_) => {}
+            Node::Leaf(leaf) => {
+                info.kind = NodeKind::Leaf;
+                info.partial = nibbles_of(&leaf.key);
+                info.value = Some(leaf.value.clone());
+            }
+            Node::Extension(ext) => {
+                info.kind = NodeKind::Extension;
+                info.partial = nibbles_of(&ext.prefix);
+                info.children.push(ChildInfo {
+                    nibbles: info.partial.clone(),
+                    reference: self.reference_to(&ext.node),
+                });
+            }
+            Node::Branch(branch) => {
+                info.kind = NodeKind::Branch;
+                info.value = branch.value.clone();
+                for (i, child) in branch.children.iter().enumerate() {
+                    if let Node::Empty = child {
+                        continue;
+                    }
+                    info.children.push(ChildInfo {
+                        nibbles: alloc::vec![i as u8],
+                        reference: self.reference_to(child),
+                    });
+                }
+            }
+        }
+        Ok(Some(info))
+    }
+
+    // Returns how a parent refers to the node `n`.
+    pub(crate) fn reference_to(&self, n: &Node) -> NodeReference {
+        if let Some(hash) = n.cached_hash() {
+            return NodeReference::Hash(hash);
+        }
+        match n {
+            Node::Hash(hash) => NodeReference::Hash(*hash),
+            _ => {
+                let encoded = Encoder::<H, C>::new(0).encode_raw(n);
+                if encoded.len() >= H::LENGTH {
+                    NodeReference::Hash(H::hash(&encoded))
+                } else {
+                    NodeReference::Inline
+                }
+            }
+        }
+    }
+
+    // Returns the node whose subtree holds every key starting with `path`.
+    // A path ending inside the key of a leaf or the prefix of an extension
+    // resolves to that leaf or extension.
+    fn node_at(&self, n: Node, path: &Nibbles) -> TrieResult<Node> {
+        if path.is_empty() {
+            return match n {
+                Node::Hash(hash) => self.recover_from_db(&hash),
+                _ => Ok(n),
+            };
+        }
+
+        match n {
+            Node::Empty => Ok(Node::Empty),
+            Node::Leaf(ref leaf) => {
+                if leaf.key.common_prefix(path) == path.len() {
+                    Ok(n.clone())
+                } else {
+                    Ok(Node::Empty)
+                }
+            }
+            Node::Branch(branch) => {
+                let child = branch.children[path.at(0)].clone();
+                self.node_at(child, &path.offset(1))
+            }
+            Node::Extension(ref ext) => {
+                let prefix = &ext.prefix;
+                let match_len = path.common_prefix(prefix);
+                if match_len == prefix.len() {
+                    self.node_at(ext.node.clone(), &path.offset(match_len))
+                } else if match_len == path.len() {
+                    Ok(n.clone())
+                } else {
+                    Ok(Node::Empty)
+                }
+            }
+            Node::Hash(hash) => {
+                let n = self.recover_from_db(&hash)?;
+                self.node_at(n, path)
+            }
+        }
+    }
+
+    fn write_dot(
+        &self,
+        dot: &mut DotWriter,
+        n: Node,
+        hash: Option<H256>,
+        depth: usize,
+        max_depth: Option<usize>,
+    ) -> TrieResult<usize> {
+        let hash_label = match hash {
+            Some(hash) => format!("\\nhash: {}", short_hex(hash.as_bytes())),
+            None => String::new(),
+        };
+        let expand = !matches!(max_depth, Some(max) if depth >= max);
+
+        match n {
+            Node::Empty => Ok(dot.node("empty", "")),
+            Node::Leaf(leaf) => {
+                let label = format!(
+                    "leaf\\nkey: {}\\nvalue: {}{}",
+                    nibbles_hex(&leaf.key),
+                    short_hex(&leaf.value),
+                    hash_label
+                );
+                Ok(dot.node(&label, ""))
+            }
+            Node::Extension(ext) => {
+                let label = format!(
+                    "extension\\nprefix: {}{}",
+                    nibbles_hex(&ext.prefix),
+                    hash_label
+                );
+                let id = dot.node(&label, "");
+                let child = if expand {
+                    self.write_dot(dot, ext.node.clone(), None, depth + 1, max_depth)?
+                } else {
+                    dot.node("...", ", style=dashed")
+                };
+                dot.edge(id, child, &nibbles_hex(&ext.prefix));
+                Ok(id)
+            }
+            Node::Branch(branch) => {
+                let value = match &branch.value {
+                    Some(v) => short_hex(v),
+                    None => String::from("-"),
+                };
+                let label = format!("branch\\nvalue: {}{}", value, hash_label);
+                let id = dot.node(&label, "");
+                for (i, child) in branch.children.iter().enumerate() {
+                    if let Node::Empty = child {
+                        continue;
+                    }
+                    let child = if expand {
+                        self.write_dot(dot, child.clone(), None, depth + 1, max_depth)?
+                    } else {
+                        dot.node("...", ", style=dashed")
+                    };
+                    dot.edge(id, child, &format!("{:x}", i));
+                }
+                Ok(id)
+            }
+            Node::Hash(hash) => {
+                let n = self.recover_from_db(&hash)?;
+                self.write_dot(dot, n, Some(hash), depth, max_depth)
+            }
+        }
+    }
+    fn write_tree(
+        &self,
+        out: &mut String,
+        n: Node,
+        hash: Option<H256>,
+        line_prefix: &str,
+        child_prefix: &str,
+    ) -> TrieResult<()> {
+        if let Node::Hash(hash) = n {
+            let n = self.recover_from_db(&hash)?;
+            return self.write_tree(out, n, Some(hash), line_prefix, child_prefix);
+        }
+
+        let mut children = Vec::new();
+        out.push_str(line_prefix);
+        match n {
+            Node::Empty => out.push_str("empty"),
+            Node::Leaf(ref leaf) => {
+                out.push_str(&format!(
+                    "leaf [{}] = {}",
+                    nibbles_hex(&leaf.key),
+                    short_hex(&leaf.value)
+                ));
+            }
+            Node::Extension(ref ext) => {
+                out.push_str(&format!("extension [{}]", nibbles_hex(&ext.prefix)));
+                children.push((String::new(), ext.node.clone()));
+            }
+            Node::Branch(ref branch) => {
+                out.push_str("branch");
+                if let Some(value) = &branch.value {
+                    out.push_str(&format!(" = {}", short_hex(value)));
+                }
+                for (i, child) in branch.children.iter().enumerate() {
+                    if let Node::Empty = child {
+                        continue;
+                    }
+                    children.push((format!("{:x}: ", i), child.clone()));
+                }
+            }
+            Node::Hash(_) => unreachable!(),
+        }
+        if let Some(hash) = hash {
+            out.push_str(&format!(" ({})", short_hex(hash.as_bytes())));
+        }
+        out.push('\n');
+
+        let count = children.len();
+        for (i, (label, child)) in children.into_iter().enumerate() {
+            let (connector, indent) = if i + 1 == count {
+                ("└── ", "    ")
+            } else {
+                ("├── ", "│   ")
+            };
+            let line_prefix = format!("{}{}{}", child_prefix, connector, label);
+            let child_prefix = format!("{}{}", child_prefix, indent);
+            self.write_tree(out, child, None, &line_prefix, &child_prefix)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> PatriciaTrieMut<'db, D, H, C> {
+    /// Renders the whole trie, including uncommitted changes, as a Graphviz DOT graph.
+    pub fn to_dot(&self) -> TrieResult<String> {
+        self.trie_ref().to_dot()
+    }
+
+    /// Renders the subtree found at the nibble `path` as a Graphviz DOT graph,
+    /// descending at most `max_depth` levels below it.
+    pub fn to_dot_at(&self, path: &[u8], max_depth: Option<usize>) -> TrieResult<String> {
+        self.trie_ref().to_dot_at(path, max_depth)
+    }
+
+    /// Renders the trie, including uncommitted changes, as an indented ASCII tree.
+    pub fn format_tree(&self) -> TrieResult<String> {
+        self.trie_ref().format_tree()
+    }
+
+    /// Returns the node starting at the nibble `path`, including uncommitted
+    /// changes. See `PatriciaTrie::node_at_path`.
+    pub fn node_at_path(&self, path: &[u8]) -> TrieResult<Option<NodeInfo>> {
+        self.trie_ref().node_at_path(path)
+    }
+}
+
+// Returns the nibbles of a key or prefix, without the terminator of leaf keys.
+fn nibbles_of(nibbles: &Nibbles) -> Vec<u8> {
+    nibbles
+        .get_data()
+        .iter()
+        .copied()
+        .filter(|n| *n < 16)
+        .collect()
+}
+
+fn nibbles_hex(nibbles: &Nibbles) -> String {
+    nibbles
+        .get_data()
+        .iter()
+        .filter(|n| **n < 16)
+        .map(|n| format!("{:x}", n))
+        .collect()
+}
+
+fn short_hex(data: &[u8]) -> String {
+    let mut s = String::from("0x");
+    for b in data.iter().take(SHORT_LEN) {
+        s.push_str(&format!("{:02x}", b));
+    }
+    if data.len() > SHORT_LEN {
+        s.push_str("..");
+    }
+    s
+}
@@ -0,0 +1,84 @@
+extern crate alloc;
+use alloc::vec::Vec;
+
+use crate::codec::NodeCodec;
+use crate::db::HashDB;
+use crate::errors::TrieError;
+use crate::hasher::Hasher;
+use crate::trie::{PatriciaTrieMut, TrieResult};
+use crate::H256;
+
+struct JournalEntry {
+    root: H256,
+    // Nodes that were first written to the db by the commit producing `root`.
+    created: Vec<H256>,
+}
+
+/// Records the roots committed by a trie in order, so the trie can later be
+/// reverted to any of them (e.g. on a chain reorganization).
+///
+/// Commits made through the journal never prune the db, since the nodes of older
+/// roots must stay available for `revert_to`. When the journal is created with
+/// `prune` set, reverting deletes the nodes created by the abandoned roots instead.
+/// The journal assumes it is the only writer of the db while it is in use.
+pub struct RootJournal {
+    entries: Vec<JournalEntry>,
+    prune: bool,
+}
+
+impl RootJournal {
+    pub fn new(prune: bool) -> Self {
+        RootJournal {
+            entries: Vec::new(),
+            prune,
+        }
+    }
+
+    /// Commits the trie and records the resulting root.
+    ///
+    /// From its first commit through a journal on, the trie checks the db for
+    /// every node it writes, to record the nodes it creates. Nodes a memory limit
+    /// flushed before that are not recorded, so reverting past them leaves them in
+    /// the db.
+    pub fn commit<D: HashDB, H: Hasher, C: NodeCodec>(
+        &mut self,
+        trie: &mut PatriciaTrieMut<D, H, C>,
+    ) -> TrieResult<H256> {
+        trie.track_created = true;
+        let (root, created) = trie.commit_nodes(false)?;
+        self.entries.push(JournalEntry { root, created });
+        Ok(root)
+    }
+
+    /// Returns the recorded roots, oldest first.
+    pub fn roots(&self) -> impl Iterator<Item = &H256> {
+        self.entries.iter().map(|entry| &entry.root)
+    }
+
+    /// Returns the most recently recorded root.
+    pub fn latest(&self) -> Option<H256> {
+        self.entries.last().map(|entry| entry.root)
+    }
+
+    /// Restores the trie to `root`, discarding its uncommitted changes, its open
+    /// checkpoints and every root recorded after the latest occurrence of `root`.
+    pub fn revert_to<D: HashDB, H: Hasher, C: NodeCodec>(
+        &mut self,
+        trie: &mut PatriciaTrieMut<D, H, C>,
+        root: H256,
+    ) -> TrieResult<()> {
+        let index = self
+            .entries
+            .iter()
+            .rposition(|entry| entry.root == root)
+            .ok_or(TrieError::InvalidStateRoot)?;
+
+        let abandoned = self.entries.split_off(index + 1);
+        if self.prune {
+            for entry in abandoned.iter().rev() {
+                trie.hashdb_mut().remove_batch(&entry.created);
+            }
+        }
+        trie.reset_to(root)
+    }
+}
@@ -29,19 +29,110 @@
 //! }
 //! ```
 
+#[cfg(feature = "std")]
+extern crate std;
+
 mod nibbles;
 mod node;
 
+#[cfg(feature = "std")]
+mod background;
+mod blob;
+mod builder;
+mod checkpoint;
+mod code;
+mod codec;
+mod cursor;
 mod db;
+mod diff;
+#[cfg(feature = "differential")]
+pub mod differential;
+mod encoder;
 mod errors;
 mod eth;
+mod exclusion;
+mod fork;
+mod gc;
+mod handle;
 mod hasher;
+mod heal;
+mod hook;
+mod inspect;
+mod journal;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "hash-db")]
+mod parity;
+mod path;
+#[cfg(feature = "poseidon")]
+mod poseidon;
+mod proof;
+mod provider;
+mod refcount;
+mod root;
+mod sample;
+mod shadow;
+mod shared;
+mod snap;
+mod snapshot;
+mod state;
+mod subtrie;
+mod sync;
 mod trie;
+mod typed;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+mod witness;
 
-pub use db::{HashDB, MemoryDB};
+#[cfg(feature = "std")]
+pub use background::PendingRoot;
+pub use blob::{BlobStore, MemoryBlobStore};
+pub use builder::TrieBuilder;
+pub use code::CodeStore;
+pub use codec::{ChildReference, DecodeLimits, DecodedNode, NodeCodec, PackedPath, RlpNodeCodec};
+pub use cursor::TrieCursor;
+#[cfg(feature = "std")]
+pub use db::SharedMemoryDB;
+pub use db::{HashDB, IterableHashDB, MemoryDB};
+pub use diff::{diff_nodes, diff_nodes_with_hasher, NodeDiff};
 pub use errors::TrieError;
-pub use eth::{SecTrieDB, SecTrieDBMut, TrieDB, TrieDBMut};
-pub use hasher::keccak256;
-pub use trie::{PatriciaTrie, PatriciaTrieMut};
+pub use eth::{
+    ordered_trie_root, withdrawals_root, SecTrieDB, SecTrieDBMut, TrieDB, TrieDBIterator,
+    TrieDBMut, TrieDBTryIterator, Withdrawal,
+};
+pub use exclusion::{verify_exclusion_proof, verify_exclusion_proof_with_hasher};
+pub use fork::TrieFork;
+pub use gc::collect_garbage;
+pub use handle::ReadHandle;
+pub use hasher::{keccak256, Hasher, KeccakHasher};
+pub use heal::TrieHealer;
+pub use hook::DbAccess;
+pub use inspect::{ChildInfo, NodeInfo, NodeKind, NodeReference};
+pub use journal::RootJournal;
+#[cfg(feature = "metrics")]
+pub use metrics::{metrics, TrieMetrics};
+pub use nibbles::{Nibbles, STACK_KEY_LEN};
+#[cfg(feature = "hash-db")]
+pub use parity::{ParityBackedDB, ParityHashDB};
+pub use path::{MemoryPathDB, PathDB, PathHashDB};
+#[cfg(feature = "poseidon")]
+pub use poseidon::PoseidonHasher;
+pub use proof::{verify_storage_slot, EIP1186AccountProof, StorageProof};
+pub use provider::NodeProvider;
+pub use refcount::{ArchiveDB, RefCountedDB};
+pub use root::{sec_trie_root, trie_root, trie_root_with_hasher};
+pub use snap::{verify_account_range, verify_range_proof, verify_storage_ranges};
+pub use snapshot::{
+    export_snapshot, export_snapshot_with_hasher, import_snapshot, read_snapshot, write_snapshot,
+    write_snapshot_with_hasher, SnapshotImporter, SnapshotReader, SNAPSHOT_VERSION,
+};
+pub use state::{genesis_state_root, Account, GenesisAccount, StateTrie, StorageTrie};
+pub use sync::{SyncClient, SyncRequest, SyncResponse, SyncServer};
+pub use trie::{
+    EmptyValues, PatriciaTrie, PatriciaTrieMut, TrieIntoIter, TrieIterator, TrieTryIntoIter,
+    TrieTryIterator, DEFAULT_NODE_CACHE_CAPACITY,
+};
+pub use typed::TypedTrie;
+pub use witness::{apply_over_witness, apply_over_witness_with_hasher};
 
-pub use ethereum_types::H256;
+pub use ethereum_types::{Address, H256, U256};
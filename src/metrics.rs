@@ -0,0 +1,171 @@
+extern crate alloc;
+use alloc::string::String;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+// Upper bounds of the buckets of the commit durations, in seconds.
+const COMMIT_BUCKETS: [f64; 8] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+static METRICS: TrieMetrics = TrieMetrics::new();
+
+/// Returns the metrics of all the tries of the process.
+pub fn metrics() -> &'static TrieMetrics {
+    &METRICS
+}
+
+/// Counters of the activity of tries, recorded by every trie of the process.
+///
+/// `render` writes them in the Prometheus text format, for the host application to
+/// serve to its scraper.
+#[derive(Debug)]
+pub struct TrieMetrics {
+    commits: AtomicU64,
+    commit_micros: AtomicU64,
+    // The number of commits that took at most each of `COMMIT_BUCKETS`.
+    commit_buckets: [AtomicU64; COMMIT_BUCKETS.len()],
+    nodes_written: AtomicU64,
+    nodes_pruned: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    proofs: AtomicU64,
+}
+
+impl TrieMetrics {
+    const fn new() -> Self {
+        Self {
+            commits: AtomicU64::new(0),
+            commit_micros: AtomicU64::new(0),
+            commit_buckets: [const { AtomicU64::new(0) }; COMMIT_BUCKETS.len()],
+            nodes_written: AtomicU64::new(0),
+            nodes_pruned: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            proofs: AtomicU64::new(0),
+        }
+    }
+
+    pub fn commits(&self) -> u64 {
+        self.commits.load(Ordering::Relaxed)
+    }
+
+    /// Returns the time spent in commits, in seconds.
+    pub fn commit_seconds(&self) -> f64 {
+        self.commit_micros.load(Ordering::Relaxed) as f64 / 1e6
+    }
+
+    /// Returns the number of nodes commits wrote to their db that it did not have.
+    pub fn nodes_written(&self) -> u64 {
+        self.nodes_written.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of nodes commits removed from their db.
+    pub fn nodes_pruned(&self) -> u64 {
+        self.nodes_pruned.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of stored nodes found in the node cache of their trie.
+    pub fn cache_hits(&self) -> u64 {
+        self.cache_hits.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of stored nodes read from the db or a node provider.
+    pub fn cache_misses(&self) -> u64 {
+        self.cache_misses.load(Ordering::Relaxed)
+    }
+
+    /// Returns the share of stored nodes found in the node cache, or 0 if none was
+    /// read.
+    pub fn cache_hit_rate(&self) -> f64 {
+        let hits = self.cache_hits();
+        match hits + self.cache_misses() {
+            0 => 0.0,
+            total => hits as f64 / total as f64,
+        }
+    }
+
+    /// Returns the number of proofs generated, whether they could be or not.
+    pub fn proofs(&self) -> u64 {
+        self.proofs.load(Ordering::Relaxed)
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            ("commits_total", "Commits of tries.", self.commits()),
+            (
+                "nodes_written_total",
+                "Nodes written to the db by commits.",
+                self.nodes_written(),
+            ),
+            (
+                "nodes_pruned_total",
+                "Nodes removed from the db by commits.",
+                self.nodes_pruned(),
+            ),
+            (
+                "node_cache_hits_total",
+                "Stored nodes found in the node cache.",
+                self.cache_hits(),
+            ),
+            (
+                "node_cache_misses_total",
+                "Stored nodes read from the db.",
+                self.cache_misses(),
+            ),
+            ("proofs_total", "Proofs generated.", self.proofs()),
+        ];
+        // Writing to a `String` cannot fail.
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP ethtrie_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE ethtrie_{} counter", name);
+            let _ = writeln!(out, "ethtrie_{} {}", name, value);
+        }
+
+        let name = "ethtrie_commit_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Time spent in commits.", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bound, count) in COMMIT_BUCKETS.iter().zip(self.commit_buckets.iter()) {
+            let count = count.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.commits());
+        let _ = writeln!(out, "{}_sum {}", name, self.commit_seconds());
+        let _ = writeln!(out, "{}_count {}", name, self.commits());
+        out
+    }
+
+    pub(crate) fn record_commit(&self, started: Instant, written: usize) {
+        let elapsed = started.elapsed();
+        let seconds = elapsed.as_secs_f64();
+        for (bound, count) in COMMIT_BUCKETS.iter().zip(self.commit_buckets.iter()) {
+            if seconds <= *bound {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.commit_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.nodes_written
+            .fetch_add(written as u64, Ordering::Relaxed);
+        self.commits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_pruned(&self, pruned: usize) {
+        self.nodes_pruned
+            .fetch_add(pruned as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_cache(&self, hit: bool) {
+        let counter = if hit {
+            &self.cache_hits
+        } else {
+            &self.cache_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_proof(&self) {
+        self.proofs.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -2,19 +2,62 @@ extern crate alloc;
 use alloc::vec;
 use alloc::vec::Vec;
 use core::cmp::min;
+use smallvec::SmallVec;
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+use crate::codec::{decode_compact, write_compact, PackedPath};
+use crate::errors::TrieError;
+use crate::trie::TrieResult;
+
+/// The length of the longest keys whose nibbles stay on the stack while they are
+/// looked up, inserted or removed, and in the paths of the nodes holding them: 32
+/// bytes, or 64 with the `long-keys` feature. Longer keys work the same but their
+/// paths are kept on the heap.
+#[cfg(not(feature = "long-keys"))]
+pub const STACK_KEY_LEN: usize = 32;
+#[cfg(feature = "long-keys")]
+pub const STACK_KEY_LEN: usize = 64;
+
+// Room for the nibbles of the longest key kept on the stack and the leaf terminator.
+type NibbleVec = SmallVec<[u8; STACK_KEY_LEN * 2 + 1]>;
+
+/// A path in the trie as nibbles, one per byte. The path of a leaf ends with the
+/// terminator `16`, which follows the last nibble of its key.
+///
+/// Paths convert to and from raw bytes and the hex-prefix (compact) encoding nodes
+/// store them in.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
 pub struct Nibbles {
-    hex_data: Vec<u8>,
+    hex_data: NibbleVec,
 }
 
 impl Nibbles {
-    pub fn from_hex(hex: Vec<u8>) -> Self {
-        Nibbles { hex_data: hex }
+    /// Takes nibbles one per byte, ending with `16` for the path of a leaf.
+    ///
+    /// Every byte must be below 16, except for a last `16`; the path is not checked
+    /// outside debug builds. Use `try_from_hex` for nibbles that are not known to be
+    /// valid.
+    pub fn from_hex(hex: &[u8]) -> Self {
+        debug_assert!(is_valid_hex(hex), "nibbles out of range: {:?}", hex);
+        Nibbles {
+            hex_data: NibbleVec::from_slice(hex),
+        }
+    }
+
+    /// Same as `from_hex`, but fails with `InvalidData` if a byte is not a nibble or
+    /// a last leaf terminator.
+    pub fn try_from_hex(hex: &[u8]) -> TrieResult<Self> {
+        if !is_valid_hex(hex) {
+            return Err(TrieError::InvalidData);
+        }
+        Ok(Nibbles {
+            hex_data: NibbleVec::from_slice(hex),
+        })
     }
 
+    /// Splits the bytes of a key into nibbles, high nibble first, adding the leaf
+    /// terminator if `is_leaf`.
     pub fn from_raw(raw: &[u8], is_leaf: bool) -> Self {
-        let mut hex_data = vec![];
+        let mut hex_data = NibbleVec::with_capacity(raw.len() * 2 + 1);
         for item in raw {
             hex_data.push(*item / 16);
             hex_data.push(*item % 16);
@@ -25,69 +68,48 @@ impl Nibbles {
         Nibbles { hex_data }
     }
 
-    pub fn from_compact(compact: &[u8]) -> Self {
-        let mut hex = vec![];
-        let flag = compact[0];
-
-        let mut is_leaf = false;
-        match flag >> 4 {
-            0x0 => {}
-            0x1 => hex.push(flag % 16),
-            0x2 => is_leaf = true,
-            0x3 => {
-                is_leaf = true;
-                hex.push(flag % 16);
-            }
-            _ => panic!("invalid data"),
-        }
-
-        for item in &compact[1..] {
-            hex.push(item / 16);
-            hex.push(item % 16);
-        }
+    pub fn from_packed(path: PackedPath, is_leaf: bool) -> Self {
+        let mut hex_data = NibbleVec::with_capacity(path.len() + 1);
+        hex_data.extend((0..path.len()).map(|i| path.at(i)));
         if is_leaf {
-            hex.push(16);
+            hex_data.push(16);
         }
+        Nibbles { hex_data }
+    }
 
-        Nibbles { hex_data: hex }
+    /// Reads a hex-prefix encoded path, as stored in leaves and extensions.
+    pub fn from_compact(compact: &[u8]) -> TrieResult<Self> {
+        let (path, is_leaf) = decode_compact(compact)?;
+        Ok(Self::from_packed(path, is_leaf))
     }
 
+    /// Returns whether the path ends with the leaf terminator.
     pub fn is_leaf(&self) -> bool {
-        self.hex_data[self.hex_data.len() - 1] == 16
+        self.hex_data.last() == Some(&16)
     }
 
+    /// Returns the hex-prefix encoding of the path, which records whether it is a
+    /// leaf's and pads an odd number of nibbles.
     pub fn encode_compact(&self) -> Vec<u8> {
-        let mut compact = vec![];
+        let mut compact = Vec::with_capacity(self.len() / 2 + 1);
         let is_leaf = self.is_leaf();
-        let mut hex = if is_leaf {
-            &self.hex_data[0..self.hex_data.len() - 1]
-        } else {
-            &self.hex_data[0..]
-        };
-        // node type    path length    |    prefix    hexchar
-        // --------------------------------------------------
-        // extension    even           |    0000      0x0
-        // extension    odd            |    0001      0x1
-        // leaf         even           |    0010      0x2
-        // leaf         odd            |    0011      0x3
-        let v = if hex.len() % 2 == 1 {
-            let v = 0x10 + hex[0];
-            hex = &hex[1..];
-            v
-        } else {
-            0x00
-        };
-
-        compact.push(v + if is_leaf { 0x20 } else { 0x00 });
-        for i in 0..(hex.len() / 2) {
-            compact.push((hex[i * 2] * 16) + (hex[i * 2 + 1]));
-        }
-
+        let end = self.len() - usize::from(is_leaf);
+        write_compact(&mut compact, &self.hex_data[..end], is_leaf);
         compact
     }
 
+    /// Packs the nibbles two per byte, without the leaf terminator, returning them
+    /// with whether the path is a leaf's. A last odd nibble is dropped.
     pub fn encode_raw(&self) -> (Vec<u8>, bool) {
         let mut raw = vec![];
+        let is_leaf = self.encode_raw_into(&mut raw);
+        (raw, is_leaf)
+    }
+
+    /// Same as `encode_raw`, but reuses the buffer `raw`. Only the low four bits of
+    /// each nibble are packed.
+    pub fn encode_raw_into(&self, raw: &mut Vec<u8>) -> bool {
+        raw.clear();
         let is_leaf = self.is_leaf();
         let hex = if is_leaf {
             &self.hex_data[0..self.hex_data.len() - 1]
@@ -96,10 +118,10 @@ impl Nibbles {
         };
 
         for i in 0..(hex.len() / 2) {
-            raw.push((hex[i * 2] * 16) + (hex[i * 2 + 1]));
+            raw.push(((hex[i * 2] & 0x0f) << 4) | (hex[i * 2 + 1] & 0x0f));
         }
 
-        (raw, is_leaf)
+        is_leaf
     }
 
     pub fn len(&self) -> usize {
@@ -110,39 +132,37 @@ impl Nibbles {
         self.len() == 0
     }
 
+    /// Returns the nibble at `i`. Panics if `i` is out of range.
     pub fn at(&self, i: usize) -> usize {
         self.hex_data[i] as usize
     }
 
+    /// Returns the number of nibbles both paths start with.
     pub fn common_prefix(&self, other_partial: &Nibbles) -> usize {
-        let s = min(self.len(), other_partial.len());
-        let mut i = 0usize;
-        while i < s {
-            if self.at(i) != other_partial.at(i) {
-                break;
-            }
-            i += 1;
-        }
-        i
+        prefix_len(&self.hex_data, &other_partial.hex_data)
     }
 
+    /// Returns the nibbles from `index` on.
     pub fn offset(&self, index: usize) -> Nibbles {
         self.slice(index, self.hex_data.len())
     }
 
+    /// Returns the nibbles from `start` to `end`. Panics if the range is out of
+    /// bounds.
     pub fn slice(&self, start: usize, end: usize) -> Nibbles {
-        Nibbles::from_hex(self.hex_data[start..end].to_vec())
+        Nibbles::from_hex(&self.hex_data[start..end])
     }
 
+    /// Returns the nibbles one per byte.
     pub fn get_data(&self) -> &[u8] {
         &self.hex_data
     }
 
     pub fn join(&self, b: &Nibbles) -> Nibbles {
-        let mut hex_data = vec![];
+        let mut hex_data = NibbleVec::with_capacity(self.len() + b.len());
         hex_data.extend_from_slice(self.get_data());
         hex_data.extend_from_slice(b.get_data());
-        Nibbles::from_hex(hex_data)
+        Nibbles { hex_data }
     }
 
     pub fn extend(&mut self, b: &Nibbles) {
@@ -162,17 +182,347 @@ impl Nibbles {
     }
 }
 
+/// A borrowed view of the nibbles of a key followed by the leaf terminator `16`,
+/// the form keys take when looked up in the trie. Walking it never allocates.
+#[derive(Debug, Clone, Copy)]
+pub struct NibbleSlice<'a> {
+    data: &'a [u8],
+    start: usize,
+    end: usize,
+    // Whether `data` holds one nibble per byte instead of two.
+    unpacked: bool,
+}
+
+impl<'a> NibbleSlice<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        NibbleSlice {
+            data,
+            start: 0,
+            end: data.len() * 2 + 1,
+            unpacked: false,
+        }
+    }
+
+    /// Views a key already split into nibbles, one per byte.
+    pub fn from_nibbles(nibbles: &'a [u8]) -> Self {
+        NibbleSlice {
+            data: nibbles,
+            start: 0,
+            end: nibbles.len() + 1,
+            unpacked: true,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn at(&self, i: usize) -> usize {
+        let i = self.start + i;
+        assert!(i < self.end, "nibble index out of range");
+        if self.unpacked {
+            return self.data.get(i).map_or(16, |nibble| *nibble as usize);
+        }
+        match self.data.get(i / 2) {
+            Some(byte) if i & 1 == 0 => (byte >> 4) as usize,
+            Some(byte) => (byte & 0x0f) as usize,
+            None => 16,
+        }
+    }
+
+    pub fn common_prefix(&self, other_partial: &Nibbles) -> usize {
+        if self.unpacked {
+            self.unpacked_common_prefix(other_partial)
+        } else {
+            self.packed_common_prefix(other_partial)
+        }
+    }
+
+    // Compares the nibbles held in `data` as one slice, then the terminator.
+    fn unpacked_common_prefix(&self, other_partial: &Nibbles) -> usize {
+        let other = other_partial.get_data();
+        let held = &self.data[min(self.start, self.data.len())..min(self.end, self.data.len())];
+        let matched = prefix_len(held, other);
+        if matched == held.len() && self.len() > held.len() && other.get(matched) == Some(&16) {
+            matched + 1
+        } else {
+            matched
+        }
+    }
+
+    #[cfg(not(feature = "simd"))]
+    fn packed_common_prefix(&self, other_partial: &Nibbles) -> usize {
+        let s = min(self.len(), other_partial.len());
+        let mut i = 0usize;
+        while i < s {
+            if self.at(i) != other_partial.at(i) {
+                break;
+            }
+            i += 1;
+        }
+        i
+    }
+
+    #[cfg(feature = "simd")]
+    fn packed_common_prefix(&self, other_partial: &Nibbles) -> usize {
+        // Unpacks the key a block at a time so whole blocks can be compared at once.
+        let other = other_partial.get_data();
+        let s = min(self.len(), other.len());
+        let mut block = [0u8; 16];
+        let mut i = 0usize;
+        while i < s {
+            let n = min(block.len(), s - i);
+            for (j, nibble) in block[..n].iter_mut().enumerate() {
+                *nibble = self.at(i + j) as u8;
+            }
+            let matched = prefix_len(&block[..n], &other[i..i + n]);
+            i += matched;
+            if matched < n {
+                break;
+            }
+        }
+        i
+    }
+
+    pub fn offset(&self, index: usize) -> NibbleSlice<'a> {
+        self.slice(index, self.len())
+    }
+
+    pub fn slice(&self, start: usize, end: usize) -> NibbleSlice<'a> {
+        assert!(
+            start <= end && end <= self.len(),
+            "nibble slice out of range"
+        );
+        NibbleSlice {
+            data: self.data,
+            start: self.start + start,
+            end: self.start + end,
+            unpacked: self.unpacked,
+        }
+    }
+
+    /// Copies the viewed nibbles into an owned `Nibbles`.
+    pub fn to_nibbles(self) -> Nibbles {
+        Nibbles {
+            hex_data: (0..self.len()).map(|i| self.at(i) as u8).collect(),
+        }
+    }
+}
+
+// The nibbles of a key of up to `STACK_KEY_LEN` bytes, such as the 32-byte hashed
+// keys of Ethereum tries, unpacked on the stack once so that each step of a lookup
+// compares them as a slice instead of splitting bytes nibble by nibble.
+pub(crate) struct KeyNibbles {
+    nibbles: [u8; STACK_KEY_LEN * 2],
+    len: usize,
+}
+
+impl KeyNibbles {
+    pub(crate) fn new(key: &[u8]) -> Option<Self> {
+        if key.len() > STACK_KEY_LEN {
+            return None;
+        }
+        let mut nibbles = [0u8; STACK_KEY_LEN * 2];
+        for (pair, byte) in nibbles.chunks_exact_mut(2).zip(key) {
+            pair[0] = byte >> 4;
+            pair[1] = byte & 0x0f;
+        }
+        Some(KeyNibbles {
+            nibbles,
+            len: key.len() * 2,
+        })
+    }
+
+    pub(crate) fn as_slice(&self) -> NibbleSlice<'_> {
+        NibbleSlice::from_nibbles(&self.nibbles[..self.len])
+    }
+}
+
+// Calls `f` with the path a lookup of `key` walks, on the stack for keys of up to
+// `STACK_KEY_LEN` bytes.
+pub(crate) fn with_key_path<T>(key: &[u8], f: impl FnOnce(NibbleSlice) -> T) -> T {
+    match KeyNibbles::new(key) {
+        Some(nibbles) => f(nibbles.as_slice()),
+        None => f(NibbleSlice::new(key)),
+    }
+}
+
+// Returns the length of the common prefix of the path of a node as stored in its
+// encoding and `partial`, without unpacking the path.
+pub(crate) fn packed_common_prefix(path: &PackedPath, partial: NibbleSlice) -> usize {
+    let s = min(path.len(), partial.len());
+    (0..s)
+        .position(|i| usize::from(path.at(i)) != partial.at(i))
+        .unwrap_or(s)
+}
+
+// Whether `partial` is the key of a leaf whose path is stored as `path`.
+pub(crate) fn packed_leaf_eq(path: &PackedPath, partial: NibbleSlice) -> bool {
+    partial.len() == path.len() + 1
+        && partial.at(path.len()) == 16
+        && packed_common_prefix(path, partial) == path.len()
+}
+
+impl PartialEq<Nibbles> for NibbleSlice<'_> {
+    fn eq(&self, other: &Nibbles) -> bool {
+        self.len() == other.len() && self.common_prefix(other) == self.len()
+    }
+}
+
+impl PartialEq<NibbleSlice<'_>> for Nibbles {
+    fn eq(&self, other: &NibbleSlice<'_>) -> bool {
+        other == self
+    }
+}
+
+// Returns whether every byte is a nibble, except for a last leaf terminator.
+fn is_valid_hex(hex: &[u8]) -> bool {
+    let path = match hex.split_last() {
+        Some((16, path)) => path,
+        _ => hex,
+    };
+    path.iter().all(|&nibble| nibble < 16)
+}
+
+// Returns the length of the common prefix of two nibble arrays.
+#[cfg(not(feature = "simd"))]
+fn prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+// Compares 16 nibbles at a time with SSE2 where available, then 8 at a time as a
+// single word, and only walks the last few nibbles one by one.
+#[cfg(feature = "simd")]
+fn prefix_len(a: &[u8], b: &[u8]) -> usize {
+    let len = min(a.len(), b.len());
+    let mut i = 0usize;
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        use core::arch::x86_64::{__m128i, _mm_cmpeq_epi8, _mm_loadu_si128, _mm_movemask_epi8};
+
+        while i + 16 <= len {
+            // SAFETY: SSE2 is part of the x86_64 baseline, and both loads read 16 bytes
+            // that are in bounds of `a` and `b`.
+            let mask = unsafe {
+                let x = _mm_loadu_si128(a.as_ptr().add(i) as *const __m128i);
+                let y = _mm_loadu_si128(b.as_ptr().add(i) as *const __m128i);
+                _mm_movemask_epi8(_mm_cmpeq_epi8(x, y)) as u32
+            };
+            if mask != 0xffff {
+                return i + (!mask).trailing_zeros() as usize;
+            }
+            i += 16;
+        }
+    }
+
+    while i + 8 <= len {
+        let mut x = [0u8; 8];
+        let mut y = [0u8; 8];
+        x.copy_from_slice(&a[i..i + 8]);
+        y.copy_from_slice(&b[i..i + 8]);
+        let diff = u64::from_le_bytes(x) ^ u64::from_le_bytes(y);
+        if diff != 0 {
+            return i + diff.trailing_zeros() as usize / 8;
+        }
+        i += 8;
+    }
+
+    while i < len && a[i] == b[i] {
+        i += 1;
+    }
+    i
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
-    fn test_nibble() {
+    fn test_nibble_slice() {
         let n = Nibbles::from_raw(b"key1", true);
-        let compact = n.encode_compact();
-        let n2 = Nibbles::from_compact(&compact);
-        let (raw, is_leaf) = n2.encode_raw();
-        assert_eq!(is_leaf, true);
-        assert_eq!(raw, b"key1");
+        let slice = NibbleSlice::new(b"key1");
+        assert_eq!(slice.to_nibbles(), n);
+        assert_eq!(slice.len(), n.len());
+        assert_eq!(slice.at(8), 16);
+
+        let tail = slice.offset(3);
+        assert_eq!(tail, n.offset(3));
+        assert_eq!(tail.slice(1, 4).to_nibbles(), n.slice(4, 7));
+        assert_eq!(tail.common_prefix(&n.offset(3)), tail.len());
+        assert_eq!(slice.common_prefix(&n.slice(0, 5)), 5);
+
+        let unpacked = NibbleSlice::from_nibbles(&n.get_data()[..8]);
+        assert_eq!(unpacked, n);
+        assert_eq!(unpacked.offset(3).slice(1, 4).to_nibbles(), n.slice(4, 7));
+    }
+
+    #[test]
+    fn test_common_prefix_long_keys() {
+        let key = [0x5au8; 40];
+        let n = Nibbles::from_raw(&key, true);
+        for i in 0..key.len() * 2 {
+            let mut other = key;
+            other[i / 2] ^= if i % 2 == 0 { 0x10 } else { 0x01 };
+            let m = Nibbles::from_raw(&other, true);
+            assert_eq!(n.common_prefix(&m), i);
+            assert_eq!(NibbleSlice::new(&key).common_prefix(&m), i);
+            if i >= 3 {
+                let tail = NibbleSlice::new(&key).offset(3);
+                assert_eq!(tail.common_prefix(&m.offset(3)), i - 3);
+            }
+        }
+        assert_eq!(n.common_prefix(&n), n.len());
+        assert_eq!(n.common_prefix(&n.slice(0, 70)), 70);
+    }
+
+    #[test]
+    fn test_key_nibbles() {
+        assert!(KeyNibbles::new(&[0; STACK_KEY_LEN + 1]).is_none());
+        let short = KeyNibbles::new(b"key1").unwrap();
+        assert_eq!(
+            short.as_slice().to_nibbles(),
+            Nibbles::from_raw(b"key1", true)
+        );
+
+        let key = [0xa5u8; 32];
+        let n = Nibbles::from_raw(&key, true);
+        let nibbles = KeyNibbles::new(&key).unwrap();
+        let path = nibbles.as_slice();
+        assert_eq!(path.to_nibbles(), n);
+        for i in 0..n.len() {
+            let mut other = n.clone();
+            other.truncate(i);
+            other.push(if n.at(i) == 16 { 0 } else { 16 });
+            assert_eq!(path.common_prefix(&other), i);
+            assert_eq!(path.offset(i).common_prefix(&n.offset(i)), n.len() - i);
+            assert_eq!(path.slice(0, i).common_prefix(&n), i);
+        }
+    }
+
+    #[test]
+    fn test_packed_paths() {
+        let key = [0x12u8, 0x34, 0x56];
+        let n = Nibbles::from_raw(&key, true);
+        let packed = PackedPath {
+            data: &key,
+            start: 1,
+        };
+        let nibbles = KeyNibbles::new(&key).unwrap();
+        let partial = nibbles.as_slice();
+        assert!(packed_leaf_eq(&packed, partial.offset(1)));
+        assert!(!packed_leaf_eq(&packed, partial.offset(2)));
+        assert!(!packed_leaf_eq(&packed, partial.slice(1, 6)));
+        assert_eq!(packed_common_prefix(&packed, partial.offset(1)), 5);
+        assert_eq!(packed_common_prefix(&packed, partial), 0);
+        assert_eq!(
+            packed_common_prefix(&packed, NibbleSlice::new(&key).offset(1)),
+            5
+        );
+        assert_eq!(n.offset(1), Nibbles::from_packed(packed, true));
     }
 }
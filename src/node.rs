@@ -1,17 +1,19 @@
 extern crate alloc;
-use alloc::rc::Rc;
+use alloc::sync::Arc;
+use alloc::vec;
 use alloc::vec::Vec;
-use core::cell::RefCell;
+use core::mem::size_of;
 use ethereum_types::H256;
 
+use crate::hasher::H256Set;
 use crate::nibbles::Nibbles;
 
 #[derive(Debug, Clone)]
 pub enum Node {
     Empty,
-    Leaf(Rc<RefCell<LeafNode>>),
-    Extension(Rc<RefCell<ExtensionNode>>),
-    Branch(Rc<RefCell<BranchNode>>),
+    Leaf(Arc<LeafNode>),
+    Extension(Arc<ExtensionNode>),
+    Branch(Arc<BranchNode>),
     Hash(H256),
 }
 
@@ -22,35 +24,121 @@ pub enum RawNodeOrHash {
 
 impl Node {
     pub fn from_leaf(key: Nibbles, value: Vec<u8>) -> Self {
-        let leaf = Rc::new(RefCell::new(LeafNode { key, value }));
+        let leaf = Arc::new(LeafNode {
+            key,
+            value,
+            hash: None,
+        });
         Node::Leaf(leaf)
     }
 
     pub fn from_branch(children: [Node; 16], value: Option<Vec<u8>>) -> Self {
-        let branch = Rc::new(RefCell::new(BranchNode { children, value }));
+        let branch = Arc::new(BranchNode {
+            children,
+            value,
+            hash: None,
+        });
         Node::Branch(branch)
     }
 
     pub fn from_extension(prefix: Nibbles, node: Node) -> Self {
-        let ext = Rc::new(RefCell::new(ExtensionNode { prefix, node }));
+        let ext = Arc::new(ExtensionNode {
+            prefix,
+            node,
+            hash: None,
+        });
         Node::Extension(ext)
     }
 
     pub fn from_hash(hash: H256) -> Self {
         Node::Hash(hash)
     }
+
+    /// Records the hash a freshly decoded node is stored under in the db.
+    pub fn with_hash(mut self, hash: H256) -> Self {
+        match &mut self {
+            Node::Leaf(leaf) => Arc::make_mut(leaf).hash = Some(hash),
+            Node::Extension(ext) => Arc::make_mut(ext).hash = Some(hash),
+            Node::Branch(branch) => Arc::make_mut(branch).hash = Some(hash),
+            Node::Empty | Node::Hash(_) => {}
+        }
+        self
+    }
+
+    /// Returns the hash of a node that is unchanged since it was loaded from the db.
+    pub fn cached_hash(&self) -> Option<H256> {
+        match self {
+            Node::Leaf(leaf) => leaf.hash,
+            Node::Extension(ext) => ext.hash,
+            Node::Branch(branch) => branch.hash,
+            Node::Empty | Node::Hash(_) => None,
+        }
+    }
+
+    /// Returns the approximate number of heap bytes held by the node and the nodes
+    /// kept in memory below it.
+    pub fn heap_size(&self) -> usize {
+        let mut size = 0;
+        let mut stack = vec![self];
+        while let Some(n) = stack.pop() {
+            match n {
+                Node::Leaf(leaf) => size += size_of::<LeafNode>() + leaf.value.capacity(),
+                Node::Extension(ext) => {
+                    size += size_of::<ExtensionNode>();
+                    stack.push(&ext.node);
+                }
+                Node::Branch(branch) => {
+                    size += size_of::<BranchNode>();
+                    size += branch.value.as_ref().map_or(0, |v| v.capacity());
+                    stack.extend(branch.children.iter());
+                }
+                Node::Empty | Node::Hash(_) => {}
+            }
+        }
+        size
+    }
 }
 
-#[derive(Debug)]
+/// Implemented by the node types that cache the hash they are stored under.
+pub trait CachedHash: Clone {
+    fn take_hash(&mut self) -> Option<H256>;
+}
+
+/// Returns the node for modification, cloning it first if it is shared.
+/// The cached hash is dropped since the node no longer matches it.
+///
+/// Every change to a node goes through here, so a node reachable from another
+/// root, trie, iterator or cache entry is never altered.
+pub fn make_mut<'a, T: CachedHash>(node: &'a mut Arc<T>, stale: &mut H256Set) -> &'a mut T {
+    let node = Arc::make_mut(node);
+    stale.extend(node.take_hash());
+    node
+}
+
+#[derive(Debug, Clone)]
 pub struct LeafNode {
     pub key: Nibbles,
     pub value: Vec<u8>,
+    pub hash: Option<H256>,
+}
+
+impl CachedHash for LeafNode {
+    fn take_hash(&mut self) -> Option<H256> {
+        self.hash.take()
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct BranchNode {
     pub children: [Node; 16],
     pub value: Option<Vec<u8>>,
+    pub hash: Option<H256>,
+}
+
+impl CachedHash for BranchNode {
+    fn take_hash(&mut self) -> Option<H256> {
+        self.hash.take()
+    }
 }
 
 impl BranchNode {
@@ -58,7 +146,7 @@ impl BranchNode {
         if i == 16 {
             match n {
                 Node::Leaf(leaf) => {
-                    self.value = Some(leaf.borrow().value.clone());
+                    self.value = Some(leaf.value.clone());
                 }
                 _ => panic!("The n must be leaf node"),
             }
@@ -68,15 +156,17 @@ impl BranchNode {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ExtensionNode {
     pub prefix: Nibbles,
     pub node: Node,
+    pub hash: Option<H256>,
 }
 
-#[derive(Debug)]
-pub struct HashNode {
-    pub hash: H256,
+impl CachedHash for ExtensionNode {
+    fn take_hash(&mut self) -> Option<H256> {
+        self.hash.take()
+    }
 }
 
 pub fn empty_children() -> [Node; 16] {
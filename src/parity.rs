@@ -0,0 +1,138 @@
+extern crate alloc;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+
+use hash_db::{AsHashDB, Hasher as ParityHasher, Prefix, EMPTY_PREFIX};
+
+use crate::db::HashDB;
+use crate::H256;
+
+/// A db of this crate seen as a `hash_db::HashDB`, so code built on `trie-db` can
+/// share its nodes. `HH` is the `hash_db::Hasher` its nodes are keyed with.
+///
+/// Prefixes are ignored, and a removal deletes the node at once instead of
+/// decreasing a reference count.
+pub struct ParityHashDB<D, HH> {
+    db: D,
+    marker: PhantomData<fn() -> HH>,
+}
+
+impl<D: HashDB, HH> ParityHashDB<D, HH> {
+    pub fn new(db: D) -> Self {
+        Self {
+            db,
+            marker: PhantomData,
+        }
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.db
+    }
+
+    pub fn inner_mut(&mut self) -> &mut D {
+        &mut self.db
+    }
+
+    pub fn into_inner(self) -> D {
+        self.db
+    }
+}
+
+impl<D, HH> hash_db::HashDB<HH, Vec<u8>> for ParityHashDB<D, HH>
+where
+    D: HashDB + Send + Sync,
+    HH: ParityHasher<Out = H256>,
+{
+    fn get(&self, key: &H256, _prefix: Prefix) -> Option<Vec<u8>> {
+        self.db.get(key)
+    }
+
+    fn contains(&self, key: &H256, _prefix: Prefix) -> bool {
+        self.db.contains(key)
+    }
+
+    fn insert(&mut self, _prefix: Prefix, value: &[u8]) -> H256 {
+        let key = HH::hash(value);
+        self.db.insert(key, value.to_vec());
+        key
+    }
+
+    fn emplace(&mut self, key: H256, _prefix: Prefix, value: Vec<u8>) {
+        self.db.insert(key, value);
+    }
+
+    fn remove(&mut self, key: &H256, _prefix: Prefix) {
+        self.db.remove(key);
+    }
+}
+
+impl<D, HH> AsHashDB<HH, Vec<u8>> for ParityHashDB<D, HH>
+where
+    D: HashDB + Send + Sync,
+    HH: ParityHasher<Out = H256>,
+{
+    fn as_hash_db(&self) -> &dyn hash_db::HashDB<HH, Vec<u8>> {
+        self
+    }
+
+    fn as_hash_db_mut<'a>(&'a mut self) -> &'a mut (dyn hash_db::HashDB<HH, Vec<u8>> + 'a) {
+        self
+    }
+}
+
+/// A `hash_db::HashDB`, such as a `memory_db::MemoryDB`, used as the db of the tries
+/// of this crate. Nodes are read and written with the empty prefix, so the db must
+/// key them by hash alone (`memory_db::HashKey`).
+pub struct ParityBackedDB<DB, HH> {
+    db: DB,
+    marker: PhantomData<fn() -> HH>,
+}
+
+impl<DB, HH> ParityBackedDB<DB, HH>
+where
+    DB: hash_db::HashDB<HH, Vec<u8>>,
+    HH: ParityHasher<Out = H256>,
+{
+    pub fn new(db: DB) -> Self {
+        Self {
+            db,
+            marker: PhantomData,
+        }
+    }
+
+    pub fn inner(&self) -> &DB {
+        &self.db
+    }
+
+    pub fn inner_mut(&mut self) -> &mut DB {
+        &mut self.db
+    }
+
+    pub fn into_inner(self) -> DB {
+        self.db
+    }
+}
+
+impl<DB, HH> HashDB for ParityBackedDB<DB, HH>
+where
+    DB: hash_db::HashDB<HH, Vec<u8>>,
+    HH: ParityHasher<Out = H256>,
+{
+    fn get(&self, key: &H256) -> Option<Vec<u8>> {
+        self.db.get(key, EMPTY_PREFIX)
+    }
+
+    fn contains(&self, key: &H256) -> bool {
+        self.db.contains(key, EMPTY_PREFIX)
+    }
+
+    fn insert(&mut self, key: H256, value: Vec<u8>) {
+        self.db.emplace(key, EMPTY_PREFIX, value);
+    }
+
+    fn remove(&mut self, key: &H256) {
+        self.db.remove(key, EMPTY_PREFIX);
+    }
+
+    fn flush(&mut self) {}
+}
@@ -0,0 +1,257 @@
+extern crate alloc;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::marker::PhantomData;
+
+use crate::codec::{ChildReference, DecodedNode, NodeCodec, RlpNodeCodec};
+use crate::db::HashDB;
+use crate::hasher::{decode_digest, H256Map, Hasher, KeccakHasher};
+use crate::H256;
+
+/// Storage of trie nodes keyed by the trie they belong to, its `owner`, and their
+/// path from its root, one nibble per byte. Each trie only keeps its latest nodes.
+pub trait PathDB {
+    fn get(&self, owner: &H256, path: &[u8]) -> Option<Vec<u8>>;
+
+    fn insert(&mut self, owner: &H256, path: &[u8], node: Vec<u8>);
+
+    /// Removes the node at `path`, but not the ones below it.
+    fn remove(&mut self, owner: &H256, path: &[u8]);
+
+    /// Removes the nodes at `prefix` and below it.
+    fn remove_prefix(&mut self, owner: &H256, prefix: &[u8]);
+}
+
+/// A `PathDB` keeping the nodes of every owner in one ordered map.
+#[derive(Default, Debug)]
+pub struct MemoryPathDB {
+    storage: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl MemoryPathDB {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of nodes stored for `owner`.
+    pub fn len(&self, owner: &H256) -> usize {
+        self.range(owner, &[]).count()
+    }
+
+    pub fn is_empty(&self, owner: &H256) -> bool {
+        self.len(owner) == 0
+    }
+
+    fn range<'a>(&'a self, owner: &H256, prefix: &[u8]) -> impl Iterator<Item = &'a Vec<u8>> {
+        let start = storage_key(owner, prefix);
+        self.storage
+            .range(start.clone()..)
+            .map(|(key, _)| key)
+            .take_while(move |key| key.starts_with(&start))
+    }
+}
+
+impl PathDB for MemoryPathDB {
+    fn get(&self, owner: &H256, path: &[u8]) -> Option<Vec<u8>> {
+        self.storage.get(&storage_key(owner, path)).cloned()
+    }
+
+    fn insert(&mut self, owner: &H256, path: &[u8], node: Vec<u8>) {
+        self.storage.insert(storage_key(owner, path), node);
+    }
+
+    fn remove(&mut self, owner: &H256, path: &[u8]) {
+        self.storage.remove(&storage_key(owner, path));
+    }
+
+    fn remove_prefix(&mut self, owner: &H256, prefix: &[u8]) {
+        let keys: Vec<Vec<u8>> = self.range(owner, prefix).cloned().collect();
+        for key in keys {
+            self.storage.remove(&key);
+        }
+    }
+}
+
+fn storage_key(owner: &H256, path: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(32 + path.len());
+    key.extend_from_slice(owner.as_bytes());
+    key.extend_from_slice(path);
+    key
+}
+
+/// The nodes of the trie of `owner` in a `PathDB`, served by hash so that a
+/// `PatriciaTrieMut` can use them as its db.
+///
+/// Lookups walk down from the root, so the path of every node asked for is known
+/// from its parent. A commit writes the nodes of the new root at their paths and
+/// removes the paths below them that the trie no longer uses, so nodes it replaced
+/// need no removal by hash. Only the latest root of the trie can be opened.
+pub struct PathHashDB<'a, P: PathDB, H: Hasher = KeccakHasher, C: NodeCodec = RlpNodeCodec> {
+    db: &'a mut P,
+    owner: H256,
+    // Paths of the nodes met so far by the hash their parent refers to them with.
+    paths: RefCell<H256Map<Vec<u8>>>,
+    // Nodes inserted since the last commit, laid out once the new root is known.
+    pending: H256Map<Vec<u8>>,
+    marker: PhantomData<fn() -> (H, C)>,
+}
+
+impl<'a, P: PathDB, H: Hasher, C: NodeCodec> PathHashDB<'a, P, H, C> {
+    pub fn new(db: &'a mut P, owner: H256) -> Self {
+        Self {
+            db,
+            owner,
+            paths: RefCell::new(H256Map::default()),
+            pending: H256Map::default(),
+            marker: PhantomData,
+        }
+    }
+
+    pub fn owner(&self) -> H256 {
+        self.owner
+    }
+
+    pub fn path_db(&self) -> &P {
+        self.db
+    }
+
+    // Records the paths of the children a node at `path` refers to by hash.
+    fn record_children(&self, path: &[u8], data: &[u8]) {
+        let mut paths = self.paths.borrow_mut();
+        for_each_hashed_child::<H, C>(data, path, |child_path, hash| {
+            paths.insert(hash, child_path);
+        });
+    }
+
+    // Writes the new nodes reachable from `root` at their paths, walking down from
+    // the root and stopping at nodes that are already stored.
+    fn write_from(&mut self, root: H256) {
+        let mut stack = vec![(Vec::new(), root)];
+        while let Some((path, hash)) = stack.pop() {
+            let data = match self.pending.get(&hash) {
+                Some(data) => data,
+                None => continue,
+            };
+            let node = match C::decode(data) {
+                Ok(node) => node,
+                Err(_) => continue,
+            };
+            // Whatever was below the node's path and is not below one of its
+            // children stored by hash is gone.
+            let owner = self.owner;
+            let mut child_path = path.clone();
+            match node {
+                DecodedNode::Empty => self.db.remove_prefix(&owner, &path),
+                DecodedNode::Leaf(..) => {
+                    for i in 0..16 {
+                        child_path.push(i);
+                        self.db.remove_prefix(&owner, &child_path);
+                        child_path.pop();
+                    }
+                }
+                DecodedNode::Extension(prefix, child) => {
+                    for k in 0..prefix.len() {
+                        if k > 0 {
+                            self.db.remove(&owner, &child_path);
+                        }
+                        for i in (0..16).filter(|i| *i != prefix.at(k)) {
+                            child_path.push(i);
+                            self.db.remove_prefix(&owner, &child_path);
+                            child_path.pop();
+                        }
+                        child_path.push(prefix.at(k));
+                    }
+                    if !matches!(child, ChildReference::Hash(_)) {
+                        self.db.remove_prefix(&owner, &child_path);
+                    }
+                }
+                DecodedNode::Branch(children, _) => {
+                    for (i, child) in children.iter().enumerate() {
+                        if !matches!(child, ChildReference::Hash(_)) {
+                            child_path.push(i as u8);
+                            self.db.remove_prefix(&owner, &child_path);
+                            child_path.pop();
+                        }
+                    }
+                }
+            }
+            self.db.insert(&owner, &path, data.clone());
+
+            let paths = self.paths.get_mut();
+            for_each_hashed_child::<H, C>(data, &path, |child_path, hash| {
+                paths.insert(hash, child_path.clone());
+                stack.push((child_path, hash));
+            });
+            paths.insert(hash, path);
+        }
+    }
+}
+
+// Calls `f` with the path and hash of each child the node `data` at `path` refers
+// to by hash. Malformed digests are skipped, the children cannot be found anyway.
+fn for_each_hashed_child<H: Hasher, C: NodeCodec>(
+    data: &[u8],
+    path: &[u8],
+    mut f: impl FnMut(Vec<u8>, H256),
+) {
+    let mut child_path = path.to_vec();
+    match C::decode(data) {
+        Ok(DecodedNode::Extension(prefix, ChildReference::Hash(digest))) => {
+            if let Ok(hash) = decode_digest::<H>(digest) {
+                child_path.extend((0..prefix.len()).map(|i| prefix.at(i)));
+                f(child_path, hash);
+            }
+        }
+        Ok(DecodedNode::Branch(children, _)) => {
+            for (i, child) in children.iter().enumerate() {
+                if let ChildReference::Hash(digest) = child {
+                    if let Ok(hash) = decode_digest::<H>(digest) {
+                        child_path.push(i as u8);
+                        f(child_path.clone(), hash);
+                        child_path.pop();
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+impl<P: PathDB, H: Hasher, C: NodeCodec> HashDB for PathHashDB<'_, P, H, C> {
+    fn get(&self, key: &H256) -> Option<Vec<u8>> {
+        if let Some(data) = self.pending.get(key) {
+            return Some(data.clone());
+        }
+        // Nodes not met yet can only be the root.
+        let path = self.paths.borrow().get(key).cloned().unwrap_or_default();
+        let data = self.db.get(&self.owner, &path)?;
+        // The path may hold a newer node than the one asked for.
+        if H::hash(&data) != *key {
+            return None;
+        }
+        self.paths.borrow_mut().insert(*key, path.clone());
+        self.record_children(&path, &data);
+        Some(data)
+    }
+
+    fn contains(&self, key: &H256) -> bool {
+        self.get(key).is_some()
+    }
+
+    fn insert(&mut self, key: H256, value: Vec<u8>) {
+        self.pending.insert(key, value);
+    }
+
+    fn remove(&mut self, key: &H256) {
+        self.pending.remove(key);
+    }
+
+    fn commit_root(&mut self, root: &H256) {
+        self.write_from(*root);
+        self.pending.clear();
+    }
+
+    fn flush(&mut self) {}
+}
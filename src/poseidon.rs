@@ -0,0 +1,212 @@
+use ethereum_types::H256;
+
+use crate::hasher::Hasher;
+
+// Poseidon over the Goldilocks field 2^64 - 2^32 + 1 with a width of 12 elements
+// (rate 8, capacity 4), the x^7 S-box, 8 full and 22 partial rounds.
+const P: u64 = 0xffff_ffff_0000_0001;
+const WIDTH: usize = 12;
+const RATE: usize = 8;
+const FULL_ROUNDS: usize = 8;
+const PARTIAL_ROUNDS: usize = 22;
+const ROUNDS: usize = FULL_ROUNDS + PARTIAL_ROUNDS;
+
+// Input bytes packed into each absorbed element, few enough to always stay below P.
+const BYTES_PER_ELEMENT: usize = 7;
+const BLOCK_BYTES: usize = RATE * BYTES_PER_ELEMENT;
+
+const ROUND_CONSTANTS: [[u64; WIDTH]; ROUNDS] = round_constants();
+const MDS: [[u64; WIDTH]; WIDTH] = mds();
+
+/// Poseidon, an arithmetic hash that is cheap to prove in SNARK circuits.
+///
+/// The digest is the first four elements of the state after absorbing the input,
+/// 7 bytes per element with `10*` padding, each element as 8 little-endian bytes.
+/// Round constants come from the Grain LFSR of the Poseidon reference
+/// implementation and the MDS matrix is the Cauchy matrix `1 / (i + j + 12)`, so
+/// circuits checking these tries must use the same parameters.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PoseidonHasher;
+
+impl Hasher for PoseidonHasher {
+    fn hash(data: &[u8]) -> H256 {
+        let mut state = [0u64; WIDTH];
+        // The input is followed by a single 1 byte, then zeros up to a whole block.
+        let blocks = data.len() / BLOCK_BYTES + 1;
+        for block in 0..blocks {
+            for (i, lane) in state[..RATE].iter_mut().enumerate() {
+                let start = block * BLOCK_BYTES + i * BYTES_PER_ELEMENT;
+                let mut bytes = [0u8; 8];
+                for (k, byte) in bytes[..BYTES_PER_ELEMENT].iter_mut().enumerate() {
+                    *byte = padded_byte(data, start + k);
+                }
+                *lane = add(*lane, u64::from_le_bytes(bytes));
+            }
+            permute(&mut state);
+        }
+
+        let mut hash = H256::zero();
+        for (chunk, lane) in hash.0.chunks_mut(8).zip(state.iter()) {
+            chunk.copy_from_slice(&lane.to_le_bytes());
+        }
+        hash
+    }
+}
+
+fn padded_byte(data: &[u8], index: usize) -> u8 {
+    match index.cmp(&data.len()) {
+        core::cmp::Ordering::Less => data[index],
+        core::cmp::Ordering::Equal => 1,
+        core::cmp::Ordering::Greater => 0,
+    }
+}
+
+fn permute(state: &mut [u64; WIDTH]) {
+    let half = FULL_ROUNDS / 2;
+    for (round, constants) in ROUND_CONSTANTS.iter().enumerate() {
+        for (lane, constant) in state.iter_mut().zip(constants.iter()) {
+            *lane = add(*lane, *constant);
+        }
+        if round < half || round >= half + PARTIAL_ROUNDS {
+            for lane in state.iter_mut() {
+                *lane = sbox(*lane);
+            }
+        } else {
+            state[0] = sbox(state[0]);
+        }
+
+        let mut mixed = [0u64; WIDTH];
+        for (out, row) in mixed.iter_mut().zip(MDS.iter()) {
+            for (entry, lane) in row.iter().zip(state.iter()) {
+                *out = add(*out, mul(*entry, *lane));
+            }
+        }
+        *state = mixed;
+    }
+}
+
+fn sbox(x: u64) -> u64 {
+    let x2 = mul(x, x);
+    let x3 = mul(x2, x);
+    let x4 = mul(x2, x2);
+    mul(x4, x3)
+}
+
+const fn add(a: u64, b: u64) -> u64 {
+    ((a as u128 + b as u128) % P as u128) as u64
+}
+
+const fn mul(a: u64, b: u64) -> u64 {
+    ((a as u128 * b as u128) % P as u128) as u64
+}
+
+const fn inverse(a: u64) -> u64 {
+    // Fermat: a^(P - 2) is the inverse of a non-zero a.
+    let mut result = 1;
+    let mut base = a;
+    let mut exp = P - 2;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = mul(result, base);
+        }
+        base = mul(base, base);
+        exp >>= 1;
+    }
+    result
+}
+
+const fn mds() -> [[u64; WIDTH]; WIDTH] {
+    let mut matrix = [[0u64; WIDTH]; WIDTH];
+    let mut i = 0;
+    while i < WIDTH {
+        let mut j = 0;
+        while j < WIDTH {
+            matrix[i][j] = inverse((i + j + WIDTH) as u64);
+            j += 1;
+        }
+        i += 1;
+    }
+    matrix
+}
+
+// The Grain LFSR keeps the last 80 bits of its sequence, the oldest in bit 0.
+const fn grain_step(state: u128) -> (u128, u128) {
+    let bit =
+        ((state >> 62) ^ (state >> 51) ^ (state >> 38) ^ (state >> 23) ^ (state >> 13) ^ state) & 1;
+    ((state >> 1) | (bit << 79), bit)
+}
+
+// Seeds the LFSR with the field type (prime), S-box type (x^alpha), field size,
+// width and round counts, then discards its first 160 bits.
+const fn grain_init() -> u128 {
+    let fields = [
+        (1, 2),
+        (0, 4),
+        (64, 12),
+        (WIDTH as u128, 12),
+        (FULL_ROUNDS as u128, 10),
+        (PARTIAL_ROUNDS as u128, 10),
+        ((1 << 30) - 1, 30),
+    ];
+    let mut state = 0u128;
+    let mut pos = 0;
+    let mut f = 0;
+    while f < fields.len() {
+        let (value, bits) = fields[f];
+        let mut b = bits;
+        while b > 0 {
+            b -= 1;
+            state |= ((value >> b) & 1) << pos;
+            pos += 1;
+        }
+        f += 1;
+    }
+    let mut i = 0;
+    while i < 160 {
+        state = grain_step(state).0;
+        i += 1;
+    }
+    state
+}
+
+// Bits are drawn in pairs and the second is kept only when the first is set.
+const fn grain_bit(state: u128) -> (u128, u128) {
+    let mut state = state;
+    loop {
+        let (next, keep) = grain_step(state);
+        let (next, bit) = grain_step(next);
+        state = next;
+        if keep == 1 {
+            return (state, bit);
+        }
+    }
+}
+
+const fn round_constants() -> [[u64; WIDTH]; ROUNDS] {
+    let mut constants = [[0u64; WIDTH]; ROUNDS];
+    let mut state = grain_init();
+    let mut round = 0;
+    while round < ROUNDS {
+        let mut lane = 0;
+        while lane < WIDTH {
+            // Values of 64 bits are sampled big-endian and rejected unless below P.
+            loop {
+                let mut value = 0u64;
+                let mut b = 0;
+                while b < 64 {
+                    let (next, bit) = grain_bit(state);
+                    state = next;
+                    value = (value << 1) | bit as u64;
+                    b += 1;
+                }
+                if value < P {
+                    constants[round][lane] = value;
+                    break;
+                }
+            }
+            lane += 1;
+        }
+        round += 1;
+    }
+    constants
+}
@@ -0,0 +1,452 @@
+extern crate alloc;
+use alloc::vec::Vec;
+
+use ethereum_types::{Address, U256};
+use rlp::Rlp;
+
+use crate::codec::{ChildReference, DecodeLimits, DecodedNode, NodeCodec, RlpNodeCodec};
+use crate::errors::TrieError;
+use crate::hasher::{decode_digest, H256Map, H256Set, Hasher, KeccakHasher};
+use crate::nibbles::{NibbleSlice, Nibbles};
+use crate::state::{decode_u256, slot_key, Account};
+use crate::trie::TrieResult;
+use crate::{keccak256, H256};
+
+/// The proof of an account and some of its storage slots, as returned by the
+/// `eth_getProof` RPC method (EIP-1186).
+///
+/// With the `serde` feature, it (de)serializes to the JSON of the RPC response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "camelCase")
+)]
+pub struct EIP1186AccountProof {
+    #[cfg_attr(feature = "serde", serde(with = "hex::fixed"))]
+    pub address: Address,
+    #[cfg_attr(feature = "serde", serde(with = "hex::fixed"))]
+    pub code_hash: H256,
+    #[cfg_attr(feature = "serde", serde(with = "hex::quantity"))]
+    pub nonce: u64,
+    #[cfg_attr(feature = "serde", serde(with = "hex::quantity"))]
+    pub balance: U256,
+    #[cfg_attr(feature = "serde", serde(with = "hex::fixed"))]
+    pub storage_hash: H256,
+    /// Encoded state trie nodes from the root to the account.
+    #[cfg_attr(feature = "serde", serde(with = "hex::bytes_list"))]
+    pub account_proof: Vec<Vec<u8>>,
+    pub storage_proof: Vec<StorageProof>,
+}
+
+/// The proof of a storage slot, part of an `EIP1186AccountProof`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StorageProof {
+    #[cfg_attr(feature = "serde", serde(with = "hex::quantity"))]
+    pub key: U256,
+    #[cfg_attr(feature = "serde", serde(with = "hex::quantity"))]
+    pub value: U256,
+    /// Encoded storage trie nodes from the root to the slot.
+    #[cfg_attr(feature = "serde", serde(with = "hex::bytes_list"))]
+    pub proof: Vec<Vec<u8>>,
+}
+
+impl EIP1186AccountProof {
+    /// Returns the proven account.
+    pub fn account(&self) -> Account {
+        Account {
+            nonce: self.nonce,
+            balance: self.balance,
+            storage_root: self.storage_hash,
+            code_hash: self.code_hash,
+        }
+    }
+
+    /// Checks the account against `state_root` and every storage slot against the
+    /// account's storage root. A missing account must be empty.
+    pub fn verify(&self, state_root: H256) -> TrieResult<()> {
+        match verify_proof(state_root, self.address.as_bytes(), &self.account_proof)? {
+            Some(data) => {
+                if rlp::decode::<Account>(&data)? != self.account() {
+                    return Err(TrieError::InvalidProof);
+                }
+            }
+            None => {
+                // Nodes may report the hashes of a missing account as zero.
+                let empty = Account::default();
+                let is_empty = self.nonce == 0
+                    && self.balance.is_zero()
+                    && (self.storage_hash == empty.storage_root || self.storage_hash.is_zero())
+                    && (self.code_hash == empty.code_hash || self.code_hash.is_zero());
+                if !is_empty {
+                    return Err(TrieError::InvalidProof);
+                }
+            }
+        }
+        self.storage_proof
+            .iter()
+            .try_for_each(|storage| storage.verify(self.storage_hash))
+    }
+}
+
+impl StorageProof {
+    /// Checks the slot value against `storage_root`. Missing slots hold zero.
+    pub fn verify(&self, storage_root: H256) -> TrieResult<()> {
+        if verify_slot(storage_root, &self.key, &self.proof)? != self.value {
+            return Err(TrieError::InvalidProof);
+        }
+        Ok(())
+    }
+}
+
+/// Returns the value of `slot` in the storage of the account at `address`, checking
+/// `account_proof` against `state_root` and `storage_proof` against the storage root
+/// of the account, as returned by `eth_getProof`. Missing accounts and slots hold
+/// zero. Returns `None` if a proof is invalid.
+pub fn verify_storage_slot(
+    state_root: H256,
+    address: &Address,
+    slot: &U256,
+    account_proof: &[Vec<u8>],
+    storage_proof: &[Vec<u8>],
+) -> Option<U256> {
+    let storage_root = match verify_proof(state_root, address.as_bytes(), account_proof).ok()? {
+        Some(data) => rlp::decode::<Account>(&data).ok()?.storage_root,
+        None => return Some(U256::zero()),
+    };
+    verify_slot(storage_root, slot, storage_proof).ok()
+}
+
+// Returns the value of `slot` proven under `storage_root`.
+fn verify_slot(storage_root: H256, slot: &U256, proof: &[Vec<u8>]) -> TrieResult<U256> {
+    if storage_root == keccak256(&rlp::NULL_RLP) || storage_root.is_zero() {
+        return Ok(U256::zero());
+    }
+    match verify_proof(storage_root, slot_key(slot).as_bytes(), proof)? {
+        Some(data) => Ok(decode_u256(&Rlp::new(&data))?),
+        None => Ok(U256::zero()),
+    }
+}
+
+// Checks a proof by secure key, returning the proven value.
+fn verify_proof(root: H256, key: &[u8], proof: &[Vec<u8>]) -> TrieResult<Option<Vec<u8>>> {
+    let key = keccak256(key);
+    verify_key_proof::<KeccakHasher, RlpNodeCodec>(root, key.as_bytes(), proof, &Default::default())
+}
+
+// Checks that `proof` is the chain of nodes from `root` down the path of `key`,
+// each one referred to by hash from the one before, and returns the value it
+// proves. The proof must end with the node holding the value, or proving there is
+// none, and hold nothing else.
+pub(crate) fn verify_key_proof<H: Hasher, C: NodeCodec>(
+    root: H256,
+    key: &[u8],
+    proof: &[Vec<u8>],
+    limits: &DecodeLimits,
+) -> TrieResult<Option<Vec<u8>>> {
+    if proof.is_empty() && root == H::hash(C::EMPTY_NODE) {
+        return Ok(None);
+    }
+    if proof.len() > limits.max_depth {
+        return Err(TrieError::LimitExceeded.at_proof_item(limits.max_depth));
+    }
+    let mut partial = NibbleSlice::new(key);
+    let mut expected = root;
+    for (index, data) in proof.iter().enumerate() {
+        // Nodes below the root shorter than a digest are embedded, not hashed.
+        if H::hash(data) != expected || (index > 0 && data.len() < H::LENGTH) {
+            return Err(TrieError::InvalidProof.at_proof_item(index));
+        }
+        match walk_node::<H, C>(data, &mut partial, limits).map_err(|e| e.at_proof_item(index))? {
+            Walked::Value(value) => {
+                if index + 1 < proof.len() {
+                    return Err(TrieError::InvalidProof.at_proof_item(index + 1));
+                }
+                return Ok(value.map(|value| value.to_vec()));
+            }
+            Walked::Hash(hash) => expected = hash,
+        }
+    }
+    Err(TrieError::InvalidProof.at_proof_item(proof.len()))
+}
+
+// Checks that `proof` holds the nodes from `root` down the paths of all of `keys`,
+// each stored once in any order, and that they prove none of the keys has a value.
+// Nodes off every path make the proof wrong.
+pub(crate) fn verify_exclusion<H: Hasher, C: NodeCodec, K: AsRef<[u8]>>(
+    root: H256,
+    keys: &[K],
+    proof: &[Vec<u8>],
+    limits: &DecodeLimits,
+) -> TrieResult<()> {
+    let hashes: Vec<H256> = proof.iter().map(|data| H::hash(data)).collect();
+    let mut nodes = H256Map::default();
+    for (index, hash) in hashes.iter().enumerate() {
+        // Nodes shorter than a digest are embedded, not hashed, unless at the root.
+        if (proof[index].len() < H::LENGTH && *hash != root) || nodes.insert(*hash, index).is_some()
+        {
+            return Err(TrieError::InvalidProof.at_proof_item(index));
+        }
+    }
+    let mut used = H256Set::default();
+    for key in keys {
+        let key = key.as_ref();
+        let mut partial = NibbleSlice::new(key);
+        let mut expected = root;
+        let mut depth = 0;
+        let value = loop {
+            let index = match nodes.get(&expected) {
+                Some(index) => *index,
+                None if expected == H::hash(C::EMPTY_NODE) && depth == 0 => break None,
+                None => return Err(TrieError::InvalidProof.at_key(key)),
+            };
+            used.insert(expected);
+            depth += 1;
+            if depth > limits.max_depth {
+                return Err(TrieError::LimitExceeded.at_proof_item(index).at_key(key));
+            }
+            match walk_node::<H, C>(&proof[index], &mut partial, limits)
+                .map_err(|e| e.at_proof_item(index).at_key(key))?
+            {
+                Walked::Value(value) => break value,
+                Walked::Hash(hash) => expected = hash,
+            }
+        };
+        if value.is_some() {
+            return Err(TrieError::InvalidProof.at_key(key));
+        }
+    }
+    match hashes.iter().position(|hash| !used.contains(hash)) {
+        Some(index) => Err(TrieError::InvalidProof.at_proof_item(index)),
+        None => Ok(()),
+    }
+}
+
+// Where the path of a key leads after an encoded node.
+enum Walked<'a> {
+    // The path ends in the node, at this value if any.
+    Value(Option<&'a [u8]>),
+    // The path goes on in the child stored under this hash.
+    Hash(H256),
+}
+
+// Follows `partial` through the encoded node `data` and the children embedded in
+// it, consuming the nibbles walked.
+fn walk_node<'a, H: Hasher, C: NodeCodec>(
+    data: &'a [u8],
+    partial: &mut NibbleSlice,
+    limits: &DecodeLimits,
+) -> TrieResult<Walked<'a>> {
+    let mut data = data;
+    let mut depth = 0;
+    loop {
+        let node = C::decode(data).map_err(|_| TrieError::InvalidProof)?;
+        limits.check(&node, data.len(), depth)?;
+        let child = match node {
+            DecodedNode::Empty => return Ok(Walked::Value(None)),
+            DecodedNode::Leaf(path, value) => {
+                let found = Nibbles::from_packed(path, true) == *partial;
+                return Ok(Walked::Value(if found { Some(value) } else { None }));
+            }
+            DecodedNode::Extension(path, child) => {
+                let prefix = Nibbles::from_packed(path, false);
+                if partial.common_prefix(&prefix) != prefix.len() {
+                    return Ok(Walked::Value(None));
+                }
+                *partial = partial.offset(prefix.len());
+                child
+            }
+            DecodedNode::Branch(children, value) => {
+                if partial.is_empty() || partial.at(0) == 16 {
+                    return Ok(Walked::Value(value));
+                }
+                let child = children[partial.at(0)];
+                *partial = partial.offset(1);
+                child
+            }
+        };
+        match child {
+            ChildReference::Empty => return Ok(Walked::Value(None)),
+            ChildReference::Hash(digest) => {
+                let hash = decode_digest::<H>(digest).map_err(|_| TrieError::InvalidProof)?;
+                return Ok(Walked::Hash(hash));
+            }
+            ChildReference::Inline(inline) if inline.len() >= H::LENGTH => {
+                return Err(TrieError::InvalidProof)
+            }
+            ChildReference::Inline(inline) => data = inline,
+        }
+        depth += 1;
+    }
+}
+
+#[cfg(feature = "serde")]
+mod hex {
+    extern crate alloc;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+    fn encode(bytes: &[u8]) -> String {
+        let mut s = String::with_capacity(2 + bytes.len() * 2);
+        s.push_str("0x");
+        for byte in bytes {
+            s.push(DIGITS[(byte >> 4) as usize] as char);
+            s.push(DIGITS[(byte & 0x0f) as usize] as char);
+        }
+        s
+    }
+
+    // Returns the digits after the `0x` prefix.
+    fn digits<E: Error>(s: &str) -> Result<&str, E> {
+        s.strip_prefix("0x")
+            .ok_or_else(|| E::custom("hex string without 0x prefix"))
+    }
+
+    fn nibble(c: u8) -> Option<u8> {
+        match c {
+            b'0'..=b'9' => Some(c - b'0'),
+            b'a'..=b'f' => Some(c - b'a' + 10),
+            b'A'..=b'F' => Some(c - b'A' + 10),
+            _ => None,
+        }
+    }
+
+    fn digit<E: Error>(c: u8) -> Result<u8, E> {
+        nibble(c).ok_or_else(|| E::custom("invalid hex digit"))
+    }
+
+    fn decode<E: Error>(s: &str) -> Result<Vec<u8>, E> {
+        let digits = digits::<E>(s)?.as_bytes();
+        if digits.len() & 1 == 1 {
+            return Err(E::custom("hex string of odd length"));
+        }
+        digits
+            .chunks(2)
+            .map(|pair| Ok((digit::<E>(pair[0])? << 4) | digit::<E>(pair[1])?))
+            .collect()
+    }
+
+    /// Fixed-size hashes and addresses as `0x` followed by all their bytes.
+    pub mod fixed {
+        use super::*;
+
+        pub fn serialize<T: AsRef<[u8]>, S: Serializer>(
+            value: &T,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            serializer.serialize_str(&encode(value.as_ref()))
+        }
+
+        pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+        where
+            T: AsMut<[u8]> + Default,
+            D: Deserializer<'de>,
+        {
+            let bytes = decode(&String::deserialize(deserializer)?)?;
+            let mut value = T::default();
+            if bytes.len() != value.as_mut().len() {
+                return Err(D::Error::custom("hex string of wrong length"));
+            }
+            value.as_mut().copy_from_slice(&bytes);
+            Ok(value)
+        }
+    }
+
+    /// Integers as `0x` followed by their hex digits without leading zeros.
+    pub mod quantity {
+        use super::*;
+        use ethereum_types::U256;
+
+        pub trait Quantity: Sized {
+            fn quantity_bytes(&self) -> [u8; 32];
+            fn parse_digits(digits: &[u8]) -> Option<Self>;
+        }
+
+        impl Quantity for u64 {
+            fn quantity_bytes(&self) -> [u8; 32] {
+                U256::from(*self).quantity_bytes()
+            }
+
+            fn parse_digits(digits: &[u8]) -> Option<Self> {
+                let value = U256::parse_digits(digits)?;
+                if value > U256::from(u64::MAX) {
+                    return None;
+                }
+                Some(value.low_u64())
+            }
+        }
+
+        impl Quantity for U256 {
+            fn quantity_bytes(&self) -> [u8; 32] {
+                let mut bytes = [0u8; 32];
+                self.to_big_endian(&mut bytes);
+                bytes
+            }
+
+            fn parse_digits(digits: &[u8]) -> Option<Self> {
+                if digits.len() > 64 {
+                    return None;
+                }
+                let mut value = U256::zero();
+                for c in digits {
+                    value = (value << 4) | U256::from(nibble(*c)?);
+                }
+                Some(value)
+            }
+        }
+
+        pub fn serialize<T: Quantity, S: Serializer>(
+            value: &T,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            let hex = encode(&value.quantity_bytes());
+            let trimmed = hex[2..].trim_start_matches('0');
+            let mut s = String::from("0x");
+            s.push_str(if trimmed.is_empty() { "0" } else { trimmed });
+            serializer.serialize_str(&s)
+        }
+
+        pub fn deserialize<'de, T: Quantity, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<T, D::Error> {
+            let s = String::deserialize(deserializer)?;
+            let digits = digits::<D::Error>(&s)?.as_bytes();
+            if digits.is_empty() {
+                return Err(D::Error::custom("empty hex quantity"));
+            }
+            T::parse_digits(digits).ok_or_else(|| D::Error::custom("invalid hex quantity"))
+        }
+    }
+
+    /// Lists of byte strings, each as `0x` followed by its bytes.
+    pub mod bytes_list {
+        use super::*;
+        use serde::ser::SerializeSeq;
+
+        pub fn serialize<S: Serializer>(
+            list: &[Vec<u8>],
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            let mut seq = serializer.serialize_seq(Some(list.len()))?;
+            for bytes in list {
+                seq.serialize_element(&encode(bytes))?;
+            }
+            seq.end()
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Vec<Vec<u8>>, D::Error> {
+            Vec::<String>::deserialize(deserializer)?
+                .iter()
+                .map(|s| decode(s))
+                .collect()
+        }
+    }
+}
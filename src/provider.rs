@@ -0,0 +1,33 @@
+extern crate alloc;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::H256;
+
+/// A source of the nodes missing from the local db, such as a peer or an RPC
+/// endpoint serving nodes by hash, for tries that are only partly stored locally.
+///
+/// Nodes are only accepted if they hash to the requested key, so the provider does
+/// not have to be trusted. Tries take providers that are `Send + Sync`, so that
+/// they can be shared between threads.
+pub trait NodeProvider {
+    /// Returns the encoded node stored under `hash`, if it can be found.
+    fn fetch(&self, hash: &H256) -> Option<Vec<u8>>;
+}
+
+impl<F: Fn(&H256) -> Option<Vec<u8>>> NodeProvider for F {
+    fn fetch(&self, hash: &H256) -> Option<Vec<u8>> {
+        self(hash)
+    }
+}
+
+// The provider of a trie, shared by its clones and forks.
+#[derive(Clone)]
+pub(crate) struct SharedProvider(pub(crate) Arc<dyn NodeProvider + Send + Sync>);
+
+impl fmt::Debug for SharedProvider {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("NodeProvider")
+    }
+}
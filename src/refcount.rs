@@ -0,0 +1,318 @@
+extern crate alloc;
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+
+use crate::codec::{NodeCodec, RlpNodeCodec};
+use crate::db::{HashDB, IterableHashDB};
+use crate::hasher::{H256Map, H256Set, Hasher, KeccakHasher};
+use crate::H256;
+
+/// A db counting the references to each node, so that pruning by a trie never
+/// deletes nodes other roots still use.
+///
+/// A node is referenced by each stored node that refers to it by hash and by each
+/// `reference` taken on it. Removals, such as the pruning done by
+/// `PatriciaTrieMut::root`, only delete nodes that have no references left, and the
+/// nodes below them that are left unreferenced in turn. Roots to keep, for instance
+/// older roots still read by other tries, must be referenced before the trie that
+/// replaces them commits.
+pub struct RefCountedDB<D: HashDB, H: Hasher = KeccakHasher, C: NodeCodec = RlpNodeCodec> {
+    db: D,
+    refs: H256Map<usize>,
+    marker: PhantomData<fn() -> (H, C)>,
+}
+
+impl<D: HashDB> RefCountedDB<D> {
+    pub fn new(db: D) -> Self {
+        Self::new_with_hasher(db)
+    }
+}
+
+impl<D: HashDB, H: Hasher, C: NodeCodec> RefCountedDB<D, H, C> {
+    /// Wraps a db whose nodes are hashed with `H` and encoded with `C`. Nodes already
+    /// in `db` are not counted, so it should be empty.
+    pub fn new_with_hasher(db: D) -> Self {
+        Self {
+            db,
+            refs: H256Map::default(),
+            marker: PhantomData,
+        }
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.db
+    }
+
+    pub fn into_inner(self) -> D {
+        self.db
+    }
+
+    /// Returns the number of references to the node `key`.
+    pub fn ref_count(&self, key: &H256) -> usize {
+        self.refs.get(key).copied().unwrap_or(0)
+    }
+
+    /// Takes a reference on `root`, which keeps it and every node below it.
+    pub fn reference(&mut self, root: H256) {
+        *self.refs.entry(root).or_insert(0) += 1;
+    }
+
+    /// Drops a reference taken with `reference`, deleting `root` and the nodes below
+    /// it that are left unreferenced.
+    pub fn dereference(&mut self, root: &H256) {
+        if let Some(count) = self.refs.get_mut(root) {
+            *count -= 1;
+            if *count == 0 {
+                self.refs.remove(root);
+                self.delete(*root);
+            }
+        }
+    }
+
+    // Deletes the node `key` if it is unreferenced, then the nodes it referred to
+    // that are left unreferenced.
+    fn delete(&mut self, key: H256) {
+        let mut stack = vec![key];
+        while let Some(key) = stack.pop() {
+            if self.refs.contains_key(&key) {
+                continue;
+            }
+            let data = match self.db.get(&key) {
+                Some(data) => data,
+                None => continue,
+            };
+            self.db.remove(&key);
+            // A db ignoring removals keeps the node and its references.
+            if self.db.contains(&key) {
+                continue;
+            }
+            for child in hashed_children::<H, C>(&data) {
+                if let Some(count) = self.refs.get_mut(&child) {
+                    *count -= 1;
+                    if *count == 0 {
+                        self.refs.remove(&child);
+                        stack.push(child);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<D: HashDB, H: Hasher, C: NodeCodec> HashDB for RefCountedDB<D, H, C> {
+    fn get(&self, key: &H256) -> Option<Vec<u8>> {
+        self.db.get(key)
+    }
+
+    fn contains(&self, key: &H256) -> bool {
+        self.db.contains(key)
+    }
+
+    fn insert(&mut self, key: H256, value: Vec<u8>) {
+        // A node stored again refers to the same children.
+        if self.db.contains(&key) {
+            return;
+        }
+        for child in hashed_children::<H, C>(&value) {
+            *self.refs.entry(child).or_insert(0) += 1;
+        }
+        self.db.insert(key, value);
+    }
+
+    /// Deletes the node only if it is unreferenced.
+    fn remove(&mut self, key: &H256) {
+        self.delete(*key);
+    }
+
+    fn commit_root(&mut self, root: &H256) {
+        self.db.commit_root(root);
+    }
+
+    fn flush(&mut self) {
+        self.db.flush();
+    }
+}
+
+impl<D: IterableHashDB, H: Hasher, C: NodeCodec> IterableHashDB for RefCountedDB<D, H, C> {
+    fn keys(&self) -> Vec<H256> {
+        self.db.keys()
+    }
+}
+
+/// A db keeping every committed root until it is explicitly released.
+///
+/// Each root a trie commits takes a reference on its nodes, counted like in a
+/// `RefCountedDB`, and the removals done by pruning are ignored. `dereference` drops
+/// the reference of a root, deleting the nodes no other kept root uses, so a node can
+/// keep a window of historical roots without the db growing without bound.
+///
+/// With `with_window`, the db releases the oldest roots itself as later ones are
+/// committed, keeping the nodes of the last few roots so that a reorg can go back to
+/// any of them. Roots pinned with `pin`, such as finalized checkpoints, are kept
+/// whatever the window until they are unpinned.
+pub struct ArchiveDB<D: HashDB, H: Hasher = KeccakHasher, C: NodeCodec = RlpNodeCodec> {
+    db: RefCountedDB<D, H, C>,
+    // Committed roots holding a reference, which they only take once, and the order
+    // they were committed in.
+    roots: H256Set,
+    order: VecDeque<H256>,
+    window: Option<usize>,
+    // Roots holding a reference of their own until unpinned.
+    pinned: H256Set,
+}
+
+impl<D: HashDB> ArchiveDB<D> {
+    pub fn new(db: D) -> Self {
+        Self::new_with_hasher(db)
+    }
+}
+
+impl<D: HashDB, H: Hasher, C: NodeCodec> ArchiveDB<D, H, C> {
+    /// Wraps a db whose nodes are hashed with `H` and encoded with `C`. Nodes already
+    /// in `db` are not counted, so it should be empty.
+    pub fn new_with_hasher(db: D) -> Self {
+        Self {
+            db: RefCountedDB::new_with_hasher(db),
+            roots: H256Set::default(),
+            order: VecDeque::new(),
+            window: None,
+            pinned: H256Set::default(),
+        }
+    }
+
+    /// Keeps only the last `roots` committed roots, releasing the oldest one when a
+    /// new root is committed. A root committed again keeps its place.
+    pub fn with_window(mut self, roots: usize) -> Self {
+        self.window = Some(roots);
+        self.release_outside_window();
+        self
+    }
+
+    pub fn inner(&self) -> &D {
+        self.db.inner()
+    }
+
+    pub fn into_inner(self) -> D {
+        self.db.into_inner()
+    }
+
+    /// Returns the number of references to the node `key`, from the kept roots and
+    /// from the stored nodes referring to it.
+    pub fn ref_count(&self, key: &H256) -> usize {
+        self.db.ref_count(key)
+    }
+
+    /// Returns whether `root` was committed and is not released yet.
+    pub fn is_kept(&self, root: &H256) -> bool {
+        self.roots.contains(root)
+    }
+
+    /// Returns the committed roots that are not released yet, oldest first.
+    pub fn roots(&self) -> Vec<H256> {
+        self.order.iter().copied().collect()
+    }
+
+    /// Releases a committed root, deleting it and the nodes below it that no other
+    /// kept root uses. Returns whether the root was kept.
+    pub fn dereference(&mut self, root: &H256) -> bool {
+        if !self.roots.remove(root) {
+            return false;
+        }
+        self.order.retain(|kept| kept != root);
+        self.db.dereference(root);
+        true
+    }
+
+    /// Keeps `root` and the nodes below it until `unpin`, even once it is released or
+    /// leaves the window. Returns false if the db does not have the root.
+    pub fn pin(&mut self, root: H256) -> bool {
+        if !self.db.contains(&root) {
+            return false;
+        }
+        if self.pinned.insert(root) {
+            self.db.reference(root);
+        }
+        true
+    }
+
+    /// Unpins `root`, deleting it and the nodes below it unless a kept root or
+    /// another pinned root still uses them. Returns whether the root was pinned.
+    pub fn unpin(&mut self, root: &H256) -> bool {
+        if !self.pinned.remove(root) {
+            return false;
+        }
+        self.db.dereference(root);
+        true
+    }
+
+    pub fn is_pinned(&self, root: &H256) -> bool {
+        self.pinned.contains(root)
+    }
+
+    /// Returns the pinned roots, in no particular order.
+    pub fn pinned(&self) -> Vec<H256> {
+        self.pinned.iter().copied().collect()
+    }
+
+    fn release_outside_window(&mut self) {
+        let window = match self.window {
+            Some(window) => window,
+            None => return,
+        };
+        while self.order.len() > window {
+            if let Some(root) = self.order.pop_front() {
+                self.roots.remove(&root);
+                self.db.dereference(&root);
+            }
+        }
+    }
+}
+
+impl<D: HashDB, H: Hasher, C: NodeCodec> HashDB for ArchiveDB<D, H, C> {
+    fn get(&self, key: &H256) -> Option<Vec<u8>> {
+        self.db.get(key)
+    }
+
+    fn contains(&self, key: &H256) -> bool {
+        self.db.contains(key)
+    }
+
+    fn insert(&mut self, key: H256, value: Vec<u8>) {
+        self.db.insert(key, value);
+    }
+
+    /// Ignored: nodes are only deleted when the roots using them are released.
+    fn remove(&mut self, _key: &H256) {}
+
+    fn remove_batch(&mut self, _keys: &[H256]) {}
+
+    /// Keeps `root` until it is released with `dereference` or leaves the window.
+    fn commit_root(&mut self, root: &H256) {
+        if self.roots.insert(*root) {
+            self.db.reference(*root);
+            self.order.push_back(*root);
+            self.release_outside_window();
+        }
+        self.db.commit_root(root);
+    }
+
+    fn flush(&mut self) {
+        self.db.flush();
+    }
+}
+
+impl<D: IterableHashDB, H: Hasher, C: NodeCodec> IterableHashDB for ArchiveDB<D, H, C> {
+    fn keys(&self) -> Vec<H256> {
+        self.db.keys()
+    }
+}
+
+// Returns the hashes of the children the node `data` refers to by hash. A node that
+// fails to decode refers to none.
+fn hashed_children<H: Hasher, C: NodeCodec>(data: &[u8]) -> Vec<H256> {
+    C::decode(data)
+        .and_then(|node| node.hashed_children::<H>())
+        .unwrap_or_default()
+}
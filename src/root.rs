@@ -0,0 +1,103 @@
+extern crate alloc;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use crate::codec::{NodeCodec, RlpNodeCodec};
+use crate::encoder::Encoder;
+use crate::hasher::{Hasher, KeccakHasher};
+use crate::nibbles::Nibbles;
+use crate::node::{empty_children, Node, RawNodeOrHash};
+use crate::H256;
+
+/// Returns the root of the trie holding `entries`, computed in memory without a db.
+///
+/// As in a trie, a key given twice keeps its last value and empty values are left
+/// out.
+pub fn trie_root<I, K, V>(entries: I) -> H256
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<[u8]>,
+    V: AsRef<[u8]>,
+{
+    trie_root_with_hasher::<KeccakHasher, RlpNodeCodec, _, _, _>(entries)
+}
+
+/// Returns the root of the secure trie holding `entries`, whose keys are hashed with
+/// keccak256 before they are inserted, like the keys of state and storage tries.
+pub fn sec_trie_root<I, K, V>(entries: I) -> H256
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<[u8]>,
+    V: AsRef<[u8]>,
+{
+    trie_root(
+        entries
+            .into_iter()
+            .map(|(key, value)| (KeccakHasher::hash(key.as_ref()), value)),
+    )
+}
+
+/// Same as `trie_root`, for a trie hashing its nodes with `H` and encoding them
+/// with `C`.
+pub fn trie_root_with_hasher<H, C, I, K, V>(entries: I) -> H256
+where
+    H: Hasher,
+    C: NodeCodec,
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<[u8]>,
+    V: AsRef<[u8]>,
+{
+    let mut sorted = BTreeMap::new();
+    for (key, value) in entries {
+        let nibbles = Nibbles::from_raw(key.as_ref(), false).get_data().to_vec();
+        sorted.insert(nibbles, value.as_ref().to_vec());
+    }
+    let entries: Vec<_> = sorted
+        .into_iter()
+        .filter(|(_, value)| !value.is_empty())
+        .collect();
+
+    match Encoder::<H, C>::new(0).encode_node(&build(&entries, 0)).0 {
+        RawNodeOrHash::Node(raw) => H::hash(&raw),
+        RawNodeOrHash::Hash(hash) => hash,
+    }
+}
+
+// Builds the subtrie holding `entries`, sorted by key and given as nibbles, which
+// share their first `depth` nibbles.
+pub(crate) fn build(entries: &[(Vec<u8>, Vec<u8>)], depth: usize) -> Node {
+    let (first, last) = match entries {
+        [] => return Node::Empty,
+        [(key, value)] => {
+            let mut key = key[depth..].to_vec();
+            key.push(16);
+            return Node::from_leaf(Nibbles::from_hex(&key), value.clone());
+        }
+        [(first, _), .., (last, _)] => (&first[depth..], &last[depth..]),
+    };
+    // The keys in between start with whatever the first and the last share.
+    let shared = first
+        .iter()
+        .zip(last.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    if shared > 0 {
+        let child = build(entries, depth + shared);
+        return Node::from_extension(Nibbles::from_hex(&first[..shared]), child);
+    }
+
+    let (value, mut rest) = match entries {
+        [(key, value), rest @ ..] if key.len() == depth => (Some(value.clone()), rest),
+        _ => (None, entries),
+    };
+    let mut children = empty_children();
+    for (i, child) in children.iter_mut().enumerate() {
+        let count = rest
+            .iter()
+            .take_while(|(key, _)| usize::from(key[depth]) == i)
+            .count();
+        *child = build(&rest[..count], depth + 1);
+        rest = &rest[count..];
+    }
+    Node::from_branch(children, value)
+}
@@ -0,0 +1,66 @@
+extern crate alloc;
+use alloc::vec::Vec;
+
+use crate::codec::NodeCodec;
+use crate::db::HashDB;
+use crate::hasher::Hasher;
+use crate::nibbles::Nibbles;
+use crate::node::Node;
+use crate::trie::{PatriciaTrie, PatriciaTrieMut, TrieResult};
+
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> PatriciaTrie<'db, D, H, C> {
+    /// Returns `n` keys of the trie picked at random, with replacement, reading only
+    /// the nodes on their paths. `rng` returns uniformly random numbers.
+    ///
+    /// Each key is found by descending from the root, taking every child of a branch,
+    /// and the value the branch holds, with the same weight. Subtries at the same
+    /// depth hold about as many keys in a trie of hashed keys, so every key has about
+    /// the same chance to be picked there. An empty trie has no keys to sample.
+    pub fn sample_keys(&self, n: usize, mut rng: impl FnMut() -> u64) -> TrieResult<Vec<Vec<u8>>> {
+        let mut keys = Vec::with_capacity(n);
+        let mut choices = Vec::with_capacity(17);
+        while keys.len() < n {
+            let mut path = Nibbles::default();
+            let mut node = self.root.clone();
+            loop {
+                node = match node {
+                    // Only the root can be empty, as empty children are never taken.
+                    Node::Empty => return Ok(keys),
+                    Node::Leaf(leaf) => {
+                        path.extend(&leaf.key);
+                        break;
+                    }
+                    Node::Extension(ext) => {
+                        path.extend(&ext.prefix);
+                        ext.node.clone()
+                    }
+                    Node::Branch(branch) => {
+                        choices.clear();
+                        choices.extend(
+                            (0..16).filter(|i| !matches!(branch.children[*i], Node::Empty)),
+                        );
+                        if branch.value.is_some() {
+                            choices.push(16);
+                        }
+                        let i = choices[(rng() % choices.len() as u64) as usize];
+                        if i == 16 {
+                            break;
+                        }
+                        path.push(i as u8);
+                        branch.children[i].clone()
+                    }
+                    Node::Hash(hash) => self.recover_from_db(&hash)?,
+                };
+            }
+            keys.push(path.encode_raw().0);
+        }
+        Ok(keys)
+    }
+}
+
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> PatriciaTrieMut<'db, D, H, C> {
+    /// Same as `PatriciaTrie::sample_keys`.
+    pub fn sample_keys(&self, n: usize, rng: impl FnMut() -> u64) -> TrieResult<Vec<Vec<u8>>> {
+        self.trie_ref().sample_keys(n, rng)
+    }
+}
@@ -0,0 +1,86 @@
+extern crate alloc;
+use alloc::vec::Vec;
+
+use crate::codec::NodeCodec;
+use crate::db::{HashDB, MemoryDB};
+use crate::errors::TrieError;
+use crate::hasher::Hasher;
+use crate::inspect::{NodeInfo, NodeReference};
+use crate::root::build;
+use crate::trie::{PatriciaTrieMut, TrieResult};
+use crate::H256;
+
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> PatriciaTrieMut<'db, D, H, C> {
+    // Checks the committed `root` against the root of a trie built straight from the
+    // sorted entries of this one, which shares none of the code updating tries in
+    // place nor their cached hashes. On a mismatch, both tries are walked down from
+    // the root through the children they disagree on, and the first node that
+    // differs otherwise is reported.
+    pub(crate) fn verify_root(&self, root: H256) -> TrieResult<()> {
+        let mut entries = Vec::new();
+        // Values kept in the blob store are rebuilt from the hashes the trie holds.
+        let mut trie = self.trie_ref();
+        trie.blobs = None;
+        trie.for_each_entry(|key, value| {
+            let nibbles = key.iter().flat_map(|byte| [byte >> 4, byte & 0x0f]);
+            entries.push((nibbles.collect::<Vec<u8>>(), value.to_vec()));
+        })?;
+        entries.sort_unstable();
+
+        let mut db = MemoryDB::new(true);
+        let rebuilt = PatriciaTrieMut::<_, H, C>::from_node(&mut db, build(&entries, 0));
+        let mut path = Vec::new();
+        loop {
+            let (committed, recomputed) =
+                match (self.node_at_path(&path)?, rebuilt.node_at_path(&path)?) {
+                    (Some(committed), Some(recomputed)) => (committed, recomputed),
+                    // The walk only goes down to nodes both tries have.
+                    _ => return Err(TrieError::InvalidData),
+                };
+            if path.is_empty() && hash_of::<H>(&recomputed) == root {
+                return Ok(());
+            }
+            match differing_child(&committed, &recomputed) {
+                Some(nibbles) => path.extend(nibbles),
+                None => {
+                    return Err(TrieError::RootMismatch {
+                        path,
+                        committed: hash_of::<H>(&committed),
+                        recomputed: hash_of::<H>(&recomputed),
+                    })
+                }
+            }
+        }
+    }
+}
+
+// Returns the nibbles leading to the child the two nodes disagree on, if they only
+// differ by one of their children stored by hash.
+fn differing_child(committed: &NodeInfo, recomputed: &NodeInfo) -> Option<Vec<u8>> {
+    let same_children = committed
+        .children
+        .iter()
+        .map(|child| &child.nibbles)
+        .eq(recomputed.children.iter().map(|child| &child.nibbles));
+    if committed.kind != recomputed.kind
+        || committed.partial != recomputed.partial
+        || committed.value != recomputed.value
+        || !same_children
+    {
+        return None;
+    }
+    committed
+        .children
+        .iter()
+        .zip(recomputed.children.iter())
+        .find(|(a, b)| a.reference != b.reference && matches!(a.reference, NodeReference::Hash(_)))
+        .map(|(child, _)| child.nibbles.clone())
+}
+
+// Returns the hash of a node, whether it is stored by hash or not.
+fn hash_of<H: Hasher>(info: &NodeInfo) -> H256 {
+    match info.reference {
+        NodeReference::Hash(hash) => hash,
+        NodeReference::Inline => H::hash(&info.encoded),
+    }
+}
@@ -0,0 +1,36 @@
+extern crate alloc;
+use alloc::sync::Arc;
+use spin::Mutex;
+
+// A value shared by a trie and the views made of it: its clones, forks and
+// iterators, which may live on other threads.
+//
+// The value is only reached through `with`, which hands it to a function that cannot
+// capture anything, so no code that could reach the value again runs while it is
+// locked, and the lock is only held for as long as a map or cache operation.
+#[derive(Debug, Default)]
+pub(crate) struct Shared<T>(Arc<Mutex<T>>);
+
+impl<T> Shared<T> {
+    pub(crate) fn new(value: T) -> Self {
+        Shared(Arc::new(Mutex::new(value)))
+    }
+
+    // Calls `f` with the value and `arg`.
+    pub(crate) fn with<A, R>(&self, arg: A, f: fn(&mut T, A) -> R) -> R {
+        f(&mut self.0.lock(), arg)
+    }
+}
+
+impl<T: Default> Shared<T> {
+    // Takes the value out, leaving the default in its place.
+    pub(crate) fn take(&self) -> T {
+        self.with((), |value, ()| core::mem::take(value))
+    }
+}
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Shared(self.0.clone())
+    }
+}
@@ -0,0 +1,265 @@
+extern crate alloc;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+
+use ethereum_types::U256;
+use rlp::RlpStream;
+
+use crate::db::{HashDB, MemoryDB};
+use crate::errors::TrieError;
+use crate::nibbles::Nibbles;
+use crate::node::{empty_children, Node};
+use crate::state::{append_u256, Account};
+use crate::trie::{PatriciaTrie, PatriciaTrieMut, TrieResult};
+use crate::{keccak256, H256};
+
+/// Verifies that `keys` and `values` are all the entries of the trie at `root` from
+/// `origin` up to the last key, as in the range responses of the snap protocol.
+///
+/// `proof` holds the nodes on the paths to `origin` and to the last key, in any
+/// order. Without a proof the entries must be the whole trie. Keys must be strictly
+/// increasing and not below `origin`, and values must not be empty. Returns whether
+/// the trie has entries after the last key, which an empty range must not have.
+pub fn verify_range_proof<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+    root: H256,
+    origin: &[u8],
+    keys: &[K],
+    values: &[V],
+    proof: &[Vec<u8>],
+) -> TrieResult<bool> {
+    if keys.len() != values.len()
+        || values.iter().any(|value| value.as_ref().is_empty())
+        || keys
+            .windows(2)
+            .any(|pair| pair[0].as_ref() >= pair[1].as_ref())
+    {
+        return Err(TrieError::InvalidProof);
+    }
+
+    if proof.is_empty() {
+        let mut memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrieMut::new(&mut memdb);
+        for (key, value) in keys.iter().zip(values) {
+            trie.insert(key.as_ref(), value.as_ref().to_vec())?;
+        }
+        if trie.root()? != root {
+            return Err(TrieError::InvalidProof);
+        }
+        return Ok(false);
+    }
+
+    if keys.first().is_some_and(|first| origin > first.as_ref()) {
+        return Err(TrieError::InvalidProof);
+    }
+    let last = keys.last().map_or(origin, |last| last.as_ref());
+
+    let mut memdb = MemoryDB::new(true);
+    for node in proof {
+        memdb.insert(keccak256(node), node.clone());
+    }
+    // The range is removed from the trie proven for its bounds, then rebuilt from
+    // the entries: the root only matches if they are exactly the removed ones.
+    let (node, more) = {
+        let trie = PatriciaTrie::new(&memdb);
+        let data = memdb.get(&root).ok_or(TrieError::InvalidProof)?;
+        let node = trie.decode_stored(&root, &data)?;
+        let left = Nibbles::from_raw(origin, false);
+        let right = Nibbles::from_raw(last, false);
+        let node = unset_range(&trie, node, Some(left.get_data()), Some(right.get_data()))?;
+        let more = has_right(&node, right.get_data());
+        (node, more)
+    };
+    if keys.is_empty() && more {
+        return Err(TrieError::InvalidProof);
+    }
+
+    let mut trie: PatriciaTrieMut<_> = PatriciaTrieMut::from_node(&mut memdb, node);
+    for (key, value) in keys.iter().zip(values) {
+        trie.insert(key.as_ref(), value.as_ref().to_vec())?;
+    }
+    if trie.root()? != root {
+        return Err(TrieError::InvalidProof);
+    }
+    Ok(more)
+}
+
+/// Verifies the accounts of an `AccountRange` snap response against the state
+/// `root`, see `verify_range_proof`. Accounts are sent in the slim format, read by
+/// `Account::from_slim_rlp`.
+pub fn verify_account_range(
+    root: H256,
+    origin: H256,
+    accounts: &[(H256, Account)],
+    proof: &[Vec<u8>],
+) -> TrieResult<bool> {
+    let keys: Vec<&[u8]> = accounts.iter().map(|(hash, _)| hash.as_bytes()).collect();
+    let values: Vec<_> = accounts
+        .iter()
+        .map(|(_, account)| rlp::encode(account))
+        .collect();
+    verify_range_proof(root, origin.as_bytes(), &keys, &values, proof)
+}
+
+/// Verifies the slots of a `StorageRanges` snap response against the storage
+/// `roots` of the requested accounts, see `verify_range_proof`.
+///
+/// The response may stop before the last requested account. Every slot list but
+/// the last must hold all the slots of its account, and `proof`, if any, proves the
+/// last one from `origin`. Returns whether the last account has more slots.
+pub fn verify_storage_ranges(
+    roots: &[H256],
+    origin: H256,
+    slots: &[Vec<(H256, U256)>],
+    proof: &[Vec<u8>],
+) -> TrieResult<bool> {
+    if slots.len() > roots.len() || (slots.is_empty() && !proof.is_empty()) {
+        return Err(TrieError::InvalidProof);
+    }
+    let mut more = false;
+    for (i, (root, range)) in roots.iter().zip(slots).enumerate() {
+        let keys: Vec<&[u8]> = range.iter().map(|(hash, _)| hash.as_bytes()).collect();
+        let values: Vec<_> = range
+            .iter()
+            .map(|(_, value)| {
+                let mut s = RlpStream::new();
+                append_u256(&mut s, value);
+                s.out()
+            })
+            .collect();
+        if i + 1 == slots.len() {
+            more = verify_range_proof(*root, origin.as_bytes(), &keys, &values, proof)?;
+        } else {
+            verify_range_proof(*root, &[], &keys, &values, &[])?;
+        }
+    }
+    Ok(more)
+}
+
+// Removes the entries between the `left` and `right` bounds from the subtrie at
+// `node`, given as the nibbles of each bound left below it. A bound is `None` once
+// the subtrie lies past it. Nodes on the path of a bound must be in the proof.
+fn unset_range(
+    trie: &PatriciaTrie<MemoryDB>,
+    node: Node,
+    left: Option<&[u8]>,
+    right: Option<&[u8]>,
+) -> TrieResult<Node> {
+    if left.is_none() && right.is_none() {
+        return Ok(Node::Empty);
+    }
+    let node = match node {
+        Node::Hash(hash) => {
+            let data = trie.hashdb.get(&hash).ok_or(TrieError::InvalidProof)?;
+            trie.decode_stored(&hash, &data)?
+        }
+        node => node,
+    };
+    match node {
+        Node::Empty | Node::Hash(_) => Ok(node),
+        Node::Leaf(ref leaf) => {
+            let key = leaf_key(&leaf.key);
+            let below = left.is_some_and(|left| key < left);
+            let above = right.is_some_and(|right| key > right);
+            if below || above {
+                Ok(node)
+            } else {
+                Ok(Node::Empty)
+            }
+        }
+        Node::Extension(ref ext) => {
+            let prefix = ext.prefix.get_data();
+            match unset_child(trie, &ext.node, prefix, left, right)? {
+                Node::Empty => Ok(Node::Empty),
+                child => Ok(Node::from_extension(ext.prefix.clone(), child)),
+            }
+        }
+        Node::Branch(ref branch) => {
+            let mut children = empty_children();
+            for (i, child) in branch.children.iter().enumerate() {
+                children[i] = unset_child(trie, child, &[i as u8], left, right)?;
+            }
+            // The value of a branch is at the path leading to it, so it is in the
+            // range unless the left bound goes further down.
+            let value = match left {
+                Some(left) if !left.is_empty() => branch.value.clone(),
+                _ => None,
+            };
+            Ok(Node::from_branch(children, value))
+        }
+    }
+}
+
+// Unsets the range in the child at `path` below a node, keeping the child whole if
+// it lies outside the range.
+fn unset_child(
+    trie: &PatriciaTrie<MemoryDB>,
+    child: &Node,
+    path: &[u8],
+    left: Option<&[u8]>,
+    right: Option<&[u8]>,
+) -> TrieResult<Node> {
+    let left = match left.map(|left| (left, compare_path(path, left))) {
+        Some((_, Ordering::Less)) => return Ok(child.clone()),
+        Some((left, Ordering::Equal)) => Some(&left[path.len()..]),
+        _ => None,
+    };
+    let right = match right.map(|right| (right, compare_path(path, right))) {
+        Some((_, Ordering::Greater)) => return Ok(child.clone()),
+        Some((right, Ordering::Equal)) => Some(&right[path.len()..]),
+        _ => None,
+    };
+    unset_range(trie, child.clone(), left, right)
+}
+
+// Returns whether the subtrie at `node` has entries after `key`, given as nibbles.
+fn has_right(node: &Node, key: &[u8]) -> bool {
+    let mut node = node;
+    let mut key = key;
+    loop {
+        match node {
+            Node::Empty => return false,
+            // Only subtries beside the proven paths stay unresolved.
+            Node::Hash(_) => return true,
+            Node::Leaf(leaf) => return leaf_key(&leaf.key) > key,
+            Node::Extension(ext) => {
+                let prefix = ext.prefix.get_data();
+                match compare_path(prefix, key) {
+                    Ordering::Equal => {
+                        key = &key[prefix.len()..];
+                        node = &ext.node;
+                    }
+                    ordering => return ordering == Ordering::Greater,
+                }
+            }
+            Node::Branch(branch) => {
+                let next = match key.first() {
+                    Some(nibble) => *nibble as usize,
+                    None => return branch.children.iter().any(|c| !matches!(c, Node::Empty)),
+                };
+                if branch.children[next + 1..]
+                    .iter()
+                    .any(|c| !matches!(c, Node::Empty))
+                {
+                    return true;
+                }
+                key = &key[1..];
+                node = &branch.children[next];
+            }
+        }
+    }
+}
+
+// Compares the keys below the node at `path` with `bound`: `Equal` if the bound
+// goes through the node, otherwise whether all of them come before or after it.
+fn compare_path(path: &[u8], bound: &[u8]) -> Ordering {
+    if bound.starts_with(path) {
+        Ordering::Equal
+    } else {
+        path.cmp(bound)
+    }
+}
+
+fn leaf_key(key: &Nibbles) -> &[u8] {
+    let data = key.get_data();
+    data.strip_suffix(&[16]).unwrap_or(data)
+}
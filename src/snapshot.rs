@@ -0,0 +1,454 @@
+extern crate alloc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+
+use rlp::{DecoderError, Rlp, RlpStream};
+
+use crate::codec::{DecodeLimits, NodeCodec, RlpNodeCodec};
+use crate::db::HashDB;
+use crate::errors::TrieError;
+use crate::hasher::{decode_digest, digest_bytes, H256Set, Hasher, KeccakHasher};
+use crate::trie::TrieResult;
+use crate::H256;
+
+/// Writes the trie at `root` to `write` as a snapshot stream, returning the number
+/// of nodes written.
+///
+/// The stream is a sequence of RLP strings: the root, then each node reachable from
+/// it once, after a node that refers to it. `write` is called once per item, so the
+/// stream can go to a file or a socket without being held in memory.
+pub fn export_snapshot<D: HashDB>(
+    db: &D,
+    root: H256,
+    write: impl FnMut(&[u8]),
+) -> TrieResult<usize> {
+    export_snapshot_with_hasher::<KeccakHasher, RlpNodeCodec, D>(db, root, write)
+}
+
+/// Writes the snapshot stream of a trie hashing its nodes with `H` and encoding
+/// them with `C`, which `SnapshotImporter::new_with_hasher` reads back. See
+/// `export_snapshot`.
+pub fn export_snapshot_with_hasher<H: Hasher, C: NodeCodec, D: HashDB>(
+    db: &D,
+    root: H256,
+    mut write: impl FnMut(&[u8]),
+) -> TrieResult<usize> {
+    write(&item(digest_bytes::<H>(&root)));
+    walk::<H, C, D>(db, root, |data| write(&item(data)))
+}
+
+/// Reads a snapshot stream from `reader`, given as chunks of any size, into `db`,
+/// returning its root. See `SnapshotImporter`.
+pub fn import_snapshot<D, I>(reader: I, db: &mut D) -> TrieResult<H256>
+where
+    D: HashDB,
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+{
+    let mut importer = SnapshotImporter::new(db);
+    for chunk in reader {
+        importer.feed(chunk.as_ref())?;
+    }
+    importer.finish()
+}
+
+/// Writes a snapshot stream made by `export_snapshot` to a db, checking every node
+/// on the way in.
+///
+/// A node is only written if it is the root or if a node written before refers to
+/// it by its hash, so a stream cannot put anything but the nodes of its trie in the
+/// db. `finish` then checks that no node of the trie is missing.
+pub struct SnapshotImporter<'db, D: HashDB, H: Hasher = KeccakHasher, C: NodeCodec = RlpNodeCodec> {
+    db: &'db mut D,
+    root: Option<H256>,
+    // The start of an item whose end is in a later chunk.
+    buffer: Vec<u8>,
+    // Nodes referred to and not received yet.
+    expected: H256Set,
+    // Nodes received or expected, which are not expected again.
+    seen: H256Set,
+    limits: DecodeLimits,
+    marker: PhantomData<fn() -> (H, C)>,
+}
+
+impl<'db, D: HashDB> SnapshotImporter<'db, D> {
+    pub fn new(db: &'db mut D) -> Self {
+        Self::new_with_hasher(db)
+    }
+}
+
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> SnapshotImporter<'db, D, H, C> {
+    /// Creates an importer for a trie hashing its nodes with `H` and encoding them
+    /// with `C`.
+    pub fn new_with_hasher(db: &'db mut D) -> Self {
+        Self {
+            db,
+            root: None,
+            buffer: Vec::new(),
+            expected: H256Set::default(),
+            seen: H256Set::default(),
+            limits: DecodeLimits::default(),
+            marker: PhantomData,
+        }
+    }
+
+    /// Bounds the nodes imported, which are rejected with
+    /// `TrieError::LimitExceeded` beyond them.
+    pub fn with_decode_limits(mut self, limits: DecodeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Returns the root of the stream, once its first item is read.
+    pub fn root(&self) -> Option<H256> {
+        self.root
+    }
+
+    /// Reads the next chunk of the stream, returning the number of nodes written.
+    /// The importer cannot be used any more after an error.
+    pub fn feed(&mut self, chunk: &[u8]) -> TrieResult<usize> {
+        let mut buffer = core::mem::take(&mut self.buffer);
+        buffer.extend_from_slice(chunk);
+        let mut offset = 0;
+        let mut written = 0;
+        while offset < buffer.len() {
+            let rlp = Rlp::new(&buffer[offset..]);
+            let len = match rlp.payload_info() {
+                Ok(info) => info.total(),
+                // The header itself is cut.
+                Err(DecoderError::RlpIsTooShort) => break,
+                Err(e) => return Err(e.into()),
+            };
+            if offset + len > buffer.len() {
+                break;
+            }
+            let data = Rlp::new(&buffer[offset..offset + len]).data()?;
+            if self.import(data)? {
+                written += 1;
+            }
+            offset += len;
+        }
+        buffer.drain(..offset);
+        self.buffer = buffer;
+        Ok(written)
+    }
+
+    /// Ends the stream, returning its root once every node of the trie is written.
+    pub fn finish(self) -> TrieResult<H256> {
+        let root = match self.root {
+            Some(root) if self.buffer.is_empty() => root,
+            _ => return Err(TrieError::Decoder(DecoderError::RlpIsTooShort)),
+        };
+        match self.expected.iter().next() {
+            Some(hash) => Err(TrieError::MissingNode(*hash)),
+            None => Ok(root),
+        }
+    }
+
+    // Reads an item of the stream, returning whether it is a node that was written.
+    fn import(&mut self, data: &[u8]) -> TrieResult<bool> {
+        if self.root.is_none() {
+            let root = decode_digest::<H>(data)?;
+            self.root = Some(root);
+            self.expected.insert(root);
+            self.seen.insert(root);
+            return Ok(false);
+        }
+        let hash = H::hash(data);
+        if !self.expected.contains(&hash) {
+            return Err(TrieError::InvalidData.in_node(hash));
+        }
+        let check = |data| {
+            let node = C::decode(data)?;
+            self.limits.check(&node, data.len(), 0)?;
+            node.hashed_children::<H>()
+        };
+        let children = check(data).map_err(|e| e.in_node(hash))?;
+        for child in children {
+            if self.seen.insert(child) {
+                self.expected.insert(child);
+            }
+        }
+        self.expected.remove(&hash);
+        self.db.insert(hash, data.to_vec());
+        Ok(true)
+    }
+}
+
+// Encodes `data` as an item of the stream.
+fn item(data: &[u8]) -> Vec<u8> {
+    let mut stream = RlpStream::new();
+    stream.append(&data);
+    stream.out()
+}
+
+// Calls `f` with each node of the trie at `root` once, after a node that refers to
+// it, returning the number of nodes.
+fn walk<H: Hasher, C: NodeCodec, D: HashDB>(
+    db: &D,
+    root: H256,
+    mut f: impl FnMut(&[u8]),
+) -> TrieResult<usize> {
+    let mut seen = H256Set::default();
+    seen.insert(root);
+    let mut stack = vec![root];
+    let mut count = 0;
+    while let Some(hash) = stack.pop() {
+        let data = match db.get(&hash) {
+            Some(data) => data,
+            // The root of the empty trie may not be stored.
+            None if hash == H::hash(C::EMPTY_NODE) => C::EMPTY_NODE.to_vec(),
+            None => return Err(TrieError::MissingNode(hash)),
+        };
+        let node = C::decode(&data).map_err(|e| e.in_node(hash))?;
+        let children = node.hashed_children::<H>().map_err(|e| e.in_node(hash))?;
+        stack.extend(children.into_iter().filter(|child| seen.insert(*child)));
+        f(&data);
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// The version of the snapshot container written by `write_snapshot`.
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+// The first bytes of a snapshot container.
+const MAGIC: &[u8; 8] = b"ethtrie\0";
+
+// The part of the header common to every version: the magic and the version.
+const PREFIX_LEN: usize = MAGIC.len() + 2;
+
+/// Writes the trie at `root` to `write` in the versioned snapshot container,
+/// returning the number of nodes written. `read_snapshot` reads it back.
+///
+/// The container is made of, with integers in big-endian:
+///
+/// - a header: the bytes `ethtrie\0`, the version as a `u16`, the id of the hasher
+///   and node codec as a `u32` (the first bytes of the root of the empty trie), the
+///   length of their digests as a `u8`, the root and the CRC-32 of these fields as a
+///   `u32`;
+/// - a record for each node reachable from the root, in the order of
+///   `export_snapshot`: the length of the node as a `u32`, the node and its CRC-32;
+/// - a length of zero ending the container.
+///
+/// Readers check the version before anything else, so that a container written
+/// in a later version fails with `TrieError::UnsupportedSnapshotVersion`.
+pub fn write_snapshot<D: HashDB>(
+    db: &D,
+    root: H256,
+    write: impl FnMut(&[u8]),
+) -> TrieResult<usize> {
+    write_snapshot_with_hasher::<KeccakHasher, RlpNodeCodec, D>(db, root, write)
+}
+
+/// Writes the snapshot container of a trie hashing its nodes with `H` and encoding
+/// them with `C`. See `write_snapshot`.
+pub fn write_snapshot_with_hasher<H: Hasher, C: NodeCodec, D: HashDB>(
+    db: &D,
+    root: H256,
+    mut write: impl FnMut(&[u8]),
+) -> TrieResult<usize> {
+    let mut header = Vec::new();
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&SNAPSHOT_VERSION.to_be_bytes());
+    header.extend_from_slice(&hasher_id::<H, C>().to_be_bytes());
+    header.push(H::LENGTH as u8);
+    header.extend_from_slice(digest_bytes::<H>(&root));
+    header.extend_from_slice(&crc32(&header).to_be_bytes());
+    write(&header);
+    let written = walk::<H, C, D>(db, root, |data| {
+        let mut record = Vec::with_capacity(data.len() + 8);
+        record.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        record.extend_from_slice(data);
+        record.extend_from_slice(&crc32(data).to_be_bytes());
+        write(&record);
+    })?;
+    write(&0u32.to_be_bytes());
+    Ok(written)
+}
+
+/// Reads a snapshot container from `reader`, given as chunks of any size, into
+/// `db`, returning its root. See `SnapshotReader`.
+pub fn read_snapshot<D, I>(reader: I, db: &mut D) -> TrieResult<H256>
+where
+    D: HashDB,
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+{
+    let mut snapshot = SnapshotReader::new(db);
+    for chunk in reader {
+        snapshot.feed(chunk.as_ref())?;
+    }
+    snapshot.finish()
+}
+
+/// Writes a snapshot container made by `write_snapshot` to a db, checking the
+/// nodes as `SnapshotImporter` does.
+///
+/// A container of another version or for another hasher is rejected before any of
+/// its nodes is read, and a record that does not match its checksum fails with
+/// `TrieError::SnapshotChecksumMismatch`.
+pub struct SnapshotReader<'db, D: HashDB, H: Hasher = KeccakHasher, C: NodeCodec = RlpNodeCodec> {
+    importer: SnapshotImporter<'db, D, H, C>,
+    // The start of a record whose end is in a later chunk.
+    buffer: Vec<u8>,
+    // Records read so far, the header included.
+    records: usize,
+    // Whether the record ending the container was read.
+    ended: bool,
+}
+
+impl<'db, D: HashDB> SnapshotReader<'db, D> {
+    pub fn new(db: &'db mut D) -> Self {
+        Self::new_with_hasher(db)
+    }
+}
+
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> SnapshotReader<'db, D, H, C> {
+    /// Creates a reader for a trie hashing its nodes with `H` and encoding them with
+    /// `C`, which must be the ones the container was written for.
+    pub fn new_with_hasher(db: &'db mut D) -> Self {
+        Self {
+            importer: SnapshotImporter::new_with_hasher(db),
+            buffer: Vec::new(),
+            records: 0,
+            ended: false,
+        }
+    }
+
+    /// Bounds the nodes read, which are rejected with `TrieError::LimitExceeded`
+    /// beyond them.
+    pub fn with_decode_limits(mut self, limits: DecodeLimits) -> Self {
+        self.importer = self.importer.with_decode_limits(limits);
+        self
+    }
+
+    /// Returns the root of the container, once its header is read.
+    pub fn root(&self) -> Option<H256> {
+        self.importer.root()
+    }
+
+    /// Reads the next chunk of the container, returning the number of nodes
+    /// written. The reader cannot be used any more after an error.
+    pub fn feed(&mut self, chunk: &[u8]) -> TrieResult<usize> {
+        let mut buffer = core::mem::take(&mut self.buffer);
+        buffer.extend_from_slice(chunk);
+        let mut offset = 0;
+        let mut written = 0;
+        while let Some((len, is_node)) = self.read_record(&buffer[offset..])? {
+            offset += len;
+            if is_node {
+                written += 1;
+            }
+        }
+        buffer.drain(..offset);
+        self.buffer = buffer;
+        Ok(written)
+    }
+
+    /// Ends the container, returning its root once every node of the trie is
+    /// written.
+    pub fn finish(self) -> TrieResult<H256> {
+        if !self.ended {
+            return Err(TrieError::InvalidData);
+        }
+        self.importer.finish()
+    }
+
+    // Reads the record at the start of `data`, returning its length and whether it
+    // is a node, or `None` if it is not whole yet.
+    fn read_record(&mut self, data: &[u8]) -> TrieResult<Option<(usize, bool)>> {
+        if self.ended {
+            // Nothing may follow the end of the container.
+            if !data.is_empty() {
+                return Err(TrieError::InvalidData);
+            }
+            return Ok(None);
+        }
+        if self.records == 0 {
+            return Ok(self.read_header(data)?.map(|len| (len, false)));
+        }
+        let len = match data.get(..4) {
+            Some(len) => read_u32(len) as usize,
+            None => return Ok(None),
+        };
+        if len == 0 {
+            self.ended = true;
+            return Ok(Some((4, false)));
+        }
+        if len > self.importer.limits.max_node_size {
+            return Err(TrieError::LimitExceeded);
+        }
+        // The length is read from the stream, and `max_node_size` is unbounded by
+        // default: on 32-bit targets, the end of the record may not fit in a usize.
+        let end = len.checked_add(8).ok_or(TrieError::LimitExceeded)?;
+        let record = match data.get(..end) {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+        let (node, checksum) = record[4..].split_at(len);
+        if crc32(node) != read_u32(checksum) {
+            return Err(TrieError::SnapshotChecksumMismatch(self.records));
+        }
+        self.records += 1;
+        self.importer.import(node)?;
+        Ok(Some((record.len(), true)))
+    }
+
+    fn read_header(&mut self, data: &[u8]) -> TrieResult<Option<usize>> {
+        let prefix = match data.get(..PREFIX_LEN) {
+            Some(prefix) => prefix,
+            None => return Ok(None),
+        };
+        if &prefix[..MAGIC.len()] != MAGIC {
+            return Err(TrieError::InvalidData);
+        }
+        let version = u16::from_be_bytes([prefix[MAGIC.len()], prefix[MAGIC.len() + 1]]);
+        if version != SNAPSHOT_VERSION {
+            return Err(TrieError::UnsupportedSnapshotVersion(version));
+        }
+        // The header is as long as the digests it was written with.
+        let len = match data.get(PREFIX_LEN + 4) {
+            Some(digest_len) => PREFIX_LEN + 5 + usize::from(*digest_len) + 4,
+            None => return Ok(None),
+        };
+        let header = match data.get(..len) {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let (fields, checksum) = header.split_at(len - 4);
+        if crc32(fields) != read_u32(checksum) {
+            return Err(TrieError::SnapshotChecksumMismatch(0));
+        }
+        let (id, root) = fields[PREFIX_LEN..].split_at(5);
+        if read_u32(id) != hasher_id::<H, C>() || usize::from(id[4]) != H::LENGTH {
+            return Err(TrieError::SnapshotHasherMismatch);
+        }
+        self.importer.import(root)?;
+        self.records = 1;
+        Ok(Some(len))
+    }
+}
+
+// Identifies `H` and `C` by the first bytes of the root of the empty trie.
+fn hasher_id<H: Hasher, C: NodeCodec>() -> u32 {
+    read_u32(H::hash(C::EMPTY_NODE).as_bytes())
+}
+
+// Reads the big-endian `u32` at the start of `data`.
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_be_bytes([data[0], data[1], data[2], data[3]])
+}
+
+// The CRC-32 of IEEE 802.3, computed bit by bit.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
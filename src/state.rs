@@ -0,0 +1,348 @@
+extern crate alloc;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use ethereum_types::{Address, U256};
+use rlp::{DecoderError, Rlp, RlpStream};
+
+use crate::eth::{SecTrieDBMut, TrieDB, TrieDBMut};
+use crate::proof::{EIP1186AccountProof, StorageProof};
+use crate::trie::TrieResult;
+use crate::{keccak256, HashDB, MemoryDB, H256};
+
+/// An account as stored in the Ethereum state trie.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    pub nonce: u64,
+    pub balance: U256,
+    /// Root of the account's storage trie.
+    pub storage_root: H256,
+    /// Hash of the account's code.
+    pub code_hash: H256,
+}
+
+impl Default for Account {
+    /// An account without storage or code.
+    fn default() -> Self {
+        Account {
+            nonce: 0,
+            balance: U256::zero(),
+            storage_root: keccak256(&rlp::NULL_RLP),
+            code_hash: keccak256(&[]),
+        }
+    }
+}
+
+impl Account {
+    /// Decodes an account in the slim format of the snap protocol, where an empty
+    /// storage root or code hash is sent as an empty string.
+    pub fn from_slim_rlp(data: &[u8]) -> Result<Self, DecoderError> {
+        let rlp = Rlp::new(data);
+        if rlp.item_count()? != 4 {
+            return Err(DecoderError::RlpIncorrectListLen);
+        }
+        let empty = Account::default();
+        let hash_or = |index, default| {
+            let item = rlp.at(index)?;
+            if item.is_empty() {
+                Ok(default)
+            } else {
+                decode_h256(&item)
+            }
+        };
+        Ok(Account {
+            nonce: rlp.val_at(0)?,
+            balance: decode_u256(&rlp.at(1)?)?,
+            storage_root: hash_or(2, empty.storage_root)?,
+            code_hash: hash_or(3, empty.code_hash)?,
+        })
+    }
+}
+
+impl rlp::Encodable for Account {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        s.begin_list(4);
+        s.append(&self.nonce);
+        append_u256(s, &self.balance);
+        s.append(&self.storage_root.as_bytes());
+        s.append(&self.code_hash.as_bytes());
+    }
+}
+
+impl rlp::Decodable for Account {
+    fn decode(rlp: &Rlp) -> Result<Self, DecoderError> {
+        if rlp.item_count()? != 4 {
+            return Err(DecoderError::RlpIncorrectListLen);
+        }
+        Ok(Account {
+            nonce: rlp.val_at(0)?,
+            balance: decode_u256(&rlp.at(1)?)?,
+            storage_root: decode_h256(&rlp.at(2)?)?,
+            code_hash: decode_h256(&rlp.at(3)?)?,
+        })
+    }
+}
+
+// Appends `value` as a big-endian integer without leading zeros.
+pub(crate) fn append_u256(s: &mut RlpStream, value: &U256) {
+    let mut bytes = [0u8; 32];
+    value.to_big_endian(&mut bytes);
+    let skip = bytes.iter().take_while(|b| **b == 0).count();
+    s.append(&&bytes[skip..]);
+}
+
+pub(crate) fn decode_u256(rlp: &Rlp) -> Result<U256, DecoderError> {
+    let data = rlp.data()?;
+    match data.first() {
+        Some(0) => Err(DecoderError::RlpInvalidIndirection),
+        _ if data.len() > 32 => Err(DecoderError::RlpIsTooBig),
+        _ => Ok(U256::from_big_endian(data)),
+    }
+}
+
+fn decode_h256(rlp: &Rlp) -> Result<H256, DecoderError> {
+    let data = rlp.data()?;
+    if data.len() != 32 {
+        return Err(DecoderError::RlpInvalidLength);
+    }
+    Ok(H256::from_slice(data))
+}
+
+/// An account of a genesis allocation.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GenesisAccount {
+    pub nonce: u64,
+    pub balance: U256,
+    pub code: Vec<u8>,
+    /// Initial storage by slot. Zero values are not stored.
+    pub storage: BTreeMap<U256, U256>,
+}
+
+/// Returns the state root of a genesis allocation, building the storage trie of each
+/// account and the state trie in memory.
+pub fn genesis_state_root<'a, I>(alloc: I) -> H256
+where
+    I: IntoIterator<Item = (&'a Address, &'a GenesisAccount)>,
+{
+    let mut memdb = MemoryDB::new(true);
+    let mut state = StateTrie::new(&mut memdb);
+    for (address, genesis) in alloc {
+        let mut storage = state
+            .storage_trie(address)
+            .expect("in-memory storage trie cannot fail");
+        for (slot, value) in genesis.storage.iter() {
+            storage
+                .set(slot, value)
+                .expect("in-memory trie insert cannot fail");
+        }
+        let account = Account {
+            nonce: genesis.nonce,
+            balance: genesis.balance,
+            storage_root: storage.root().expect("in-memory trie commit cannot fail"),
+            code_hash: keccak256(&genesis.code),
+        };
+        state
+            .set_account(address, &account)
+            .expect("in-memory trie insert cannot fail");
+    }
+    state.root().expect("in-memory trie commit cannot fail")
+}
+
+/// The Ethereum state trie, mapping the keccak hash of each address to its account.
+///
+/// Addresses are 20 bytes, so they are hashed here rather than by a `SecTrieDBMut`,
+/// which hashes 32-byte keys.
+pub struct StateTrie<'db, D: HashDB> {
+    trie: TrieDBMut<'db, D>,
+}
+
+impl<'db, D: HashDB> StateTrie<'db, D> {
+    pub fn new(db: &'db mut D) -> Self {
+        Self {
+            trie: TrieDBMut::new(db),
+        }
+    }
+
+    pub fn from(db: &'db mut D, root: H256) -> TrieResult<Self> {
+        Ok(Self {
+            trie: TrieDBMut::from(db, root)?,
+        })
+    }
+
+    pub fn trie(&self) -> &TrieDBMut<'db, D> {
+        &self.trie
+    }
+
+    pub fn trie_mut(&mut self) -> &mut TrieDBMut<'db, D> {
+        &mut self.trie
+    }
+
+    /// Returns the account at `address`, if it exists.
+    pub fn get_account(&self, address: &Address) -> TrieResult<Option<Account>> {
+        match self.trie.get(&address_key(address))? {
+            Some(data) => Ok(Some(rlp::decode(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Creates or replaces the account at `address`.
+    pub fn set_account(&mut self, address: &Address, account: &Account) -> TrieResult<()> {
+        self.trie
+            .insert(&address_key(address), rlp::encode(account).to_vec())
+    }
+
+    /// Removes the account at `address`, returning whether it existed.
+    pub fn remove_account(&mut self, address: &Address) -> TrieResult<bool> {
+        self.trie.remove(&address_key(address))
+    }
+
+    /// Opens the storage trie of the account at `address`, empty if the account does
+    /// not exist. Its new root has to be set on the account once it is committed.
+    ///
+    /// The storage trie does not prune: its nodes are in the db of the state trie,
+    /// where the storage of other accounts may hold the same nodes, such as when two
+    /// contracts have the same storage. The nodes it replaces stay in the db until
+    /// `collect_garbage` deletes them.
+    pub fn storage_trie(&mut self, address: &Address) -> TrieResult<StorageTrie<'_, D>> {
+        let storage_root = self
+            .get_account(address)?
+            .map_or_else(|| keccak256(&rlp::NULL_RLP), |account| account.storage_root);
+        Ok(StorageTrie::from(self.trie.hashdb_mut(), storage_root)?.with_pruning(false))
+    }
+
+    /// Returns the proof of the account at `address` and of its `slots`, as returned
+    /// by `eth_getProof`. A missing account is proven as an empty one.
+    pub fn get_proof(&self, address: &Address, slots: &[U256]) -> TrieResult<EIP1186AccountProof> {
+        let account = self.get_account(address)?.unwrap_or_default();
+        let storage = if account.storage_root == keccak256(&rlp::NULL_RLP) {
+            None
+        } else {
+            Some(TrieDB::from(self.trie.hashdb(), account.storage_root)?)
+        };
+
+        let mut storage_proof = Vec::with_capacity(slots.len());
+        for slot in slots {
+            let proof = match &storage {
+                Some(storage) => {
+                    let key = keccak256(slot_key(slot).as_bytes());
+                    let value = match storage.get(&key)? {
+                        Some(data) => decode_u256(&Rlp::new(&data))?,
+                        None => U256::zero(),
+                    };
+                    StorageProof {
+                        key: *slot,
+                        value,
+                        proof: storage.get_proof(&key)?,
+                    }
+                }
+                None => StorageProof {
+                    key: *slot,
+                    value: U256::zero(),
+                    proof: Vec::new(),
+                },
+            };
+            storage_proof.push(proof);
+        }
+
+        Ok(EIP1186AccountProof {
+            address: *address,
+            code_hash: account.code_hash,
+            nonce: account.nonce,
+            balance: account.balance,
+            storage_hash: account.storage_root,
+            account_proof: self.trie.get_proof(&address_key(address))?,
+            storage_proof,
+        })
+    }
+
+    /// Saves all the nodes in the db, clears the cache data, recalculates the root.
+    /// Returns the root hash of the trie.
+    pub fn root(&mut self) -> TrieResult<H256> {
+        self.trie.root()
+    }
+
+    /// Iterates over the accounts by hashed address.
+    pub fn iter(&self) -> impl Iterator<Item = TrieResult<(H256, Account)>> + '_ {
+        self.trie.try_iter().map(|entry| {
+            let (key, data) = entry?;
+            Ok((key, rlp::decode(&data)?))
+        })
+    }
+}
+
+fn address_key(address: &Address) -> H256 {
+    keccak256(address.as_bytes())
+}
+
+/// The storage trie of an account, mapping the keccak hash of each slot to the RLP
+/// encoding of its value without leading zeros. Zero values are not stored.
+pub struct StorageTrie<'db, D: HashDB> {
+    trie: SecTrieDBMut<'db, D>,
+}
+
+impl<'db, D: HashDB> StorageTrie<'db, D> {
+    pub fn new(db: &'db mut D) -> Self {
+        Self {
+            trie: SecTrieDBMut::new(db),
+        }
+    }
+
+    /// Opens the storage trie at `root`, which may be the root of the empty trie
+    /// even if the db does not hold it.
+    pub fn from(db: &'db mut D, root: H256) -> TrieResult<Self> {
+        if root == keccak256(&rlp::NULL_RLP) {
+            return Ok(Self::new(db));
+        }
+        Ok(Self {
+            trie: SecTrieDBMut::from(db, root)?,
+        })
+    }
+
+    /// Sets whether `root` prunes the replaced nodes, see
+    /// `PatriciaTrieMut::with_pruning`. Storage tries sharing a db with the storage of
+    /// other accounts must not prune.
+    pub fn with_pruning(mut self, prune: bool) -> Self {
+        self.trie = self.trie.with_pruning(prune);
+        self
+    }
+
+    pub fn trie(&self) -> &SecTrieDBMut<'db, D> {
+        &self.trie
+    }
+
+    pub fn trie_mut(&mut self) -> &mut SecTrieDBMut<'db, D> {
+        &mut self.trie
+    }
+
+    /// Returns the value of `slot`, zero if it was never set.
+    pub fn get(&self, slot: &U256) -> TrieResult<U256> {
+        match self.trie.get(&slot_key(slot))? {
+            Some(data) => Ok(decode_u256(&Rlp::new(&data))?),
+            None => Ok(U256::zero()),
+        }
+    }
+
+    /// Sets the value of `slot`, removing it from the trie if `value` is zero.
+    pub fn set(&mut self, slot: &U256, value: &U256) -> TrieResult<()> {
+        let key = slot_key(slot);
+        if value.is_zero() {
+            self.trie.remove(&key)?;
+            return Ok(());
+        }
+        let mut s = RlpStream::new();
+        append_u256(&mut s, value);
+        self.trie.insert(&key, s.out().to_vec())
+    }
+
+    /// Saves all the nodes in the db, clears the cache data, recalculates the root.
+    /// Returns the root hash of the trie.
+    pub fn root(&mut self) -> TrieResult<H256> {
+        self.trie.root()
+    }
+}
+
+pub(crate) fn slot_key(slot: &U256) -> H256 {
+    let mut key = H256::zero();
+    slot.to_big_endian(key.as_bytes_mut());
+    key
+}
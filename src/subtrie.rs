@@ -0,0 +1,147 @@
+extern crate alloc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::codec::NodeCodec;
+use crate::db::HashDB;
+use crate::encoder::Encoder;
+use crate::hasher::{H256Map, H256Set, Hasher};
+use crate::nibbles::Nibbles;
+use crate::node::{Node, RawNodeOrHash};
+use crate::trie::{PatriciaTrie, PatriciaTrieMut, TrieResult};
+use crate::H256;
+
+// The root of a standalone trie and its nodes by hash.
+type Subtrie = (H256, Vec<(H256, Vec<u8>)>);
+
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> PatriciaTrie<'db, D, H, C> {
+    /// Returns the root and the nodes of a standalone trie holding the entries whose
+    /// key starts with `prefix`, keyed by the rest of their key.
+    ///
+    /// The nodes come root first, each after a node that refers to it by hash, and
+    /// include every node of the trie, so they can be written to another db and
+    /// opened at the root, or checked against it. Nodes below the prefix are shared
+    /// with this trie, and only the node the prefix ends in is encoded anew.
+    pub fn subtrie(&self, prefix: &[u8]) -> TrieResult<Subtrie> {
+        let top = self.node_under(&Nibbles::from_raw(prefix, false))?;
+        let mut encoder = Encoder::<H, C>::new(0);
+        let (encoded, _) = encoder.encode_node(&top);
+        let (encoded_nodes, _) = encoder.finish();
+        let mut fresh: H256Map<Vec<u8>> = encoded_nodes.collect();
+        let root = match encoded {
+            RawNodeOrHash::Node(raw) => {
+                let hash = H::hash(&raw);
+                fresh.insert(hash, raw);
+                hash
+            }
+            RawNodeOrHash::Hash(hash) => hash,
+        };
+
+        let mut nodes = Vec::new();
+        let mut seen = H256Set::default();
+        seen.insert(root);
+        let mut stack = vec![root];
+        while let Some(hash) = stack.pop() {
+            let data = match fresh.remove(&hash) {
+                Some(data) => data,
+                None => self.load_encoded(&hash)?.to_vec(),
+            };
+            let children = C::decode(&data)
+                .and_then(|node| node.hashed_children::<H>())
+                .map_err(|e| e.in_node(hash))?;
+            stack.extend(
+                children
+                    .into_iter()
+                    .rev()
+                    .filter(|child| seen.insert(*child)),
+            );
+            nodes.push((hash, data));
+        }
+        Ok((root, nodes))
+    }
+
+    /// Returns the number of keys starting with `prefix`, walking only the nodes below
+    /// it. With a `limit`, the count stops there, so the result is at most `limit`.
+    pub fn count_prefix(&self, prefix: &[u8], limit: Option<usize>) -> TrieResult<usize> {
+        let limit = limit.unwrap_or(usize::MAX);
+        let mut count = 0;
+        let mut stack = vec![self.node_under(&Nibbles::from_raw(prefix, false))?];
+        while let Some(n) = stack.pop() {
+            if count >= limit {
+                break;
+            }
+            match n {
+                Node::Empty => {}
+                Node::Leaf(_) => count += 1,
+                Node::Branch(branch) => {
+                    count += usize::from(branch.value.is_some());
+                    stack.extend(
+                        branch
+                            .children
+                            .iter()
+                            .filter(|child| !matches!(child, Node::Empty))
+                            .cloned(),
+                    );
+                }
+                Node::Extension(ext) => stack.push(ext.node.clone()),
+                Node::Hash(hash) => stack.push(self.recover_from_db(&hash)?),
+            }
+        }
+        Ok(count.min(limit))
+    }
+
+    // Returns the node holding the entries below `path`, with the nibbles of `path`
+    // cut from the top of its key or prefix.
+    fn node_under(&self, path: &Nibbles) -> TrieResult<Node> {
+        let mut n = self.root.clone();
+        let mut rest = path.clone();
+        loop {
+            n = match n {
+                Node::Hash(hash) => self.recover_from_db(&hash)?,
+                n if rest.is_empty() => return Ok(n),
+                Node::Empty => return Ok(Node::Empty),
+                Node::Branch(branch) => {
+                    let child = branch.children[rest.at(0)].clone();
+                    rest = rest.offset(1);
+                    child
+                }
+                Node::Extension(ext) => {
+                    let matched = ext.prefix.common_prefix(&rest);
+                    if matched == ext.prefix.len() {
+                        rest = rest.offset(matched);
+                        ext.node.clone()
+                    } else if matched == rest.len() {
+                        return Ok(Node::from_extension(
+                            ext.prefix.offset(matched),
+                            ext.node.clone(),
+                        ));
+                    } else {
+                        return Ok(Node::Empty);
+                    }
+                }
+                // The key of a leaf ends with the terminator, which `rest` lacks.
+                Node::Leaf(leaf) => {
+                    if leaf.key.common_prefix(&rest) == rest.len() {
+                        return Ok(Node::from_leaf(
+                            leaf.key.offset(rest.len()),
+                            leaf.value.clone(),
+                        ));
+                    }
+                    return Ok(Node::Empty);
+                }
+            };
+        }
+    }
+}
+
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> PatriciaTrieMut<'db, D, H, C> {
+    /// Same as `PatriciaTrie::subtrie`. Uncommitted changes are included.
+    pub fn subtrie(&self, prefix: &[u8]) -> TrieResult<Subtrie> {
+        self.trie_ref().subtrie(prefix)
+    }
+
+    /// Same as `PatriciaTrie::count_prefix`.
+    pub fn count_prefix(&self, prefix: &[u8], limit: Option<usize>) -> TrieResult<usize> {
+        self.trie_ref().count_prefix(prefix, limit)
+    }
+}
@@ -0,0 +1,311 @@
+extern crate alloc;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+
+use crate::codec::{ChildReference, DecodeLimits, DecodedNode, NodeCodec, RlpNodeCodec};
+use crate::db::HashDB;
+use crate::errors::TrieError;
+use crate::hasher::{decode_digest, H256Map, H256Set, Hasher, KeccakHasher};
+use crate::trie::TrieResult;
+use crate::H256;
+
+/// A request for the nodes of the trie at `root` below `path`, given as nibbles one
+/// per byte. The path leads to a node stored by hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncRequest {
+    pub root: H256,
+    pub path: Vec<u8>,
+    /// Most nodes to answer with.
+    pub max_nodes: usize,
+}
+
+/// The answer to a `SyncRequest`: the node at its path, then nodes below it, each
+/// after a node that refers to it by hash.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncResponse {
+    pub nodes: Vec<Vec<u8>>,
+}
+
+/// Answers the `SyncRequest`s of peers from the tries of a db.
+pub struct SyncServer<'db, D: HashDB, H: Hasher = KeccakHasher, C: NodeCodec = RlpNodeCodec> {
+    db: &'db D,
+    max_nodes: usize,
+    marker: PhantomData<fn() -> (H, C)>,
+}
+
+impl<'db, D: HashDB> SyncServer<'db, D> {
+    pub fn new(db: &'db D) -> Self {
+        Self::new_with_hasher(db)
+    }
+}
+
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> SyncServer<'db, D, H, C> {
+    /// Creates a server for tries hashing their nodes with `H` and encoding them with
+    /// `C`.
+    pub fn new_with_hasher(db: &'db D) -> Self {
+        Self {
+            db,
+            max_nodes: 1024,
+            marker: PhantomData,
+        }
+    }
+
+    /// Caps the nodes of a response, whatever the request asks for. 1024 by default.
+    pub fn with_max_nodes(mut self, max_nodes: usize) -> Self {
+        self.max_nodes = max_nodes;
+        self
+    }
+
+    /// Returns the node at the path of `request` and the nodes below it, depth-first
+    /// from the left. Fails if the path does not lead to a node stored by hash.
+    pub fn serve(&self, request: &SyncRequest) -> TrieResult<SyncResponse> {
+        let mut hash = request.root;
+        let mut data = self.load(&hash)?;
+        let mut path = &request.path[..];
+        // Nodes embedded in their parent are walked through, but cannot be the one
+        // at the path.
+        let mut is_stored = true;
+        while !path.is_empty() {
+            let (consumed, child) = step::<C>(&data, path).map_err(|e| e.in_node(hash))?;
+            path = &path[consumed..];
+            let next = match child {
+                ChildReference::Hash(digest) => {
+                    hash = decode_digest::<H>(digest)?;
+                    self.load(&hash)?
+                }
+                ChildReference::Inline(inline) => inline.to_vec(),
+                ChildReference::Empty => return Err(TrieError::InvalidData),
+            };
+            is_stored = matches!(child, ChildReference::Hash(_));
+            data = next;
+        }
+        if !is_stored {
+            return Err(TrieError::InvalidData);
+        }
+
+        let max_nodes = request.max_nodes.min(self.max_nodes);
+        let mut nodes = Vec::new();
+        let mut seen = H256Set::default();
+        seen.insert(hash);
+        let mut stack = vec![hash];
+        while nodes.len() < max_nodes {
+            let hash = match stack.pop() {
+                Some(hash) => hash,
+                None => break,
+            };
+            let data = self.load(&hash)?;
+            let children = C::decode(&data)
+                .and_then(|node| node.hashed_children::<H>())
+                .map_err(|e| e.in_node(hash))?;
+            stack.extend(
+                children
+                    .into_iter()
+                    .rev()
+                    .filter(|child| seen.insert(*child)),
+            );
+            nodes.push(data);
+        }
+        Ok(SyncResponse { nodes })
+    }
+
+    fn load(&self, hash: &H256) -> TrieResult<Vec<u8>> {
+        match self.db.get(hash) {
+            Some(data) => Ok(data),
+            // The root of the empty trie may not be stored.
+            None if *hash == H::hash(C::EMPTY_NODE) => Ok(C::EMPTY_NODE.to_vec()),
+            None => Err(TrieError::MissingNode(*hash)),
+        }
+    }
+}
+
+/// Fetches the trie at a root from peers answering `SyncRequest`s with a
+/// `SyncServer`, until the db holds every node of it.
+///
+/// The client walks the trie from the root, through the nodes the db already has,
+/// and asks for the subtries it lacks through `requests`, from left to right. The
+/// transport is up to the caller, who hands the responses back to `process`. Each
+/// node is checked against the hash its parent refers to it by before it is
+/// written, and subtries a response leaves out are requested again later, so the
+/// trie is complete and matches the root once `is_complete`.
+pub struct SyncClient<'db, D: HashDB, H: Hasher = KeccakHasher, C: NodeCodec = RlpNodeCodec> {
+    db: &'db mut D,
+    root: H256,
+    // Subtries to look for in the db, by path, with the hash of their top node.
+    pending: BTreeMap<Vec<u8>, H256>,
+    // Subtries requested and not answered yet.
+    requested: BTreeMap<Vec<u8>, H256>,
+    max_nodes: usize,
+    limits: DecodeLimits,
+    marker: PhantomData<fn() -> (H, C)>,
+}
+
+impl<'db, D: HashDB> SyncClient<'db, D> {
+    pub fn new(db: &'db mut D, root: H256) -> Self {
+        Self::new_with_hasher(db, root)
+    }
+}
+
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> SyncClient<'db, D, H, C> {
+    /// Creates a client for a trie hashing its nodes with `H` and encoding them with
+    /// `C`.
+    pub fn new_with_hasher(db: &'db mut D, root: H256) -> Self {
+        // The root of the empty trie needs no fetching.
+        if root == H::hash(C::EMPTY_NODE) && !db.contains(&root) {
+            db.insert(root, C::EMPTY_NODE.to_vec());
+        }
+        let mut pending = BTreeMap::new();
+        pending.insert(Vec::new(), root);
+        Self {
+            db,
+            root,
+            pending,
+            requested: BTreeMap::new(),
+            max_nodes: 256,
+            limits: DecodeLimits::default(),
+            marker: PhantomData,
+        }
+    }
+
+    /// Sets the most nodes a request asks for. 256 by default.
+    pub fn with_max_nodes(mut self, max_nodes: usize) -> Self {
+        self.max_nodes = max_nodes;
+        self
+    }
+
+    /// Bounds the nodes received, which are rejected with
+    /// `TrieError::LimitExceeded` beyond them.
+    pub fn with_decode_limits(mut self, limits: DecodeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn root(&self) -> H256 {
+        self.root
+    }
+
+    /// Returns up to `max` requests for subtries missing from the db, walking on
+    /// through the nodes the db has. A subtrie is not requested again until its
+    /// response fails to `process` or `retry` is called.
+    pub fn requests(&mut self, max: usize) -> TrieResult<Vec<SyncRequest>> {
+        let mut requests = Vec::new();
+        while requests.len() < max {
+            let (path, hash) = match self.pending.pop_first() {
+                Some(subtrie) => subtrie,
+                None => break,
+            };
+            match self.db.get(&hash) {
+                Some(data) => match self.children(&path, &data) {
+                    Ok(children) => self.pending.extend(children),
+                    Err(e) => {
+                        self.pending.insert(path, hash);
+                        return Err(e.in_node(hash));
+                    }
+                },
+                None => {
+                    requests.push(SyncRequest {
+                        root: self.root,
+                        path: path.clone(),
+                        max_nodes: self.max_nodes,
+                    });
+                    self.requested.insert(path, hash);
+                }
+            }
+        }
+        Ok(requests)
+    }
+
+    /// Writes the nodes answering `request` to the db, returning how many were
+    /// written. If the response is invalid, the subtrie is requested again.
+    pub fn process(&mut self, request: &SyncRequest, response: SyncResponse) -> TrieResult<usize> {
+        let top = match self.requested.remove(&request.path) {
+            Some(top) if request.root == self.root => top,
+            _ => return Err(TrieError::InvalidData),
+        };
+        // Nodes written before an error are checked already, and the subtrie is
+        // walked through them when requested again.
+        self.write(&request.path, top, response.nodes)
+            .inspect_err(|_| {
+                self.pending.insert(request.path.clone(), top);
+            })
+    }
+
+    /// Puts the subtries requested but not answered back to be requested, for
+    /// instance after a request failed.
+    pub fn retry(&mut self) {
+        self.pending.append(&mut self.requested);
+    }
+
+    /// Returns the number of subtries requested and not answered yet.
+    pub fn pending(&self) -> usize {
+        self.requested.len()
+    }
+
+    /// Returns whether every node of the trie is in the db.
+    pub fn is_complete(&self) -> bool {
+        self.pending.is_empty() && self.requested.is_empty()
+    }
+
+    fn write(&mut self, path: &[u8], top: H256, nodes: Vec<Vec<u8>>) -> TrieResult<usize> {
+        let mut expected = H256Map::default();
+        expected.insert(top, path.to_vec());
+        let mut written = 0;
+        for data in nodes {
+            let hash = H::hash(&data);
+            let path = expected
+                .remove(&hash)
+                .ok_or_else(|| TrieError::InvalidData.in_node(hash))?;
+            let children = self.children(&path, &data).map_err(|e| e.in_node(hash))?;
+            for (path, child) in children {
+                expected.entry(child).or_insert(path);
+            }
+            self.db.insert(hash, data);
+            written += 1;
+        }
+        // The subtries left out are requested later.
+        self.pending
+            .extend(expected.into_iter().map(|(hash, path)| (path, hash)));
+        Ok(written)
+    }
+
+    // Returns the paths and hashes of the children the node `data` at `path` refers
+    // to by hash. Children embedded in it cannot refer to nodes by hash themselves.
+    fn children(&self, path: &[u8], data: &[u8]) -> TrieResult<Vec<(Vec<u8>, H256)>> {
+        let node = C::decode(data)?;
+        self.limits.check(&node, data.len(), 0)?;
+        let mut children = Vec::new();
+        match node {
+            DecodedNode::Extension(ext, ChildReference::Hash(digest)) => {
+                let mut child_path = path.to_vec();
+                child_path.extend((0..ext.len()).map(|i| ext.at(i)));
+                children.push((child_path, decode_digest::<H>(digest)?));
+            }
+            DecodedNode::Branch(refs, _) => {
+                for (i, child) in refs.iter().enumerate() {
+                    if let ChildReference::Hash(digest) = child {
+                        let mut child_path = path.to_vec();
+                        child_path.push(i as u8);
+                        children.push((child_path, decode_digest::<H>(digest)?));
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(children)
+    }
+}
+
+// Returns how many nibbles of `path` the node `data` consumes, and the child it
+// leads to.
+fn step<'a, C: NodeCodec>(data: &'a [u8], path: &[u8]) -> TrieResult<(usize, ChildReference<'a>)> {
+    match C::decode(data)? {
+        DecodedNode::Branch(children, _) if path[0] < 16 => Ok((1, children[usize::from(path[0])])),
+        DecodedNode::Extension(ext, child)
+            if ext.len() <= path.len() && (0..ext.len()).all(|i| ext.at(i) == path[i]) =>
+        {
+            Ok((ext.len(), child))
+        }
+        _ => Err(TrieError::InvalidData),
+    }
+}
@@ -1,50 +1,156 @@
 extern crate alloc;
-use alloc::rc::Rc;
+use alloc::borrow::Cow;
+use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
-use core::cell::RefCell;
+use core::marker::PhantomData;
 use ethereum_types::H256;
 
-use crate::hasher::keccak256;
-use hashbrown::{HashMap, HashSet};
-use rlp::{Prototype, Rlp, RlpStream};
+use crate::hasher::{check_digest_length, decode_digest, H256Map, H256Set, Hasher, KeccakHasher};
+use lru::LruCache;
 
-use crate::db::{HashDB, MemoryDB};
+use crate::blob::{BlobStore, SharedBlobs};
+use crate::checkpoint::Checkpoint;
+use crate::codec::{ChildReference, DecodeLimits, DecodedNode, NodeCodec, RlpNodeCodec};
+use crate::db::HashDB;
+use crate::encoder::Encoder;
 use crate::errors::TrieError;
-use crate::nibbles::Nibbles;
-use crate::node::{empty_children, BranchNode, Node, RawNodeOrHash};
+use crate::hook::{DbAccess, SharedHook};
+use crate::nibbles::{packed_common_prefix, packed_leaf_eq, with_key_path, NibbleSlice, Nibbles};
+use crate::node::{self, empty_children, BranchNode, ExtensionNode, Node, RawNodeOrHash};
+use crate::proof::verify_key_proof;
+use crate::provider::{NodeProvider, SharedProvider};
+use crate::shared::Shared;
 
 pub type TrieResult<T> = Result<T, TrieError>;
 
-const HASH_LEN: usize = 32;
+/// Number of nodes kept in memory by default, see `with_node_cache_capacity`.
+pub const DEFAULT_NODE_CACHE_CAPACITY: usize = 1024;
+
+// Nodes read from the db keyed by the hash they are stored under. A hash always maps
+// to the same node, so the cache is shared by clones and forks and is never
+// invalidated.
+pub(crate) type NodeCache = Shared<LruCache<H256, CachedNode>>;
+
+// Lookups only decode the parts of a node on their path, so nodes they read are kept
+// encoded until something needs them decoded. Decoded nodes keep their encoding too,
+// so proofs can hand it out without encoding the node again.
+#[derive(Clone)]
+pub(crate) enum CachedNode {
+    Decoded(Node, Arc<[u8]>),
+    Encoded(Arc<[u8]>),
+}
+
+// Where a lookup continues after walking an encoded node.
+enum Step<'a> {
+    // The lookup ends, with the value found in the node if any.
+    Value(Option<&'a [u8]>),
+    // The lookup continues in the child, after the given number of nibbles.
+    Child(Node, usize),
+}
+
+// What `get_or_insert_at` found at a key.
+enum Slot {
+    // The value held at the key.
+    Held(Vec<u8>),
+    // Nothing, the default was inserted.
+    Inserted,
+    // Nothing, and the default was empty so nothing was inserted.
+    Vacant,
+}
+
+pub(crate) fn new_node_cache() -> NodeCache {
+    Shared::new(LruCache::new(DEFAULT_NODE_CACHE_CAPACITY))
+}
 
-pub struct PatriciaTrie<'db, D: HashDB> {
-    root: Node,
-    hashdb: &'db D,
-    cache: Rc<RefCell<HashMap<H256, Vec<u8>>>>,
-    gen_keys: Rc<RefCell<HashSet<H256>>>,
+pub struct PatriciaTrie<'db, D: HashDB, H: Hasher = KeccakHasher, C: NodeCodec = RlpNodeCodec> {
+    pub(crate) root: Node,
+    pub(crate) hashdb: &'db D,
+    pub(crate) cache: Shared<H256Map<Vec<u8>>>,
+    pub(crate) gen_keys: Shared<H256Set>,
+    pub(crate) node_cache: NodeCache,
+    pub(crate) provider: Option<SharedProvider>,
+    pub(crate) blobs: Option<SharedBlobs>,
+    pub(crate) hook: Option<SharedHook>,
+    pub(crate) lenient: bool,
+    pub(crate) limits: DecodeLimits,
+    pub(crate) marker: PhantomData<fn() -> (H, C)>,
 }
 
-impl<'db, D: HashDB> Clone for PatriciaTrie<'db, D> {
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> Clone for PatriciaTrie<'db, D, H, C> {
     fn clone(&self) -> Self {
         Self {
             root: self.root.clone(),
             hashdb: self.hashdb,
             cache: self.cache.clone(),
             gen_keys: self.gen_keys.clone(),
+            node_cache: self.node_cache.clone(),
+            provider: self.provider.clone(),
+            blobs: self.blobs.clone(),
+            hook: self.hook.clone(),
+            lenient: self.lenient,
+            limits: self.limits,
+            marker: PhantomData,
         }
     }
 }
 
 #[derive(Debug)]
-pub struct PatriciaTrieMut<'db, D: HashDB> {
-    root: Node,
+pub struct PatriciaTrieMut<'db, D: HashDB, H: Hasher = KeccakHasher, C: NodeCodec = RlpNodeCodec> {
+    pub(crate) root: Node,
     hashdb: &'db mut D,
-    cache: Rc<RefCell<HashMap<H256, Vec<u8>>>>,
-    passing_keys: HashSet<H256>,
-    gen_keys: Rc<RefCell<HashSet<H256>>>,
+    cache: Shared<H256Map<Vec<u8>>>,
+    pub(crate) passing_keys: H256Set,
+    gen_keys: Shared<H256Set>,
+    node_cache: NodeCache,
+    provider: Option<SharedProvider>,
+    blobs: Option<SharedBlobs>,
+    pub(crate) hook: Option<SharedHook>,
+    // Whether nodes missing from the db are read as empty subtries.
+    lenient: bool,
+    limits: DecodeLimits,
+    pub(crate) retained_levels: usize,
+    // Hash of the root as of the last commit, cleared by any change to the trie.
+    pub(crate) root_hash: Option<H256>,
+    // Hash of the root as of the last commit, or the root the trie was opened at,
+    // kept across changes. `None` until an empty trie is first committed.
+    committed_root: Option<H256>,
+    // Estimated size of the changes made since the last flush, and the size at which
+    // they are flushed to the db.
+    pub(crate) pending_bytes: usize,
+    memory_limit: Option<usize>,
+    // Whether the nodes written to the db are kept in the node cache.
+    cache_writes: bool,
+    // Whether the keys first written to the db are recorded in `flushed`, which
+    // costs a read of the db for every node written. Only a `RootJournal` needs them.
+    pub(crate) track_created: bool,
+    // Keys first written to the db since the last commit.
+    flushed: Vec<H256>,
+    // Number of nodes written to the db since the last commit.
+    written: usize,
+    // Whether committed roots are checked against roots recomputed from scratch.
+    shadow: bool,
+    // Whether commits remove the nodes the trie passed through from the db.
+    prune: bool,
+    empty_values: EmptyValues,
+    // States saved by `begin`, innermost last.
+    pub(crate) checkpoints: Vec<Checkpoint>,
+    marker: PhantomData<fn() -> (H, C)>,
+}
+
+/// What inserting an empty value does. Tries cannot hold empty values, whose
+/// encoding is the one of no value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmptyValues {
+    /// Removes the key, as Ethereum clients do. The default.
+    Remove,
+    /// Fails with `TrieError::InvalidData`, for tries where an empty value is a bug.
+    Reject,
 }
 
+// Rough heap cost of a change besides its key and value: the nodes copied on its path.
+const CHANGE_OVERHEAD: usize = 4 * core::mem::size_of::<BranchNode>();
+
 #[derive(Clone, Debug)]
 enum TraceStatus {
     Start,
@@ -82,14 +188,42 @@ impl From<Node> for TraceNode {
     }
 }
 
-pub struct TrieIterator<'db, D: HashDB> {
-    trie: PatriciaTrie<'db, D>,
+/// An iterator over the keys and values of a trie in key order, returned by `iter`.
+///
+/// It ends at the first node or value it fails to read, see `TrieTryIterator` for
+/// the error.
+pub struct TrieIterator<'db, D: HashDB, H: Hasher = KeccakHasher, C: NodeCodec = RlpNodeCodec> {
+    inner: TrieTryIterator<'db, D, H, C>,
+}
+
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> Iterator for TrieIterator<'db, D, H, C> {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()?.ok()
+    }
+}
+
+/// An iterator over the keys and values of a trie in key order, returned by
+/// `try_iter`, yielding the node or value it fails to read as an error, which ends
+/// the iteration.
+pub struct TrieTryIterator<'db, D: HashDB, H: Hasher = KeccakHasher, C: NodeCodec = RlpNodeCodec> {
+    trie: PatriciaTrie<'db, D, H, C>,
     nibble: Nibbles,
     nodes: Vec<TraceNode>,
 }
 
-impl<'db, D: HashDB> Iterator for TrieIterator<'db, D> {
-    type Item = (Vec<u8>, Vec<u8>);
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> TrieTryIterator<'db, D, H, C> {
+    // Ends the iteration with `error`, since the entries after a node that could not
+    // be read are unknown.
+    fn fail(&mut self, error: TrieError) -> Option<TrieResult<(Vec<u8>, Vec<u8>)>> {
+        self.nodes.clear();
+        Some(Err(error))
+    }
+}
+
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> Iterator for TrieTryIterator<'db, D, H, C> {
+    type Item = TrieResult<(Vec<u8>, Vec<u8>)>;
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
@@ -102,12 +236,12 @@ impl<'db, D: HashDB> Iterator for TrieIterator<'db, D> {
                         match *node {
                             Node::Leaf(ref leaf) => {
                                 let cur_len = self.nibble.len();
-                                self.nibble.truncate(cur_len - leaf.borrow().key.len());
+                                self.nibble.truncate(cur_len - leaf.key.len());
                             }
 
                             Node::Extension(ref ext) => {
                                 let cur_len = self.nibble.len();
-                                self.nibble.truncate(cur_len - ext.borrow().prefix.len());
+                                self.nibble.truncate(cur_len - ext.prefix.len());
                             }
 
                             Node::Branch(_) => {
@@ -119,31 +253,39 @@ impl<'db, D: HashDB> Iterator for TrieIterator<'db, D> {
                     }
 
                     (TraceStatus::Doing, Node::Extension(ref ext)) => {
-                        self.nibble.extend(&ext.borrow().prefix);
-                        self.nodes.push((ext.borrow().node.clone()).into());
+                        self.nibble.extend(&ext.prefix);
+                        self.nodes.push((ext.node.clone()).into());
                     }
 
                     (TraceStatus::Doing, Node::Leaf(ref leaf)) => {
-                        self.nibble.extend(&leaf.borrow().key);
-                        return Some((self.nibble.encode_raw().0, leaf.borrow().value.clone()));
+                        self.nibble.extend(&leaf.key);
+                        let key = self.nibble.encode_raw().0;
+                        return match self.trie.resolve_value(leaf.value.clone()) {
+                            Ok(value) => Some(Ok((key, value))),
+                            Err(e) => self.fail(e.at_key(&key)),
+                        };
                     }
 
                     (TraceStatus::Doing, Node::Branch(ref branch)) => {
-                        let value = branch.borrow().value.clone();
-                        if value.is_none() {
-                            continue;
+                        let value = branch.value.clone();
+                        if let Some(value) = value {
+                            let key = self.nibble.encode_raw().0;
+                            return match self.trie.resolve_value(value) {
+                                Ok(value) => Some(Ok((key, value))),
+                                Err(e) => self.fail(e.at_key(&key)),
+                            };
                         } else {
-                            return Some((self.nibble.encode_raw().0, value.unwrap()));
+                            continue;
                         }
                     }
 
                     (TraceStatus::Doing, Node::Hash(hash_node)) => {
-                        if let Ok(n) = self.trie.recover_from_db(&hash_node) {
-                            self.nodes.pop();
-                            self.nodes.push(n.into());
-                        } else {
-                            //error!();
-                            return None;
+                        match self.trie.recover_from_db(hash_node) {
+                            Ok(n) => {
+                                self.nodes.pop();
+                                self.nodes.push(n.into());
+                            }
+                            Err(e) => return self.fail(e),
                         }
                     }
 
@@ -155,7 +297,7 @@ impl<'db, D: HashDB> Iterator for TrieIterator<'db, D> {
                             self.nibble.push(i);
                         }
                         self.nodes
-                            .push((branch.borrow().children[i as usize].clone()).into());
+                            .push((branch.children[i as usize].clone()).into());
                     }
 
                     (_, Node::Empty) => {
@@ -170,97 +312,464 @@ impl<'db, D: HashDB> Iterator for TrieIterator<'db, D> {
     }
 }
 
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> IntoIterator for &PatriciaTrie<'db, D, H, C> {
+    type Item = (Vec<u8>, Vec<u8>);
+    type IntoIter = TrieIterator<'db, D, H, C>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, 'db, D: HashDB, H: Hasher, C: NodeCodec> IntoIterator
+    for &'a PatriciaTrieMut<'db, D, H, C>
+{
+    type Item = (Vec<u8>, Vec<u8>);
+    type IntoIter = TrieIterator<'a, D, H, C>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// An iterator over the keys and values of a trie that consumes it, returned by
+/// `PatriciaTrie::into_iter`.
+///
+/// Nodes only this iterator holds are taken apart and their values moved out, and
+/// nodes read from the db are decoded for it alone instead of through the node
+/// cache, so values are only copied out of nodes shared with other tries or the
+/// cache. Like `TrieIterator`, it ends at the first node or value it fails to read,
+/// see `TrieTryIntoIter` for the error.
+pub struct TrieIntoIter<'db, D: HashDB, H: Hasher = KeccakHasher, C: NodeCodec = RlpNodeCodec> {
+    inner: TrieTryIntoIter<'db, D, H, C>,
+}
+
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> Iterator for TrieIntoIter<'db, D, H, C> {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()?.ok()
+    }
+}
+
+/// An iterator over the keys and values of a trie that consumes it, returned by
+/// `PatriciaTrie::try_into_iter`. It yields the node or value it fails to read as
+/// an error, which ends the iteration.
+pub struct TrieTryIntoIter<'db, D: HashDB, H: Hasher = KeccakHasher, C: NodeCodec = RlpNodeCodec> {
+    trie: PatriciaTrie<'db, D, H, C>,
+    // Nodes left to visit, the last first, with the length of the path to their
+    // parent and the nibble leading from it to them, if any.
+    stack: Vec<(Node, usize, Option<u8>)>,
+    path: Nibbles,
+}
+
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> IntoIterator for PatriciaTrie<'db, D, H, C> {
+    type Item = (Vec<u8>, Vec<u8>);
+    type IntoIter = TrieIntoIter<'db, D, H, C>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        TrieIntoIter {
+            inner: self.try_into_iter(),
+        }
+    }
+}
+
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> Iterator for TrieTryIntoIter<'db, D, H, C> {
+    type Item = TrieResult<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((node, depth, nibble)) = self.stack.pop() {
+            self.path.truncate(depth);
+            if let Some(nibble) = nibble {
+                self.path.push(nibble);
+            }
+            let value = match node {
+                Node::Empty => None,
+                Node::Hash(hash) => match self.trie.take_from_db(&hash) {
+                    Ok(node) => {
+                        self.stack.push((node, self.path.len(), None));
+                        None
+                    }
+                    Err(e) => {
+                        self.stack.clear();
+                        return Some(Err(e));
+                    }
+                },
+                Node::Leaf(leaf) => {
+                    let leaf = Arc::try_unwrap(leaf).unwrap_or_else(|leaf| (*leaf).clone());
+                    self.path.extend(&leaf.key);
+                    Some(leaf.value)
+                }
+                Node::Extension(ext) => {
+                    let ext = Arc::try_unwrap(ext).unwrap_or_else(|ext| (*ext).clone());
+                    self.path.extend(&ext.prefix);
+                    self.stack.push((ext.node, self.path.len(), None));
+                    None
+                }
+                Node::Branch(branch) => {
+                    let mut branch =
+                        Arc::try_unwrap(branch).unwrap_or_else(|branch| (*branch).clone());
+                    // Children are pushed last first, and the value of the branch
+                    // comes before all of them.
+                    for (i, child) in branch.children.iter_mut().enumerate().rev() {
+                        if !matches!(child, Node::Empty) {
+                            let child = core::mem::replace(child, Node::Empty);
+                            self.stack.push((child, self.path.len(), Some(i as u8)));
+                        }
+                    }
+                    branch.value
+                }
+            };
+            if let Some(value) = value {
+                let key = self.path.encode_raw().0;
+                return match self.trie.resolve_value(value) {
+                    Ok(value) => Some(Ok((key, value))),
+                    Err(e) => {
+                        self.stack.clear();
+                        Some(Err(e.at_key(&key)))
+                    }
+                };
+            }
+        }
+        None
+    }
+}
+
 impl<'db, D: HashDB> PatriciaTrie<'db, D> {
     pub fn new(db: &'db D) -> Self {
+        Self::new_with_hasher(db)
+    }
+
+    pub fn from(db: &'db D, root: H256) -> TrieResult<Self> {
+        Self::from_with_hasher(db, root)
+    }
+}
+
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> PatriciaTrie<'db, D, H, C> {
+    /// Creates an empty trie hashing its nodes with `H` and encoding them with `C`.
+    pub fn new_with_hasher(db: &'db D) -> Self {
+        check_digest_length::<H>();
         Self {
             root: Node::Empty,
-            cache: Rc::new(RefCell::new(HashMap::new())),
-            gen_keys: Rc::new(RefCell::new(HashSet::new())),
+            cache: Shared::default(),
+            gen_keys: Shared::default(),
+            node_cache: new_node_cache(),
+            provider: None,
+            blobs: None,
+            hook: None,
+            lenient: false,
+            limits: DecodeLimits::default(),
+            marker: PhantomData,
             hashdb: db,
         }
     }
 
-    pub fn from(db: &'db D, root: H256) -> TrieResult<Self> {
+    /// Opens the trie at `root` whose nodes were hashed with `H` and encoded with `C`.
+    pub fn from_with_hasher(db: &'db D, root: H256) -> TrieResult<Self> {
+        check_digest_length::<H>();
         match db.get(&root) {
             Some(data) => {
                 let mut trie = Self {
                     root: Node::Empty,
-                    cache: Rc::new(RefCell::new(HashMap::new())),
-                    gen_keys: Rc::new(RefCell::new(HashSet::new())),
+                    cache: Shared::default(),
+                    gen_keys: Shared::default(),
+                    node_cache: new_node_cache(),
+                    provider: None,
+                    blobs: None,
+                    hook: None,
+                    lenient: false,
+                    limits: DecodeLimits::default(),
+                    marker: PhantomData,
                     hashdb: db,
                 };
 
-                trie.root = trie.decode_node(&data)?;
+                trie.root = trie.decode_stored(&root, &data)?;
                 Ok(trie)
             }
             None => Err(TrieError::InvalidStateRoot),
         }
     }
 
+    /// Opens the trie at `root`, fetching the nodes missing from the db, the root
+    /// included, from `provider`.
+    pub fn from_with_provider(
+        db: &'db D,
+        root: H256,
+        provider: impl NodeProvider + Send + Sync + 'static,
+    ) -> TrieResult<Self> {
+        let mut trie = Self::new_with_hasher(db).with_node_provider(provider);
+        trie.root = trie.load_root(&root)?;
+        Ok(trie)
+    }
+
+    /// Sets how many decoded nodes are kept in memory, `0` disables the cache.
+    pub fn with_node_cache_capacity(self, capacity: usize) -> Self {
+        self.node_cache
+            .with(capacity, |cache, capacity| cache.resize(capacity));
+        self
+    }
+
+    /// Fetches the nodes missing from the db from `provider`. They are kept in
+    /// memory, since this trie cannot write to the db.
+    pub fn with_node_provider(
+        mut self,
+        provider: impl NodeProvider + Send + Sync + 'static,
+    ) -> Self {
+        self.provider = Some(SharedProvider(Arc::new(provider)));
+        self
+    }
+
+    /// Reads the values longer than `threshold` bytes from `store`, where the trie
+    /// that wrote them kept them, see `PatriciaTrieMut::with_blob_store`.
+    pub fn with_blob_store(
+        mut self,
+        store: impl BlobStore + Send + Sync + 'static,
+        threshold: usize,
+    ) -> Self {
+        self.blobs = Some(SharedBlobs {
+            store: Arc::new(store),
+            threshold,
+        });
+        self
+    }
+
+    /// Calls `hook` on every read of the trie from the db from then on, with the hash
+    /// and length of the node read, for audit logs, request tracing or prefetching.
+    pub fn with_db_hook(mut self, hook: impl Fn(&DbAccess) + Send + Sync + 'static) -> Self {
+        self.hook = Some(SharedHook(Arc::new(hook)));
+        self
+    }
+
+    /// Reads the nodes missing from the db as empty subtries instead of failing with
+    /// `TrieError::MissingNode`, for tries only partly stored on purpose.
+    pub fn with_lenient_mode(mut self) -> Self {
+        self.lenient = true;
+        self
+    }
+
+    /// Bounds the nodes read from then on from the db or the node provider, and the
+    /// proofs verified, failing with `TrieError::LimitExceeded` beyond them.
+    pub fn with_decode_limits(mut self, limits: DecodeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
     pub fn hashdb(&self) -> &D {
         self.hashdb
     }
 
-    pub fn iter(&self) -> TrieIterator<'db, D> {
-        let mut nodes = Vec::new();
-        nodes.push(self.root.clone().into());
+    /// Returns an iterator over the keys and values of the trie in key order.
+    ///
+    /// The iteration ends at a node missing from the db or a value missing from the
+    /// blob store, which `try_iter` yields as an error.
+    pub fn iter(&self) -> TrieIterator<'db, D, H, C> {
         TrieIterator {
+            inner: self.try_iter(),
+        }
+    }
+
+    /// Returns an iterator over the keys and values of the trie in key order that
+    /// yields a node missing from the db, or a value missing from the blob store,
+    /// as an error, which ends the iteration.
+    pub fn try_iter(&self) -> TrieTryIterator<'db, D, H, C> {
+        let nodes = vec![self.root.clone().into()];
+        TrieTryIterator {
             trie: self.clone(),
             nibble: Nibbles::from_raw(&[], false),
             nodes,
         }
     }
 
-    /// Returns the value for key stored in the trie.
+    /// Same as `into_iter`, but yields the node or value it fails to read as an
+    /// error, which ends the iteration.
+    pub fn try_into_iter(mut self) -> TrieTryIntoIter<'db, D, H, C> {
+        let root = core::mem::replace(&mut self.root, Node::Empty);
+        TrieTryIntoIter {
+            trie: self,
+            stack: vec![(root, 0, None)],
+            path: Nibbles::default(),
+        }
+    }
+
+    /// Calls `f` with every key and value in the trie in key order. Unlike `iter`,
+    /// values are borrowed from the nodes instead of being copied out.
+    pub fn for_each_entry(&self, mut f: impl FnMut(&[u8], &[u8])) -> TrieResult<()> {
+        let mut path = Nibbles::from_raw(&[], false);
+        let mut key = Vec::new();
+        // Nodes left to visit, with the path length above them and the branch index
+        // leading to them.
+        let mut stack = vec![(self.root.clone(), 0, None)];
+        while let Some((n, depth, index)) = stack.pop() {
+            path.truncate(depth);
+            if let Some(index) = index {
+                path.push(index);
+            }
+            match n {
+                Node::Empty => {}
+                Node::Leaf(leaf) => {
+                    path.extend(&leaf.key);
+                    path.encode_raw_into(&mut key);
+                    f(&key, &self.resolve_ref(&leaf.value)?);
+                }
+                Node::Branch(branch) => {
+                    if let Some(value) = &branch.value {
+                        path.encode_raw_into(&mut key);
+                        f(&key, &self.resolve_ref(value)?);
+                    }
+                    for (i, child) in branch.children.iter().enumerate().rev() {
+                        if !matches!(child, Node::Empty) {
+                            stack.push((child.clone(), path.len(), Some(i as u8)));
+                        }
+                    }
+                }
+                Node::Extension(ext) => {
+                    path.extend(&ext.prefix);
+                    stack.push((ext.node.clone(), path.len(), None));
+                }
+                Node::Hash(hash) => {
+                    stack.push((self.recover_from_db(&hash)?, path.len(), None));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the value for key stored in the trie. Keys of up to `STACK_KEY_LEN`
+    /// bytes, hashed keys among them, are walked on the stack with their nibbles
+    /// compared in blocks.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
     pub fn get(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
-        self.get_at(self.root.clone(), &Nibbles::from_raw(key, true))
+        with_key_path(key, |path| self.get_at(self.root.clone(), path)).map_err(|e| e.at_key(key))
     }
 
     /// Checks that the key is present in the trie
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
     pub fn contains(&self, key: &[u8]) -> TrieResult<bool> {
-        Ok(self
-            .get_at(self.root.clone(), &Nibbles::from_raw(key, true))?
-            .map_or(false, |_| true))
+        with_key_path(key, |path| self.contains_at(self.root.clone(), path))
+            .map_err(|e| e.at_key(key))
     }
 
-    fn get_at(&self, n: Node, partial: &Nibbles) -> TrieResult<Option<Vec<u8>>> {
-        match n {
-            Node::Empty => Ok(None),
-            Node::Leaf(leaf) => {
-                let borrow_leaf = leaf.borrow();
+    /// Returns the value stored under a key given as its nibbles, one per byte, which
+    /// saves converting keys that are looked up repeatedly.
+    pub fn get_nibbles(&self, path: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        self.get_at(self.root.clone(), nibble_path(path)?)
+    }
 
-                if &borrow_leaf.key == partial {
-                    Ok(Some(borrow_leaf.value.clone()))
-                } else {
-                    Ok(None)
-                }
+    fn get_at(&self, n: Node, partial: NibbleSlice) -> TrieResult<Option<Vec<u8>>> {
+        match self.lookup_at(n, partial, |value| value.map(|v| v.to_vec()))? {
+            Some(value) => self.resolve_value(value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // Reads a value kept in the blob store back from it.
+    pub(crate) fn resolve_value(&self, value: Vec<u8>) -> TrieResult<Vec<u8>> {
+        match &self.blobs {
+            Some(blobs) => blobs.resolve::<H>(value),
+            None => Ok(value),
+        }
+    }
+
+    // Same as `resolve_value`, borrowing the values the trie holds itself.
+    fn resolve_ref<'a>(&self, value: &'a [u8]) -> TrieResult<Cow<'a, [u8]>> {
+        match &self.blobs {
+            Some(blobs) if value.len() == H::LENGTH => {
+                blobs.resolve::<H>(value.to_vec()).map(Cow::Owned)
             }
-            Node::Branch(branch) => {
-                let borrow_branch = branch.borrow();
+            _ => Ok(Cow::Borrowed(value)),
+        }
+    }
 
-                if partial.is_empty() || partial.at(0) == 16 {
-                    Ok(borrow_branch.value.clone())
-                } else {
+    // Same walk as `get_at`, but stops at the node holding the value without copying it.
+    fn contains_at(&self, n: Node, partial: NibbleSlice) -> TrieResult<bool> {
+        self.lookup_at(n, partial, |value| value.is_some())
+    }
+
+    // Walks to the value at `partial` and hands it to `found`. Nodes not yet decoded
+    // are walked in their encoded form.
+    fn lookup_at<T>(
+        &self,
+        n: Node,
+        partial: NibbleSlice,
+        found: impl FnOnce(Option<&[u8]>) -> T,
+    ) -> TrieResult<T> {
+        let mut n = n;
+        let mut partial = partial;
+        loop {
+            n = match n {
+                Node::Empty => return Ok(found(None)),
+                Node::Leaf(leaf) => {
+                    if leaf.key == partial {
+                        return Ok(found(Some(&leaf.value)));
+                    } else {
+                        return Ok(found(None));
+                    }
+                }
+                Node::Branch(branch) => {
+                    if partial.is_empty() || partial.at(0) == 16 {
+                        return Ok(found(branch.value.as_deref()));
+                    }
                     let index = partial.at(0);
-                    self.get_at(borrow_branch.children[index].clone(), &partial.offset(1))
+                    partial = partial.offset(1);
+                    branch.children[index].clone()
                 }
-            }
-            Node::Extension(extension) => {
-                let extension = extension.borrow();
+                Node::Extension(extension) => {
+                    let prefix = &extension.prefix;
+                    let match_len = partial.common_prefix(prefix);
+                    if match_len != prefix.len() {
+                        return Ok(found(None));
+                    }
+                    partial = partial.offset(match_len);
+                    extension.node.clone()
+                }
+                Node::Hash(hash) => match self.load(&hash)? {
+                    None => {
+                        self.check_missing(&hash)?;
+                        return Ok(found(None));
+                    }
+                    Some(CachedNode::Decoded(n, _)) => n,
+                    Some(CachedNode::Encoded(data)) => match self
+                        .step_encoded(&data, partial)
+                        .map_err(|e| e.in_node(hash))?
+                    {
+                        Step::Value(value) => return Ok(found(value)),
+                        Step::Child(n, consumed) => {
+                            partial = partial.offset(consumed);
+                            n
+                        }
+                    },
+                },
+            };
+        }
+    }
 
-                let prefix = &extension.prefix;
-                let match_len = partial.common_prefix(&prefix);
-                if match_len == prefix.len() {
-                    self.get_at(extension.node.clone(), &partial.offset(match_len))
-                } else {
-                    Ok(None)
+    // Follows `partial` one node down in the encoded node `data`. Only the child on
+    // the path is decoded, and only if it is embedded in `data`.
+    fn step_encoded<'a>(&self, data: &'a [u8], partial: NibbleSlice) -> TrieResult<Step<'a>> {
+        let node = C::decode(data)?;
+        self.limits.check(&node, data.len(), 0)?;
+        let (child, consumed) = match node {
+            DecodedNode::Empty => return Ok(Step::Value(None)),
+            DecodedNode::Leaf(path, value) => {
+                if packed_leaf_eq(&path, partial) {
+                    return Ok(Step::Value(Some(value)));
+                }
+                return Ok(Step::Value(None));
+            }
+            DecodedNode::Extension(path, child) => {
+                if packed_common_prefix(&path, partial) != path.len() {
+                    return Ok(Step::Value(None));
                 }
+                (child, path.len())
             }
-            Node::Hash(hash) => {
-                let n = self.recover_from_db(&hash)?;
-                self.get_at(n, partial)
+            DecodedNode::Branch(children, value) => {
+                if partial.is_empty() || partial.at(0) == 16 {
+                    return Ok(Step::Value(value));
+                }
+                (children[partial.at(0)], 1)
             }
-        }
+        };
+        Ok(Step::Child(self.decode_child(child, 1)?, consumed))
     }
 
     /// Prove constructs a merkle proof for key. The result contains all encoded nodes
@@ -271,96 +780,531 @@ impl<'db, D: HashDB> PatriciaTrie<'db, D> {
     /// nodes of the longest existing prefix of the key (at least the root node), ending
     /// with the node that proves the absence of the key.
     pub fn get_proof(&self, key: &[u8]) -> TrieResult<Vec<Vec<u8>>> {
-        let mut path = self.get_path_at(self.root.clone(), &Nibbles::from_raw(key, true))?;
-        match self.root {
-            Node::Empty => {}
-            _ => path.push(self.root.clone()),
+        let proof = self.get_proof_shared(key)?;
+        Ok(proof.iter().map(|node| node.to_vec()).collect())
+    }
+
+    /// Same as `get_proof`, but the encoded nodes are shared with the node cache
+    /// instead of being copied. Nodes read from the db are never encoded again.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "get_proof", level = "debug", skip_all)
+    )]
+    pub fn get_proof_shared(&self, key: &[u8]) -> TrieResult<Vec<Arc<[u8]>>> {
+        #[cfg(feature = "metrics")]
+        crate::metrics::metrics().record_proof();
+        self.proof_nodes(key).map_err(|e| e.at_key(key))
+    }
+
+    pub(crate) fn proof_nodes(&self, key: &[u8]) -> TrieResult<Vec<Arc<[u8]>>> {
+        let mut proof = Vec::new();
+        let mut partial = NibbleSlice::new(key);
+        let mut n = self.root.clone();
+        let mut is_root = true;
+        loop {
+            // Only nodes stored by hash are proof items, the others are embedded in
+            // their parent.
+            let node = match n {
+                Node::Empty => break,
+                Node::Hash(hash) => match self.load(&hash)? {
+                    None => {
+                        self.check_missing(&hash)?;
+                        break;
+                    }
+                    Some(CachedNode::Decoded(node, data)) => {
+                        proof.push(data);
+                        node
+                    }
+                    Some(CachedNode::Encoded(data)) => {
+                        proof.push(data.clone());
+                        match self
+                            .step_encoded(&data, partial)
+                            .map_err(|e| e.in_node(hash))?
+                        {
+                            Step::Value(_) => break,
+                            Step::Child(child, consumed) => {
+                                partial = partial.offset(consumed);
+                                n = child;
+                                is_root = false;
+                                continue;
+                            }
+                        }
+                    }
+                },
+                n => {
+                    let stored = match n.cached_hash() {
+                        Some(hash) => self.load(&hash)?,
+                        None => None,
+                    };
+                    match stored {
+                        Some(CachedNode::Decoded(_, data)) | Some(CachedNode::Encoded(data)) => {
+                            proof.push(data)
+                        }
+                        None => {
+                            let data = self.encode_raw(n.clone());
+                            if is_root || data.len() >= H::LENGTH {
+                                proof.push(data.into());
+                            }
+                        }
+                    }
+                    n
+                }
+            };
+            is_root = false;
+
+            n = match node {
+                Node::Branch(branch) => {
+                    if partial.is_empty() || partial.at(0) == 16 {
+                        break;
+                    }
+                    let index = partial.at(0);
+                    partial = partial.offset(1);
+                    branch.children[index].clone()
+                }
+                Node::Extension(ext) => {
+                    let match_len = partial.common_prefix(&ext.prefix);
+                    if match_len != ext.prefix.len() {
+                        break;
+                    }
+                    partial = partial.offset(match_len);
+                    ext.node.clone()
+                }
+                _ => break,
+            };
         }
-        Ok(path.into_iter().rev().map(|n| self.encode_raw(n)).collect())
+        Ok(proof)
     }
 
     /// return value if key exists, None if key not exist, Error if proof is wrong
+    ///
+    /// The proof must be the nodes from the root down the path of the key, in order,
+    /// as returned by `get_proof`. Each node must be the one its parent refers to,
+    /// and nodes missing from the path or off it make the proof wrong.
     pub fn verify_proof(
         &self,
         root_hash: H256,
         key: &[u8],
         proof: Vec<Vec<u8>>,
     ) -> TrieResult<Option<Vec<u8>>> {
-        let mut memdb = MemoryDB::new(true);
-        for node_encoded in proof.into_iter() {
-            let hash = keccak256(&node_encoded);
+        verify_key_proof::<H, C>(root_hash, key, &proof, &self.limits)
+    }
+}
 
-            if root_hash.eq(&hash) || node_encoded.len() >= HASH_LEN {
-                memdb.insert(hash, node_encoded);
-            }
+#[cfg(feature = "parallel")]
+impl<'db, D: HashDB + Sync, H: Hasher, C: NodeCodec> PatriciaTrie<'db, D, H, C> {
+    /// Returns a parallel iterator over the keys and values of the trie. The subtries
+    /// below the first branch are walked by separate workers sharing the caches,
+    /// blob store, node provider and settings of the trie, so they yield the entries
+    /// `try_iter` does.
+    pub fn par_iter(
+        &self,
+    ) -> TrieResult<impl rayon::iter::ParallelIterator<Item = TrieResult<(Vec<u8>, Vec<u8>)>> + 'db>
+    where
+        H: 'db,
+        C: 'db,
+    {
+        use rayon::prelude::*;
+
+        let trie = self.clone();
+        Ok(self
+            .subtries()?
+            .into_par_iter()
+            .flat_map_iter(move |(nibble, node)| TrieTryIterator {
+                trie: trie.clone(),
+                nibble,
+                nodes: vec![node.into()],
+            }))
+    }
+
+    // Splits the trie at its first branch, returning each subtrie with the path
+    // leading to it. The value of the branch itself becomes a leaf.
+    fn subtries(&self) -> TrieResult<Vec<(Nibbles, Node)>> {
+        let mut path = Nibbles::from_raw(&[], false);
+        let mut n = self.root.clone();
+        loop {
+            n = match n {
+                Node::Hash(hash) => self.recover_from_db(&hash)?,
+                Node::Extension(ext) => {
+                    path.extend(&ext.prefix);
+                    ext.node.clone()
+                }
+                Node::Branch(branch) => {
+                    let mut subtries = Vec::new();
+                    if let Some(value) = &branch.value {
+                        let leaf = Node::from_leaf(Nibbles::from_hex(&[16]), value.clone());
+                        subtries.push((path.clone(), leaf));
+                    }
+                    for (i, child) in branch.children.iter().enumerate() {
+                        if !matches!(child, Node::Empty) {
+                            let mut child_path = path.clone();
+                            child_path.push(i as u8);
+                            subtries.push((child_path, child.clone()));
+                        }
+                    }
+                    return Ok(subtries);
+                }
+                n => return Ok(vec![(path, n)]),
+            };
         }
-        let trie = PatriciaTrieMut::from(&mut memdb, root_hash).or(Err(TrieError::InvalidProof))?;
-        trie.get(key).or(Err(TrieError::InvalidProof))
     }
 }
 
-impl<'a, 'db: 'a, D: HashDB> From<&'a PatriciaTrieMut<'db, D>> for PatriciaTrie<'a, D> {
-    fn from(trie: &'a PatriciaTrieMut<'db, D>) -> Self {
+impl<'a, 'db: 'a, D: HashDB, H: Hasher, C: NodeCodec> From<&'a PatriciaTrieMut<'db, D, H, C>>
+    for PatriciaTrie<'a, D, H, C>
+{
+    fn from(trie: &'a PatriciaTrieMut<'db, D, H, C>) -> Self {
         trie.trie_ref()
     }
 }
 
 impl<'db, D: HashDB> PatriciaTrieMut<'db, D> {
+    pub fn new(db: &'db mut D) -> Self {
+        Self::new_with_hasher(db)
+    }
+
+    pub fn from(db: &'db mut D, root: H256) -> TrieResult<Self> {
+        Self::from_with_hasher(db, root)
+    }
+
+    /// Creates a trie holding `entries`, the counterpart of `FromIterator` for a
+    /// trie that needs a db. Nothing is committed.
+    pub fn from_entries<K: AsRef<[u8]>>(
+        db: &'db mut D,
+        entries: impl IntoIterator<Item = (K, Vec<u8>)>,
+    ) -> TrieResult<Self> {
+        Self::from_entries_with_hasher(db, entries)
+    }
+}
+
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> PatriciaTrieMut<'db, D, H, C> {
     pub fn hashdb_mut(&mut self) -> &mut D {
         self.hashdb
     }
 
+    /// Sets how many decoded nodes are kept in memory, `0` disables the cache.
+    pub fn with_node_cache_capacity(self, capacity: usize) -> Self {
+        self.node_cache
+            .with(capacity, |cache, capacity| cache.resize(capacity));
+        self
+    }
+
+    /// Fetches the nodes missing from the db from `provider`, as needed by lookups
+    /// and changes. Fetched nodes are written to the db by the next `root`.
+    pub fn with_node_provider(
+        mut self,
+        provider: impl NodeProvider + Send + Sync + 'static,
+    ) -> Self {
+        self.provider = Some(SharedProvider(Arc::new(provider)));
+        self
+    }
+
+    /// Keeps the values longer than `threshold` bytes in `store` instead of the
+    /// nodes, which hold the hash of the value in its place, so that large values
+    /// such as contract code do not bloat nodes and proofs. Values exactly as long
+    /// as a hash are kept in the store too, so that they cannot be mistaken for one.
+    ///
+    /// Lookups and iterators read the values back from the store, while proofs hold
+    /// the hashes. The store must be set whenever the trie is opened, with the same
+    /// hasher, and values stay in it once removed from the trie, since other keys or
+    /// tries may hold them too.
+    pub fn with_blob_store(
+        mut self,
+        store: impl BlobStore + Send + Sync + 'static,
+        threshold: usize,
+    ) -> Self {
+        self.blobs = Some(SharedBlobs {
+            store: Arc::new(store),
+            threshold,
+        });
+        self
+    }
+
+    /// Calls `hook` on every read, write and removal of the trie in the db from then
+    /// on, with the hash of the node and its length when known.
+    pub fn with_db_hook(mut self, hook: impl Fn(&DbAccess) + Send + Sync + 'static) -> Self {
+        self.hook = Some(SharedHook(Arc::new(hook)));
+        self
+    }
+
+    /// Reads the nodes missing from the db as empty subtries, see
+    /// `PatriciaTrie::with_lenient_mode`. Changes below them are then lost.
+    pub fn with_lenient_mode(mut self) -> Self {
+        self.lenient = true;
+        self
+    }
+
+    /// Bounds the nodes read and the proofs verified, see
+    /// `PatriciaTrie::with_decode_limits`.
+    pub fn with_decode_limits(mut self, limits: DecodeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
     pub fn hashdb(&self) -> &D {
         self.hashdb
     }
 
-    pub fn iter(&self) -> TrieIterator<D> {
-        let trie: PatriciaTrie<D> = self.into();
+    /// Returns an iterator over the keys and values of the trie in key order,
+    /// including the changes made since the last commit.
+    pub fn iter(&self) -> TrieIterator<'_, D, H, C> {
+        let trie: PatriciaTrie<D, H, C> = self.into();
         trie.iter()
     }
 
-    pub fn new(db: &'db mut D) -> Self {
+    /// Same as `iter`, but yields the node or value it fails to read as an error,
+    /// see `PatriciaTrie::try_iter`.
+    pub fn try_iter(&self) -> TrieTryIterator<'_, D, H, C> {
+        let trie: PatriciaTrie<D, H, C> = self.into();
+        trie.try_iter()
+    }
+
+    /// Returns an iterator over the keys and values of the trie as of the last
+    /// commit, or as opened if it was never committed, leaving out the changes made
+    /// since. The nodes of that root stay in the db until the next commit prunes
+    /// them.
+    pub fn iter_committed(&self) -> TrieResult<TrieIterator<'_, D, H, C>> {
+        Ok(self.committed()?.iter())
+    }
+
+    /// Returns a parallel iterator over the keys and values of the trie, see
+    /// `PatriciaTrie::par_iter`.
+    #[cfg(feature = "parallel")]
+    pub fn par_iter(
+        &self,
+    ) -> TrieResult<impl rayon::iter::ParallelIterator<Item = TrieResult<(Vec<u8>, Vec<u8>)>> + '_>
+    where
+        D: Sync,
+        H: 'db,
+        C: 'db,
+    {
+        let trie: PatriciaTrie<D, H, C> = self.into();
+        trie.par_iter()
+    }
+
+    /// Calls `f` with every key and value in the trie in key order, see
+    /// `PatriciaTrie::for_each_entry`.
+    pub fn for_each_entry(&self, f: impl FnMut(&[u8], &[u8])) -> TrieResult<()> {
+        let trie: PatriciaTrie<D, H, C> = self.into();
+        trie.for_each_entry(f)
+    }
+
+    /// Creates an empty trie hashing its nodes with `H` and encoding them with `C`.
+    pub fn new_with_hasher(db: &'db mut D) -> Self {
+        check_digest_length::<H>();
         Self {
             root: Node::Empty,
-            cache: Rc::new(RefCell::new(HashMap::new())),
-            passing_keys: HashSet::new(),
-            gen_keys: Rc::new(RefCell::new(HashSet::new())),
+            cache: Shared::default(),
+            passing_keys: H256Set::default(),
+            gen_keys: Shared::default(),
+            node_cache: new_node_cache(),
+            provider: None,
+            blobs: None,
+            hook: None,
+            lenient: false,
+            limits: DecodeLimits::default(),
+            retained_levels: 0,
+            root_hash: None,
+            committed_root: None,
+            pending_bytes: 0,
+            memory_limit: None,
+            cache_writes: false,
+            track_created: false,
+            flushed: Vec::new(),
+            written: 0,
+            shadow: false,
+            prune: true,
+            empty_values: EmptyValues::Remove,
+            checkpoints: Vec::new(),
+            marker: PhantomData,
             hashdb: db,
         }
     }
 
-    pub fn from(db: &'db mut D, root: H256) -> TrieResult<Self> {
+    // Opens a trie whose root node was put together in memory, with nothing committed.
+    pub(crate) fn from_node(db: &'db mut D, root: Node) -> Self {
+        let mut trie = Self::new_with_hasher(db);
+        trie.root = root;
+        trie
+    }
+
+    /// Same as `from_entries`, for a trie hashing its nodes with `H` and encoding
+    /// them with `C`.
+    pub fn from_entries_with_hasher<K: AsRef<[u8]>>(
+        db: &'db mut D,
+        entries: impl IntoIterator<Item = (K, Vec<u8>)>,
+    ) -> TrieResult<Self> {
+        let mut trie = Self::new_with_hasher(db);
+        trie.try_extend(entries)?;
+        Ok(trie)
+    }
+
+    /// Opens the trie at `root` whose nodes were hashed with `H` and encoded with `C`.
+    pub fn from_with_hasher(db: &'db mut D, root: H256) -> TrieResult<Self> {
+        check_digest_length::<H>();
         match db.get(&root) {
             Some(data) => {
                 let mut trie = Self {
                     root: Node::Empty,
-                    cache: Rc::new(RefCell::new(HashMap::new())),
-                    passing_keys: HashSet::new(),
-                    gen_keys: Rc::new(RefCell::new(HashSet::new())),
+                    cache: Shared::default(),
+                    passing_keys: H256Set::default(),
+                    gen_keys: Shared::default(),
+                    node_cache: new_node_cache(),
+                    provider: None,
+                    blobs: None,
+                    hook: None,
+                    lenient: false,
+                    limits: DecodeLimits::default(),
+                    retained_levels: 0,
+                    root_hash: Some(root),
+                    committed_root: Some(root),
+                    pending_bytes: 0,
+                    memory_limit: None,
+                    cache_writes: false,
+                    track_created: false,
+                    flushed: Vec::new(),
+                    written: 0,
+                    shadow: false,
+                    prune: true,
+                    empty_values: EmptyValues::Remove,
+                    checkpoints: Vec::new(),
+                    marker: PhantomData,
                     hashdb: db,
                 };
 
-                trie.root = trie.decode_node(&data)?;
+                trie.root = trie.decode_stored(&root, &data)?;
                 Ok(trie)
             }
             None => Err(TrieError::InvalidStateRoot),
         }
     }
 
+    /// Opens the trie at `root`, fetching the nodes missing from the db, the root
+    /// included, from `provider`.
+    pub fn from_with_provider(
+        db: &'db mut D,
+        root: H256,
+        provider: impl NodeProvider + Send + Sync + 'static,
+    ) -> TrieResult<Self> {
+        let mut trie = Self::new_with_hasher(db).with_node_provider(provider);
+        trie.open_at(root)?;
+        Ok(trie)
+    }
+
+    // Replaces the empty root of a trie just created with the node stored under
+    // `root`.
+    pub(crate) fn open_at(&mut self, root: H256) -> TrieResult<()> {
+        self.root = self.trie_ref().load_root(&root)?;
+        self.root_hash = Some(root);
+        self.committed_root = Some(root);
+        Ok(())
+    }
+
+    /// Sets how many levels below the root stay decoded in memory after a commit.
+    /// Deeper nodes are dropped and read back from the db when needed again.
+    pub fn with_retained_levels(mut self, levels: usize) -> Self {
+        self.retained_levels = levels;
+        self
+    }
+
+    /// Writes pending changes to the db ahead of `root` once their estimated size
+    /// exceeds `bytes`, so long imports don't keep every changed node in memory.
+    /// Nodes replaced after such a flush are still pruned by the next `root`.
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Keeps the nodes written to the db by commits and flushes in the node cache, so
+    /// reads right after a commit don't go back to the db for them. Off by default,
+    /// as bulk imports rarely read back what they wrote.
+    pub fn with_write_caching(mut self, enabled: bool) -> Self {
+        self.cache_writes = enabled;
+        self
+    }
+
+    /// Checks every root committed against the root of a trie rebuilt from scratch
+    /// out of the entries of this one, failing with `TrieError::RootMismatch` at the
+    /// first subtrie where they differ. Meant for debugging, as each commit then
+    /// walks the whole trie.
+    pub fn with_shadow_verification(mut self) -> Self {
+        self.shadow = true;
+        self
+    }
+
+    /// Sets whether `root` removes from the db the nodes replaced since the last
+    /// commit, which it does by default. Tries sharing their db with older roots
+    /// still in use keep them.
+    pub fn with_pruning(mut self, prune: bool) -> Self {
+        self.prune = prune;
+        self
+    }
+
+    /// Sets what inserting an empty value does, removing the key by default.
+    pub fn with_empty_values(mut self, empty_values: EmptyValues) -> Self {
+        self.empty_values = empty_values;
+        self
+    }
+
+    /// Returns the approximate number of heap bytes held by the nodes in memory, the
+    /// node cache and the nodes waiting to be written to the db.
+    pub fn memory_usage(&self) -> usize {
+        let cached = self.node_cache.with((), |cache, ()| {
+            cache
+                .iter()
+                .map(|(_, node)| {
+                    core::mem::size_of::<(H256, CachedNode)>()
+                        + match node {
+                            CachedNode::Decoded(node, data) => node.heap_size() + data.len(),
+                            CachedNode::Encoded(data) => data.len(),
+                        }
+                })
+                .sum::<usize>()
+        });
+        let written = self.cache.with((), |cache, ()| {
+            cache
+                .values()
+                .map(|data| core::mem::size_of::<H256>() + data.capacity())
+                .sum::<usize>()
+        });
+        self.root.heap_size() + cached + written
+    }
+
     /// Returns the value for key stored in the trie.
     pub fn get(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
         self.trie_ref().get(key)
     }
 
+    /// Returns the value for key including the changes made since the last commit,
+    /// the same as `get`.
+    pub fn get_pending(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        self.get(key)
+    }
+
+    /// Returns the value for key as of the last commit, or as opened if the trie was
+    /// never committed, leaving out the changes made since.
+    pub fn get_committed(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        self.committed()?.get(key)
+    }
+
     /// Checks that the key is present in the trie
     pub fn contains(&self, key: &[u8]) -> TrieResult<bool> {
         self.trie_ref().contains(key)
     }
 
+    /// Returns the value stored under a key given as its nibbles, see
+    /// `PatriciaTrie::get_nibbles`.
+    pub fn get_nibbles(&self, path: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        self.trie_ref().get_nibbles(path)
+    }
+
     pub fn get_proof(&self, key: &[u8]) -> TrieResult<Vec<Vec<u8>>> {
         self.trie_ref().get_proof(key)
     }
 
+    pub fn get_proof_shared(&self, key: &[u8]) -> TrieResult<Vec<Arc<[u8]>>> {
+        self.trie_ref().get_proof_shared(key)
+    }
+
     /// return value if key exists, None if key not exist, Error if proof is wrong
     pub fn verify_proof(
         &self,
@@ -371,465 +1315,1142 @@ impl<'db, D: HashDB> PatriciaTrieMut<'db, D> {
         self.trie_ref().verify_proof(root_hash, key, proof)
     }
 
-    fn trie_ref(&self) -> PatriciaTrie<D> {
+    // Returns a read-only view of the trie as of the last commit.
+    pub(crate) fn committed(&self) -> TrieResult<PatriciaTrie<'_, D, H, C>> {
+        let mut trie = self.trie_ref();
+        trie.root = match self.committed_root {
+            Some(root) => trie.load_root(&root)?,
+            None => Node::Empty,
+        };
+        Ok(trie)
+    }
+
+    pub(crate) fn trie_ref(&self) -> PatriciaTrie<'_, D, H, C> {
         PatriciaTrie {
             root: self.root.clone(),
             hashdb: self.hashdb(),
             cache: self.cache.clone(),
             gen_keys: self.gen_keys.clone(),
+            node_cache: self.node_cache.clone(),
+            provider: self.provider.clone(),
+            blobs: self.blobs.clone(),
+            hook: self.hook.clone(),
+            lenient: self.lenient,
+            limits: self.limits,
+            marker: PhantomData,
         }
     }
 }
 
-impl<'db, D: HashDB> PatriciaTrieMut<'db, D> {
-    /// Inserts value into trie and modifies it if it exists
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> PatriciaTrieMut<'db, D, H, C> {
+    /// Inserts value into trie and modifies it if it exists. Short keys take the same
+    /// fast path as in `PatriciaTrie::get`.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
     pub fn insert(&mut self, key: &[u8], value: Vec<u8>) -> TrieResult<()> {
+        with_key_path(key, |path| self.insert_path(path, key.len(), value))
+            .map_err(|e| e.at_key(key))
+    }
+
+    /// Removes any existing value for key from the trie.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
+    pub fn remove(&mut self, key: &[u8]) -> TrieResult<bool> {
+        with_key_path(key, |path| self.remove_path(path, key.len())).map_err(|e| e.at_key(key))
+    }
+
+    /// Same as `insert`, with the key given as its nibbles, one per byte.
+    pub fn insert_nibbles(&mut self, path: &[u8], value: Vec<u8>) -> TrieResult<()> {
+        self.insert_path(nibble_path(path)?, path.len() / 2, value)
+    }
+
+    /// Same as `remove`, with the key given as its nibbles, one per byte.
+    pub fn remove_nibbles(&mut self, path: &[u8]) -> TrieResult<bool> {
+        self.remove_path(nibble_path(path)?, path.len() / 2)
+    }
+
+    fn insert_path(&mut self, path: NibbleSlice, key_len: usize, value: Vec<u8>) -> TrieResult<()> {
         if value.is_empty() {
-            self.remove(key)?;
+            if self.empty_values == EmptyValues::Reject {
+                return Err(TrieError::InvalidData);
+            }
+            self.remove_path(path, key_len)?;
             return Ok(());
         }
-        let root = self.root.clone();
-        self.root = self.insert_at(root, Nibbles::from_raw(key, true), value)?;
+        let value = match &self.blobs {
+            Some(blobs) => blobs.put::<H>(value),
+            None => value,
+        };
+        let size = key_len + value.len();
+        self.with_root(|trie, root, passing_keys| trie.insert_at(root, path, value, passing_keys))?;
+        self.track_change(size);
         Ok(())
     }
 
-    /// Removes any existing value for key from the trie.
-    pub fn remove(&mut self, key: &[u8]) -> TrieResult<bool> {
-        let (n, removed) = self.delete_at(self.root.clone(), &Nibbles::from_raw(key, true))?;
-        self.root = n;
-        Ok(removed)
+    /// Returns the value for key, or inserts the value `default` returns and returns
+    /// it if there is none. The key is looked up and the value inserted in one walk
+    /// down the trie, and `default` is only called if the key is missing.
+    ///
+    /// An empty default is not inserted, as with `insert`, and is returned as is
+    /// unless empty values are rejected.
+    pub fn get_or_insert_with(
+        &mut self,
+        key: &[u8],
+        default: impl FnOnce() -> Vec<u8>,
+    ) -> TrieResult<Vec<u8>> {
+        with_key_path(key, |path| {
+            self.get_or_insert_path(path, key.len(), default)
+        })
+        .map_err(|e| e.at_key(key))
     }
-}
 
-impl<'db, D: HashDB> PatriciaTrieMut<'db, D> {
-    fn insert_at(&mut self, n: Node, partial: Nibbles, value: Vec<u8>) -> TrieResult<Node> {
-        match n {
-            Node::Empty => Ok(Node::from_leaf(partial, value)),
-            Node::Leaf(leaf) => {
-                let mut borrow_leaf = leaf.borrow_mut();
+    fn get_or_insert_path(
+        &mut self,
+        path: NibbleSlice,
+        key_len: usize,
+        default: impl FnOnce() -> Vec<u8>,
+    ) -> TrieResult<Vec<u8>> {
+        let empty_values = self.empty_values;
+        let blobs = self.blobs.clone();
+        let mut inserted = Vec::new();
+        let mut held_len = 0;
+        let root_hash = self.root_hash;
+        let slot = self.with_root(|trie, root, passing_keys| {
+            trie.get_or_insert_at(
+                root,
+                path,
+                || {
+                    inserted = default();
+                    if inserted.is_empty() {
+                        if empty_values == EmptyValues::Reject {
+                            return Err(TrieError::InvalidData);
+                        }
+                        return Ok(None);
+                    }
+                    let held = match &blobs {
+                        Some(blobs) => blobs.put::<H>(inserted.clone()),
+                        None => inserted.clone(),
+                    };
+                    held_len = held.len();
+                    Ok(Some(held))
+                },
+                passing_keys,
+            )
+        });
+        if !matches!(slot, Ok(Slot::Inserted)) {
+            // Nothing changed, so the root committed last still holds.
+            self.root_hash = root_hash;
+        }
+        match slot? {
+            Slot::Held(value) => self.trie_ref().resolve_value(value),
+            Slot::Inserted => {
+                self.track_change(key_len + held_len);
+                Ok(inserted)
+            }
+            Slot::Vacant => Ok(inserted),
+        }
+    }
 
-                let old_partial = &borrow_leaf.key;
-                let match_index = partial.common_prefix(old_partial);
-                if match_index == old_partial.len() {
-                    // replace leaf value
-                    borrow_leaf.value = value;
-                    return Ok(Node::Leaf(leaf.clone()));
+    fn remove_path(&mut self, path: NibbleSlice, key_len: usize) -> TrieResult<bool> {
+        let removed =
+            self.with_root(|trie, root, passing_keys| trie.delete_at(root, path, passing_keys))?;
+        self.track_change(key_len);
+        Ok(removed)
+    }
+
+    /// Applies a set of changes as one unit and commits them, returning the new root.
+    /// `None` removes a key, and later changes to the same key win.
+    ///
+    /// The changes are sorted by key and applied in one walk down the trie, which
+    /// goes through the nodes their keys share, and loads them, only once.
+    ///
+    /// If a change fails, the ones applied before it are rolled back and nothing is
+    /// committed. Like `root`, it fails with `TrieError::OpenCheckpoints`, changing
+    /// nothing, while a checkpoint is open.
+    pub fn apply_changes(
+        &mut self,
+        changes: impl IntoIterator<Item = (Vec<u8>, Option<Vec<u8>>)>,
+    ) -> TrieResult<H256> {
+        if !self.checkpoints.is_empty() {
+            return Err(TrieError::OpenCheckpoints(self.checkpoints.len()));
+        }
+        let mut changes: Vec<_> = changes.into_iter().collect();
+        // A stable sort keeps the changes to one key in their original order, and
+        // reversed, the first of them is the one that wins.
+        changes.sort_by(|a, b| a.0.cmp(&b.0));
+        changes.reverse();
+        changes.dedup_by(|a, b| a.0 == b.0);
+        changes.reverse();
+
+        let (keys, values): (Vec<_>, Vec<_>) = changes.into_iter().unzip();
+        let mut batch = Vec::with_capacity(keys.len());
+        for (key, value) in keys.iter().zip(values) {
+            let value = match value {
+                Some(value) if value.is_empty() => {
+                    if self.empty_values == EmptyValues::Reject {
+                        return Err(TrieError::InvalidData.at_key(key));
+                    }
+                    None
                 }
+                Some(value) => Some(match &self.blobs {
+                    Some(blobs) => blobs.put::<H>(value),
+                    None => value,
+                }),
+                None => None,
+            };
+            batch.push(Change {
+                key,
+                path: NibbleSlice::new(key),
+                value,
+            });
+        }
+        if !batch.is_empty() {
+            self.begin();
+            let result = self.with_root(|trie, root, passing_keys| {
+                trie.apply_at(root, &mut batch, 0, passing_keys)
+            });
+            if let Err(e) = result {
+                self.rollback();
+                return Err(e);
+            }
+            self.commit_checkpoint();
+        }
+        self.root()
+    }
 
-                let mut branch = BranchNode {
-                    children: empty_children(),
-                    value: None,
-                };
+    /// Inserts every entry of `entries`, in order, stopping at the first that fails.
+    /// The entries inserted before it stay in the trie. Nothing is committed.
+    ///
+    /// The trie does not implement `Extend`, whose `extend` could only panic where
+    /// this fails. See `from_entries` to create a trie from entries.
+    pub fn try_extend<K: AsRef<[u8]>>(
+        &mut self,
+        entries: impl IntoIterator<Item = (K, Vec<u8>)>,
+    ) -> TrieResult<()> {
+        for (key, value) in entries {
+            self.insert(key.as_ref(), value)?;
+        }
+        Ok(())
+    }
 
-                let n = Node::from_leaf(
-                    old_partial.offset(match_index + 1),
-                    borrow_leaf.value.clone(),
-                );
-                branch.insert(old_partial.at(match_index), n);
+    // Adds a change to the pending size, flushing once it exceeds the memory limit.
+    fn track_change(&mut self, size: usize) {
+        self.pending_bytes += size + CHANGE_OVERHEAD;
+        if matches!(self.memory_limit, Some(limit) if self.pending_bytes > limit) {
+            self.flush();
+        }
+    }
 
-                let n = Node::from_leaf(partial.offset(match_index + 1), value);
-                branch.insert(partial.at(match_index), n);
+    // Runs a mutation of the root node with read access to the db and the set of
+    // passing keys. The root is put back even if the mutation fails.
+    fn with_root<T>(
+        &mut self,
+        f: impl FnOnce(&PatriciaTrie<D, H, C>, &mut Node, &mut H256Set) -> TrieResult<T>,
+    ) -> TrieResult<T> {
+        self.root_hash = None;
+        let mut root = core::mem::replace(&mut self.root, Node::Empty);
+        let trie = PatriciaTrie {
+            root: Node::Empty,
+            hashdb: &*self.hashdb,
+            cache: self.cache.clone(),
+            gen_keys: self.gen_keys.clone(),
+            node_cache: self.node_cache.clone(),
+            provider: self.provider.clone(),
+            blobs: self.blobs.clone(),
+            hook: self.hook.clone(),
+            lenient: self.lenient,
+            limits: self.limits,
+            marker: PhantomData,
+        };
+        let result = f(&trie, &mut root, &mut self.passing_keys);
+        self.root = root;
+        result
+    }
+}
 
-                if match_index == 0 {
-                    return Ok(Node::Branch(Rc::new(RefCell::new(branch))));
-                }
+// Views a key given as nibbles. Keys are made of whole bytes, so the path must have
+// an even length and hold nibbles only.
+fn nibble_path(path: &[u8]) -> TrieResult<NibbleSlice<'_>> {
+    if path.len() & 1 != 0 || path.iter().any(|nibble| *nibble > 0x0f) {
+        return Err(TrieError::InvalidData);
+    }
+    Ok(NibbleSlice::from_nibbles(path))
+}
 
-                // if include a common prefix
-                Ok(Node::from_extension(
-                    partial.slice(0, match_index),
-                    Node::Branch(Rc::new(RefCell::new(branch))),
-                ))
+// Mutations work on the slot holding a node and never modify a node that is shared
+// with another trie, fork or iterator: `node::make_mut` clones such a node first, so
+// only uniquely owned nodes are updated in place. It also drops the hash cached on
+// the node, which marks the path as dirty for the next commit. The dropped hashes,
+// along with those of nodes replaced outright, are the candidates for pruning.
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> PatriciaTrie<'db, D, H, C> {
+    pub(crate) fn insert_at(
+        &self,
+        n: &mut Node,
+        partial: NibbleSlice,
+        value: Vec<u8>,
+        passing_keys: &mut H256Set,
+    ) -> TrieResult<()> {
+        let mut n = n;
+        let mut partial = partial;
+        loop {
+            // An extension the key leaves midway is split first at that point.
+            let mut prefix_len = 0;
+            if let Node::Extension(ext) = n {
+                prefix_len = partial.common_prefix(&ext.prefix);
+                if prefix_len != ext.prefix.len() {
+                    passing_keys.extend(ext.hash);
+                    *n = split_extension(ext, prefix_len);
+                }
             }
-            Node::Branch(branch) => {
-                let mut borrow_branch = branch.borrow_mut();
 
-                if partial.at(0) == 0x10 {
-                    borrow_branch.value = Some(value);
-                    return Ok(Node::Branch(branch.clone()));
+            match n {
+                Node::Empty => {
+                    *n = Node::from_leaf(partial.to_nibbles(), value);
+                    return Ok(());
                 }
+                Node::Leaf(leaf) => {
+                    let match_index = partial.common_prefix(&leaf.key);
+                    if match_index == leaf.key.len() {
+                        // replace leaf value
+                        node::make_mut(leaf, passing_keys).value = value;
+                        return Ok(());
+                    }
 
-                let child = borrow_branch.children[partial.at(0)].clone();
-                let new_child = self.insert_at(child, partial.offset(1), value)?;
-                borrow_branch.children[partial.at(0)] = new_child;
-                Ok(Node::Branch(branch.clone()))
-            }
-            Node::Extension(ext) => {
-                let mut borrow_ext = ext.borrow_mut();
-
-                let prefix = &borrow_ext.prefix;
-                let sub_node = borrow_ext.node.clone();
-                let match_index = partial.common_prefix(&prefix);
+                    passing_keys.extend(leaf.hash);
+                    let old_partial = &leaf.key;
 
-                if match_index == 0 {
                     let mut branch = BranchNode {
                         children: empty_children(),
                         value: None,
+                        hash: None,
                     };
-                    branch.insert(
-                        prefix.at(0),
-                        if prefix.len() == 1 {
-                            sub_node
-                        } else {
-                            Node::from_extension(prefix.offset(1), sub_node)
-                        },
-                    );
-                    let node = Node::Branch(Rc::new(RefCell::new(branch)));
 
-                    return self.insert_at(node, partial, value);
-                }
+                    let old =
+                        Node::from_leaf(old_partial.offset(match_index + 1), leaf.value.clone());
+                    branch.insert(old_partial.at(match_index), old);
+
+                    let new = Node::from_leaf(partial.offset(match_index + 1).to_nibbles(), value);
+                    branch.insert(partial.at(match_index), new);
 
-                if match_index == prefix.len() {
-                    let new_node = self.insert_at(sub_node, partial.offset(match_index), value)?;
-                    return Ok(Node::from_extension(prefix.clone(), new_node));
+                    let branch = Node::Branch(Arc::new(branch));
+                    *n = if match_index == 0 {
+                        branch
+                    } else {
+                        // if include a common prefix
+                        Node::from_extension(partial.slice(0, match_index).to_nibbles(), branch)
+                    };
+                    return Ok(());
                 }
+                Node::Branch(branch) => {
+                    let branch = node::make_mut(branch, passing_keys);
 
-                let new_ext = Node::from_extension(prefix.offset(match_index), sub_node);
-                let new_node = self.insert_at(new_ext, partial.offset(match_index), value)?;
-                borrow_ext.prefix = prefix.slice(0, match_index);
-                borrow_ext.node = new_node;
-                Ok(Node::Extension(ext.clone()))
-            }
-            Node::Hash(hash_node) => {
-                self.passing_keys.insert(hash_node);
-                let n = self.recover_from_db(&hash_node)?;
-                self.insert_at(n, partial, value)
+                    if partial.at(0) == 0x10 {
+                        branch.value = Some(value);
+                        return Ok(());
+                    }
+
+                    n = &mut branch.children[partial.at(0)];
+                    partial = partial.offset(1);
+                }
+                Node::Extension(ext) => {
+                    n = &mut node::make_mut(ext, passing_keys).node;
+                    partial = partial.offset(prefix_len);
+                }
+                Node::Hash(hash_node) => {
+                    let hash_node = *hash_node;
+                    *n = self.recover_from_db(&hash_node)?;
+                }
             }
         }
     }
 
-    fn delete_at(&mut self, n: Node, partial: &Nibbles) -> TrieResult<(Node, bool)> {
-        let (new_n, deleted) = match n {
-            Node::Empty => Ok((Node::Empty, false)),
-            Node::Leaf(leaf) => {
-                let borrow_leaf = leaf.borrow();
+    // Walks to the value at `partial` and returns it, or inserts the value `default`
+    // gives if there is none, in the same walk. The nodes on the path are only
+    // copied for modification, on the way back up, if the default was inserted.
+    fn get_or_insert_at(
+        &self,
+        root: &mut Node,
+        partial: NibbleSlice,
+        default: impl FnOnce() -> TrieResult<Option<Vec<u8>>>,
+        passing_keys: &mut H256Set,
+    ) -> TrieResult<Slot> {
+        // The nodes above `n`, with the branch slot leading down from each, `None`
+        // for an extension.
+        let mut path: Vec<(Node, Option<usize>)> = Vec::new();
+        let mut n = root.clone();
+        let mut partial = partial;
+        loop {
+            let (child, index, consumed) = match &n {
+                Node::Leaf(leaf) if leaf.key == partial => {
+                    return Ok(Slot::Held(leaf.value.clone()))
+                }
+                Node::Branch(branch) if partial.at(0) == 0x10 => match &branch.value {
+                    Some(value) => return Ok(Slot::Held(value.clone())),
+                    None => break,
+                },
+                Node::Branch(branch) => {
+                    let index = partial.at(0);
+                    (branch.children[index].clone(), Some(index), 1)
+                }
+                Node::Extension(ext) if partial.common_prefix(&ext.prefix) == ext.prefix.len() => {
+                    (ext.node.clone(), None, ext.prefix.len())
+                }
+                Node::Hash(hash) => {
+                    let hash = *hash;
+                    n = self.recover_from_db(&hash)?;
+                    continue;
+                }
+                _ => break,
+            };
+            path.push((core::mem::replace(&mut n, child), index));
+            partial = partial.offset(consumed);
+        }
 
-                if &borrow_leaf.key == partial {
-                    return Ok((Node::Empty, true));
+        // The key ends in `n`, which lacks it.
+        let value = match default()? {
+            Some(value) => value,
+            None => return Ok(Slot::Vacant),
+        };
+        self.insert_at(&mut n, partial, value, passing_keys)?;
+        while let Some((mut parent, index)) = path.pop() {
+            match (&mut parent, index) {
+                (Node::Branch(branch), Some(index)) => {
+                    node::make_mut(branch, passing_keys).children[index] = n
                 }
-                Ok((Node::Leaf(leaf.clone()), false))
+                (Node::Extension(ext), None) => node::make_mut(ext, passing_keys).node = n,
+                _ => unreachable!(),
             }
-            Node::Branch(branch) => {
-                let mut borrow_branch = branch.borrow_mut();
+            n = parent;
+        }
+        *root = n;
+        Ok(Slot::Inserted)
+    }
 
-                if partial.at(0) == 0x10 {
-                    borrow_branch.value = None;
-                    return Ok((Node::Branch(branch.clone()), true));
+    pub(crate) fn delete_at(
+        &self,
+        root: &mut Node,
+        partial: NibbleSlice,
+        passing_keys: &mut H256Set,
+    ) -> TrieResult<bool> {
+        // The nodes above `n`, taken out of the trie while walking down.
+        let mut path: Vec<PathEntry> = Vec::new();
+        let mut n = core::mem::replace(root, Node::Empty);
+        let mut partial = partial;
+
+        let found = loop {
+            match &mut n {
+                Node::Empty => break Ok(false),
+                Node::Leaf(leaf) => {
+                    if leaf.key != partial {
+                        break Ok(false);
+                    }
+                    passing_keys.extend(leaf.hash);
+                    n = Node::Empty;
+                    break Ok(true);
                 }
+                Node::Branch(branch) => {
+                    if partial.at(0) == 0x10 {
+                        if branch.value.is_none() {
+                            break Ok(false);
+                        }
+                        node::make_mut(branch, passing_keys).value = None;
+                        break Ok(true);
+                    }
 
-                let index = partial.at(0);
-                let node = borrow_branch.children[index].clone();
-
-                let (new_n, deleted) = self.delete_at(node, &partial.offset(1))?;
-                if deleted {
-                    borrow_branch.children[index] = new_n;
+                    let hash = branch.hash;
+                    let index = partial.at(0);
+                    let children = &mut node::make_mut(branch, passing_keys).children;
+                    let child = core::mem::replace(&mut children[index], Node::Empty);
+                    path.push(PathEntry {
+                        node: core::mem::replace(&mut n, child),
+                        hash,
+                        index: Some(index),
+                    });
+                    partial = partial.offset(1);
                 }
+                Node::Extension(ext) => {
+                    let match_len = partial.common_prefix(&ext.prefix);
+                    if match_len != ext.prefix.len() {
+                        break Ok(false);
+                    }
 
-                Ok((Node::Branch(branch.clone()), deleted))
+                    let hash = ext.hash;
+                    let sub_node = &mut node::make_mut(ext, passing_keys).node;
+                    let child = core::mem::replace(sub_node, Node::Empty);
+                    path.push(PathEntry {
+                        node: core::mem::replace(&mut n, child),
+                        hash,
+                        index: None,
+                    });
+                    partial = partial.offset(match_len);
+                }
+                Node::Hash(hash_node) => match self.recover_from_db(hash_node) {
+                    Ok(node) => n = node,
+                    Err(e) => break Err(e),
+                },
             }
-            Node::Extension(ext) => {
-                let mut borrow_ext = ext.borrow_mut();
+        };
 
-                let prefix = &borrow_ext.prefix;
-                let match_len = partial.common_prefix(prefix);
+        // Put the path back together from the bottom up, collapsing the nodes left
+        // with a single child if the key was removed.
+        let deleted = *found.as_ref().unwrap_or(&false);
+        let mut result = found;
+        if deleted {
+            result = self.degenerate(&mut n, passing_keys).and(result);
+        }
+        while let Some(entry) = path.pop() {
+            n = entry.attach(n, deleted, passing_keys);
+            if deleted && result.is_ok() {
+                result = self.degenerate(&mut n, passing_keys).and(result);
+            }
+        }
+        *root = n;
+        result
+    }
 
-                if match_len == prefix.len() {
-                    let (new_n, deleted) =
-                        self.delete_at(borrow_ext.node.clone(), &partial.offset(match_len))?;
+    // Applies `changes`, sorted by key with one change per key, to the subtrie at
+    // `n`, whose path is the first `depth` nibbles of all their keys. Each node is
+    // walked once for all the changes below it. Returns whether the subtrie changed.
+    fn apply_at(
+        &self,
+        n: &mut Node,
+        changes: &mut [Change],
+        depth: usize,
+        passing_keys: &mut H256Set,
+    ) -> TrieResult<bool> {
+        let first = changes[0].key;
+        if let Node::Hash(hash) = n {
+            let hash = *hash;
+            *n = self.recover_from_db(&hash).map_err(|e| e.at_key(first))?;
+        }
 
-                    if deleted {
-                        borrow_ext.node = new_n;
+        match n {
+            // Nothing below is stored in the db, so the changes are applied one by one.
+            Node::Empty | Node::Leaf(_) => {
+                let mut changed = false;
+                for change in changes.iter_mut() {
+                    let partial = change.path.offset(depth);
+                    changed |= match change.value.take() {
+                        Some(value) => self
+                            .insert_at(n, partial, value, passing_keys)
+                            .map(|()| true),
+                        None => self.delete_at(n, partial, passing_keys),
                     }
-
-                    Ok((Node::Extension(ext.clone()), deleted))
-                } else {
-                    Ok((Node::Extension(ext.clone()), false))
+                    .map_err(|e| e.at_key(change.key))?;
                 }
+                Ok(changed)
             }
-            Node::Hash(hash_node) => {
-                self.passing_keys.insert(hash_node);
+            Node::Extension(ext) => {
+                // The keys inserted that leave the extension midway split it first.
+                let split = changes
+                    .iter()
+                    .filter(|change| change.value.is_some())
+                    .map(|change| change.path.offset(depth).common_prefix(&ext.prefix))
+                    .min()
+                    .unwrap_or(ext.prefix.len());
+                if split < ext.prefix.len() {
+                    passing_keys.extend(ext.hash);
+                    *n = split_extension(ext, split);
+                    return self.apply_at(n, changes, depth, passing_keys);
+                }
 
-                let n = self.recover_from_db(&hash_node)?;
-                self.delete_at(n, partial)
+                // The keys going through it are together; removing the others is a
+                // no-op.
+                let prefix_len = ext.prefix.len();
+                let through = |change: &Change| {
+                    change.path.offset(depth).common_prefix(&ext.prefix) == prefix_len
+                };
+                let start = match changes.iter().position(through) {
+                    Some(start) => start,
+                    None => return Ok(false),
+                };
+                let end = start + changes[start..].iter().take_while(|c| through(c)).count();
+                let hash = ext.hash;
+                let ext = node::make_mut(ext, passing_keys);
+                let changed = self.apply_at(
+                    &mut ext.node,
+                    &mut changes[start..end],
+                    depth + prefix_len,
+                    passing_keys,
+                )?;
+                if !changed {
+                    restore_hash(&mut ext.hash, hash, passing_keys);
+                    return Ok(false);
+                }
+                if let Node::Empty = ext.node {
+                    *n = Node::Empty;
+                }
+                self.degenerate(n, passing_keys)
+                    .map_err(|e| e.at_key(first))?;
+                Ok(true)
             }
-        }?;
-
-        if deleted {
-            Ok((self.degenerate(new_n)?, deleted))
-        } else {
-            Ok((new_n, deleted))
+            Node::Branch(branch) => {
+                let hash = branch.hash;
+                let branch = node::make_mut(branch, passing_keys);
+                let mut changed = false;
+                let mut rest = changes;
+                // A key ending here sorts before the keys going on below.
+                if rest[0].path.at(depth) == 0x10 {
+                    let value = rest[0].value.take();
+                    changed = value.is_some() || branch.value.is_some();
+                    branch.value = value;
+                    rest = &mut rest[1..];
+                }
+                while !rest.is_empty() {
+                    let index = rest[0].path.at(depth);
+                    let len = rest
+                        .iter()
+                        .take_while(|change| change.path.at(depth) == index)
+                        .count();
+                    let (group, tail) = rest.split_at_mut(len);
+                    changed |=
+                        self.apply_at(&mut branch.children[index], group, depth + 1, passing_keys)?;
+                    rest = tail;
+                }
+                if !changed {
+                    restore_hash(&mut branch.hash, hash, passing_keys);
+                    return Ok(false);
+                }
+                if branch.value.is_none()
+                    && branch.children.iter().all(|c| matches!(c, Node::Empty))
+                {
+                    *n = Node::Empty;
+                }
+                self.degenerate(n, passing_keys)
+                    .map_err(|e| e.at_key(first))?;
+                Ok(true)
+            }
+            Node::Hash(_) => unreachable!(),
         }
     }
 
-    fn degenerate(&mut self, n: Node) -> TrieResult<Node> {
-        match n {
-            Node::Branch(branch) => {
-                let borrow_branch = branch.borrow();
+    fn degenerate(&self, n: &mut Node, passing_keys: &mut H256Set) -> TrieResult<()> {
+        loop {
+            let new_node = match n {
+                Node::Branch(branch) => {
+                    let mut used_indexs = Vec::new();
+                    for (index, node) in branch.children.iter().enumerate() {
+                        match node {
+                            Node::Empty => continue,
+                            _ => used_indexs.push(index),
+                        }
+                    }
 
-                let mut used_indexs = Vec::new();
-                for (index, node) in borrow_branch.children.iter().enumerate() {
-                    match node {
-                        Node::Empty => continue,
-                        _ => used_indexs.push(index),
+                    // if only a value node, transmute to leaf.
+                    if used_indexs.is_empty() && branch.value.is_some() {
+                        let key = Nibbles::from_raw(&[], true);
+                        let value = branch.value.clone().unwrap();
+                        Node::from_leaf(key, value)
+                    // if only one node. make an extension.
+                    } else if used_indexs.len() == 1 && branch.value.is_none() {
+                        let used_index = used_indexs[0];
+                        let n = branch.children[used_index].clone();
+
+                        Node::from_extension(Nibbles::from_hex(&[used_index as u8]), n)
+                    } else {
+                        return Ok(());
                     }
                 }
-
-                // if only a value node, transmute to leaf.
-                if used_indexs.is_empty() && borrow_branch.value.is_some() {
-                    let key = Nibbles::from_raw(&[], true);
-                    let value = borrow_branch.value.clone().unwrap();
-                    Ok(Node::from_leaf(key, value))
-                // if only one node. make an extension.
-                } else if used_indexs.len() == 1 && borrow_branch.value.is_none() {
-                    let used_index = used_indexs[0];
-                    let n = borrow_branch.children[used_index].clone();
-
-                    let new_node =
-                        Node::from_extension(Nibbles::from_hex(vec![used_index as u8]), n);
-                    self.degenerate(new_node)
-                } else {
-                    Ok(Node::Branch(branch.clone()))
+                Node::Extension(ext) => {
+                    let prefix = &ext.prefix;
+                    match &ext.node {
+                        Node::Extension(sub_ext) => {
+                            passing_keys.extend(sub_ext.hash);
+                            let new_prefix = prefix.join(&sub_ext.prefix);
+                            Node::from_extension(new_prefix, sub_ext.node.clone())
+                        }
+                        Node::Leaf(leaf) => {
+                            passing_keys.extend(leaf.hash);
+                            let new_prefix = prefix.join(&leaf.key);
+                            Node::from_leaf(new_prefix, leaf.value.clone())
+                        }
+                        // try again after recovering node from the db.
+                        Node::Hash(hash) => {
+                            let new_node = self.recover_from_db(hash)?;
+                            Node::from_extension(prefix.clone(), new_node)
+                        }
+                        _ => return Ok(()),
+                    }
                 }
-            }
-            Node::Extension(ext) => {
-                let borrow_ext = ext.borrow();
+                _ => return Ok(()),
+            };
 
-                let prefix = &borrow_ext.prefix;
-                match borrow_ext.node.clone() {
-                    Node::Extension(sub_ext) => {
-                        let borrow_sub_ext = sub_ext.borrow();
+            passing_keys.extend(n.cached_hash());
+            *n = new_node;
+        }
+    }
+}
 
-                        let new_prefix = prefix.join(&borrow_sub_ext.prefix);
-                        let new_n = Node::from_extension(new_prefix, borrow_sub_ext.node.clone());
-                        self.degenerate(new_n)
-                    }
-                    Node::Leaf(leaf) => {
-                        let borrow_leaf = leaf.borrow();
+// Splits an extension at `match_index` into a branch, keeping the first
+// `match_index` nibbles as an extension above it.
+fn split_extension(ext: &ExtensionNode, match_index: usize) -> Node {
+    let prefix = &ext.prefix;
+    let rest = if prefix.len() == match_index + 1 {
+        ext.node.clone()
+    } else {
+        Node::from_extension(prefix.offset(match_index + 1), ext.node.clone())
+    };
+    let mut branch = BranchNode {
+        children: empty_children(),
+        value: None,
+        hash: None,
+    };
+    branch.insert(prefix.at(match_index), rest);
+    let branch = Node::Branch(Arc::new(branch));
+
+    if match_index == 0 {
+        branch
+    } else {
+        Node::from_extension(prefix.slice(0, match_index), branch)
+    }
+}
 
-                        let new_prefix = prefix.join(&borrow_leaf.key);
-                        Ok(Node::from_leaf(new_prefix, borrow_leaf.value.clone()))
-                    }
-                    // try again after recovering node from the db.
-                    Node::Hash(hash) => {
-                        self.passing_keys.insert(hash);
+// A change applied by `apply_changes`: the key, its path and the value to store, or
+// `None` to remove the key.
+struct Change<'a> {
+    key: &'a [u8],
+    path: NibbleSlice<'a>,
+    value: Option<Vec<u8>>,
+}
 
-                        let new_node = self.recover_from_db(&hash)?;
+// Gives a node that ended up unchanged the hash it had, so that it is neither
+// re-encoded nor pruned.
+fn restore_hash(slot: &mut Option<H256>, hash: Option<H256>, passing_keys: &mut H256Set) {
+    if let Some(hash) = hash {
+        passing_keys.remove(&hash);
+    }
+    *slot = hash;
+}
 
-                        let n = Node::from_extension(borrow_ext.prefix.clone(), new_node);
-                        self.degenerate(n)
-                    }
-                    _ => Ok(Node::Extension(ext.clone())),
-                }
+// A node on the path walked by `delete_at`, taken out of the trie with the child on
+// the path detached. `index` is the branch slot of that child, `None` for the child
+// of an extension, and `hash` the hash the node had before it was marked dirty.
+struct PathEntry {
+    node: Node,
+    hash: Option<H256>,
+    index: Option<usize>,
+}
+
+impl PathEntry {
+    // Puts the child back. If nothing was deleted below, the node is unchanged and
+    // gets its hash back, so it is neither re-encoded nor pruned.
+    fn attach(self, child: Node, deleted: bool, passing_keys: &mut H256Set) -> Node {
+        let mut n = self.node;
+        let hash_slot = match (&mut n, self.index) {
+            (Node::Branch(branch), Some(index)) => {
+                let branch = node::make_mut(branch, passing_keys);
+                branch.children[index] = child;
+                &mut branch.hash
+            }
+            (Node::Extension(ext), None) => {
+                let ext = node::make_mut(ext, passing_keys);
+                ext.node = child;
+                &mut ext.hash
             }
-            _ => Ok(n),
+            _ => unreachable!(),
+        };
+        if !deleted {
+            if let Some(hash) = self.hash {
+                passing_keys.remove(&hash);
+            }
+            *hash_slot = self.hash;
         }
+        n
     }
+}
 
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> PatriciaTrieMut<'db, D, H, C> {
     /// Saves all the nodes in the db, clears the cache data, recalculates the root.
     /// Returns the root hash of the trie.
+    ///
+    /// Fails with `TrieError::OpenCheckpoints`, committing nothing, while a
+    /// checkpoint is open.
     pub fn root(&mut self) -> TrieResult<H256> {
-        let encoded = self.encode_node(self.root.clone());
+        let (root_hash, _) = self.commit_nodes(self.prune)?;
+        Ok(root_hash)
+    }
+
+    // Writes the cached nodes to the db and returns the root hash together with the keys
+    // of the nodes that were not in the db before. When `prune` is set, the nodes this
+    // trie passed through that are not part of the new root are removed from the db.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "commit", level = "debug", skip_all)
+    )]
+    pub(crate) fn commit_nodes(&mut self, prune: bool) -> TrieResult<(H256, Vec<H256>)> {
+        // Checkpoints cannot go back to nodes pruned from the db, so they must all be
+        // closed first.
+        if !self.checkpoints.is_empty() {
+            return Err(TrieError::OpenCheckpoints(self.checkpoints.len()));
+        }
+        #[cfg(feature = "metrics")]
+        let started = std::time::Instant::now();
+        // Nothing changed since the last commit, but fetched nodes may still have to
+        // be written.
+        if let Some(root_hash) = self.root_hash {
+            self.write_cache();
+            self.hashdb.commit_root(&root_hash);
+            let created = core::mem::take(&mut self.flushed);
+            #[cfg(feature = "metrics")]
+            crate::metrics::metrics().record_commit(started, self.written);
+            self.written = 0;
+            return Ok((root_hash, created));
+        }
+
+        let root_hash = self.write_nodes();
+        self.hashdb.commit_root(&root_hash);
+        let created = core::mem::take(&mut self.flushed);
+
+        let gen_keys = self.gen_keys.take();
+        if prune {
+            let removed_keys: Vec<H256> = self
+                .passing_keys
+                .iter()
+                .filter(|h| !gen_keys.contains(h))
+                .copied()
+                .collect();
+
+            self.hashdb.remove_batch(&removed_keys);
+            for hash in removed_keys.iter() {
+                self.report(DbAccess::Remove { hash: *hash });
+            }
+            #[cfg(feature = "tracing")]
+            tracing::debug!(removed = removed_keys.len(), "pruned nodes");
+            #[cfg(feature = "metrics")]
+            crate::metrics::metrics().record_pruned(removed_keys.len());
+        }
+        #[cfg(feature = "tracing")]
+        tracing::debug!(root = ?root_hash, written = self.written, "committed");
+        #[cfg(feature = "metrics")]
+        crate::metrics::metrics().record_commit(started, self.written);
+        self.written = 0;
+
+        self.passing_keys.clear();
+        self.root_hash = Some(root_hash);
+        self.committed_root = Some(root_hash);
+        if self.shadow {
+            self.verify_root(root_hash)?;
+        }
+        Ok((root_hash, created))
+    }
+
+    // Writes pending changes to the db to free the memory they hold. Unlike a commit,
+    // the keys the trie passed through that are not part of the flushed tree stay
+    // recorded, so the next commit can still prune them.
+    fn flush(&mut self) {
+        self.write_nodes();
+        let gen_keys = self.gen_keys.take();
+        self.passing_keys.retain(|key| !gen_keys.contains(key));
+    }
+
+    // Encodes the changed nodes, writes them to the db and keeps the committed root in
+    // memory. Returns the root hash and records the keys that were new to the db.
+    fn write_nodes(&mut self) -> H256 {
+        let (encoded, root) = self.commit_node(&self.root, self.retained_levels);
         let root_hash = match encoded {
             RawNodeOrHash::Node(raw) => {
-                let hash = keccak256(&raw);
-                self.cache.borrow_mut().insert(hash.clone(), raw);
+                let hash = H::hash(&raw);
+                self.cache
+                    .with((hash, raw), |cache, (hash, raw)| cache.insert(hash, raw));
                 hash
             }
             RawNodeOrHash::Hash(hash) => hash,
         };
 
-        for (k, v) in self.cache.borrow_mut().drain() {
+        self.write_cache();
+        self.root = root;
+        self.pending_bytes = 0;
+        root_hash
+    }
+
+    // Writes the nodes waiting in the cache to the db, recording the new ones if
+    // `track_created` is set.
+    pub(crate) fn write_cache(&mut self) {
+        // The nodes are taken out before the db is called.
+        for (k, v) in self.cache.take() {
+            if self.track_created && !self.hashdb.contains(&k) {
+                self.flushed.push(k);
+            }
+            self.written += 1;
+            self.report(DbAccess::Insert {
+                hash: k,
+                len: v.len(),
+            });
+            if self.cache_writes {
+                let node = CachedNode::Encoded(v.as_slice().into());
+                self.node_cache
+                    .with((k, node), |cache, (key, node)| cache.put(key, node));
+            }
             self.hashdb.insert(k, v);
         }
+    }
 
-        let removed_keys: Vec<H256> = self
-            .passing_keys
-            .iter()
-            .filter(|h| !self.gen_keys.borrow().contains(h))
-            .map(|h| *h)
-            .collect();
-
-        self.hashdb.remove_batch(&removed_keys);
-
-        self.gen_keys.borrow_mut().clear();
+    // Discards all uncommitted changes and reopens the trie at `root`.
+    pub(crate) fn reset_to(&mut self, root: H256) -> TrieResult<()> {
+        let data = self.hashdb.get(&root);
+        self.report(DbAccess::Get {
+            hash: root,
+            len: data.as_ref().map(Vec::len),
+        });
+        let data = data.ok_or(TrieError::InvalidStateRoot)?;
+        self.root = self.decode_stored(&root, &data)?;
+        self.root_hash = Some(root);
+        self.committed_root = Some(root);
+        self.cache.take();
+        self.gen_keys.take();
         self.passing_keys.clear();
-        self.root = self.recover_from_db(&root_hash)?;
-        Ok(root_hash)
+        self.flushed.clear();
+        self.written = 0;
+        self.pending_bytes = 0;
+        self.checkpoints.clear();
+        Ok(())
     }
 
-    fn encode_node(&self, n: Node) -> RawNodeOrHash {
-        let trie: PatriciaTrie<D> = self.into();
-        trie.encode_node(n)
+    fn report(&self, access: DbAccess) {
+        if let Some(hook) = &self.hook {
+            (hook.0)(&access);
+        }
     }
 
-    fn decode_node(&self, data: &[u8]) -> TrieResult<Node> {
-        let trie: PatriciaTrie<D> = self.into();
-        trie.decode_node(data)
+    fn commit_node(&self, n: &Node, retain: usize) -> (RawNodeOrHash, Node) {
+        let trie: PatriciaTrie<D, H, C> = self.into();
+        trie.commit_node(n, retain)
     }
 
-    fn recover_from_db(&self, key: &H256) -> TrieResult<Node> {
-        let trie: PatriciaTrie<D> = self.into();
-        trie.recover_from_db(key)
+    fn decode_stored(&self, key: &H256, data: &[u8]) -> TrieResult<Node> {
+        let trie: PatriciaTrie<D, H, C> = self.into();
+        trie.decode_stored(key, data)
     }
 }
 
-impl<'db, D: HashDB> PatriciaTrie<'db, D> {
-    // Get nodes path along the key, only the nodes whose encode length is greater than
-    // hash length are added.
-    // For embedded nodes whose data are already contained in their parent node, we don't need to
-    // add them in the path.
-    // In the code below, we only add the nodes get by `get_node_from_hash`, because they contains
-    // all data stored in db, including nodes whose encoded data is less than hash length.
-    fn get_path_at(&self, n: Node, partial: &Nibbles) -> TrieResult<Vec<Node>> {
+impl<'db, D: HashDB, H: Hasher, C: NodeCodec> PatriciaTrie<'db, D, H, C> {
+    // Collects the hashes of all db-stored nodes reachable from the root into `live`.
+    // Subtrees whose hash is already in `live` are not walked again, so sharing the set
+    // across several roots only visits each common subtree once.
+    pub(crate) fn reachable_hashes(&self, live: &mut H256Set) -> TrieResult<()> {
+        self.reachable_hashes_at(self.root.clone(), live)
+    }
+
+    fn reachable_hashes_at(&self, n: Node, live: &mut H256Set) -> TrieResult<()> {
         match n {
-            Node::Empty | Node::Leaf(_) => Ok(Vec::new()),
+            Node::Empty | Node::Leaf(_) => Ok(()),
             Node::Branch(branch) => {
-                let borrow_branch = branch.borrow();
-
-                if partial.is_empty() || partial.at(0) == 16 {
-                    Ok(Vec::new())
-                } else {
-                    let node = borrow_branch.children[partial.at(0)].clone();
-                    self.get_path_at(node, &partial.offset(1))
-                }
-            }
-            Node::Extension(ext) => {
-                let borrow_ext = ext.borrow();
-
-                let prefix = &borrow_ext.prefix;
-                let match_len = partial.common_prefix(prefix);
-
-                if match_len == prefix.len() {
-                    self.get_path_at(borrow_ext.node.clone(), &partial.offset(match_len))
-                } else {
-                    Ok(Vec::new())
+                for child in branch.children.iter() {
+                    self.reachable_hashes_at(child.clone(), live)?;
                 }
+                Ok(())
             }
+            Node::Extension(ext) => self.reachable_hashes_at(ext.node.clone(), live),
             Node::Hash(hash_node) => {
+                if !live.insert(hash_node) {
+                    return Ok(());
+                }
                 let n = self.recover_from_db(&hash_node)?;
-                let mut rest = self.get_path_at(n.clone(), partial)?;
-                rest.push(n);
-                Ok(rest)
+                self.reachable_hashes_at(n, live)
             }
         }
     }
 
-    fn recover_from_db(&self, key: &H256) -> TrieResult<Node> {
-        match self.hashdb.get(key) {
-            Some(value) => Ok(self.decode_node(&value)?),
-            None => Ok(Node::Empty),
+    pub(crate) fn recover_from_db(&self, key: &H256) -> TrieResult<Node> {
+        let (node, data) = match self.load(key)? {
+            None => {
+                self.check_missing(key)?;
+                return Ok(Node::Empty);
+            }
+            Some(CachedNode::Decoded(node, _)) => return Ok(node),
+            Some(CachedNode::Encoded(data)) => (self.decode_stored(key, &data)?, data),
+        };
+        self.cache_node(key, CachedNode::Decoded(node.clone(), data));
+        Ok(node)
+    }
+
+    // Same as `recover_from_db`, but nodes read from the db are not kept decoded in
+    // the node cache, so the caller owns them alone.
+    fn take_from_db(&self, key: &H256) -> TrieResult<Node> {
+        match self.load(key)? {
+            None => {
+                self.check_missing(key)?;
+                Ok(Node::Empty)
+            }
+            Some(CachedNode::Decoded(node, _)) => Ok(node),
+            Some(CachedNode::Encoded(data)) => self.decode_stored(key, &data),
         }
     }
 
-    fn encode_node(&self, n: Node) -> RawNodeOrHash {
-        // Returns the hash value directly to avoid double counting.
-        if let Node::Hash(hash_node) = n {
-            return RawNodeOrHash::Hash(hash_node);
+    // Returns the node stored under `key` as found in the cache, reading it from the
+    // db if needed. Returns `None` if the db does not have it.
+    fn load(&self, key: &H256) -> TrieResult<Option<CachedNode>> {
+        let cached = self
+            .node_cache
+            .with(*key, |cache, key| cache.get(&key).cloned());
+        #[cfg(feature = "metrics")]
+        crate::metrics::metrics().record_cache(cached.is_some());
+        if let Some(node) = cached {
+            return Ok(Some(node));
+        }
+        let value = self.hashdb.get(key);
+        self.report(DbAccess::Get {
+            hash: *key,
+            len: value.as_ref().map(Vec::len),
+        });
+        #[cfg(feature = "tracing")]
+        tracing::trace!(hash = ?key, found = value.is_some(), "db read");
+        match value {
+            Some(value) => {
+                let node = CachedNode::Encoded(value.into());
+                self.cache_node(key, node.clone());
+                Ok(Some(node))
+            }
+            None => match self.fetch(key)? {
+                Some(data) => {
+                    let node = CachedNode::Encoded(data);
+                    self.cache_node(key, node.clone());
+                    Ok(Some(node))
+                }
+                None => Ok(None),
+            },
         }
+    }
 
-        let data = self.encode_raw(n.clone());
-        // Nodes smaller than 32 bytes are stored inside their parent,
-        // Nodes equal to 32 bytes are returned directly
-        if data.len() < HASH_LEN {
-            RawNodeOrHash::Node(data)
-        } else {
-            let hash = keccak256(&data);
-            self.cache.borrow_mut().insert(hash, data);
+    // Returns the encoding of the node stored under `key`, failing if it is missing.
+    pub(crate) fn load_encoded(&self, key: &H256) -> TrieResult<Arc<[u8]>> {
+        match self.load(key)? {
+            Some(CachedNode::Decoded(_, data)) | Some(CachedNode::Encoded(data)) => Ok(data),
+            None => Err(TrieError::MissingNode(*key)),
+        }
+    }
 
-            self.gen_keys.borrow_mut().insert(hash);
-            RawNodeOrHash::Hash(hash)
+    // Hands an access to the db to the hook, if any.
+    fn report(&self, access: DbAccess) {
+        if let Some(hook) = &self.hook {
+            (hook.0)(&access);
         }
     }
 
-    fn encode_raw(&self, n: Node) -> Vec<u8> {
-        match n {
-            Node::Empty => rlp::NULL_RLP.to_vec(),
-            Node::Leaf(leaf) => {
-                let borrow_leaf = leaf.borrow();
+    fn cache_node(&self, key: &H256, node: CachedNode) {
+        self.node_cache
+            .with((*key, node), |cache, (key, node)| cache.put(key, node));
+    }
 
-                let mut stream = RlpStream::new_list(2);
-                stream.append(&borrow_leaf.key.encode_compact());
-                stream.append(&borrow_leaf.value);
-                stream.out()
-            }
-            Node::Branch(branch) => {
-                let borrow_branch = branch.borrow();
-
-                let mut stream = RlpStream::new_list(17);
-                for i in 0..16 {
-                    let n = borrow_branch.children[i].clone();
-                    let data = self.encode_node(n);
-                    match data {
-                        RawNodeOrHash::Hash(data) => stream.append(&data.as_bytes()),
-                        RawNodeOrHash::Node(data) => stream.append_raw(&data, 1),
-                    };
-                }
+    // Fails on a node found neither in the db nor by the provider, unless the trie
+    // reads missing nodes as empty.
+    fn check_missing(&self, key: &H256) -> TrieResult<()> {
+        if self.lenient {
+            Ok(())
+        } else {
+            Err(TrieError::MissingNode(*key))
+        }
+    }
 
-                match &borrow_branch.value {
-                    Some(v) => stream.append(v),
-                    None => stream.append_empty_data(),
-                };
-                stream.out()
+    // Finds a node missing from the db among the nodes waiting to be written, or
+    // fetches it from the node provider. Fetched nodes must hash to `key` and wait in
+    // the cache to be written by the next commit.
+    fn fetch(&self, key: &H256) -> TrieResult<Option<Arc<[u8]>>> {
+        let pending = self.cache.with(*key, |cache, key| {
+            cache.get(&key).map(|data| Arc::from(data.as_slice()))
+        });
+        if pending.is_some() {
+            return Ok(pending);
+        }
+        let provider = match &self.provider {
+            Some(provider) => provider,
+            None => return Ok(None),
+        };
+        match provider.0.fetch(key) {
+            Some(data) if H::hash(&data) == *key => {
+                let shared = data.as_slice().into();
+                self.cache
+                    .with((*key, data), |cache, (key, data)| cache.insert(key, data));
+                Ok(Some(shared))
             }
-            Node::Extension(ext) => {
-                let borrow_ext = ext.borrow();
-
-                let mut stream = RlpStream::new_list(2);
-                stream.append(&borrow_ext.prefix.encode_compact());
-                let data = self.encode_node(borrow_ext.node.clone());
-                match data {
-                    RawNodeOrHash::Hash(data) => stream.append(&data.as_bytes()),
-                    RawNodeOrHash::Node(data) => stream.append_raw(&data, 1),
-                };
+            Some(_) => Err(TrieError::InvalidData.in_node(*key)),
+            None => Ok(None),
+        }
+    }
 
-                stream.out()
-            }
-            Node::Hash(_hash) => unreachable!(),
+    // Decodes the root node stored under `root`.
+    pub(crate) fn load_root(&self, root: &H256) -> TrieResult<Node> {
+        match self.load(root)? {
+            Some(CachedNode::Decoded(node, _)) => Ok(node),
+            Some(CachedNode::Encoded(data)) => self.decode_stored(root, &data),
+            None => Err(TrieError::InvalidStateRoot),
         }
     }
 
-    fn decode_node(&self, data: &[u8]) -> TrieResult<Node> {
-        let r = Rlp::new(data);
+    // Decodes a node read from the db under `key`, remembering the hash on the node.
+    // A root smaller than a hash is stored by hash too, but would be embedded if it
+    // became a child, so only larger nodes keep their hash.
+    pub(crate) fn decode_stored(&self, key: &H256, data: &[u8]) -> TrieResult<Node> {
+        let node = self.decode_node(data).map_err(|e| e.in_node(*key))?;
+        if data.len() >= H::LENGTH {
+            Ok(node.with_hash(*key))
+        } else {
+            Ok(node)
+        }
+    }
 
-        match r.prototype()? {
-            Prototype::Data(0) => Ok(Node::Empty),
-            Prototype::List(2) => {
-                let key = r.at(0)?.data()?;
-                let key = Nibbles::from_compact(key);
+    pub(crate) fn encode_node(&self, n: Node) -> RawNodeOrHash {
+        self.commit_node(&n, 0).0
+    }
 
-                if key.is_leaf() {
-                    Ok(Node::from_leaf(key, r.at(1)?.data()?.to_vec()))
-                } else {
-                    let n = self.decode_node(r.at(1)?.as_raw())?;
+    // Encodes `n` and returns it as committed, keeping `retain` levels below it in
+    // memory with their hashes cached.
+    pub(crate) fn commit_node(&self, n: &Node, retain: usize) -> (RawNodeOrHash, Node) {
+        let mut encoder = Encoder::<H, C>::new(retain);
+        let encoded = encoder.encode_node(n);
+        self.save_encoded(encoder);
+        encoded
+    }
 
-                    Ok(Node::from_extension(key, n))
-                }
-            }
-            Prototype::List(17) => {
-                let mut nodes = empty_children();
-                #[allow(clippy::needless_range_loop)]
-                for i in 0..nodes.len() {
-                    let rlp_data = r.at(i)?;
-                    let n = self.decode_node(rlp_data.as_raw())?;
-                    nodes[i] = n;
-                }
+    fn encode_raw(&self, n: Node) -> Vec<u8> {
+        let mut encoder = Encoder::<H, C>::new(0);
+        let data = encoder.encode_raw(&n);
+        self.save_encoded(encoder);
+        data
+    }
 
-                // The last element is a value node.
-                let value_rlp = r.at(16)?;
-                let value = if value_rlp.is_empty() {
-                    None
-                } else {
-                    Some(value_rlp.data()?.to_vec())
-                };
+    fn save_encoded(&self, encoder: Encoder<H, C>) {
+        let (nodes, gen_keys) = encoder.finish();
+        self.cache.with(nodes, |cache, nodes| cache.extend(nodes));
+        self.gen_keys
+            .with(gen_keys, |keys, gen_keys| keys.extend(gen_keys));
+    }
 
-                Ok(Node::from_branch(nodes, value))
-            }
-            _ => {
-                if r.is_data() && r.size() == HASH_LEN {
-                    Ok(Node::from_hash(H256::from_slice(&r.data().unwrap())))
-                } else {
-                    Err(TrieError::InvalidData)
+    fn decode_node(&self, data: &[u8]) -> TrieResult<Node> {
+        self.decode_node_at(data, 0)
+    }
+
+    // Decodes a node embedded `depth` levels below the node read from the db.
+    fn decode_node_at(&self, data: &[u8], depth: usize) -> TrieResult<Node> {
+        let node = C::decode(data)?;
+        self.limits.check(&node, data.len(), depth)?;
+        match node {
+            DecodedNode::Empty => Ok(Node::Empty),
+            DecodedNode::Leaf(path, value) => Ok(Node::from_leaf(
+                Nibbles::from_packed(path, true),
+                value.to_vec(),
+            )),
+            DecodedNode::Extension(path, child) => Ok(Node::from_extension(
+                Nibbles::from_packed(path, false),
+                self.decode_child(child, depth + 1)?,
+            )),
+            DecodedNode::Branch(children, value) => {
+                let mut nodes = empty_children();
+                for (node, child) in nodes.iter_mut().zip(children.iter()) {
+                    *node = self.decode_child(*child, depth + 1)?;
                 }
+                Ok(Node::from_branch(nodes, value.map(|v| v.to_vec())))
+            }
+        }
+    }
+
+    fn decode_child(&self, child: ChildReference, depth: usize) -> TrieResult<Node> {
+        match child {
+            ChildReference::Empty => Ok(Node::Empty),
+            ChildReference::Hash(digest) => Ok(Node::from_hash(decode_digest::<H>(digest)?)),
+            // Nodes are only embedded in their parent when shorter than a digest.
+            ChildReference::Inline(data) if data.len() >= H::LENGTH => {
+                Err(TrieError::NonCanonicalNode)
             }
+            ChildReference::Inline(data) => self.decode_node_at(data, depth),
         }
     }
 }
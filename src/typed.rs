@@ -0,0 +1,106 @@
+extern crate alloc;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+
+use rlp::{Decodable, Encodable};
+
+use crate::codec::{NodeCodec, RlpNodeCodec};
+use crate::db::HashDB;
+use crate::hasher::{Hasher, KeccakHasher};
+use crate::trie::{PatriciaTrieMut, TrieResult};
+use crate::H256;
+
+/// A trie storing RLP encoded values of type `V`, encoding them on insertion and
+/// decoding them on lookup.
+pub struct TypedTrie<'db, D: HashDB, V, H: Hasher = KeccakHasher, C: NodeCodec = RlpNodeCodec> {
+    trie: PatriciaTrieMut<'db, D, H, C>,
+    value: PhantomData<fn() -> V>,
+}
+
+impl<'db, D: HashDB, V: Encodable + Decodable> TypedTrie<'db, D, V> {
+    pub fn new(db: &'db mut D) -> Self {
+        PatriciaTrieMut::new(db).into()
+    }
+
+    pub fn from(db: &'db mut D, root: H256) -> TrieResult<Self> {
+        Ok(PatriciaTrieMut::from(db, root)?.into())
+    }
+}
+
+impl<'db, D: HashDB, V: Encodable + Decodable, H: Hasher, C: NodeCodec> TypedTrie<'db, D, V, H, C> {
+    pub fn trie(&self) -> &PatriciaTrieMut<'db, D, H, C> {
+        &self.trie
+    }
+
+    pub fn trie_mut(&mut self) -> &mut PatriciaTrieMut<'db, D, H, C> {
+        &mut self.trie
+    }
+
+    pub fn into_inner(self) -> PatriciaTrieMut<'db, D, H, C> {
+        self.trie
+    }
+
+    /// Returns the decoded value for key stored in the trie.
+    pub fn get(&self, key: &[u8]) -> TrieResult<Option<V>> {
+        match self.trie.get(key)? {
+            Some(data) => Ok(Some(rlp::decode(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn contains(&self, key: &[u8]) -> TrieResult<bool> {
+        self.trie.contains(key)
+    }
+
+    /// Inserts the encoding of value into trie and modifies it if it exists
+    pub fn insert(&mut self, key: &[u8], value: &V) -> TrieResult<()> {
+        self.trie.insert(key, rlp::encode(value).to_vec())
+    }
+
+    /// Removes any existing value for key from the trie.
+    pub fn remove(&mut self, key: &[u8]) -> TrieResult<bool> {
+        self.trie.remove(key)
+    }
+
+    /// Saves all the nodes in the db, clears the cache data, recalculates the root.
+    /// Returns the root hash of the trie.
+    pub fn root(&mut self) -> TrieResult<H256> {
+        self.trie.root()
+    }
+
+    /// Iterates over the entries in key order, decoding each value.
+    pub fn iter(&self) -> impl Iterator<Item = TrieResult<(Vec<u8>, V)>> + '_ {
+        self.trie.try_iter().map(|entry| {
+            let (key, data) = entry?;
+            Ok((key, rlp::decode(&data)?))
+        })
+    }
+
+    pub fn get_proof(&self, key: &[u8]) -> TrieResult<Vec<Vec<u8>>> {
+        self.trie.get_proof(key)
+    }
+
+    /// Returns the decoded value proven by `proof` for key under `root_hash`.
+    pub fn verify_proof(
+        &self,
+        root_hash: H256,
+        key: &[u8],
+        proof: Vec<Vec<u8>>,
+    ) -> TrieResult<Option<V>> {
+        match self.trie.verify_proof(root_hash, key, proof)? {
+            Some(data) => Ok(Some(rlp::decode(&data)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<'db, D: HashDB, V, H: Hasher, C: NodeCodec> From<PatriciaTrieMut<'db, D, H, C>>
+    for TypedTrie<'db, D, V, H, C>
+{
+    fn from(trie: PatriciaTrieMut<'db, D, H, C>) -> Self {
+        Self {
+            trie,
+            value: PhantomData,
+        }
+    }
+}
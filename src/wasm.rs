@@ -0,0 +1,247 @@
+//! Bindings for JavaScript, built with wasm-bindgen, so that light clients running in
+//! a browser can check `eth_getProof` results and compute roots with this crate.
+//!
+//! Byte strings are passed as `Uint8Array`s, lists of them as arrays, and failures
+//! are thrown as `Error`s.
+
+extern crate alloc;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use js_sys::{Array, Uint8Array};
+use wasm_bindgen::prelude::*;
+
+use crate::codec::RlpNodeCodec;
+use crate::db::MemoryDB;
+use crate::errors::TrieError;
+use crate::hasher::KeccakHasher;
+use crate::proof::{verify_key_proof, EIP1186AccountProof};
+use crate::trie::{PatriciaTrie, PatriciaTrieMut};
+use crate::H256;
+
+/// Returns the value of `key` proven by `proof` under `root`, or `undefined` if the
+/// proof shows the key is absent. Throws if the proof is invalid.
+#[wasm_bindgen(js_name = verifyProof)]
+pub fn verify_proof(root: &[u8], key: &[u8], proof: Array) -> Result<Option<Vec<u8>>, JsError> {
+    check_proof(root, key, &bytes_list(&proof)).map_err(js_error)
+}
+
+/// Checks an `eth_getProof` response, given as JSON, against `state_root`: the
+/// account against the state trie and each slot against the account's storage.
+/// Throws if a proof is invalid.
+#[wasm_bindgen(js_name = verifyAccountProof)]
+pub fn verify_account_proof(state_root: &[u8], response: &str) -> Result<(), JsError> {
+    check_account_proof(state_root, response).map_err(js_error)
+}
+
+/// Returns the root of the trie holding `values[i]` under `keys[i]`.
+#[wasm_bindgen(js_name = trieRoot)]
+pub fn trie_root(keys: Array, values: Array) -> Result<Vec<u8>, JsError> {
+    root_of(&bytes_list(&keys), &bytes_list(&values)).map_err(js_error)
+}
+
+// The work of the exported functions, on plain Rust values so that it can be done
+// and tested outside a JS runtime. Errors are the messages of the `Error`s thrown.
+
+fn check_proof(root: &[u8], key: &[u8], proof: &[Vec<u8>]) -> Result<Option<Vec<u8>>, String> {
+    verify_key_proof::<KeccakHasher, RlpNodeCodec>(digest(root)?, key, proof, &Default::default())
+        .map_err(|e| e.to_string())
+}
+
+fn check_account_proof(state_root: &[u8], response: &str) -> Result<(), String> {
+    let proof: EIP1186AccountProof =
+        serde_json::from_str(response).map_err(|e| format!("invalid proof: {}", e))?;
+    proof.verify(digest(state_root)?).map_err(|e| e.to_string())
+}
+
+fn root_of(keys: &[Vec<u8>], values: &[Vec<u8>]) -> Result<Vec<u8>, String> {
+    if keys.len() != values.len() {
+        return Err("keys and values differ in length".to_string());
+    }
+    let mut trie = Trie::empty()?;
+    for (key, value) in keys.iter().zip(values) {
+        trie.update(|trie| trie.insert(key, value.clone()))?;
+    }
+    Ok(trie.root())
+}
+
+/// A trie held in memory, committed after each update.
+#[wasm_bindgen]
+pub struct Trie {
+    db: MemoryDB,
+    root: H256,
+}
+
+#[wasm_bindgen]
+impl Trie {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Result<Trie, JsError> {
+        Self::empty().map_err(js_error)
+    }
+
+    /// Inserts a value, an empty one removing the key.
+    pub fn insert(&mut self, key: &[u8], value: Vec<u8>) -> Result<(), JsError> {
+        self.update(|trie| trie.insert(key, value))
+            .map_err(js_error)
+    }
+
+    /// Removes `key`, returning whether it was there.
+    pub fn remove(&mut self, key: &[u8]) -> Result<bool, JsError> {
+        self.update(|trie| trie.remove(key)).map_err(js_error)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, JsError> {
+        self.lookup(key).map_err(js_error)
+    }
+
+    /// Returns the nodes proving the value of `key`, or its absence, under `root`.
+    #[wasm_bindgen(js_name = getProof)]
+    pub fn get_proof(&self, key: &[u8]) -> Result<Array, JsError> {
+        let proof = self.proof(key).map_err(js_error)?;
+        Ok(proof
+            .iter()
+            .map(|node| JsValue::from(Uint8Array::from(&node[..])))
+            .collect())
+    }
+
+    pub fn root(&self) -> Vec<u8> {
+        self.root.as_bytes().to_vec()
+    }
+}
+
+impl Trie {
+    fn empty() -> Result<Self, String> {
+        let mut db = MemoryDB::new(true);
+        let root = PatriciaTrieMut::new(&mut db)
+            .root()
+            .map_err(|e| e.to_string())?;
+        Ok(Self { db, root })
+    }
+
+    // Opens the trie for reading.
+    fn trie(&self) -> Result<PatriciaTrie<'_, MemoryDB>, String> {
+        PatriciaTrie::from(&self.db, self.root).map_err(|e| e.to_string())
+    }
+
+    fn lookup(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.trie()?.get(key).map_err(|e| e.to_string())
+    }
+
+    fn proof(&self, key: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+        self.trie()?.get_proof(key).map_err(|e| e.to_string())
+    }
+
+    // Runs `f` on the trie, then commits it.
+    fn update<R>(
+        &mut self,
+        f: impl FnOnce(&mut PatriciaTrieMut<MemoryDB>) -> Result<R, TrieError>,
+    ) -> Result<R, String> {
+        let mut trie = PatriciaTrieMut::from(&mut self.db, self.root).map_err(|e| e.to_string())?;
+        let result = f(&mut trie).map_err(|e| e.to_string())?;
+        self.root = trie.root().map_err(|e| e.to_string())?;
+        Ok(result)
+    }
+}
+
+// Reads a root, which must be a whole digest.
+fn digest(data: &[u8]) -> Result<H256, String> {
+    if data.len() != 32 {
+        return Err("a root must be 32 bytes long".to_string());
+    }
+    Ok(H256::from_slice(data))
+}
+
+// Reads an array of `Uint8Array`s.
+fn bytes_list(array: &Array) -> Vec<Vec<u8>> {
+    array
+        .iter()
+        .map(|item| Uint8Array::new(&item).to_vec())
+        .collect()
+}
+
+fn js_error(message: String) -> JsError {
+    JsError::new(&message)
+}
+
+// Creating a `JsValue` needs a JS runtime, so the exported functions are only called
+// where they succeed, and the errors are checked on the functions they wrap.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use ethereum_types::{Address, U256};
+
+    use crate::state::{Account, StateTrie};
+
+    #[test]
+    fn test_trie_and_proofs() {
+        let mut trie = Trie::new().unwrap();
+        let keys: Vec<_> = (0..50u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        let values: Vec<_> = (0..50u32).map(|i| vec![i as u8 + 1; 40]).collect();
+        for (key, value) in keys.iter().zip(values.iter()) {
+            trie.insert(key, value.clone()).unwrap();
+        }
+        assert!(trie.remove(&keys[0]).unwrap());
+        assert!(!trie.remove(&keys[0]).unwrap());
+        trie.insert(&keys[0], values[0].clone()).unwrap();
+        assert_eq!(trie.get(&keys[7]).unwrap(), Some(values[7].clone()));
+        assert_eq!(trie.get(b"missing").unwrap(), None);
+
+        let root = trie.root();
+        assert_eq!(root_of(&keys, &values).unwrap(), root);
+        assert_eq!(
+            root,
+            crate::trie_root(keys.iter().cloned().zip(values.iter().cloned())).as_bytes()
+        );
+        assert_eq!(
+            root_of(&keys[1..], &values).unwrap_err(),
+            "keys and values differ in length"
+        );
+
+        let proof = trie.proof(&keys[7]).unwrap();
+        assert_eq!(
+            check_proof(&root, &keys[7], &proof).unwrap(),
+            Some(values[7].clone())
+        );
+        let proof = trie.proof(b"missing").unwrap();
+        assert_eq!(check_proof(&root, b"missing", &proof).unwrap(), None);
+        assert!(check_proof(&root, &keys[7], &proof).is_err());
+        assert_eq!(
+            check_proof(&root[1..], &keys[7], &proof).unwrap_err(),
+            "a root must be 32 bytes long"
+        );
+    }
+
+    #[test]
+    fn test_account_proof() {
+        let mut memdb = MemoryDB::new(true);
+        let mut state = StateTrie::new(&mut memdb);
+        let address = Address::repeat_byte(0xaa);
+        let mut storage = state.storage_trie(&address).unwrap();
+        for slot in 0..10u64 {
+            storage
+                .set(&U256::from(slot), &U256::from(slot + 1))
+                .unwrap();
+        }
+        let account = Account {
+            nonce: 1,
+            storage_root: storage.root().unwrap(),
+            ..Account::default()
+        };
+        state.set_account(&address, &account).unwrap();
+        let root = state.root().unwrap();
+
+        let proof = state.get_proof(&address, &[U256::from(3)]).unwrap();
+        let response = serde_json::to_string(&proof).unwrap();
+        verify_account_proof(root.as_bytes(), &response).unwrap();
+
+        let mut forged = proof.clone();
+        forged.storage_proof[0].value = U256::from(5);
+        let forged = serde_json::to_string(&forged).unwrap();
+        assert!(check_account_proof(root.as_bytes(), &forged).is_err());
+        assert!(check_account_proof(root.as_bytes(), "{}")
+            .unwrap_err()
+            .starts_with("invalid proof"));
+    }
+}
@@ -0,0 +1,47 @@
+extern crate alloc;
+use alloc::vec::Vec;
+
+use crate::codec::{NodeCodec, RlpNodeCodec};
+use crate::db::{HashDB, MemoryDB};
+use crate::errors::TrieError;
+use crate::hasher::{Hasher, KeccakHasher};
+use crate::trie::{PatriciaTrieMut, TrieResult};
+use crate::H256;
+
+/// Applies `changes` to the trie at `prev_root` knowing only the nodes in `witness`,
+/// and returns the root after them, as a stateless validator checks the state
+/// transition of a block against the witness it comes with.
+///
+/// `None` removes a key, as in `PatriciaTrieMut::apply_changes`. The witness must
+/// hold every node the changes walk through, including the siblings merged into
+/// their parent when a removal leaves a branch with a single child. Otherwise this
+/// fails with `TrieError::MissingNode`. Nodes of the witness the changes do not need
+/// are ignored.
+pub fn apply_over_witness(
+    prev_root: H256,
+    witness: impl IntoIterator<Item = Vec<u8>>,
+    changes: impl IntoIterator<Item = (Vec<u8>, Option<Vec<u8>>)>,
+) -> TrieResult<H256> {
+    apply_over_witness_with_hasher::<KeccakHasher, RlpNodeCodec>(prev_root, witness, changes)
+}
+
+/// Same as `apply_over_witness`, for a trie hashing its nodes with `H` and encoding
+/// them with `C`.
+pub fn apply_over_witness_with_hasher<H: Hasher, C: NodeCodec>(
+    prev_root: H256,
+    witness: impl IntoIterator<Item = Vec<u8>>,
+    changes: impl IntoIterator<Item = (Vec<u8>, Option<Vec<u8>>)>,
+) -> TrieResult<H256> {
+    let mut db = MemoryDB::new(true);
+    for node in witness {
+        db.insert(H::hash(&node), node);
+    }
+    // The root of the empty trie needs no witness.
+    if prev_root == H::hash(C::EMPTY_NODE) {
+        db.insert(prev_root, C::EMPTY_NODE.to_vec());
+    }
+    if !db.contains(&prev_root) {
+        return Err(TrieError::MissingNode(prev_root));
+    }
+    PatriciaTrieMut::<_, H, C>::from_with_hasher(&mut db, prev_root)?.apply_changes(changes)
+}
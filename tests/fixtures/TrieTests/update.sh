@@ -0,0 +1,27 @@
+#!/bin/sh
+# Replaces the fixtures in this directory with the TrieTests files of
+# https://github.com/ethereum/tests, whole and unedited, and records the commit
+# they were taken from in UPSTREAM.
+#
+#     tests/fixtures/TrieTests/update.sh [<commit or branch>]
+#
+# Defaults to the `develop` branch.
+set -eu
+
+dir=$(cd "$(dirname "$0")" && pwd)
+rev=${1:-develop}
+files="trietest.json trieanyorder.json hex_encoded_securetrie_test.json"
+
+tmp=$(mktemp -d)
+trap 'rm -rf "$tmp"' EXIT
+
+# Only the blobs of the files checked out are downloaded.
+git -C "$tmp" init -q
+git -C "$tmp" remote add origin https://github.com/ethereum/tests.git
+git -C "$tmp" fetch -q --depth 1 --filter=blob:none origin "$rev"
+for file in $files; do
+    git -C "$tmp" checkout -q FETCH_HEAD -- "TrieTests/$file"
+    cp "$tmp/TrieTests/$file" "$dir/$file"
+done
+git -C "$tmp" rev-parse FETCH_HEAD > "$dir/UPSTREAM"
+echo "TrieTests fixtures updated to ethereum/tests $(cat "$dir/UPSTREAM")"
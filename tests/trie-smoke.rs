@@ -1,10 +1,17 @@
 use rand::distributions::Alphanumeric;
 use rand::seq::SliceRandom;
 use rand::{thread_rng, Rng};
+use std::cell::Cell;
 use std::collections::{HashMap, HashSet};
 
 use ethereum_types::H256;
-use ethtrie::{keccak256, HashDB, MemoryDB, PatriciaTrieMut};
+use ethtrie::{
+    apply_over_witness, collect_garbage, diff_nodes, genesis_state_root, keccak256,
+    ordered_trie_root, sec_trie_root, trie_root, verify_storage_slot, Account, ArchiveDB,
+    ChildReference, DecodedNode, HashDB, Hasher, IterableHashDB, KeccakHasher, MemoryDB, Nibbles,
+    NodeCodec, NodeDiff, PackedPath, PatriciaTrieMut, RefCountedDB, RootJournal, StateTrie,
+    StorageTrie, TrieError, TypedTrie,
+};
 
 #[test]
 fn test_trie_insert() {
@@ -43,8 +50,8 @@ fn test_trie_contains() {
     let mut memdb = MemoryDB::new(true);
     let mut trie = PatriciaTrieMut::new(&mut memdb);
     trie.insert(b"test", b"test".to_vec()).unwrap();
-    assert_eq!(true, trie.contains(b"test").unwrap());
-    assert_eq!(false, trie.contains(b"test2").unwrap());
+    assert!(trie.contains(b"test").unwrap());
+    assert!(!trie.contains(b"test2").unwrap());
 }
 
 #[test]
@@ -53,7 +60,7 @@ fn test_trie_remove() {
     let mut trie = PatriciaTrieMut::new(&mut memdb);
     trie.insert(b"test", b"test".to_vec()).unwrap();
     let removed = trie.remove(b"test").unwrap();
-    assert_eq!(true, removed)
+    assert!(removed);
 }
 
 #[test]
@@ -67,7 +74,7 @@ fn test_trie_random_remove() {
         trie.insert(val, val.to_vec()).unwrap();
 
         let removed = trie.remove(val).unwrap();
-        assert_eq!(true, removed);
+        assert!(removed);
     }
 }
 
@@ -131,11 +138,11 @@ fn test_trie_from_root_and_delete() {
 
     let mut trie = PatriciaTrieMut::from(&mut memdb, root).unwrap();
     let removed = trie.remove(b"test44").unwrap();
-    assert_eq!(true, removed);
+    assert!(removed);
     let removed = trie.remove(b"test33").unwrap();
-    assert_eq!(true, removed);
+    assert!(removed);
     let removed = trie.remove(b"test23").unwrap();
-    assert_eq!(true, removed);
+    assert!(removed);
 }
 
 #[test]
@@ -171,7 +178,7 @@ fn test_multiple_trie_roots() {
             trie1.root().unwrap()
         };
         let mut trie2 = PatriciaTrieMut::from(&mut memdb, root).unwrap();
-        trie2.remove(&k1.as_bytes()).unwrap();
+        trie2.remove(k1.as_bytes()).unwrap();
         trie2.root().unwrap()
     };
 
@@ -273,7 +280,7 @@ fn iterator_trie() {
         kv_delete.insert(b"test14".to_vec());
 
         kv_delete.iter().for_each(|k| {
-            trie.remove(&k).unwrap();
+            trie.remove(k).unwrap();
         });
 
         kv2.retain(|k, _| !kv_delete.contains(k));
@@ -285,7 +292,4570 @@ fn iterator_trie() {
     }
 
     let trie = PatriciaTrieMut::from(&mut memdb, root1).unwrap();
+    let mut entries = vec![];
+    trie.for_each_entry(|k, v| entries.push((k.to_vec(), v.to_vec())))
+        .unwrap();
+    assert_eq!(entries, trie.iter().collect::<Vec<_>>());
+
+    trie.iter()
+        .for_each(|(k, v)| assert_eq!(kv.remove(&k).unwrap(), v));
+    assert!(kv.is_empty());
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn test_par_iter_matches_iter() {
+    use ethtrie::{MemoryBlobStore, PatriciaTrie};
+    use rayon::iter::ParallelIterator;
+
+    let mut memdb = MemoryDB::new(true);
+    let root = {
+        let mut trie = PatriciaTrieMut::new(&mut memdb);
+        for i in 0..300u32 {
+            let key = keccak256(&i.to_be_bytes());
+            trie.insert(key.as_bytes(), i.to_be_bytes().to_vec())
+                .unwrap();
+        }
+        trie.root().unwrap()
+    };
+
+    let mut trie = PatriciaTrieMut::from(&mut memdb, root).unwrap();
+    trie.insert(b"uncommitted", b"value".to_vec()).unwrap();
+    let mut entries: Vec<_> = trie.par_iter().unwrap().map(Result::unwrap).collect();
+    entries.sort();
+    assert_eq!(entries.len(), 301);
+    assert_eq!(entries, trie.iter().collect::<Vec<_>>());
+    drop(trie);
+
+    // Workers read values from the blob store and nodes flushed by the memory limit
+    // like the trie they come from.
+    let store = MemoryBlobStore::new();
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb)
+        .with_blob_store(store.clone(), 16)
+        .with_memory_limit(4 * 1024);
+    for i in 0..300u32 {
+        let key = keccak256(&i.to_be_bytes());
+        trie.insert(key.as_bytes(), vec![i as u8; 40 + i as usize % 7])
+            .unwrap();
+    }
+    let expected: Vec<_> = trie.iter().collect();
+    assert!(expected.iter().all(|(_, value)| value.len() >= 40));
+    let mut entries: Vec<_> = trie.par_iter().unwrap().map(Result::unwrap).collect();
+    entries.sort();
+    assert_eq!(entries, expected);
+    let root = trie.root().unwrap();
+    let trie = PatriciaTrie::from(&memdb, root)
+        .unwrap()
+        .with_blob_store(store, 16);
+    let mut entries: Vec<_> = trie.par_iter().unwrap().map(Result::unwrap).collect();
+    entries.sort();
+    assert_eq!(entries, expected);
+}
+
+#[test]
+fn test_trie_is_send_sync() {
+    use ethtrie::{PatriciaTrie, TrieFork, TrieIterator};
+
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<PatriciaTrie<MemoryDB>>();
+    assert_send_sync::<PatriciaTrieMut<MemoryDB>>();
+    assert_send_sync::<TrieIterator<MemoryDB>>();
+    assert_send_sync::<TrieFork<MemoryDB>>();
+
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    for i in 0..1000u32 {
+        trie.insert(&keccak256(&i.to_be_bytes()).0, vec![i as u8; 40])
+            .unwrap();
+    }
+    let root = trie.root().unwrap();
+
+    // Readers on several threads share one trie and its node cache.
+    let trie = PatriciaTrie::from(&memdb, root).unwrap();
+    std::thread::scope(|scope| {
+        for t in 0..4u32 {
+            let trie = &trie;
+            scope.spawn(move || {
+                for i in (t..1000).step_by(4) {
+                    let key = keccak256(&i.to_be_bytes());
+                    assert_eq!(trie.get(&key.0).unwrap(), Some(vec![i as u8; 40]));
+                    assert!(!trie.get_proof(&key.0).unwrap().is_empty());
+                }
+            });
+        }
+        let trie = trie.clone();
+        scope.spawn(move || assert_eq!(trie.iter().count(), 1000));
+    });
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_shared_memory_db() {
+    use ethtrie::{PatriciaTrie, SharedMemoryDB};
+
+    let db = SharedMemoryDB::new(true);
+    let writers: Vec<_> = (0..4u32)
+        .map(|t| {
+            let mut db = db.clone();
+            std::thread::spawn(move || {
+                let mut trie = PatriciaTrieMut::new(&mut db);
+                for i in 0..100u32 {
+                    trie.insert(&(t * 100 + i).to_be_bytes(), vec![t as u8; 40])
+                        .unwrap();
+                }
+                trie.root().unwrap()
+            })
+        })
+        .collect();
+    let roots: Vec<_> = writers.into_iter().map(|w| w.join().unwrap()).collect();
+
+    for (t, root) in roots.into_iter().enumerate() {
+        let trie = PatriciaTrie::from(&db, root).unwrap();
+        let entries: Vec<_> = trie.iter().collect();
+        assert_eq!(entries.len(), 100);
+        assert!(entries.iter().all(|(_, value)| value[0] == t as u8));
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_read_handle() {
+    use ethtrie::{ReadHandle, SharedMemoryDB};
+
+    let key = |i: u32| keccak256(&i.to_be_bytes()).0;
+    // Removals are ignored, so the writer leaves the nodes of older roots in place.
+    let mut db = SharedMemoryDB::new(false);
+    let mut writer_db = db.clone();
+    let mut trie = PatriciaTrieMut::new(&mut db);
+    for i in 0..500u32 {
+        trie.insert(&key(i), vec![1; 40]).unwrap();
+    }
+    let first = trie.root().unwrap();
+    let handle = ReadHandle::new(writer_db.clone(), first).unwrap();
+    assert_eq!(handle.root(), first);
+
+    let next = std::thread::scope(|scope| {
+        for t in 0..4u32 {
+            let handle = handle.clone();
+            scope.spawn(move || {
+                for i in (t..500).step_by(4) {
+                    assert_eq!(handle.get(&key(i)).unwrap(), Some(vec![1; 40]));
+                    let proof = handle.get_proof(&key(i)).unwrap();
+                    assert_eq!(
+                        PatriciaTrieMut::new(&mut MemoryDB::new(true))
+                            .verify_proof(first, &key(i), proof)
+                            .unwrap(),
+                        Some(vec![1; 40])
+                    );
+                }
+                assert_eq!(handle.iter().count(), 500);
+            });
+        }
+        let writer = scope.spawn(move || {
+            let mut trie = PatriciaTrieMut::from(&mut writer_db, first).unwrap();
+            for i in 0..500u32 {
+                trie.insert(&key(i), vec![2; 40]).unwrap();
+            }
+            trie.remove(&key(0)).unwrap();
+            trie.root().unwrap()
+        });
+        writer.join().unwrap()
+    });
+
+    // The handle still reads the root it was opened at, and moves on to the next.
+    assert_eq!(handle.get(&key(0)).unwrap(), Some(vec![1; 40]));
+    let handle = handle.at(next).unwrap();
+    assert_eq!(handle.get(&key(0)).unwrap(), None);
+    assert_eq!(handle.get(&key(1)).unwrap(), Some(vec![2; 40]));
+    assert!(!handle.contains(&key(0)).unwrap());
+    assert!(matches!(
+        handle.at(keccak256(b"unknown")),
+        Err(TrieError::InvalidStateRoot)
+    ));
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_commit_in_background() {
+    use ethtrie::{ReadHandle, SharedMemoryDB};
+
+    let key = |i: u32| keccak256(&i.to_be_bytes()).0;
+    let expected = |blocks: u32| {
+        let mut db = MemoryDB::new(true);
+        let mut trie = PatriciaTrieMut::new(&mut db);
+        for i in 0..blocks * 100 {
+            trie.insert(&key(i), vec![i as u8; 40]).unwrap();
+        }
+        trie.root().unwrap()
+    };
+
+    let mut db = SharedMemoryDB::new(false);
+    let background_db = db.clone();
+    let mut trie = PatriciaTrieMut::new(&mut db);
+    let mut pending = Vec::new();
+    for block in 0..3u32 {
+        for i in block * 100..(block + 1) * 100 {
+            trie.insert(&key(i), vec![i as u8; 40]).unwrap();
+        }
+        // The next block is executed while the previous one is committed.
+        pending.push(trie.commit_in_background(background_db.clone()));
+    }
+    let roots: Vec<H256> = pending.into_iter().map(|root| root.wait()).collect();
+    assert_eq!(roots, vec![expected(1), expected(2), expected(3)]);
+    assert_eq!(trie.root().unwrap(), expected(3));
+
+    // A state committed already is handed back at once.
+    let unchanged = trie.commit_in_background(background_db.clone());
+    assert!(unchanged.is_finished());
+    assert_eq!(unchanged.wait(), expected(3));
+
+    for (block, root) in roots.into_iter().enumerate() {
+        let handle = ReadHandle::new(background_db.clone(), root).unwrap();
+        assert_eq!(handle.iter().count(), (block + 1) * 100);
+    }
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn test_tracing() {
+    use std::sync::{Arc, Mutex};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    // Records the names of the spans opened and of the fields of the events.
+    #[derive(Default, Clone)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes) -> Id {
+            let mut names = self.0.lock().unwrap();
+            names.push(span.metadata().name().to_string());
+            Id::from_u64(names.len() as u64)
+        }
+
+        fn record(&self, _: &Id, _: &Record) {}
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, event: &Event) {
+            let mut names = self.0.lock().unwrap();
+            names.extend(event.fields().map(|field| field.name().to_string()));
+        }
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    let recorder = Recorder::default();
+    tracing::subscriber::with_default(recorder.clone(), || {
+        let mut memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrieMut::new(&mut memdb);
+        for i in 0..100u32 {
+            trie.insert(&keccak256(&i.to_be_bytes()).0, vec![i as u8; 40])
+                .unwrap();
+        }
+        trie.root().unwrap();
+        trie.remove(&keccak256(&0u32.to_be_bytes()).0).unwrap();
+        let root = trie.root().unwrap();
+
+        let trie = PatriciaTrieMut::from(&mut memdb, root).unwrap();
+        let key = keccak256(&1u32.to_be_bytes());
+        assert!(trie.get(&key.0).unwrap().is_some());
+        assert!(trie.contains(&key.0).unwrap());
+        trie.get_proof(&key.0).unwrap();
+    });
+
+    let names = recorder.0.lock().unwrap();
+    let count = |name: &str| names.iter().filter(|n| n.as_str() == name).count();
+    assert_eq!(count("insert"), 100);
+    assert_eq!(count("remove"), 1);
+    assert_eq!(count("commit"), 2);
+    assert_eq!(count("written"), 2);
+    assert_eq!(count("removed"), 2);
+    for name in ["get", "contains", "get_proof"] {
+        assert_eq!(count(name), 1, "{}", name);
+    }
+    assert!(count("found") > 0);
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn test_metrics() {
+    // The metrics are shared by the tests running alongside, so only lower bounds of
+    // their changes are known.
+    let metrics = ethtrie::metrics();
+    let (commits, written, pruned) = (
+        metrics.commits(),
+        metrics.nodes_written(),
+        metrics.nodes_pruned(),
+    );
+    let (hits, misses, proofs) = (
+        metrics.cache_hits(),
+        metrics.cache_misses(),
+        metrics.proofs(),
+    );
+
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    for i in 0..100u32 {
+        trie.insert(&keccak256(&i.to_be_bytes()).0, vec![i as u8; 40])
+            .unwrap();
+    }
+    trie.root().unwrap();
+    trie.remove(&keccak256(&0u32.to_be_bytes()).0).unwrap();
+    let root = trie.root().unwrap();
+    assert!(metrics.commits() >= commits + 2);
+    assert!(metrics.nodes_written() >= written + 100);
+    assert!(metrics.nodes_pruned() > pruned);
+
+    let trie = PatriciaTrieMut::from(&mut memdb, root).unwrap();
+    let key = keccak256(&1u32.to_be_bytes());
+    trie.get(&key.0).unwrap();
+    trie.get(&key.0).unwrap();
+    trie.get_proof(&key.0).unwrap();
+    assert!(metrics.cache_misses() > misses);
+    assert!(metrics.cache_hits() > hits);
+    assert!(metrics.cache_hit_rate() > 0.0);
+    assert!(metrics.proofs() > proofs);
+
+    let text = metrics.render();
+    for name in [
+        "ethtrie_commits_total",
+        "ethtrie_nodes_written_total",
+        "ethtrie_nodes_pruned_total",
+        "ethtrie_node_cache_hits_total",
+        "ethtrie_node_cache_misses_total",
+        "ethtrie_proofs_total",
+        "ethtrie_commit_duration_seconds_bucket{le=\"+Inf\"}",
+        "ethtrie_commit_duration_seconds_count",
+    ] {
+        assert!(text.contains(name), "{}", name);
+    }
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn test_inspect_cli() {
+    use std::process::Command;
+
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    for i in 0..50u32 {
+        trie.insert(&keccak256(&i.to_be_bytes()).0, vec![i as u8; 40])
+            .unwrap();
+    }
+    let root = trie.root().unwrap();
+    let mut snapshot = Vec::new();
+    ethtrie::write_snapshot(&memdb, root, |bytes| snapshot.extend_from_slice(bytes)).unwrap();
+    let path = std::env::temp_dir().join(format!("ethtrie-inspect-{}.snap", std::process::id()));
+    std::fs::write(&path, &snapshot).unwrap();
+
+    let inspect = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_ethtrie-inspect"))
+            .arg(&path)
+            .args(args)
+            .output()
+            .unwrap();
+        (
+            output.status.code(),
+            String::from_utf8(output.stdout).unwrap(),
+        )
+    };
+
+    let key = keccak256(&7u32.to_be_bytes());
+    let (code, out) = inspect(&["get", &hex::encode(key.0)]);
+    assert_eq!(code, Some(0));
+    assert!(out.contains(&format!("value: 0x{}", hex::encode([7u8; 40]))));
+    let proof = PatriciaTrieMut::from(&mut memdb, root)
+        .unwrap()
+        .get_proof(&key.0)
+        .unwrap();
+    for node in proof {
+        assert!(out.contains(&hex::encode(node)));
+    }
+
+    let (code, out) = inspect(&["stats"]);
+    assert_eq!(code, Some(0));
+    assert!(out.contains("leaves: 50"));
+    assert!(out.contains(&format!("stored nodes: {} ", memdb.keys().len())));
+    assert!(out.contains("problems: 0"));
+
+    let (code, out) = inspect(&["children"]);
+    assert_eq!(code, Some(0));
+    assert!(out.contains("node: branch"));
+    assert_eq!(out.matches("stored 0x").count(), 17);
+    let (code, out) = inspect(&["children", &format!("{:x}", key[0] >> 4)]);
+    assert_eq!(code, Some(0));
+    assert!(out.contains("path: "));
+
+    let (code, out) = inspect(&["verify"]);
+    assert_eq!(code, Some(0));
+    assert!(out.contains(&format!(
+        "{} stored nodes checked, 0 problems",
+        memdb.keys().len()
+    )));
+    let missing = keccak256(b"missing");
+    let (code, out) = inspect(&["--root", &hex::encode(missing.0), "verify"]);
+    assert_eq!(code, Some(1));
+    assert!(out.contains(&format!("missing node {:?}", missing)));
+
+    let (code, _) = inspect(&["unknown"]);
+    assert_eq!(code, Some(2));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_collect_garbage_keeps_live_roots() {
+    let mut memdb = MemoryDB::new(true);
+    let mut kv = HashMap::new();
+    for i in 0..50u8 {
+        kv.insert(vec![i; 20], vec![i; 40]);
+    }
+
+    let live_root = {
+        let mut trie = PatriciaTrieMut::new(&mut memdb);
+        kv.iter()
+            .for_each(|(k, v)| trie.insert(k, v.clone()).unwrap());
+        trie.root().unwrap()
+    };
+    let dead_root = {
+        let mut trie = PatriciaTrieMut::new(&mut memdb);
+        trie.insert(&[0xffu8; 20], vec![0xff; 40]).unwrap();
+        trie.insert(&[0xfeu8; 20], vec![0xfe; 40]).unwrap();
+        trie.root().unwrap()
+    };
+    memdb.insert(H256::repeat_byte(1), b"garbage".to_vec());
+
+    let removed = collect_garbage(&mut memdb, &[live_root]).unwrap();
+    assert!(removed >= 2);
+    assert!(!memdb.contains(&dead_root));
+    assert!(!memdb.contains(&H256::repeat_byte(1)));
+
+    let trie = PatriciaTrieMut::from(&mut memdb, live_root).unwrap();
     trie.iter()
         .for_each(|(k, v)| assert_eq!(kv.remove(&k).unwrap(), v));
     assert!(kv.is_empty());
+
+    assert_eq!(collect_garbage(&mut memdb, &[live_root]).unwrap(), 0);
+    assert!(collect_garbage(&mut memdb, &[dead_root]).is_err());
+
+    // A db that ignores removals keeps its nodes, and none are counted as deleted.
+    let mut memdb = MemoryDB::new(false);
+    memdb.insert(H256::repeat_byte(1), b"garbage".to_vec());
+    assert_eq!(collect_garbage(&mut memdb, &[]).unwrap(), 0);
+    assert!(memdb.contains(&H256::repeat_byte(1)));
+}
+
+#[test]
+fn test_ref_counted_db() {
+    let keys: Vec<_> = (0..200u32).map(|i| keccak256(&i.to_be_bytes())).collect();
+    let mut db = RefCountedDB::new(MemoryDB::new(true));
+    let root1 = {
+        let mut trie = PatriciaTrieMut::new(&mut db);
+        for key in keys.iter() {
+            trie.insert(key.as_bytes(), vec![1; 40]).unwrap();
+        }
+        trie.root().unwrap()
+    };
+
+    // The first root is kept while referenced, although the trie prunes it.
+    db.reference(root1);
+    let root2 = {
+        let mut trie = PatriciaTrieMut::from(&mut db, root1).unwrap();
+        for key in keys.iter().take(20) {
+            trie.insert(key.as_bytes(), vec![2; 40]).unwrap();
+        }
+        trie.root().unwrap()
+    };
+    let root3 = {
+        let mut trie = PatriciaTrieMut::from(&mut db, root2).unwrap();
+        trie.remove(keys[0].as_bytes()).unwrap();
+        trie.root().unwrap()
+    };
+    assert_eq!(db.ref_count(&root1), 1);
+    assert!(!db.contains(&root2));
+    for (root, count) in [(root1, 200), (root3, 199)] {
+        let trie = PatriciaTrieMut::from(&mut db, root).unwrap();
+        assert_eq!(trie.iter().count(), count);
+    }
+
+    // Once released, only the nodes of the latest root are left.
+    db.dereference(&root1);
+    assert!(!db.contains(&root1));
+    let mut live = HashSet::new();
+    let mut plain = MemoryDB::new(true);
+    {
+        let mut trie = PatriciaTrieMut::new(&mut plain);
+        for (i, key) in keys.iter().enumerate().skip(1) {
+            let value = if i < 20 { 2 } else { 1 };
+            trie.insert(key.as_bytes(), vec![value; 40]).unwrap();
+        }
+        assert_eq!(trie.root().unwrap(), root3);
+    }
+    live.extend(plain.keys());
+    assert_eq!(db.keys().into_iter().collect::<HashSet<_>>(), live);
+}
+
+#[test]
+fn test_archive_db() {
+    let keys: Vec<_> = (0..200u32).map(|i| keccak256(&i.to_be_bytes())).collect();
+    let mut db = ArchiveDB::new(MemoryDB::new(true));
+    let mut roots = Vec::new();
+    let mut trie = PatriciaTrieMut::new(&mut db);
+    for block in 0..4u8 {
+        for key in keys.iter().skip(block as usize * 10).take(50) {
+            trie.insert(key.as_bytes(), vec![block + 1; 40]).unwrap();
+        }
+        roots.push(trie.root().unwrap());
+    }
+    // Committing a root again takes no other reference.
+    assert_eq!(trie.root().unwrap(), roots[3]);
+    drop(trie);
+
+    // Pruning is ignored, so every committed root is readable.
+    for root in roots.iter() {
+        assert!(db.is_kept(root));
+        let trie = PatriciaTrieMut::from(&mut db, *root).unwrap();
+        assert!(trie.iter().count() >= 50);
+    }
+    assert_eq!(db.roots(), roots);
+
+    // Releasing a root deletes the nodes only it used.
+    let before = db.keys().len();
+    assert!(db.dereference(&roots[1]));
+    assert!(!db.dereference(&roots[1]));
+    assert!(!db.contains(&roots[1]));
+    assert!(db.keys().len() < before);
+    for root in [roots[0], roots[2], roots[3]] {
+        let trie = PatriciaTrieMut::from(&mut db, root).unwrap();
+        assert!(trie.iter().count() >= 50);
+    }
+
+    for root in [roots[0], roots[2]] {
+        db.dereference(&root);
+    }
+    let mut plain = MemoryDB::new(true);
+    {
+        let mut trie = PatriciaTrieMut::new(&mut plain);
+        for block in 0..4u8 {
+            for key in keys.iter().skip(block as usize * 10).take(50) {
+                trie.insert(key.as_bytes(), vec![block + 1; 40]).unwrap();
+            }
+        }
+        assert_eq!(trie.root().unwrap(), roots[3]);
+    }
+    let live: HashSet<_> = plain.keys().into_iter().collect();
+    assert_eq!(db.keys().into_iter().collect::<HashSet<_>>(), live);
+
+    db.dereference(&roots[3]);
+    assert!(db.keys().is_empty());
+    assert!(db.roots().is_empty());
+}
+
+#[test]
+fn test_archive_db_window() {
+    let keys: Vec<_> = (0..200u32).map(|i| keccak256(&i.to_be_bytes())).collect();
+    let mut db = ArchiveDB::new(MemoryDB::new(true)).with_window(3);
+    let mut roots = Vec::new();
+    {
+        let mut trie = PatriciaTrieMut::new(&mut db);
+        for block in 0..6u8 {
+            for key in keys.iter().skip(block as usize * 10).take(50) {
+                trie.insert(key.as_bytes(), vec![block + 1; 40]).unwrap();
+            }
+            roots.push(trie.root().unwrap());
+        }
+    }
+    // Only the nodes of the last three roots are left.
+    assert_eq!(db.roots(), roots[3..]);
+    for root in roots[..3].iter() {
+        assert!(!db.contains(root));
+    }
+    assert_eq!(collect_garbage(&mut db, &roots[3..]).unwrap(), 0);
+
+    // A reorg replaces the last root with a sibling of it, and both stay readable.
+    let sibling = {
+        let mut trie = PatriciaTrieMut::from(&mut db, roots[4]).unwrap();
+        trie.insert(keys[199].as_bytes(), vec![9; 40]).unwrap();
+        trie.root().unwrap()
+    };
+    assert_eq!(db.roots(), vec![roots[4], roots[5], sibling]);
+    for root in [roots[4], roots[5], sibling] {
+        let trie = PatriciaTrieMut::from(&mut db, root).unwrap();
+        assert!(trie.iter().count() >= 50);
+    }
+
+    // Shrinking the window releases the oldest roots at once.
+    let mut db = db.with_window(1);
+    assert_eq!(db.roots(), vec![sibling]);
+    assert_eq!(collect_garbage(&mut db, &[sibling]).unwrap(), 0);
+}
+
+#[test]
+fn test_archive_db_pinning() {
+    let keys: Vec<_> = (0..200u32).map(|i| keccak256(&i.to_be_bytes())).collect();
+    let mut db = ArchiveDB::new(MemoryDB::new(true)).with_window(2);
+    let mut roots = Vec::new();
+    let mut commit_blocks = |db: &mut ArchiveDB<MemoryDB>, blocks: std::ops::Range<u8>| {
+        let mut trie = match roots.last() {
+            Some(root) => PatriciaTrieMut::from(db, *root).unwrap(),
+            None => PatriciaTrieMut::new(db),
+        };
+        for block in blocks {
+            for key in keys.iter().skip(block as usize * 10).take(50) {
+                trie.insert(key.as_bytes(), vec![block + 1; 40]).unwrap();
+            }
+            roots.push(trie.root().unwrap());
+        }
+        roots.clone()
+    };
+
+    let first = commit_blocks(&mut db, 0..2);
+    assert!(db.pin(first[0]));
+    assert!(!db.pin(keccak256(b"unknown")));
+    let roots = commit_blocks(&mut db, 2..6);
+
+    // The pinned root left the window but is kept, unlike its sibling.
+    assert_eq!(db.roots(), roots[4..]);
+    assert!(db.is_pinned(&roots[0]));
+    assert_eq!(db.pinned(), vec![roots[0]]);
+    assert!(!db.contains(&roots[1]));
+    assert_eq!(
+        PatriciaTrieMut::from(&mut db, roots[0])
+            .unwrap()
+            .iter()
+            .count(),
+        50
+    );
+    assert_eq!(
+        collect_garbage(&mut db, &[roots[0], roots[4], roots[5]]).unwrap(),
+        0
+    );
+
+    // Pinning holds against releases too, and unpinning deletes the root.
+    assert!(db.pin(roots[5]));
+    assert!(db.dereference(&roots[5]));
+    assert!(db.contains(&roots[5]));
+    assert!(db.unpin(&roots[0]));
+    assert!(!db.unpin(&roots[0]));
+    assert!(!db.contains(&roots[0]));
+    assert_eq!(collect_garbage(&mut db, &[roots[4], roots[5]]).unwrap(), 0);
+}
+
+#[test]
+fn test_to_dot() {
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    trie.insert(b"doe", b"reindeer".to_vec()).unwrap();
+    trie.insert(b"dog", b"puppy".to_vec()).unwrap();
+    trie.insert(b"dogglesworth", b"cat".to_vec()).unwrap();
+    trie.root().unwrap();
+
+    let dot = trie.to_dot().unwrap();
+    assert!(dot.starts_with("digraph trie {"));
+    assert!(dot.trim_end().ends_with('}'));
+    assert!(dot.contains("extension\\nprefix: 646f6"));
+    assert!(dot.contains("leaf\\nkey: \\nvalue: 0x7265696e.."));
+    assert!(dot.contains("[label=\"7\"]"));
+    assert!(dot.contains("hash: 0x"));
+    assert!(!dot.contains("..."));
+
+    let shallow = trie.to_dot_at(&[], Some(1)).unwrap();
+    assert!(shallow.contains("..."));
+    assert!(!shallow.contains("leaf"));
+
+    let subtree = trie.to_dot_at(&[6, 4, 6, 15, 6, 7], None).unwrap();
+    assert!(!subtree.contains("reindeer"));
+    assert!(!subtree.contains("0x7265696e"));
+    assert!(subtree.contains("0x70757070.."));
+}
+
+#[test]
+fn test_format_tree() {
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    assert_eq!(trie.format_tree().unwrap(), "empty\n");
+
+    trie.insert(b"do", b"verb".to_vec()).unwrap();
+    trie.insert(b"dog", b"puppy".to_vec()).unwrap();
+    trie.insert(b"horse", b"stallion".to_vec()).unwrap();
+
+    let expected = "\
+extension [6]
+└── branch
+    ├── 4: extension [6f]
+    │   └── branch = 0x76657262
+    │       └── 6: leaf [7] = 0x70757070..
+    └── 8: leaf [6f727365] = 0x7374616c..
+";
+    assert_eq!(trie.format_tree().unwrap(), expected);
+}
+
+#[test]
+fn test_node_at_path() {
+    use ethtrie::{NodeKind, NodeReference};
+
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    let empty = trie.node_at_path(&[]).unwrap().unwrap();
+    assert_eq!(empty.kind, NodeKind::Empty);
+    assert_eq!(empty.encoded, vec![0x80]);
+    assert_eq!(empty.reference, NodeReference::Hash(keccak256(&[0x80])));
+
+    trie.insert(b"do", b"verb".to_vec()).unwrap();
+    trie.insert(b"dog", b"puppy".to_vec()).unwrap();
+    trie.insert(b"horse", [b's'; 40].to_vec()).unwrap();
+    // Uncommitted nodes are reported as they will be committed.
+    let uncommitted = trie.node_at_path(&[]).unwrap().unwrap();
+    let root = trie.root().unwrap();
+    assert_eq!(uncommitted.reference, NodeReference::Hash(root));
+
+    let info = trie.node_at_path(&[]).unwrap().unwrap();
+    assert_eq!(info, uncommitted);
+    assert_eq!(info.kind, NodeKind::Extension);
+    assert_eq!(info.partial, vec![6]);
+    assert_eq!(Some(info.encoded), memdb.get(&root));
+
+    let trie = PatriciaTrieMut::from(&mut memdb, root).unwrap();
+    let branch = trie.node_at_path(&[6]).unwrap().unwrap();
+    assert_eq!(branch.kind, NodeKind::Branch);
+    assert_eq!(branch.value, None);
+    let nibbles: Vec<_> = branch.children.iter().map(|c| c.nibbles.clone()).collect();
+    assert_eq!(nibbles, vec![vec![4], vec![8]]);
+
+    let horse = trie.node_at_path(&[6, 8]).unwrap().unwrap();
+    assert_eq!(horse.kind, NodeKind::Leaf);
+    assert_eq!(horse.partial, vec![6, 15, 7, 2, 7, 3, 6, 5]);
+    assert_eq!(horse.value, Some([b's'; 40].to_vec()));
+    assert_eq!(horse.reference, branch.children[1].reference);
+    assert!(matches!(horse.reference, NodeReference::Hash(_)));
+
+    let ext = trie.node_at_path(&[6, 4]).unwrap().unwrap();
+    assert_eq!(ext.kind, NodeKind::Extension);
+    assert_eq!(ext.partial, vec![6, 15]);
+    let verb = trie.node_at_path(&[6, 4, 6, 15]).unwrap().unwrap();
+    assert_eq!(verb.kind, NodeKind::Branch);
+    assert_eq!(verb.value, Some(b"verb".to_vec()));
+    let dog = trie.node_at_path(&[6, 4, 6, 15, 6]).unwrap().unwrap();
+    assert_eq!(dog.kind, NodeKind::Leaf);
+    assert_eq!(dog.partial, vec![7]);
+    assert_eq!(dog.reference, NodeReference::Inline);
+
+    // Paths ending inside a prefix or at an empty slot lead to no node.
+    assert_eq!(trie.node_at_path(&[6, 4, 6]).unwrap(), None);
+    assert_eq!(trie.node_at_path(&[7]).unwrap(), None);
+    assert_eq!(trie.node_at_path(&[6, 5]).unwrap(), None);
+    assert!(matches!(
+        trie.node_at_path(&[16]),
+        Err(TrieError::InvalidData)
+    ));
+}
+
+#[test]
+fn test_trie_cursor() {
+    use ethtrie::NodeKind;
+
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    trie.insert(b"do", b"verb".to_vec()).unwrap();
+    trie.insert(b"dog", b"puppy".to_vec()).unwrap();
+    trie.insert(b"horse", [b's'; 40].to_vec()).unwrap();
+    let root = trie.root().unwrap();
+
+    let trie = PatriciaTrieMut::from(&mut memdb, root).unwrap();
+    let mut cursor = trie.cursor().unwrap();
+    assert_eq!(cursor.kind(), NodeKind::Extension);
+    assert_eq!(cursor.hash(), Some(root));
+    assert_eq!(Some(cursor.encoded()), trie.hashdb().get(&root));
+    assert!(!cursor.ascend());
+
+    assert!(cursor.seek(&[6, 8]).unwrap());
+    assert_eq!(cursor.kind(), NodeKind::Leaf);
+    assert_eq!(cursor.depth(), 2);
+    assert_eq!(cursor.key(), Some(b"horse".to_vec()));
+    assert_eq!(cursor.hash(), Some(keccak256(&cursor.encoded())));
+    assert!(cursor.ascend());
+    assert_eq!(cursor.path(), &[6]);
+    assert_eq!(cursor.kind(), NodeKind::Branch);
+    assert!(!cursor.descend(5).unwrap());
+    assert!(cursor.descend(4).unwrap());
+    assert_eq!(cursor.kind(), NodeKind::Extension);
+    assert!(cursor.descend(6).unwrap());
+    assert_eq!(cursor.path(), &[6, 4, 6, 15]);
+    assert_eq!(cursor.value(), Some(&b"verb"[..]));
+    assert!(cursor.descend(6).unwrap());
+    assert_eq!(cursor.value(), Some(&b"puppy"[..]));
+    // The leaf is embedded in its parent.
+    assert_eq!(cursor.hash(), None);
+
+    // A path ending inside a prefix leaves the cursor above it.
+    assert!(!cursor.seek(&[6, 4, 6]).unwrap());
+    assert_eq!(cursor.path(), &[6, 4]);
+    assert!(cursor.seek(&[16]).is_err());
+
+    // Stepping through the values visits them in key order, both ways.
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    let mut rng = thread_rng();
+    for _ in 0..300 {
+        let len = rng.gen_range(1, 6);
+        let key: Vec<u8> = (0..len).map(|_| rng.gen_range(0, 4)).collect();
+        trie.insert(&key, key.clone()).unwrap();
+    }
+    let keys: Vec<_> = trie.iter().map(|(key, _)| key).collect();
+    let mut cursor = trie.cursor().unwrap();
+    let mut forward = Vec::new();
+    while cursor.next_leaf().unwrap() {
+        assert_eq!(cursor.value(), cursor.key().as_deref());
+        forward.push(cursor.key().unwrap());
+    }
+    assert_eq!(forward, keys);
+    let last = cursor.path().to_vec();
+    assert!(!cursor.next_leaf().unwrap());
+    assert_eq!(cursor.path(), &last[..]);
+
+    let mut backward = vec![cursor.key().unwrap()];
+    while cursor.prev_leaf().unwrap() {
+        backward.push(cursor.key().unwrap());
+    }
+    backward.reverse();
+    assert_eq!(backward, keys);
+
+    let empty = PatriciaTrieMut::new(&mut memdb);
+    let mut cursor = empty.cursor().unwrap();
+    assert_eq!(cursor.kind(), NodeKind::Empty);
+    assert_eq!(cursor.hash(), Some(keccak256(&rlp::NULL_RLP)));
+    assert!(!cursor.next_leaf().unwrap());
+}
+
+#[test]
+fn test_subtrie() {
+    let slot = |i: u32| keccak256(&i.to_be_bytes()).0;
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    let mut storage = Vec::new();
+    for (owner, slots) in [(b"owner-a", 100u32), (b"owner-b", 1), (b"owner-c", 30)] {
+        for i in 0..slots {
+            let mut key = owner.to_vec();
+            key.extend_from_slice(&slot(i));
+            trie.insert(&key, vec![owner[6]; 40]).unwrap();
+            if owner == b"owner-a" {
+                storage.push((slot(i), vec![owner[6]; 40]));
+            }
+        }
+    }
+    // A prefix ending inside the path of a node re-roots the node below it.
+    let expected = |entries: &[([u8; 32], Vec<u8>)], cut: usize| {
+        let mut db = MemoryDB::new(true);
+        let mut expected = PatriciaTrieMut::new(&mut db);
+        for (key, value) in entries {
+            expected.insert(&key[cut..], value.clone()).unwrap();
+        }
+        expected.root().unwrap()
+    };
+
+    for committed in [false, true] {
+        if committed {
+            trie.root().unwrap();
+        }
+        let (root, nodes) = trie.subtrie(b"owner-a").unwrap();
+        assert_eq!(root, expected(&storage, 0));
+        assert_eq!(nodes[0].0, root);
+        let mut db = MemoryDB::new(true);
+        for (hash, data) in nodes {
+            assert_eq!(keccak256(&data), hash);
+            db.insert(hash, data);
+        }
+        let sub = PatriciaTrieMut::from(&mut db, root).unwrap();
+        assert_eq!(sub.iter().count(), storage.len());
+        assert_eq!(sub.get(&storage[7].0).unwrap(), Some(storage[7].1.clone()));
+    }
+
+    // The single entry of a prefix becomes a leaf at the root.
+    let (root, nodes) = trie.subtrie(b"owner-b").unwrap();
+    assert_eq!(root, expected(&[(slot(0), vec![b'b'; 40])], 0));
+    assert_eq!(nodes.len(), 1);
+
+    let mut key = b"owner-a".to_vec();
+    key.push(storage[3].0[0]);
+    let under: Vec<_> = storage
+        .iter()
+        .filter(|(slot, _)| slot[0] == storage[3].0[0])
+        .cloned()
+        .collect();
+    assert_eq!(trie.subtrie(&key).unwrap().0, expected(&under, 1));
+
+    let (root, nodes) = trie.subtrie(b"owner-z").unwrap();
+    assert_eq!(root, keccak256(&[0x80]));
+    assert_eq!(nodes, vec![(root, vec![0x80])]);
+    let whole = trie.root().unwrap();
+    assert_eq!(trie.subtrie(b"").unwrap().0, whole);
+}
+
+#[test]
+fn test_count_prefix() {
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    for (owner, slots) in [(b"owner-a", 300u32), (b"owner-b", 1), (b"owner-c", 40)] {
+        for i in 0..slots {
+            let mut key = owner.to_vec();
+            key.extend_from_slice(keccak256(&i.to_be_bytes()).as_bytes());
+            trie.insert(&key, vec![1; 40]).unwrap();
+        }
+    }
+    // A key that is a prefix of others is held by a branch.
+    trie.insert(b"owner-c", vec![2]).unwrap();
+
+    for committed in [false, true] {
+        if committed {
+            trie.root().unwrap();
+        }
+        assert_eq!(trie.count_prefix(b"owner-a", None).unwrap(), 300);
+        assert_eq!(trie.count_prefix(b"owner-b", None).unwrap(), 1);
+        assert_eq!(trie.count_prefix(b"owner-c", None).unwrap(), 41);
+        assert_eq!(trie.count_prefix(b"owner", None).unwrap(), 342);
+        assert_eq!(trie.count_prefix(b"", None).unwrap(), 342);
+        assert_eq!(trie.count_prefix(b"owner-z", None).unwrap(), 0);
+        assert_eq!(trie.count_prefix(b"owner-a", Some(10)).unwrap(), 10);
+        assert_eq!(trie.count_prefix(b"owner-b", Some(10)).unwrap(), 1);
+        assert_eq!(trie.count_prefix(b"owner-a", Some(0)).unwrap(), 0);
+    }
+}
+
+#[test]
+fn test_sample_keys() {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    let mut rng = StdRng::seed_from_u64(7);
+    assert!(trie.sample_keys(10, || rng.gen()).unwrap().is_empty());
+
+    let keys: Vec<_> = (0..64u32).map(|i| keccak256(&i.to_be_bytes())).collect();
+    for key in keys.iter() {
+        trie.insert(key.as_bytes(), vec![1; 40]).unwrap();
+    }
+    // A key that is a prefix of others is held by a branch.
+    trie.insert(&keys[0][..31], vec![2]).unwrap();
+    trie.root().unwrap();
+
+    let mut counts = HashMap::new();
+    for key in trie.sample_keys(6500, || rng.gen()).unwrap() {
+        assert!(trie.contains(&key).unwrap());
+        *counts.entry(key).or_insert(0) += 1;
+    }
+    // Every key is picked, none of them far less often than the 100 times of a
+    // uniform pick.
+    assert_eq!(counts.len(), 65);
+    assert!(counts.values().all(|count| *count > 20));
+}
+
+#[test]
+fn test_diff_nodes() {
+    let key = |i: u32| keccak256(&i.to_be_bytes()).0;
+    let mut memdb = MemoryDB::new(false);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    for i in 0..500u32 {
+        trie.insert(&key(i), vec![1; 40]).unwrap();
+    }
+    let root_a = trie.root().unwrap();
+    let (_, nodes_a) = trie.subtrie(b"").unwrap();
+    for i in 0..20u32 {
+        trie.insert(&key(i * 7), vec![2; 40]).unwrap();
+        trie.remove(&key(i * 11 + 1)).unwrap();
+    }
+    trie.insert(&key(1000), vec![3; 40]).unwrap();
+    let root_b = trie.root().unwrap();
+    let (_, nodes_b) = trie.subtrie(b"").unwrap();
+    let entries_b = trie.iter().count();
+
+    let set_a: HashSet<H256> = nodes_a.iter().map(|(hash, _)| *hash).collect();
+    let set_b: HashSet<H256> = nodes_b.iter().map(|(hash, _)| *hash).collect();
+    let diff = diff_nodes(&memdb, root_a, root_b).unwrap();
+    assert_eq!(diff.added[0], root_b);
+    assert_eq!(diff.removed[0], root_a);
+    assert_eq!(
+        diff.added.iter().copied().collect::<HashSet<_>>(),
+        &set_b - &set_a
+    );
+    assert_eq!(
+        diff.removed.iter().copied().collect::<HashSet<_>>(),
+        &set_a - &set_b
+    );
+    assert!(diff.added.len() < set_b.len() / 2);
+
+    // The nodes of the first root and the added ones make up the second.
+    let mut synced = MemoryDB::new(true);
+    for (hash, data) in nodes_a {
+        synced.insert(hash, data);
+    }
+    for hash in diff.added.iter() {
+        synced.insert(*hash, memdb.get(hash).unwrap());
+    }
+    let trie = PatriciaTrieMut::from(&mut synced, root_b).unwrap();
+    assert_eq!(trie.iter().count(), entries_b);
+
+    assert_eq!(
+        diff_nodes(&memdb, root_b, root_b).unwrap(),
+        NodeDiff::default()
+    );
+    let empty = keccak256(&rlp::NULL_RLP);
+    let diff = diff_nodes(&memdb, empty, root_a).unwrap();
+    assert_eq!(diff.added.len(), set_a.len());
+    assert_eq!(diff.removed, vec![empty]);
+}
+
+#[test]
+fn test_trie_contains_after_commit() {
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    trie.insert(b"do", b"verb".to_vec()).unwrap();
+    trie.insert(b"dog", b"puppy".to_vec()).unwrap();
+    trie.insert(b"doge", b"coin".to_vec()).unwrap();
+    trie.insert(b"horse", b"stallion".to_vec()).unwrap();
+    trie.root().unwrap();
+
+    for key in [&b"do"[..], b"dog", b"doge", b"horse"].iter() {
+        assert!(trie.contains(key).unwrap());
+    }
+    for key in [&b"d"[..], b"dogs", b"hors", b"horses", b""].iter() {
+        assert!(!trie.contains(key).unwrap());
+    }
+}
+
+#[test]
+fn test_root_journal_revert() {
+    let mut memdb = MemoryDB::new(true);
+    let mut journal = RootJournal::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+
+    trie.insert(b"test", b"test".to_vec()).unwrap();
+    trie.insert(b"test1", b"test1".to_vec()).unwrap();
+    let root1 = journal.commit(&mut trie).unwrap();
+
+    trie.insert(b"test2", b"test2".to_vec()).unwrap();
+    trie.remove(b"test").unwrap();
+    let root2 = journal.commit(&mut trie).unwrap();
+
+    trie.insert(b"test3", [3u8; 40].to_vec()).unwrap();
+    let root3 = journal.commit(&mut trie).unwrap();
+    trie.insert(b"uncommitted", b"value".to_vec()).unwrap();
+
+    assert_eq!(
+        journal.roots().copied().collect::<Vec<_>>(),
+        vec![root1, root2, root3]
+    );
+    assert!(journal.revert_to(&mut trie, H256::zero()).is_err());
+
+    journal.revert_to(&mut trie, root2).unwrap();
+    assert_eq!(journal.latest(), Some(root2));
+    assert_eq!(trie.get(b"test3").unwrap(), None);
+    assert_eq!(trie.get(b"uncommitted").unwrap(), None);
+    assert_eq!(trie.get(b"test2").unwrap(), Some(b"test2".to_vec()));
+
+    journal.revert_to(&mut trie, root1).unwrap();
+    assert_eq!(trie.get(b"test").unwrap(), Some(b"test".to_vec()));
+    assert_eq!(trie.get(b"test2").unwrap(), None);
+    assert_eq!(trie.root().unwrap(), root1);
+
+    assert!(!memdb.contains(&root2));
+    assert!(!memdb.contains(&root3));
+}
+
+#[test]
+fn test_fork_does_not_affect_original() {
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    for i in 0..100u8 {
+        trie.insert(&[i, i / 2], vec![i; 40]).unwrap();
+    }
+    let root = trie.root().unwrap();
+    trie.insert(b"pending", b"value".to_vec()).unwrap();
+
+    let expected = {
+        let mut memdb = MemoryDB::new(true);
+        let mut expected = PatriciaTrieMut::new(&mut memdb);
+        for i in 0..100u8 {
+            if i % 3 != 0 {
+                expected.insert(&[i, i / 2], vec![i; 40]).unwrap();
+            }
+        }
+        expected.insert(b"pending", b"forked".to_vec()).unwrap();
+        expected.insert(b"new", b"new".to_vec()).unwrap();
+        expected.root().unwrap()
+    };
+
+    let fork_root = {
+        let mut fork = trie.fork();
+        for i in (0..100u8).step_by(3) {
+            assert!(fork.remove(&[i, i / 2]).unwrap());
+        }
+        fork.insert(b"pending", b"forked".to_vec()).unwrap();
+        fork.insert(b"new", b"new".to_vec()).unwrap();
+        assert_eq!(fork.get(b"pending").unwrap(), Some(b"forked".to_vec()));
+        assert_eq!(fork.get(&[0, 0]).unwrap(), None);
+        assert_eq!(fork.iter().count(), 68);
+        fork.root_hash()
+    };
+    assert_eq!(fork_root, expected);
+
+    for i in 0..100u8 {
+        assert_eq!(trie.get(&[i, i / 2]).unwrap(), Some(vec![i; 40]));
+    }
+    assert_eq!(trie.get(b"pending").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(trie.get(b"new").unwrap(), None);
+    trie.remove(b"pending").unwrap();
+    assert_eq!(trie.root().unwrap(), root);
+}
+
+#[test]
+fn test_remove_branch_value_collapses_branch() {
+    let expected = {
+        let mut memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrieMut::new(&mut memdb);
+        trie.insert(b"dog", b"puppy".to_vec()).unwrap();
+        trie.root().unwrap()
+    };
+
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    trie.insert(b"do", b"verb".to_vec()).unwrap();
+    trie.insert(b"dog", b"puppy".to_vec()).unwrap();
+    trie.root().unwrap();
+    assert!(trie.remove(b"do").unwrap());
+    assert!(!trie.remove(b"do").unwrap());
+    assert_eq!(trie.root().unwrap(), expected);
+}
+
+#[test]
+fn test_iterator_unaffected_by_later_mutation() {
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    let mut kv = HashMap::new();
+    for i in 0..50u8 {
+        kv.insert(vec![i, 0xaa], vec![i; 8]);
+    }
+    kv.iter()
+        .for_each(|(k, v)| trie.insert(k, v.clone()).unwrap());
+
+    let mut fork = trie.fork();
+    let iter = fork.iter();
+    for i in 0..50u8 {
+        fork.insert(&[i, 0xaa], vec![0xff; 8]).unwrap();
+        fork.insert(&[i, 0xbb], vec![0xff; 8]).unwrap();
+        fork.remove(&[i / 2, 0xaa]).unwrap();
+    }
+
+    iter.for_each(|(k, v)| assert_eq!(kv.remove(&k).unwrap(), v));
+    assert!(kv.is_empty());
+}
+
+#[test]
+fn test_trie_into_iter() {
+    use ethtrie::{MemoryBlobStore, PatriciaTrie};
+
+    let mut rng = thread_rng();
+    let mut memdb = MemoryDB::new(true);
+    let store = MemoryBlobStore::new();
+    let mut trie = PatriciaTrieMut::new(&mut memdb).with_blob_store(store.clone(), 64);
+    for i in 0..300u32 {
+        let key: Vec<u8> = (0..rng.gen_range(1, 5))
+            .map(|_| rng.gen_range(0, 4))
+            .collect();
+        let mut value = key.clone();
+        value.extend(vec![i as u8; rng.gen_range(1, 100)]);
+        trie.insert(&key, value).unwrap();
+    }
+    let expected: Vec<_> = trie.iter().collect();
+    // Uncommitted nodes held in memory.
+    let pending: PatriciaTrie<_> = (&trie).into();
+    assert!(pending.into_iter().eq(expected.clone()));
+    let root = trie.root().unwrap();
+
+    let committed = PatriciaTrie::from(&memdb, root)
+        .unwrap()
+        .with_blob_store(store, 64);
+    let shared = committed.clone();
+    assert!(committed.into_iter().eq(expected.clone()));
+    // Nodes shared with another trie are left intact.
+    assert!(shared.clone().into_iter().eq(expected.clone()));
+    assert!(shared.iter().eq(expected.clone()));
+    assert_eq!(PatriciaTrie::new(&memdb).into_iter().count(), 0);
+
+    // A value missing from the blob store ends the iteration, with its error for
+    // `try_into_iter`.
+    let missing = PatriciaTrie::from(&memdb, root)
+        .unwrap()
+        .with_blob_store(MemoryBlobStore::new(), 64);
+    assert!(missing.clone().into_iter().count() < expected.len());
+    let mut iter = missing.try_into_iter();
+    assert!(iter.by_ref().any(|entry| matches!(
+        entry,
+        Err(TrieError::AtKey { ref error, .. }) if matches!(**error, TrieError::MissingBlob(_))
+    )));
+    assert!(iter.next().is_none());
+}
+
+#[test]
+fn test_get_or_insert_with() {
+    use ethtrie::{EmptyValues, MemoryBlobStore, TrieBuilder};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let mut rng = thread_rng();
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    let mut expected = HashMap::new();
+    for i in 0..300u32 {
+        let key: Vec<u8> = (0..rng.gen_range(1, 4))
+            .map(|_| rng.gen_range(0, 6))
+            .collect();
+        let value = [key.as_slice(), &i.to_be_bytes()].concat();
+        let held = expected.entry(key.clone()).or_insert_with(|| value.clone());
+        let called = Cell::new(false);
+        let got = trie
+            .get_or_insert_with(&key, || {
+                called.set(true);
+                value.clone()
+            })
+            .unwrap();
+        assert_eq!(&got, held);
+        assert_eq!(called.get(), *held == value);
+        if i % 50 == 0 {
+            trie.root().unwrap();
+        }
+    }
+    let root = trie.root().unwrap();
+    assert_eq!(root, ethtrie::trie_root(expected.clone()));
+
+    // Values found leave the trie and the db as they were, and the root committed
+    // last stands, so committing again neither re-encodes nor re-verifies the trie.
+    let mut nodes = memdb.keys();
+    nodes.sort();
+    let accesses = Arc::new(AtomicUsize::new(0));
+    let counter = accesses.clone();
+    let mut trie = PatriciaTrieMut::from(&mut memdb, root)
+        .unwrap()
+        .with_node_cache_capacity(0)
+        .with_shadow_verification()
+        .with_db_hook(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+    for (key, value) in &expected {
+        assert_eq!(
+            &trie.get_or_insert_with(key, || unreachable!()).unwrap(),
+            value
+        );
+    }
+    accesses.store(0, Ordering::SeqCst);
+    assert_eq!(trie.root().unwrap(), root);
+    assert_eq!(accesses.load(Ordering::SeqCst), 0);
+    let mut after = memdb.keys();
+    after.sort();
+    assert_eq!(after, nodes);
+
+    let store = MemoryBlobStore::new();
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb).with_blob_store(store, 8);
+    let long = vec![7u8; 100];
+    assert_eq!(
+        trie.get_or_insert_with(b"key", || long.clone()).unwrap(),
+        long
+    );
+    assert_eq!(trie.get_or_insert_with(b"key", Vec::new).unwrap(), long);
+
+    // An empty default is not inserted.
+    assert_eq!(
+        trie.get_or_insert_with(b"other", Vec::new).unwrap(),
+        Vec::<u8>::new()
+    );
+    assert!(!trie.contains(b"other").unwrap());
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = TrieBuilder::new()
+        .with_empty_values(EmptyValues::Reject)
+        .build_mut(&mut memdb);
+    assert!(matches!(
+        trie.get_or_insert_with(b"key", Vec::new),
+        Err(TrieError::AtKey { ref key, .. }) if key == b"key"
+    ));
+    assert!(trie.iter().next().is_none());
+}
+
+#[test]
+fn test_extend_trie() {
+    use ethtrie::{EmptyValues, TrieBuilder};
+
+    let entries: Vec<_> = (0..200u32)
+        .map(|i| (i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec()))
+        .collect();
+    let expected = ethtrie::trie_root(entries.clone());
+
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    trie.try_extend(entries[..100].iter().cloned()).unwrap();
+    trie.try_extend(
+        entries[100..]
+            .iter()
+            .filter(|(key, _)| key[3] % 2 == 0)
+            .cloned(),
+    )
+    .unwrap();
+    trie.try_extend(entries[100..].iter().map(|(k, v)| (k, v.clone())))
+        .unwrap();
+    assert_eq!(trie.root().unwrap(), expected);
+
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::from_entries(&mut memdb, entries.clone()).unwrap();
+    assert!(trie.iter().eq(entries.clone()));
+    assert_eq!(trie.root().unwrap(), expected);
+
+    // Entries before the failing one stay in the trie.
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = TrieBuilder::new()
+        .with_empty_values(EmptyValues::Reject)
+        .build_mut(&mut memdb);
+    let failing = vec![
+        (b"a".to_vec(), b"1".to_vec()),
+        (b"b".to_vec(), Vec::new()),
+        (b"c".to_vec(), b"3".to_vec()),
+    ];
+    assert!(matches!(
+        trie.try_extend(failing.clone()),
+        Err(TrieError::AtKey { ref key, .. }) if key == b"b"
+    ));
+    assert_eq!(trie.get(b"a").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(trie.get(b"c").unwrap(), None);
+}
+
+#[test]
+fn test_iter_uncommitted_changes() {
+    use std::collections::BTreeMap;
+
+    let mut rng = thread_rng();
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb).with_memory_limit(4 * 1024);
+    assert_eq!(trie.iter_committed().unwrap().count(), 0);
+
+    let mut pending = BTreeMap::new();
+    let mut committed = BTreeMap::new();
+    for round in 0..20u8 {
+        for _ in 0..40 {
+            let key: Vec<u8> = (0..rng.gen_range(1, 4))
+                .map(|_| rng.gen_range(0, 8))
+                .collect();
+            if rng.gen_range(0, 3) == 0 {
+                trie.remove(&key).unwrap();
+                pending.remove(&key);
+            } else {
+                // Values are unique, so that pruning never drops a node still used
+                // elsewhere.
+                let mut value = key.clone();
+                value.extend(vec![round; rng.gen_range(1, 40)]);
+                trie.insert(&key, value.clone()).unwrap();
+                pending.insert(key, value);
+            }
+        }
+        // Entries flushed by the memory limit and entries only held in memory alike.
+        assert!(trie.iter().eq(pending.clone()));
+        assert!(trie.iter_committed().unwrap().eq(committed.clone()));
+        if round % 3 == 0 {
+            trie.root().unwrap();
+            committed = pending.clone();
+        }
+    }
+    let root = trie.root().unwrap();
+    let mut trie = PatriciaTrieMut::from(&mut memdb, root).unwrap();
+    trie.remove(pending.keys().next().unwrap()).unwrap();
+    assert!(trie.iter_committed().unwrap().eq(pending));
+}
+
+#[test]
+fn test_get_pending_and_committed() {
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb).with_memory_limit(1024);
+    trie.insert(b"a", b"1".to_vec()).unwrap();
+    assert_eq!(trie.get_pending(b"a").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(trie.get_committed(b"a").unwrap(), None);
+    trie.root().unwrap();
+    assert_eq!(trie.get_committed(b"a").unwrap(), Some(b"1".to_vec()));
+
+    // Writes flushed by the memory limit are still pending.
+    for i in 0..100u32 {
+        trie.insert(&i.to_be_bytes(), vec![i as u8; 40]).unwrap();
+    }
+    trie.insert(b"a", b"2".to_vec()).unwrap();
+    trie.remove(b"a").unwrap();
+    assert_eq!(trie.get_pending(b"a").unwrap(), None);
+    assert_eq!(trie.get_committed(b"a").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(
+        trie.get_pending(&7u32.to_be_bytes()).unwrap(),
+        Some(vec![7; 40])
+    );
+    assert_eq!(trie.get_committed(&7u32.to_be_bytes()).unwrap(), None);
+
+    trie.begin();
+    trie.insert(b"b", b"3".to_vec()).unwrap();
+    trie.rollback();
+    assert_eq!(trie.get_pending(b"b").unwrap(), None);
+    let new_root = trie.root().unwrap();
+    assert_eq!(trie.get_committed(b"a").unwrap(), None);
+
+    let mut trie = PatriciaTrieMut::from(&mut memdb, new_root).unwrap();
+    trie.insert(&7u32.to_be_bytes(), vec![0; 40]).unwrap();
+    assert_eq!(
+        trie.get_committed(&7u32.to_be_bytes()).unwrap(),
+        Some(vec![7; 40])
+    );
+}
+
+#[derive(Default)]
+struct CountingDB {
+    inner: MemoryDB,
+    gets: Cell<usize>,
+    inserts: usize,
+}
+
+impl HashDB for CountingDB {
+    fn get(&self, key: &H256) -> Option<Vec<u8>> {
+        self.gets.set(self.gets.get() + 1);
+        self.inner.get(key)
+    }
+
+    fn contains(&self, key: &H256) -> bool {
+        self.inner.contains(key)
+    }
+
+    fn insert(&mut self, key: H256, value: Vec<u8>) {
+        self.inserts += 1;
+        self.inner.insert(key, value)
+    }
+
+    fn remove(&mut self, key: &H256) {
+        self.inner.remove(key)
+    }
+
+    fn flush(&mut self) {}
+}
+
+#[test]
+fn test_root_only_rehashes_dirty_paths() {
+    let mut db = CountingDB::default();
+    let mut trie = PatriciaTrieMut::new(&mut db);
+    for i in 0..200u32 {
+        let key = keccak256(&i.to_be_bytes());
+        trie.insert(key.as_bytes(), key.as_bytes().to_vec())
+            .unwrap();
+    }
+    let root = trie.root().unwrap();
+
+    // Walks and resolves a full path without changing anything.
+    let mut missing = keccak256(&0u32.to_be_bytes());
+    missing.0[31] ^= 1;
+    assert!(!trie.remove(missing.as_bytes()).unwrap());
+    let inserts = trie.hashdb().inserts;
+    assert_eq!(trie.root().unwrap(), root);
+    assert_eq!(trie.hashdb().inserts, inserts);
+
+    // Only the nodes on the modified path are written again.
+    trie.insert(missing.as_bytes(), b"value".to_vec()).unwrap();
+    trie.root().unwrap();
+    assert!(trie.hashdb().inserts - inserts < 8);
+
+    // Untouched subtries stay hashes across commits and are never read back.
+    let key = keccak256(&1u32.to_be_bytes());
+    trie.insert(key.as_bytes(), b"value".to_vec()).unwrap();
+    let (gets, inserts) = (trie.hashdb().gets.get(), trie.hashdb().inserts);
+    trie.root().unwrap();
+    assert_eq!(trie.hashdb().gets.get(), gets);
+    assert!(trie.hashdb().inserts - inserts < 8);
+}
+
+#[test]
+fn test_noop_remove_keeps_nodes_when_pruning() {
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    for i in 0..200u32 {
+        let key = keccak256(&i.to_be_bytes());
+        trie.insert(key.as_bytes(), key.as_bytes().to_vec())
+            .unwrap();
+    }
+    let root = trie.root().unwrap();
+
+    let mut missing = keccak256(&0u32.to_be_bytes());
+    missing.0[31] ^= 1;
+    assert!(!trie.remove(missing.as_bytes()).unwrap());
+    assert_eq!(trie.root().unwrap(), root);
+
+    let trie = PatriciaTrieMut::from(&mut memdb, root).unwrap();
+    for i in 0..200u32 {
+        let key = keccak256(&i.to_be_bytes());
+        assert_eq!(
+            trie.get(key.as_bytes()).unwrap(),
+            Some(key.as_bytes().to_vec())
+        );
+    }
+}
+
+#[test]
+fn test_node_cache_avoids_db_reads() {
+    let mut db = CountingDB::default();
+    let mut trie = PatriciaTrieMut::new(&mut db);
+    for i in 0..200u32 {
+        let key = keccak256(&i.to_be_bytes());
+        trie.insert(key.as_bytes(), key.as_bytes().to_vec())
+            .unwrap();
+    }
+    let root = trie.root().unwrap();
+    let key = keccak256(&7u32.to_be_bytes());
+
+    let trie = PatriciaTrieMut::from(&mut db, root).unwrap();
+    trie.get(key.as_bytes()).unwrap();
+    let gets = trie.hashdb().gets.get();
+    assert!(gets > 0);
+    trie.get(key.as_bytes()).unwrap();
+    assert_eq!(trie.hashdb().gets.get(), gets);
+
+    let trie = PatriciaTrieMut::from(&mut db, root)
+        .unwrap()
+        .with_node_cache_capacity(0);
+    trie.get(key.as_bytes()).unwrap();
+    let gets = trie.hashdb().gets.get();
+    trie.get(key.as_bytes()).unwrap();
+    assert!(trie.hashdb().gets.get() > gets);
+}
+
+#[test]
+fn test_deep_trie_insert_get_remove() {
+    // Every key is a prefix of the next, which nests a branch per nibble.
+    let keys: Vec<Vec<u8>> = (0..1000).map(|len| vec![0xffu8; len]).collect();
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    for key in keys.iter() {
+        trie.insert(key, b"value".to_vec()).unwrap();
+    }
+    for key in keys.iter() {
+        assert_eq!(trie.get(key).unwrap(), Some(b"value".to_vec()));
+    }
+    for key in keys.iter().rev() {
+        assert!(trie.remove(key).unwrap());
+        assert!(!trie.contains(key).unwrap());
+    }
+}
+
+#[test]
+fn test_update_after_lookups_from_db() {
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    for i in 0..100u32 {
+        trie.insert(&i.to_be_bytes(), i.to_le_bytes().to_vec())
+            .unwrap();
+    }
+    let root = trie.root().unwrap();
+
+    // Lookups leave the nodes they read encoded in the cache, updates decode them.
+    let mut trie = PatriciaTrieMut::from(&mut memdb, root).unwrap();
+    for i in 0..100u32 {
+        assert_eq!(
+            trie.get(&i.to_be_bytes()).unwrap(),
+            Some(i.to_le_bytes().to_vec())
+        );
+    }
+    assert!(!trie.contains(&100u32.to_be_bytes()).unwrap());
+    trie.insert(&100u32.to_be_bytes(), b"new".to_vec()).unwrap();
+    assert!(trie.remove(&100u32.to_be_bytes()).unwrap());
+    assert_eq!(trie.root().unwrap(), root);
+    assert_eq!(trie.iter().count(), 100);
+}
+
+#[test]
+fn test_repeated_root_skips_commit() {
+    let mut db = CountingDB::default();
+    let mut trie = PatriciaTrieMut::new(&mut db);
+    trie.insert(b"k", b"v".to_vec()).unwrap();
+    let root = trie.root().unwrap();
+
+    let (gets, inserts) = (trie.hashdb().gets.get(), trie.hashdb().inserts);
+    assert_eq!(trie.root().unwrap(), root);
+    assert_eq!(trie.hashdb().gets.get(), gets);
+    assert_eq!(trie.hashdb().inserts, inserts);
+
+    trie.insert(b"k2", b"v2".to_vec()).unwrap();
+    assert_ne!(trie.root().unwrap(), root);
+    trie.remove(b"k2").unwrap();
+    assert_eq!(trie.root().unwrap(), root);
+}
+
+#[test]
+fn test_custom_hasher() {
+    // Keccak over the reversed input, standing in for another hash function.
+    struct ReversedKeccak;
+
+    impl Hasher for ReversedKeccak {
+        fn hash(data: &[u8]) -> H256 {
+            let reversed: Vec<u8> = data.iter().rev().copied().collect();
+            keccak256(&reversed)
+        }
+    }
+
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::<_, ReversedKeccak>::new_with_hasher(&mut memdb);
+    for i in 0..50u32 {
+        trie.insert(&i.to_be_bytes(), vec![i as u8; 40]).unwrap();
+    }
+    let root = trie.root().unwrap();
+    let proof = trie.get_proof(&7u32.to_be_bytes()).unwrap();
+    assert_eq!(
+        trie.verify_proof(root, &7u32.to_be_bytes(), proof.clone())
+            .unwrap(),
+        Some(vec![7; 40])
+    );
+
+    let mut keccak_db = MemoryDB::new(true);
+    let mut keccak_trie = PatriciaTrieMut::new(&mut keccak_db);
+    for i in 0..50u32 {
+        keccak_trie
+            .insert(&i.to_be_bytes(), vec![i as u8; 40])
+            .unwrap();
+    }
+    assert_ne!(keccak_trie.root().unwrap(), root);
+    assert!(keccak_trie
+        .verify_proof(root, &7u32.to_be_bytes(), proof)
+        .is_err());
+
+    let trie = PatriciaTrieMut::<_, ReversedKeccak>::from_with_hasher(&mut memdb, root).unwrap();
+    assert_eq!(trie.get(&49u32.to_be_bytes()).unwrap(), Some(vec![49; 40]));
+}
+
+#[test]
+fn test_nibble_path_api() {
+    fn nibbles(key: &[u8]) -> Vec<u8> {
+        key.iter().flat_map(|b| vec![b >> 4, b & 0x0f]).collect()
+    }
+
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    trie.insert(b"dog", b"puppy".to_vec()).unwrap();
+    trie.insert_nibbles(&nibbles(b"doge"), b"coin".to_vec())
+        .unwrap();
+
+    assert_eq!(
+        trie.get_nibbles(&nibbles(b"dog")).unwrap(),
+        Some(b"puppy".to_vec())
+    );
+    assert_eq!(trie.get(b"doge").unwrap(), Some(b"coin".to_vec()));
+    assert_eq!(trie.get_nibbles(&nibbles(b"do")).unwrap(), None);
+    assert!(trie.get_nibbles(&[1, 2, 3]).is_err());
+    assert!(trie.insert_nibbles(&[1, 16], b"v".to_vec()).is_err());
+
+    assert!(trie.remove_nibbles(&nibbles(b"dog")).unwrap());
+    assert_eq!(trie.get(b"dog").unwrap(), None);
+    assert_eq!(
+        trie.iter().collect::<Vec<_>>(),
+        vec![(b"doge".to_vec(), b"coin".to_vec())]
+    );
+}
+
+#[test]
+fn test_nibbles() {
+    // The hex-prefix examples of the yellow paper.
+    let cases: [(&[u8], &[u8]); 4] = [
+        (&[1, 2, 3, 4, 5], &[0x11, 0x23, 0x45]),
+        (&[0, 1, 2, 3, 4, 5], &[0x00, 0x01, 0x23, 0x45]),
+        (&[0, 15, 1, 12, 11, 8, 16], &[0x20, 0x0f, 0x1c, 0xb8]),
+        (&[15, 1, 12, 11, 8, 16], &[0x3f, 0x1c, 0xb8]),
+    ];
+    for (hex, compact) in cases {
+        let nibbles = Nibbles::from_hex(hex);
+        assert_eq!(nibbles.encode_compact(), compact);
+        assert_eq!(Nibbles::from_compact(compact).unwrap(), nibbles);
+    }
+    assert_eq!(Nibbles::default().encode_compact(), [0x00]);
+    assert!(matches!(
+        Nibbles::from_compact(&[0x4f]),
+        Err(TrieError::InvalidCompactEncoding)
+    ));
+
+    let key = Nibbles::from_raw(b"dog", true);
+    assert_eq!(key.get_data(), [6, 4, 6, 15, 6, 7, 16]);
+    assert!(key.is_leaf());
+    assert_eq!(key.encode_raw(), (b"dog".to_vec(), true));
+    let other = Nibbles::from_raw(b"doge", true);
+    assert_eq!(key.common_prefix(&other), 6);
+    assert_eq!(key.slice(2, 4).join(&key.offset(4)), key.offset(2));
+    assert!(!key.slice(0, 6).is_leaf());
+
+    assert_eq!(Nibbles::try_from_hex(key.get_data()).unwrap(), key);
+    assert_eq!(Nibbles::try_from_hex(&[]).unwrap(), Nibbles::default());
+    for hex in [&[1, 17][..], &[16, 1], &[16, 16], &[0xff]] {
+        assert!(matches!(
+            Nibbles::try_from_hex(hex),
+            Err(TrieError::InvalidData)
+        ));
+    }
+}
+
+#[test]
+fn test_apply_changes() {
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    let changes = (0..100u32).map(|i| (i.to_be_bytes().to_vec(), Some(vec![i as u8 + 1])));
+    trie.apply_changes(changes).unwrap();
+
+    let root = trie
+        .apply_changes(vec![
+            (5u32.to_be_bytes().to_vec(), None),
+            (7u32.to_be_bytes().to_vec(), Some(b"first".to_vec())),
+            (200u32.to_be_bytes().to_vec(), Some(b"new".to_vec())),
+            (7u32.to_be_bytes().to_vec(), Some(b"second".to_vec())),
+        ])
+        .unwrap();
+    assert_eq!(trie.get(&5u32.to_be_bytes()).unwrap(), None);
+    assert_eq!(
+        trie.get(&7u32.to_be_bytes()).unwrap(),
+        Some(b"second".to_vec())
+    );
+
+    let mut expected_db = MemoryDB::new(true);
+    let mut expected = PatriciaTrieMut::new(&mut expected_db);
+    for i in (0..100u32).filter(|i| *i != 5) {
+        expected
+            .insert(&i.to_be_bytes(), vec![i as u8 + 1])
+            .unwrap();
+    }
+    expected
+        .insert(&7u32.to_be_bytes(), b"second".to_vec())
+        .unwrap();
+    expected
+        .insert(&200u32.to_be_bytes(), b"new".to_vec())
+        .unwrap();
+    assert_eq!(expected.root().unwrap(), root);
+
+    // Nothing is applied while a checkpoint is open.
+    trie.begin();
+    assert!(matches!(
+        trie.apply_changes(vec![(b"a".to_vec(), None)]),
+        Err(TrieError::OpenCheckpoints(1))
+    ));
+    assert!(trie.rollback());
+    assert_eq!(trie.root().unwrap(), root);
+
+    // A rejected value fails before anything is changed.
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = ethtrie::TrieBuilder::new()
+        .with_empty_values(ethtrie::EmptyValues::Reject)
+        .build_mut(&mut memdb);
+    trie.insert(b"kept", b"value".to_vec()).unwrap();
+    let root = trie.root().unwrap();
+    let result = trie.apply_changes(vec![
+        (b"a".to_vec(), Some(b"1".to_vec())),
+        (b"kept".to_vec(), None),
+        (b"z".to_vec(), Some(Vec::new())),
+    ]);
+    assert!(matches!(result, Err(TrieError::AtKey { ref key, .. }) if key == b"z"));
+    assert_eq!(trie.get(b"a").unwrap(), None);
+    assert_eq!(trie.get(b"kept").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(trie.root().unwrap(), root);
+
+    // A change failing midway rolls back the ones before it.
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    let keys: Vec<_> = (0..100u32).map(|i| keccak256(&i.to_be_bytes())).collect();
+    for key in &keys {
+        trie.insert(key.as_bytes(), vec![1]).unwrap();
+    }
+    let root = trie.root().unwrap();
+    let proof = trie.get_proof(keys[0].as_bytes()).unwrap();
+    drop(trie);
+    memdb.remove(&keccak256(&proof[1]));
+    let mut trie = PatriciaTrieMut::from(&mut memdb, root).unwrap();
+    let changes = keys.iter().map(|key| (key.0.to_vec(), Some(vec![2])));
+    assert!(matches!(
+        root_cause(trie.apply_changes(changes)),
+        Err(TrieError::MissingNode(_))
+    ));
+    let unchanged = keys
+        .iter()
+        .filter_map(|key| trie.get(key.as_bytes()).ok())
+        .all(|value| value == Some(vec![1]));
+    assert!(unchanged);
+    assert_eq!(trie.root().unwrap(), root);
+}
+
+#[test]
+fn test_apply_changes_matches_one_by_one() {
+    use ethtrie::DbAccess;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    // Short keys over few bytes, so that keys are prefixes of others and branches
+    // hold values. Values start with their key, so that no two nodes are the same
+    // and pruning can be checked against a trie built from scratch.
+    let mut rng = StdRng::seed_from_u64(11);
+    let random_key = |rng: &mut StdRng| -> Vec<u8> {
+        let len = rng.gen_range(1, 4);
+        (0..len).map(|_| rng.gen_range(0, 4) * 0x11).collect()
+    };
+    for round in 0..50 {
+        let mut memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrieMut::new(&mut memdb);
+        let mut expected = BTreeMap::new();
+        for _ in 0..rng.gen_range(0, 40) {
+            let key = random_key(&mut rng);
+            let value = [&key[..], &[round; 40]].concat();
+            trie.insert(&key, value.clone()).unwrap();
+            expected.insert(key, value);
+        }
+        let root = trie.root().unwrap();
+
+        // Inserts, removals of present and absent keys, empty values and several
+        // changes to one key.
+        let mut changes = Vec::new();
+        for i in 0..rng.gen_range(1, 30) {
+            let key = random_key(&mut rng);
+            let value = match rng.gen_range(0, 3) {
+                0 => None,
+                1 => Some(Vec::new()),
+                _ => Some([&key[..], &vec![i as u8; rng.gen_range(1, 40)]].concat()),
+            };
+            changes.push((key, value));
+        }
+        for (key, value) in changes.iter().cloned() {
+            match value.filter(|value| !value.is_empty()) {
+                Some(value) => expected.insert(key, value),
+                None => expected.remove(&key),
+            };
+        }
+
+        // The trie is reopened so that every node is read from the db, once.
+        let reads = Arc::new(Mutex::new(Vec::new()));
+        let mut trie = {
+            let reads = reads.clone();
+            PatriciaTrieMut::from(&mut memdb, root)
+                .unwrap()
+                .with_db_hook(move |access| {
+                    if let DbAccess::Get { hash, .. } = access {
+                        reads.lock().unwrap().push(*hash);
+                    }
+                })
+        };
+        let new_root = trie.apply_changes(changes).unwrap();
+        let reads = reads.lock().unwrap().clone();
+        assert_eq!(reads.iter().collect::<HashSet<_>>().len(), reads.len());
+        assert_eq!(trie.iter().collect::<BTreeMap<_, _>>(), expected);
+
+        let mut expected_db = MemoryDB::new(true);
+        let mut expected_trie = PatriciaTrieMut::new(&mut expected_db);
+        for (key, value) in &expected {
+            expected_trie.insert(key, value.clone()).unwrap();
+        }
+        assert_eq!(new_root, expected_trie.root().unwrap());
+        drop(trie);
+        // Pruning kept the nodes of the new root and dropped the others.
+        assert_eq!(memdb.keys().len(), expected_db.keys().len());
+    }
+}
+
+#[test]
+fn test_checkpoints() {
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    for i in 0..100u32 {
+        trie.insert(&i.to_be_bytes(), vec![1; 40]).unwrap();
+    }
+    trie.root().unwrap();
+    trie.insert(b"outer", b"kept".to_vec()).unwrap();
+
+    trie.begin();
+    trie.insert(b"frame", b"1".to_vec()).unwrap();
+    trie.begin();
+    trie.remove(&7u32.to_be_bytes()).unwrap();
+    trie.insert(b"frame", b"2".to_vec()).unwrap();
+    trie.begin();
+    trie.insert(&8u32.to_be_bytes(), b"reverted".to_vec())
+        .unwrap();
+    assert_eq!(trie.checkpoint_depth(), 3);
+
+    // The innermost frame reverts, the middle one returns into the outer one.
+    assert!(trie.rollback());
+    assert_eq!(trie.get(&8u32.to_be_bytes()).unwrap(), Some(vec![1; 40]));
+    assert!(trie.commit_checkpoint());
+    assert_eq!(trie.get(b"frame").unwrap(), Some(b"2".to_vec()));
+    assert_eq!(trie.get(&7u32.to_be_bytes()).unwrap(), None);
+
+    // Reverting the outer frame drops the changes the middle one merged into it.
+    assert!(trie.rollback());
+    assert_eq!(trie.checkpoint_depth(), 0);
+    assert!(!trie.rollback());
+    assert!(!trie.commit_checkpoint());
+    assert_eq!(trie.get(b"frame").unwrap(), None);
+    assert_eq!(trie.get(&7u32.to_be_bytes()).unwrap(), Some(vec![1; 40]));
+    let root = trie.root().unwrap();
+
+    let mut expected = MemoryDB::new(true);
+    let mut expected = PatriciaTrieMut::new(&mut expected);
+    for i in 0..100u32 {
+        expected.insert(&i.to_be_bytes(), vec![1; 40]).unwrap();
+    }
+    expected.insert(b"outer", b"kept".to_vec()).unwrap();
+    assert_eq!(root, expected.root().unwrap());
+
+    // Rolling back to a committed state prunes nothing the state still needs.
+    trie.begin();
+    for i in 0..50u32 {
+        trie.remove(&i.to_be_bytes()).unwrap();
+    }
+    trie.rollback();
+    assert_eq!(trie.root().unwrap(), root);
+    drop(trie);
+    let trie = PatriciaTrieMut::from(&mut memdb, root).unwrap();
+    assert_eq!(trie.iter().count(), 101);
+
+    // A commit to the db is refused while checkpoints are open, and leaves them
+    // open to be rolled back.
+    let mut trie = PatriciaTrieMut::from(&mut memdb, root).unwrap();
+    trie.begin();
+    trie.insert(b"frame", b"3".to_vec()).unwrap();
+    trie.begin();
+    trie.insert(b"frame", b"4".to_vec()).unwrap();
+    assert!(matches!(trie.root(), Err(TrieError::OpenCheckpoints(2))));
+    assert_eq!(trie.checkpoint_depth(), 2);
+    assert!(trie.rollback());
+    assert!(matches!(trie.root(), Err(TrieError::OpenCheckpoints(1))));
+    assert!(trie.commit_checkpoint());
+    let committed = trie.root().unwrap();
+    assert_ne!(committed, root);
+    assert_eq!(trie.get(b"frame").unwrap(), Some(b"3".to_vec()));
+}
+
+#[test]
+fn test_apply_over_witness() {
+    use ethtrie::DbAccess;
+    use std::sync::{Arc, Mutex};
+
+    let key = |i: u32| keccak256(&i.to_be_bytes()).0.to_vec();
+    let mut memdb = MemoryDB::new(false);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    for i in 0..300u32 {
+        trie.insert(&key(i), vec![1; 40]).unwrap();
+    }
+    let prev_root = trie.root().unwrap();
+    let mut changes: Vec<_> = (0..10u32).map(|i| (key(i * 3), None)).collect();
+    changes.push((key(7), Some(vec![2; 40])));
+    changes.push((key(1000), Some(vec![3; 40])));
+
+    // The witness is made of the nodes a full node reads to apply the changes.
+    let reads = Arc::new(Mutex::new(vec![prev_root]));
+    let post_root = {
+        let reads = reads.clone();
+        PatriciaTrieMut::from(&mut memdb, prev_root)
+            .unwrap()
+            .with_db_hook(move |access| {
+                if let DbAccess::Get { hash, len: Some(_) } = access {
+                    reads.lock().unwrap().push(*hash);
+                }
+            })
+            .apply_changes(changes.clone())
+            .unwrap()
+    };
+    let witness: Vec<_> = reads
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|hash| memdb.get(hash).unwrap())
+        .collect();
+    assert!(witness.len() < memdb.keys().len() / 4);
+    assert_eq!(
+        apply_over_witness(prev_root, witness.clone(), changes.clone()).unwrap(),
+        post_root
+    );
+
+    // Any node missing from the witness makes the transition fail.
+    for missing in 0..witness.len() {
+        let mut partial = witness.clone();
+        let node = partial.remove(missing);
+        match apply_over_witness(prev_root, partial, changes.clone()) {
+            Err(e) => assert!(
+                matches!(e.root_cause(), TrieError::MissingNode(hash) if *hash == keccak256(&node))
+            ),
+            Ok(root) => panic!("applied without a node of the witness: {:?}", root),
+        }
+    }
+
+    let empty = keccak256(&rlp::NULL_RLP);
+    assert_eq!(
+        apply_over_witness(
+            empty,
+            Vec::new(),
+            vec![(b"dog".to_vec(), Some(b"puppy".to_vec()))]
+        )
+        .unwrap(),
+        trie_root([("dog", "puppy")])
+    );
+}
+
+#[test]
+fn test_memory_limit_flushes_early() {
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb).with_memory_limit(16 * 1024);
+    for i in 0..500u32 {
+        let key = keccak256(&i.to_be_bytes());
+        trie.insert(key.as_bytes(), key.as_bytes().to_vec())
+            .unwrap();
+    }
+    for i in (0..500u32).step_by(3) {
+        trie.remove(keccak256(&i.to_be_bytes()).as_bytes()).unwrap();
+    }
+    assert!(!trie.hashdb().keys().is_empty());
+    let usage = trie.memory_usage();
+    let root = trie.root().unwrap();
+    assert!(trie.memory_usage() < usage);
+
+    let mut expected_db = MemoryDB::new(true);
+    let mut expected = PatriciaTrieMut::new(&mut expected_db);
+    for i in (0..500u32).filter(|i| i % 3 != 0) {
+        let key = keccak256(&i.to_be_bytes());
+        expected
+            .insert(key.as_bytes(), key.as_bytes().to_vec())
+            .unwrap();
+    }
+    assert_eq!(expected.root().unwrap(), root);
+
+    // Nodes written by the flushes and replaced afterwards were pruned.
+    assert_eq!(collect_garbage(&mut memdb, &[root]).unwrap(), 0);
+}
+
+#[test]
+fn test_write_caching() {
+    use ethtrie::{DbAccess, PatriciaTrie};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let reads = Arc::new(AtomicUsize::new(0));
+    let hook = {
+        let reads = reads.clone();
+        move |access: &DbAccess| {
+            if let DbAccess::Get { .. } = access {
+                reads.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    };
+    let keys: Vec<H256> = (0..200u32).map(|i| keccak256(&i.to_be_bytes())).collect();
+    let reads_after_commit = |cached: bool| {
+        let mut memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::builder()
+            .with_memory_limit(8 * 1024)
+            .with_write_caching(cached)
+            .build_mut(&mut memdb)
+            .with_db_hook(hook.clone());
+        for key in &keys {
+            trie.insert(key.as_bytes(), vec![1; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        reads.store(0, Ordering::Relaxed);
+        for key in &keys {
+            assert_eq!(trie.get(key.as_bytes()).unwrap(), Some(vec![1; 40]));
+        }
+        assert_eq!(trie.root().unwrap(), root);
+        reads.load(Ordering::Relaxed)
+    };
+    assert!(reads_after_commit(false) > 0);
+    assert_eq!(reads_after_commit(true), 0);
+}
+
+#[test]
+fn test_shadow_verification() {
+    use ethtrie::NodeReference;
+
+    let mut rng = thread_rng();
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb).with_shadow_verification();
+    let mut keys = Vec::new();
+    for round in 0..10 {
+        for _ in 0..50 {
+            let len = rng.gen_range(1, 6);
+            let key: Vec<u8> = (0..len).map(|_| rng.gen_range(0, 4)).collect();
+            trie.insert(&key, vec![round; rng.gen_range(1, 40)])
+                .unwrap();
+            keys.push(key);
+        }
+        for key in keys.choose_multiple(&mut rng, 20) {
+            trie.remove(key).unwrap();
+        }
+        trie.root().unwrap();
+    }
+
+    let mut memdb = MemoryDB::new(false);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    for i in 0..200u32 {
+        let key = keccak256(&i.to_be_bytes());
+        trie.insert(key.as_bytes(), key.as_bytes().to_vec())
+            .unwrap();
+    }
+    let root = trie.root().unwrap();
+    let children = trie.node_at_path(&[]).unwrap().unwrap().children;
+    let hash = |i: usize| match children[i].reference {
+        NodeReference::Hash(hash) => hash,
+        NodeReference::Inline => panic!("inline child"),
+    };
+    let (corrupted, other) = (hash(3), hash(5));
+
+    // A corrupted db goes unnoticed by incremental hashing, which trusts the hashes
+    // of untouched subtries.
+    let data = memdb.get(&other).unwrap();
+    memdb.insert(corrupted, data);
+    let mut trie = PatriciaTrieMut::from(&mut memdb, root).unwrap();
+    trie.insert(&[0; 32], b"value".to_vec()).unwrap();
+    assert!(trie.root().is_ok());
+
+    let mut trie = PatriciaTrieMut::from(&mut memdb, root)
+        .unwrap()
+        .with_shadow_verification();
+    trie.insert(&[0; 32], b"value".to_vec()).unwrap();
+    match trie.root() {
+        Err(TrieError::RootMismatch {
+            path,
+            committed,
+            recomputed,
+        }) => {
+            assert_eq!(path, vec![3]);
+            assert_eq!(committed, corrupted);
+            assert_eq!(recomputed, other);
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn test_committed_levels_stay_in_memory() {
+    let mut db = CountingDB::default();
+    let mut trie = PatriciaTrieMut::new(&mut db)
+        .with_node_cache_capacity(0)
+        .with_retained_levels(1);
+    for i in 0..200u32 {
+        let key = keccak256(&i.to_be_bytes());
+        trie.insert(key.as_bytes(), key.as_bytes().to_vec())
+            .unwrap();
+    }
+    trie.root().unwrap();
+
+    // The root and its children are still decoded, only the rest is read back.
+    let gets = trie.hashdb().gets.get();
+    let key = keccak256(&7u32.to_be_bytes());
+    assert_eq!(
+        trie.get(key.as_bytes()).unwrap(),
+        Some(key.as_bytes().to_vec())
+    );
+    let reads = trie.hashdb().gets.get() - gets;
+    assert!(reads > 0 && reads < 3);
+
+    trie.remove(key.as_bytes()).unwrap();
+    let root = trie.root().unwrap();
+    let mut memdb = MemoryDB::new(true);
+    let mut expected = PatriciaTrieMut::new(&mut memdb);
+    for i in (0..200u32).filter(|i| *i != 7) {
+        let key = keccak256(&i.to_be_bytes());
+        expected
+            .insert(key.as_bytes(), key.as_bytes().to_vec())
+            .unwrap();
+    }
+    assert_eq!(expected.root().unwrap(), root);
+}
+
+#[test]
+fn test_keccak_vectors() {
+    // Run under each backend: tiny-keccak by default, then `--features sha3` and
+    // `--features keccak-asm`.
+    let vectors: [(&[u8], &str); 3] = [
+        (
+            b"",
+            "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470",
+        ),
+        (
+            &rlp::NULL_RLP,
+            "56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421",
+        ),
+        (
+            b"The quick brown fox jumps over the lazy dog",
+            "4d741b6f1eb29cb2a9b9911c82f56fa8d73b04959d3d9d222895df6c0b28aa15",
+        ),
+    ];
+    for (data, digest) in vectors.iter() {
+        assert_eq!(hex::encode(keccak256(data)), *digest);
+    }
+    // An input over several blocks of the sponge.
+    let long: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+    assert_eq!(
+        hex::encode(keccak256(&long)),
+        "af692982e84a5a9688359025660a7857cd28ee7c8d867cfa1677baf2e6d1f63b"
+    );
+
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    assert_eq!(trie.root().unwrap(), keccak256(&rlp::NULL_RLP));
+}
+
+#[test]
+fn test_short_digest_hasher() {
+    // Keccak truncated to 20 bytes, as used by chains with shorter digests.
+    struct ShortKeccak;
+
+    impl Hasher for ShortKeccak {
+        const LENGTH: usize = 20;
+
+        fn hash(data: &[u8]) -> H256 {
+            let mut hash = keccak256(data);
+            hash.0[20..].fill(0);
+            hash
+        }
+    }
+
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::<_, ShortKeccak>::new_with_hasher(&mut memdb);
+    // Small values give nodes between 20 and 32 bytes, which are now stored by hash.
+    for i in 0..100u32 {
+        trie.insert(&i.to_be_bytes(), vec![i as u8]).unwrap();
+    }
+    let root = trie.root().unwrap();
+    assert!(root.as_bytes()[20..].iter().all(|b| *b == 0));
+    let proof = trie.get_proof(&42u32.to_be_bytes()).unwrap();
+    assert_eq!(
+        trie.verify_proof(root, &42u32.to_be_bytes(), proof)
+            .unwrap(),
+        Some(vec![42])
+    );
+
+    let mut trie = PatriciaTrieMut::<_, ShortKeccak>::from_with_hasher(&mut memdb, root).unwrap();
+    for i in 0..100u32 {
+        assert_eq!(trie.get(&i.to_be_bytes()).unwrap(), Some(vec![i as u8]));
+    }
+    for i in 50..100u32 {
+        trie.remove(&i.to_be_bytes()).unwrap();
+    }
+    let root = trie.root().unwrap();
+
+    let mut expected_db = MemoryDB::new(true);
+    let mut expected = PatriciaTrieMut::<_, ShortKeccak>::new_with_hasher(&mut expected_db);
+    for i in 0..50u32 {
+        expected.insert(&i.to_be_bytes(), vec![i as u8]).unwrap();
+    }
+    assert_eq!(expected.root().unwrap(), root);
+    assert!(memdb
+        .keys()
+        .iter()
+        .all(|key| key.as_bytes()[20..] == [0; 12]));
+}
+
+#[cfg(feature = "poseidon")]
+#[test]
+fn test_poseidon_hasher() {
+    use ethtrie::PoseidonHasher;
+
+    assert_ne!(PoseidonHasher::hash(b""), PoseidonHasher::hash(&[0]));
+    assert_ne!(
+        PoseidonHasher::hash(&[0; 55]),
+        PoseidonHasher::hash(&[0; 56])
+    );
+    assert_eq!(PoseidonHasher::hash(b"abc"), PoseidonHasher::hash(b"abc"));
+
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::<_, PoseidonHasher>::new_with_hasher(&mut memdb);
+    for i in 0..100u32 {
+        trie.insert(&i.to_be_bytes(), vec![i as u8; 20]).unwrap();
+    }
+    let root = trie.root().unwrap();
+    assert_ne!(root, keccak256(&rlp::NULL_RLP));
+    let proof = trie.get_proof(&9u32.to_be_bytes()).unwrap();
+    assert_eq!(
+        trie.verify_proof(root, &9u32.to_be_bytes(), proof).unwrap(),
+        Some(vec![9; 20])
+    );
+
+    let trie = PatriciaTrieMut::<_, PoseidonHasher>::from_with_hasher(&mut memdb, root).unwrap();
+    assert_eq!(trie.get(&99u32.to_be_bytes()).unwrap(), Some(vec![99; 20]));
+}
+
+#[cfg(feature = "differential")]
+#[test]
+fn test_differential() {
+    use ethtrie::differential::{replay, Op};
+
+    let mut rng = thread_rng();
+    for _ in 0..200 {
+        // Few short keys, so that operations often hit the same entries and paths.
+        let keys: Vec<Vec<u8>> = (0..rng.gen_range(1, 40))
+            .map(|_| {
+                (0..rng.gen_range(0, 4))
+                    .map(|_| rng.gen_range(0, 4))
+                    .collect()
+            })
+            .collect();
+        let ops: Vec<Op> = (0..rng.gen_range(1, 200))
+            .map(|_| {
+                let key = keys.choose(&mut rng).unwrap().clone();
+                match rng.gen_range(0, 10) {
+                    0..=3 => {
+                        let len = *[0, 1, 31, 32, 33].choose(&mut rng).unwrap();
+                        Op::Insert(key, (0..len).map(|_| rng.gen()).collect())
+                    }
+                    4..=5 => Op::Remove(key),
+                    6 => Op::Get(key),
+                    7 => Op::Contains(key),
+                    _ => Op::Root,
+                }
+            })
+            .chain(Some(Op::Root))
+            .collect();
+        if let Err(divergence) = replay(&ops) {
+            panic!("{} in {:?}", divergence, ops);
+        }
+    }
+}
+
+#[test]
+fn test_typed_trie() {
+    #[derive(Debug, PartialEq)]
+    struct Account {
+        nonce: u64,
+        balance: u64,
+    }
+
+    impl rlp::Encodable for Account {
+        fn rlp_append(&self, s: &mut rlp::RlpStream) {
+            s.begin_list(2).append(&self.nonce).append(&self.balance);
+        }
+    }
+
+    impl rlp::Decodable for Account {
+        fn decode(rlp: &rlp::Rlp) -> Result<Self, rlp::DecoderError> {
+            Ok(Account {
+                nonce: rlp.val_at(0)?,
+                balance: rlp.val_at(1)?,
+            })
+        }
+    }
+
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = TypedTrie::<_, Account>::new(&mut memdb);
+    for i in 0..20u64 {
+        let account = Account {
+            nonce: i,
+            balance: i * 1000,
+        };
+        trie.insert(&i.to_be_bytes(), &account).unwrap();
+    }
+    assert_eq!(
+        trie.get(&3u64.to_be_bytes()).unwrap(),
+        Some(Account {
+            nonce: 3,
+            balance: 3000
+        })
+    );
+    assert_eq!(trie.get(b"missing").unwrap(), None);
+    assert_eq!(trie.iter().count(), 20);
+    let root = trie.root().unwrap();
+    let proof = trie.get_proof(&5u64.to_be_bytes()).unwrap();
+    assert_eq!(
+        trie.verify_proof(root, &5u64.to_be_bytes(), proof)
+            .unwrap()
+            .map(|account| account.balance),
+        Some(5000)
+    );
+
+    // Values that do not decode as the trie's type are reported, not skipped.
+    trie.trie_mut().insert(b"bad", vec![0xc1, 0x01]).unwrap();
+    assert!(trie.get(b"bad").is_err());
+    assert!(trie.iter().any(|entry| entry.is_err()));
+
+    let trie = TypedTrie::<_, Account>::from(&mut memdb, root).unwrap();
+    assert_eq!(trie.get(&19u64.to_be_bytes()).unwrap().unwrap().nonce, 19);
+}
+
+// A tag-length codec standing in for a non-Ethereum node encoding.
+struct TaggedCodec;
+
+impl TaggedCodec {
+    fn append_path(path: &[u8], out: &mut Vec<u8>) {
+        out.push(path.len() as u8);
+        // Odd paths start with a padding nibble.
+        let padded: Vec<u8> = std::iter::repeat_n(0, path.len() % 2)
+            .chain(path.iter().copied())
+            .collect();
+        out.extend(padded.chunks(2).map(|pair| (pair[0] << 4) | pair[1]));
+    }
+
+    fn append_child(child: ChildReference<'_>, out: &mut Vec<u8>, hashes: &mut Vec<usize>) {
+        match child {
+            ChildReference::Empty => out.push(0),
+            ChildReference::Hash(digest) => {
+                out.push(1);
+                hashes.push(out.len());
+                out.extend_from_slice(digest);
+            }
+            ChildReference::Inline(data) => {
+                out.push(2);
+                out.push(data.len() as u8);
+                out.extend_from_slice(data);
+            }
+        }
+    }
+
+    fn read_path(data: &[u8]) -> (PackedPath<'_>, &[u8]) {
+        let len = data[0] as usize;
+        let bytes = len.div_ceil(2);
+        let path = PackedPath {
+            data: &data[1..1 + bytes],
+            start: len % 2,
+        };
+        (path, &data[1 + bytes..])
+    }
+
+    fn read_child(data: &[u8]) -> (ChildReference<'_>, &[u8]) {
+        match data[0] {
+            0 => (ChildReference::Empty, &data[1..]),
+            1 => (ChildReference::Hash(&data[1..33]), &data[33..]),
+            _ => {
+                let len = data[1] as usize;
+                (ChildReference::Inline(&data[2..2 + len]), &data[2 + len..])
+            }
+        }
+    }
+}
+
+impl NodeCodec for TaggedCodec {
+    const EMPTY_NODE: &'static [u8] = &[0];
+
+    fn encode_leaf(path: &[u8], value: &[u8], out: &mut Vec<u8>) {
+        out.push(1);
+        Self::append_path(path, out);
+        out.extend_from_slice(value);
+    }
+
+    fn encode_extension(
+        path: &[u8],
+        child: ChildReference<'_>,
+        out: &mut Vec<u8>,
+        hashes: &mut Vec<usize>,
+    ) {
+        out.push(2);
+        Self::append_path(path, out);
+        Self::append_child(child, out, hashes);
+    }
+
+    fn encode_branch(
+        children: &[ChildReference<'_>; 16],
+        value: Option<&[u8]>,
+        out: &mut Vec<u8>,
+        hashes: &mut Vec<usize>,
+    ) {
+        out.push(3);
+        for child in children.iter() {
+            Self::append_child(*child, out, hashes);
+        }
+        if let Some(value) = value {
+            out.extend_from_slice(value);
+        }
+    }
+
+    fn decode(data: &[u8]) -> Result<DecodedNode<'_>, TrieError> {
+        Ok(match data[0] {
+            0 => DecodedNode::Empty,
+            1 => {
+                let (path, value) = Self::read_path(&data[1..]);
+                DecodedNode::Leaf(path, value)
+            }
+            2 => {
+                let (path, rest) = Self::read_path(&data[1..]);
+                DecodedNode::Extension(path, Self::read_child(rest).0)
+            }
+            _ => {
+                let mut children = [ChildReference::Empty; 16];
+                let mut rest = &data[1..];
+                for child in children.iter_mut() {
+                    let (read, tail) = Self::read_child(rest);
+                    *child = read;
+                    rest = tail;
+                }
+                let value = if rest.is_empty() { None } else { Some(rest) };
+                DecodedNode::Branch(children, value)
+            }
+        })
+    }
+}
+
+#[test]
+fn test_custom_node_codec() {
+    type TaggedTrie<'db> = PatriciaTrieMut<'db, MemoryDB, KeccakHasher, TaggedCodec>;
+
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = TaggedTrie::new_with_hasher(&mut memdb);
+    assert_eq!(trie.root().unwrap(), keccak256(&[0]));
+    // Keys like "key1" and "key10" give branches with values.
+    let keys: Vec<Vec<u8>> = (0..200).map(|i| format!("key{}", i).into_bytes()).collect();
+    for (i, key) in keys.iter().enumerate() {
+        trie.insert(key, vec![i as u8; i % 40]).unwrap();
+    }
+    let root = trie.root().unwrap();
+
+    let mut rlp_db = MemoryDB::new(true);
+    let mut rlp_trie = PatriciaTrieMut::new(&mut rlp_db);
+    for (i, key) in keys.iter().enumerate() {
+        rlp_trie.insert(key, vec![i as u8; i % 40]).unwrap();
+    }
+    assert_ne!(rlp_trie.root().unwrap(), root);
+
+    let proof = trie.get_proof(&keys[77]).unwrap();
+    assert_eq!(
+        trie.verify_proof(root, &keys[77], proof).unwrap(),
+        Some(vec![77; 37])
+    );
+
+    let mut trie = TaggedTrie::from_with_hasher(&mut memdb, root).unwrap();
+    // Inserting an empty value inserts nothing.
+    let mut expected: HashMap<Vec<u8>, Vec<u8>> = keys
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| i % 40 != 0)
+        .map(|(i, key)| (key.clone(), vec![i as u8; i % 40]))
+        .collect();
+    for (key, value) in expected.iter() {
+        assert_eq!(trie.get(key).unwrap().as_ref(), Some(value));
+    }
+    for key in keys.iter().step_by(2) {
+        trie.remove(key).unwrap();
+        expected.remove(key);
+    }
+    trie.root().unwrap();
+    assert_eq!(trie.iter().collect::<HashMap<_, _>>(), expected);
+}
+
+#[test]
+fn test_ordered_trie_root() {
+    let empty: Vec<Vec<u8>> = Vec::new();
+    assert_eq!(ordered_trie_root(empty), keccak256(&rlp::NULL_RLP));
+
+    let items: Vec<Vec<u8>> = (0..300u32)
+        .map(|i| format!("tx{}", i).into_bytes())
+        .collect();
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    for (i, item) in items.iter().enumerate() {
+        trie.insert(&rlp::encode(&i), item.clone()).unwrap();
+    }
+    assert_eq!(ordered_trie_root(&items), trie.root().unwrap());
+}
+
+#[test]
+fn test_trie_root() {
+    let empty: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+    assert_eq!(trie_root(empty), keccak256(&rlp::NULL_RLP));
+    let root = trie_root([
+        ("doe", "reindeer"),
+        ("dog", "puppy"),
+        ("dogglesworth", "cat"),
+    ]);
+    assert_eq!(
+        hex::encode(root),
+        "8aad789dff2f538bca5d8ea56e8abe10f4c7ba3a5dea95fea4cd6e7c3a1168d3"
+    );
+
+    let mut rng = thread_rng();
+    let mut entries = Vec::new();
+    for _ in 0..500 {
+        let key: Vec<u8> = (0..rng.gen_range(0, 5))
+            .map(|_| rng.gen_range(0, 4))
+            .collect();
+        let value = vec![rng.gen(); rng.gen_range(0, 40)];
+        entries.push((key, value));
+    }
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    for (key, value) in entries.iter() {
+        trie.insert(key, value.clone()).unwrap();
+    }
+    assert_eq!(trie_root(entries.clone()), trie.root().unwrap());
+
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    for (key, value) in entries.iter() {
+        trie.insert(keccak256(key).as_bytes(), value.clone())
+            .unwrap();
+    }
+    assert_eq!(sec_trie_root(entries), trie.root().unwrap());
+}
+
+#[test]
+fn test_state_trie() {
+    use ethtrie::{Address, U256};
+
+    let empty = Account::default();
+    assert_eq!(rlp::decode::<Account>(&rlp::encode(&empty)).unwrap(), empty);
+
+    let mut memdb = MemoryDB::new(false);
+    let mut state = StateTrie::new(&mut memdb);
+    let address = Address::repeat_byte(0x11);
+    assert_eq!(state.get_account(&address).unwrap(), None);
+
+    let mut storage = state.storage_trie(&address).unwrap();
+    storage.set(&U256::from(1), &U256::from(42)).unwrap();
+    let storage_root = storage.root().unwrap();
+
+    let account = Account {
+        nonce: 7,
+        balance: U256::from(10).pow(U256::from(30)),
+        storage_root,
+        ..Account::default()
+    };
+    state.set_account(&address, &account).unwrap();
+    state
+        .set_account(&Address::repeat_byte(0x22), &Account::default())
+        .unwrap();
+    let root = state.root().unwrap();
+    assert_eq!(state.get_account(&address).unwrap(), Some(account.clone()));
+    assert_eq!(state.iter().count(), 2);
+
+    let mut expected_db = MemoryDB::new(false);
+    let mut expected = PatriciaTrieMut::new(&mut expected_db);
+    expected
+        .insert(
+            keccak256(address.as_bytes()).as_bytes(),
+            rlp::encode(&account).to_vec(),
+        )
+        .unwrap();
+    expected
+        .insert(
+            keccak256(Address::repeat_byte(0x22).as_bytes()).as_bytes(),
+            rlp::encode(&Account::default()).to_vec(),
+        )
+        .unwrap();
+    assert_eq!(expected.root().unwrap(), root);
+
+    let mut state = StateTrie::from(&mut memdb, root).unwrap();
+    let storage = state.storage_trie(&address).unwrap();
+    assert_eq!(storage.get(&U256::from(1)).unwrap(), U256::from(42));
+    assert!(state.remove_account(&address).unwrap());
+    assert_eq!(state.get_account(&address).unwrap(), None);
+}
+
+#[test]
+fn test_code_store() {
+    use ethtrie::{Address, CodeStore};
+
+    let mut codes = CodeStore::new(MemoryDB::new(true));
+    let empty_hash = keccak256(&[]);
+    assert!(codes.contains(&empty_hash));
+    assert_eq!(codes.get(&empty_hash), Some(Vec::new()));
+    assert_eq!(codes.insert(Vec::new()), empty_hash);
+    assert!(codes.inner().keys().is_empty());
+
+    let mut memdb = MemoryDB::new(false);
+    let mut state = StateTrie::new(&mut memdb);
+    let contract = Address::repeat_byte(0x11);
+    let wallet = Address::repeat_byte(0x22);
+    state.set_account(&wallet, &Account::default()).unwrap();
+    assert_eq!(state.get_code(&contract, &codes).unwrap(), None);
+    assert_eq!(state.get_code(&wallet, &codes).unwrap(), Some(Vec::new()));
+
+    let code = vec![0x60, 0x00, 0x60, 0x00, 0xf3];
+    let code_hash = state.set_code(&contract, code.clone(), &mut codes).unwrap();
+    assert_eq!(code_hash, keccak256(&code));
+    assert!(codes.contains(&code_hash));
+    let account = state.get_account(&contract).unwrap().unwrap();
+    assert_eq!(account.code_hash, code_hash);
+    assert_eq!(state.get_code(&contract, &codes).unwrap(), Some(code));
+
+    // An account whose code was not stored cannot resolve it.
+    let root = state.root().unwrap();
+    let state = StateTrie::from(&mut memdb, root).unwrap();
+    let other = CodeStore::new(MemoryDB::new(true));
+    assert!(matches!(
+        state.get_code(&contract, &other),
+        Err(TrieError::MissingBlob(hash)) if hash == code_hash
+    ));
+}
+
+#[test]
+fn test_sec_trie_preimages() {
+    use ethtrie::{BlobStore, MemoryBlobStore, SecTrieDB, SecTrieDBMut};
+
+    let preimages = MemoryBlobStore::new();
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = SecTrieDBMut::new(&mut memdb).with_preimage_store(preimages.clone());
+    let mut expected = HashMap::new();
+    for i in 0..50u64 {
+        let key = H256::from_low_u64_be(i);
+        trie.insert(&key, vec![i as u8; 8]).unwrap();
+        expected.insert(key, vec![i as u8; 8]);
+    }
+    trie.remove(&H256::from_low_u64_be(3)).unwrap();
+    expected.remove(&H256::from_low_u64_be(3));
+    let root = trie.root().unwrap();
+
+    let entries: HashMap<_, _> = trie.iter_preimages().map(Result::unwrap).collect();
+    assert_eq!(entries, expected);
+
+    // Entries come in the order of the hashed keys.
+    let trie = SecTrieDB::from(&memdb, root)
+        .unwrap()
+        .with_preimage_store(preimages.clone());
+    let keys: Vec<H256> = trie.iter_preimages().map(|e| e.unwrap().0).collect();
+    let mut sorted = keys.clone();
+    sorted.sort_by_key(|key| keccak256(key.as_bytes()));
+    assert_eq!(keys, sorted);
+    assert_eq!(keys.len(), expected.len());
+
+    let hashed = keccak256(H256::from_low_u64_be(7).as_bytes());
+    assert!(SecTrieDB::from(&memdb, root)
+        .unwrap()
+        .iter_preimages()
+        .all(|entry| matches!(entry, Err(TrieError::MissingBlob(_)))));
+    let partial = MemoryBlobStore::new();
+    for key in expected.keys().filter(|key| key.to_low_u64_be() != 7) {
+        partial.insert(keccak256(key.as_bytes()), key.as_bytes().to_vec());
+    }
+    let trie = SecTrieDB::from(&memdb, root)
+        .unwrap()
+        .with_preimage_store(partial);
+    let missing: Vec<_> = trie.iter_preimages().filter_map(Result::err).collect();
+    assert!(matches!(missing[..], [TrieError::MissingBlob(hash)] if hash == hashed));
+}
+
+#[test]
+fn test_iterate_by_reference() {
+    use ethtrie::{PatriciaTrie, SecTrieDB, SecTrieDBMut, TrieDB, TrieDBMut};
+
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    for i in 0..40u8 {
+        trie.insert(&[i, i / 3], vec![i; 3]).unwrap();
+    }
+    let mut count = 0;
+    for (key, value) in &trie {
+        assert_eq!(value, vec![key[0]; 3]);
+        count += 1;
+    }
+    assert_eq!(count, 40);
+    let root = trie.root().unwrap();
+    let trie = PatriciaTrie::from(&memdb, root).unwrap();
+    assert!((&trie).into_iter().eq(trie.iter()));
+
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = TrieDBMut::new(&mut memdb);
+    for i in 0..40u64 {
+        trie.insert(&H256::from_low_u64_be(i), vec![i as u8; 3])
+            .unwrap();
+    }
+    let root = trie.root().unwrap();
+    trie.insert(&H256::from_low_u64_be(40), vec![40; 3])
+        .unwrap();
+    assert_eq!((&trie).into_iter().count(), 41);
+    assert_eq!(trie.iter_committed().unwrap().count(), 40);
+    let trie = TrieDB::from(&memdb, root).unwrap();
+    let mut keys = Vec::new();
+    for (key, value) in &trie {
+        assert_eq!(value, vec![key.to_low_u64_be() as u8; 3]);
+        keys.push(key);
+    }
+    assert_eq!(keys, (0..40).map(H256::from_low_u64_be).collect::<Vec<_>>());
+
+    // A key that is not a hash ends iteration: `iter` stops, `try_iter` and
+    // `for_each_entry` report the error after the same entries.
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    for i in 0..10u64 {
+        trie.insert(H256::from_low_u64_be(i).as_bytes(), vec![i as u8; 3])
+            .unwrap();
+    }
+    trie.insert(&[0xff; 31], b"short".to_vec()).unwrap();
+    let root = trie.root().unwrap();
+    let trie = TrieDB::from(&memdb, root).unwrap();
+    assert_eq!(trie.iter().count(), 10);
+    let listed = trie.try_iter().take_while(Result::is_ok).count();
+    let mut visited = 0;
+    let error = trie.for_each_entry(|_, _| visited += 1).unwrap_err();
+    assert!(matches!(error, TrieError::AtKey { ref key, .. } if key == &[0xff; 31]));
+    assert_eq!((visited, listed), (10, 10));
+    let mut iter = trie.try_iter();
+    assert!(matches!(
+        iter.find_map(Result::err),
+        Some(TrieError::AtKey { ref key, .. }) if key == &[0xff; 31]
+    ));
+    assert!(iter.next().is_none());
+
+    // Secure tries yield the hashed keys, in their order.
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = SecTrieDBMut::new(&mut memdb);
+    for i in 0..40u64 {
+        trie.insert(&H256::from_low_u64_be(i), vec![i as u8; 3])
+            .unwrap();
+    }
+    let expected: HashSet<_> = (0..40u64)
+        .map(|i| keccak256(H256::from_low_u64_be(i).as_bytes()))
+        .collect();
+    assert_eq!(
+        (&trie)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect::<HashSet<_>>(),
+        expected
+    );
+    let root = trie.root().unwrap();
+    let trie = SecTrieDB::from(&memdb, root).unwrap();
+    let keys: Vec<_> = (&trie).into_iter().map(|(key, _)| key).collect();
+    let mut sorted = keys.clone();
+    sorted.sort();
+    assert_eq!(keys, sorted);
+    let mut entries = 0;
+    trie.for_each_entry(|key, value| {
+        assert!(expected.contains(key));
+        assert_eq!(value.len(), 3);
+        entries += 1;
+    })
+    .unwrap();
+    assert_eq!(entries, 40);
+}
+
+#[test]
+fn test_storage_trie() {
+    use ethtrie::U256;
+
+    let mut memdb = MemoryDB::new(true);
+    let mut storage = StorageTrie::new(&mut memdb);
+    assert_eq!(storage.get(&U256::from(5)).unwrap(), U256::zero());
+    storage.set(&U256::from(5), &U256::from(0x1234)).unwrap();
+    storage.set(&U256::MAX, &U256::MAX).unwrap();
+    storage.set(&U256::from(6), &U256::from(1)).unwrap();
+    assert_eq!(storage.get(&U256::MAX).unwrap(), U256::MAX);
+
+    // Values are stored as RLP without leading zeros, under the hash of the slot.
+    let mut slot = H256::zero();
+    slot.0[31] = 5;
+    assert_eq!(
+        storage.trie().get(&slot).unwrap(),
+        Some(vec![0x82, 0x12, 0x34])
+    );
+
+    // Setting a slot to zero deletes it.
+    storage.set(&U256::from(6), &U256::zero()).unwrap();
+    assert_eq!(storage.trie().get(&H256::from_low_u64_be(6)).unwrap(), None);
+    let root = storage.root().unwrap();
+
+    let mut expected_db = MemoryDB::new(true);
+    let mut expected = StorageTrie::new(&mut expected_db);
+    expected.set(&U256::MAX, &U256::MAX).unwrap();
+    expected.set(&U256::from(5), &U256::from(0x1234)).unwrap();
+    assert_eq!(expected.root().unwrap(), root);
+
+    let mut storage = StorageTrie::from(&mut memdb, root).unwrap();
+    storage.set(&U256::from(5), &U256::zero()).unwrap();
+    storage.set(&U256::MAX, &U256::zero()).unwrap();
+    assert_eq!(storage.root().unwrap(), keccak256(&rlp::NULL_RLP));
+}
+
+#[test]
+fn test_shared_storage_nodes() {
+    use ethtrie::{Address, U256};
+
+    // Two contracts with the same storage share its nodes in the db of the state.
+    let mut memdb = MemoryDB::new(true);
+    let mut state = StateTrie::new(&mut memdb);
+    let (first, second) = (Address::repeat_byte(1), Address::repeat_byte(2));
+    for address in [first, second] {
+        let mut storage = state.storage_trie(&address).unwrap();
+        for slot in 0..20u64 {
+            storage
+                .set(&U256::from(slot), &U256::from(slot + 1))
+                .unwrap();
+        }
+        let storage_root = storage.root().unwrap();
+        let account = Account {
+            storage_root,
+            ..Account::default()
+        };
+        state.set_account(&address, &account).unwrap();
+    }
+
+    let mut storage = state.storage_trie(&first).unwrap();
+    storage.set(&U256::from(3), &U256::from(100)).unwrap();
+    let storage_root = storage.root().unwrap();
+    let account = Account {
+        storage_root,
+        ..Account::default()
+    };
+    state.set_account(&first, &account).unwrap();
+    let root = state.root().unwrap();
+
+    let mut state = StateTrie::from(&mut memdb, root).unwrap();
+    let storage = state.storage_trie(&second).unwrap();
+    for slot in 0..20u64 {
+        assert_eq!(
+            storage.get(&U256::from(slot)).unwrap(),
+            U256::from(slot + 1)
+        );
+    }
+}
+
+#[test]
+fn test_eip1186_proof() {
+    use ethtrie::{Address, U256};
+
+    let mut memdb = MemoryDB::new(true);
+    let mut state = StateTrie::new(&mut memdb);
+    let address = Address::repeat_byte(0xaa);
+    let mut storage = state.storage_trie(&address).unwrap();
+    for slot in 0..20u64 {
+        storage
+            .set(&U256::from(slot), &U256::from(slot * 1000 + 1))
+            .unwrap();
+    }
+    let storage_root = storage.root().unwrap();
+    let account = Account {
+        nonce: 1,
+        balance: U256::from(5000),
+        storage_root,
+        ..Account::default()
+    };
+    state.set_account(&address, &account).unwrap();
+    for i in 0..50u8 {
+        state
+            .set_account(&Address::repeat_byte(i), &Account::default())
+            .unwrap();
+    }
+    let root = state.root().unwrap();
+
+    let proof = state
+        .get_proof(&address, &[U256::from(3), U256::from(100)])
+        .unwrap();
+    assert_eq!(proof.account(), account);
+    assert_eq!(proof.storage_proof[0].value, U256::from(3001));
+    assert_eq!(proof.storage_proof[1].value, U256::zero());
+    proof.verify(root).unwrap();
+
+    let mut forged = proof.clone();
+    forged.balance = U256::from(5001);
+    assert!(forged.verify(root).is_err());
+    let mut forged = proof.clone();
+    forged.storage_proof[1].value = U256::from(1);
+    assert!(forged.verify(root).is_err());
+
+    let missing = state
+        .get_proof(&Address::repeat_byte(0xbb), &[U256::from(3)])
+        .unwrap();
+    missing.verify(root).unwrap();
+
+    let verify_slot = |proof: &ethtrie::EIP1186AccountProof, i: usize| {
+        verify_storage_slot(
+            root,
+            &proof.address,
+            &proof.storage_proof[i].key,
+            &proof.account_proof,
+            &proof.storage_proof[i].proof,
+        )
+    };
+    assert_eq!(verify_slot(&proof, 0), Some(U256::from(3001)));
+    assert_eq!(verify_slot(&proof, 1), Some(U256::zero()));
+    assert_eq!(verify_slot(&missing, 0), Some(U256::zero()));
+    let forged_root = keccak256(b"forged");
+    let slot = &proof.storage_proof[0];
+    assert_eq!(
+        verify_storage_slot(
+            forged_root,
+            &address,
+            &slot.key,
+            &proof.account_proof,
+            &slot.proof
+        ),
+        None
+    );
+
+    #[cfg(feature = "serde")]
+    {
+        let json = serde_json::to_value(&proof).unwrap();
+        assert_eq!(json["balance"], "0x1388");
+        assert_eq!(json["nonce"], "0x1");
+        assert_eq!(json["storageProof"][0]["key"], "0x3");
+        assert_eq!(json["address"], format!("0x{}", "aa".repeat(20)).as_str());
+        assert_eq!(
+            json["accountProof"][0],
+            format!("0x{}", hex::encode(&proof.account_proof[0])).as_str()
+        );
+        let decoded: ethtrie::EIP1186AccountProof = serde_json::from_value(json).unwrap();
+        assert_eq!(decoded, proof);
+
+        // Keys are often sent zero padded to 32 bytes.
+        let mut json = serde_json::to_value(&proof).unwrap();
+        json["storageProof"][0]["key"] = format!("0x{:064x}", 3).into();
+        let decoded: ethtrie::EIP1186AccountProof = serde_json::from_value(json).unwrap();
+        assert_eq!(decoded.storage_proof[0].key, U256::from(3));
+    }
+}
+
+#[test]
+fn test_genesis_state_root() {
+    use ethtrie::{Address, GenesisAccount, U256};
+    use std::collections::BTreeMap;
+
+    let empty: BTreeMap<Address, GenesisAccount> = BTreeMap::new();
+    assert_eq!(genesis_state_root(&empty), keccak256(&rlp::NULL_RLP));
+
+    let mut alloc = BTreeMap::new();
+    alloc.insert(
+        Address::repeat_byte(1),
+        GenesisAccount {
+            balance: U256::from(10).pow(U256::from(24)),
+            ..GenesisAccount::default()
+        },
+    );
+    let mut storage = BTreeMap::new();
+    storage.insert(U256::zero(), U256::from(0xbeef));
+    storage.insert(U256::from(1), U256::zero());
+    alloc.insert(
+        Address::repeat_byte(2),
+        GenesisAccount {
+            nonce: 1,
+            balance: U256::zero(),
+            code: vec![0x60, 0x00, 0x60, 0x00, 0xf3],
+            storage,
+        },
+    );
+    let root = genesis_state_root(&alloc);
+
+    // The same state built from raw tries.
+    let mut storage_db = MemoryDB::new(true);
+    let mut storage_trie = PatriciaTrieMut::new(&mut storage_db);
+    storage_trie
+        .insert(keccak256(&[0; 32]).as_bytes(), vec![0x82, 0xbe, 0xef])
+        .unwrap();
+    let storage_root = storage_trie.root().unwrap();
+
+    let mut memdb = MemoryDB::new(true);
+    let mut state = PatriciaTrieMut::new(&mut memdb);
+    let accounts = [
+        (
+            Address::repeat_byte(1),
+            Account {
+                balance: U256::from(10).pow(U256::from(24)),
+                ..Account::default()
+            },
+        ),
+        (
+            Address::repeat_byte(2),
+            Account {
+                nonce: 1,
+                balance: U256::zero(),
+                storage_root,
+                code_hash: keccak256(&[0x60, 0x00, 0x60, 0x00, 0xf3]),
+            },
+        ),
+    ];
+    for (address, account) in accounts.iter() {
+        state
+            .insert(
+                keccak256(address.as_bytes()).as_bytes(),
+                rlp::encode(account).to_vec(),
+            )
+            .unwrap();
+    }
+    assert_eq!(state.root().unwrap(), root);
+}
+
+#[test]
+fn test_withdrawals_root() {
+    use ethtrie::{withdrawals_root, Address, Withdrawal};
+
+    assert_eq!(withdrawals_root(&[]), keccak256(&rlp::NULL_RLP));
+
+    let withdrawals: Vec<Withdrawal> = (0..20u64)
+        .map(|i| Withdrawal {
+            index: 1000 + i,
+            validator_index: 50_000 + i * 7,
+            address: Address::repeat_byte(i as u8),
+            amount: 32_000_000_000 + i,
+        })
+        .collect();
+    let encoded = rlp::encode(&withdrawals[3]);
+    assert_eq!(rlp::decode::<Withdrawal>(&encoded).unwrap(), withdrawals[3]);
+
+    let items: Vec<Vec<u8>> = withdrawals
+        .iter()
+        .map(|w| {
+            let mut s = rlp::RlpStream::new_list(4);
+            s.append(&w.index)
+                .append(&w.validator_index)
+                .append(&w.address.as_bytes())
+                .append(&w.amount);
+            s.out().to_vec()
+        })
+        .collect();
+    assert_eq!(withdrawals_root(&withdrawals), ordered_trie_root(&items));
+}
+
+#[test]
+fn test_exclusion_proof() {
+    use ethtrie::verify_exclusion_proof;
+
+    let key = |i: u32| keccak256(&i.to_be_bytes()).0;
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    for i in 0..1000u32 {
+        trie.insert(&key(i), vec![1; 20]).unwrap();
+    }
+    let root = trie.root().unwrap();
+
+    let absent: Vec<_> = (1000..1100u32).map(key).collect();
+    let proof = trie.get_exclusion_proof(&absent).unwrap();
+    verify_exclusion_proof(root, &absent, &proof).unwrap();
+    // The keys share the nodes near the root.
+    let separate: usize = absent
+        .iter()
+        .map(|key| trie.get_proof(key).unwrap().len())
+        .sum();
+    assert!(proof.len() < separate / 2);
+
+    // The proof only covers the keys it was made for.
+    let mut more = absent.clone();
+    more.push(key(2000));
+    assert!(verify_exclusion_proof(root, &more, &proof).is_err());
+    // A present key cannot be proven absent.
+    let mut present = absent.clone();
+    present.push(key(7));
+    assert!(matches!(
+        trie.get_exclusion_proof(&present),
+        Err(TrieError::AtKey { .. })
+    ));
+    let inclusion = trie.get_proof(&key(7)).unwrap();
+    assert!(verify_exclusion_proof(root, &[key(7)], &inclusion).is_err());
+
+    // Nodes off the paths of the keys, or tampered with, are rejected.
+    let mut padded = proof.clone();
+    padded.extend(inclusion.iter().skip(1).cloned());
+    assert!(verify_exclusion_proof(root, &absent, &padded).is_err());
+    let mut tampered = proof.clone();
+    tampered.pop();
+    assert!(verify_exclusion_proof(root, &absent, &tampered).is_err());
+    assert!(verify_exclusion_proof(keccak256(b"other"), &absent, &proof).is_err());
+
+    // The proof items can come in any order.
+    let mut shuffled = proof.clone();
+    shuffled.shuffle(&mut thread_rng());
+    verify_exclusion_proof(root, &absent, &shuffled).unwrap();
+
+    let empty_root = keccak256(&rlp::NULL_RLP);
+    verify_exclusion_proof(empty_root, &absent, &[]).unwrap();
+}
+
+#[test]
+fn test_range_proof() {
+    use ethtrie::verify_range_proof;
+
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    let mut entries: Vec<(Vec<u8>, Vec<u8>)> = (0..200u32)
+        .map(|i| {
+            let key = keccak256(&i.to_be_bytes()).as_bytes().to_vec();
+            (key, format!("value{}", i).into_bytes())
+        })
+        .collect();
+    for (key, value) in entries.iter() {
+        trie.insert(key, value.clone()).unwrap();
+    }
+    let root = trie.root().unwrap();
+    entries.sort();
+    let keys: Vec<Vec<u8>> = entries.iter().map(|(k, _)| k.clone()).collect();
+    let values: Vec<Vec<u8>> = entries.iter().map(|(_, v)| v.clone()).collect();
+    let proof_of = |origin: &[u8], last: &[u8]| {
+        let mut proof = trie.get_proof(origin).unwrap();
+        proof.extend(trie.get_proof(last).unwrap());
+        proof
+    };
+
+    assert!(!verify_range_proof(root, &[], &keys, &values, &[]).unwrap());
+    assert!(verify_range_proof(root, &[], &keys[1..], &values[1..], &[]).is_err());
+
+    for (start, end) in [(0, 1), (0, 50), (10, 60), (150, 200), (199, 200)] {
+        let proof = proof_of(&keys[start], &keys[end - 1]);
+        let more = verify_range_proof(
+            root,
+            &keys[start],
+            &keys[start..end],
+            &values[start..end],
+            &proof,
+        )
+        .unwrap();
+        assert_eq!(more, end < 200);
+    }
+
+    // Origins that are not in the trie.
+    let zero = [0u8; 32];
+    let proof = proof_of(&zero, &keys[29]);
+    assert!(verify_range_proof(root, &zero, &keys[..30], &values[..30], &proof).unwrap());
+    let mut origin = keys[9].clone();
+    origin[31] = origin[31].wrapping_add(1);
+    let proof = proof_of(&origin, &keys[59]);
+    assert!(verify_range_proof(root, &origin, &keys[10..60], &values[10..60], &proof).unwrap());
+
+    // Incomplete, altered or unordered ranges are rejected.
+    let proof = proof_of(&keys[10], &keys[59]);
+    let mut missing = (keys[10..60].to_vec(), values[10..60].to_vec());
+    missing.0.remove(20);
+    missing.1.remove(20);
+    assert!(verify_range_proof(root, &keys[10], &missing.0, &missing.1, &proof).is_err());
+    assert!(verify_range_proof(root, &keys[10], &keys[11..60], &values[11..60], &proof).is_err());
+    let mut altered = values[10..60].to_vec();
+    altered[5] = b"forged".to_vec();
+    assert!(verify_range_proof(root, &keys[10], &keys[10..60], &altered, &proof).is_err());
+    let mut unordered = keys[10..60].to_vec();
+    unordered.swap(3, 4);
+    assert!(verify_range_proof(root, &keys[10], &unordered, &values[10..60], &proof).is_err());
+    assert!(verify_range_proof(root, &keys[11], &keys[10..60], &values[10..60], &proof).is_err());
+
+    // An empty range proves there is nothing after its origin.
+    let end = [0xffu8; 32];
+    let proof = trie.get_proof(&end).unwrap();
+    let none: &[Vec<u8>] = &[];
+    assert!(!verify_range_proof(root, &end, none, none, &proof).unwrap());
+    let proof = trie.get_proof(&zero).unwrap();
+    assert!(verify_range_proof(root, &zero, none, none, &proof).is_err());
+}
+
+#[test]
+fn test_snap_ranges() {
+    use ethtrie::{verify_account_range, verify_storage_ranges, Address, U256};
+
+    let mut memdb = MemoryDB::new(true);
+    let mut state = StateTrie::new(&mut memdb);
+    let mut storage_roots = Vec::new();
+    let mut storage_slots = Vec::new();
+    for i in 0..3u8 {
+        let address = Address::repeat_byte(i);
+        let mut storage = state.storage_trie(&address).unwrap();
+        for slot in 0..(20 * i as u64) {
+            storage
+                .set(&U256::from(slot), &U256::from(slot + 1))
+                .unwrap();
+        }
+        let storage_root = storage.root().unwrap();
+        let slots: Vec<(H256, U256)> = storage
+            .trie()
+            .trie()
+            .iter()
+            .map(|(key, value)| {
+                let value = rlp::Rlp::new(&value).data().unwrap().to_vec();
+                (key, U256::from_big_endian(&value))
+            })
+            .collect();
+        let account = Account {
+            nonce: i as u64,
+            storage_root,
+            ..Account::default()
+        };
+        state.set_account(&address, &account).unwrap();
+        storage_roots.push(storage_root);
+        storage_slots.push(slots);
+    }
+    for i in 3..40u8 {
+        state
+            .set_account(&Address::repeat_byte(i), &Account::default())
+            .unwrap();
+    }
+    let root = state.root().unwrap();
+
+    let accounts: Vec<(H256, Account)> = state.iter().map(|entry| entry.unwrap()).collect();
+    assert!(!verify_account_range(root, H256::zero(), &accounts, &[]).unwrap());
+    let mut proof = state.trie().get_proof(&H256::zero()).unwrap();
+    proof.extend(state.trie().get_proof(&accounts[19].0).unwrap());
+    assert!(verify_account_range(root, H256::zero(), &accounts[..20], &proof).unwrap());
+    let mut forged = accounts[..20].to_vec();
+    forged[3].1.nonce += 1;
+    assert!(verify_account_range(root, H256::zero(), &forged, &proof).is_err());
+
+    // The last account is cut short and proven, the others are complete.
+    let origin = H256::zero();
+    let mut slots = storage_slots.clone();
+    slots[2].truncate(15);
+    let proof = {
+        let storage = StorageTrie::from(state.trie_mut().hashdb_mut(), storage_roots[2]).unwrap();
+        let mut proof = storage.trie().trie().get_proof(&origin).unwrap();
+        proof.extend(storage.trie().trie().get_proof(&slots[2][14].0).unwrap());
+        proof
+    };
+    assert!(verify_storage_ranges(&storage_roots, origin, &slots, &proof).unwrap());
+    assert!(!verify_storage_ranges(&storage_roots, origin, &storage_slots, &[]).unwrap());
+    assert!(verify_storage_ranges(&storage_roots, origin, &slots, &[]).is_err());
+    slots[1].pop();
+    assert!(verify_storage_ranges(&storage_roots, origin, &slots, &proof).is_err());
+
+    let slim = rlp::encode_list::<Vec<u8>, _>(&[vec![7], vec![0x01, 0x00], vec![], vec![]]);
+    let account = Account::from_slim_rlp(&slim).unwrap();
+    assert_eq!(account.nonce, 7);
+    assert_eq!(account.balance, U256::from(256));
+    assert_eq!(account.storage_root, Account::default().storage_root);
+}
+
+#[cfg(feature = "hash-db")]
+#[test]
+fn test_parity_hash_db() {
+    use ethtrie::{ParityBackedDB, ParityHashDB, PatriciaTrie};
+    use hash_db::{HashDB as _, EMPTY_PREFIX};
+    use memory_db::HashKey;
+
+    struct Keccak;
+    impl hash_db::Hasher for Keccak {
+        type Out = H256;
+        type StdHasher = std::collections::hash_map::DefaultHasher;
+        const LENGTH: usize = 32;
+        fn hash(data: &[u8]) -> H256 {
+            keccak256(data)
+        }
+    }
+
+    // A trie stored in a memory-db, as used with trie-db.
+    let store = memory_db::MemoryDB::<Keccak, HashKey<Keccak>, Vec<u8>>::default();
+    let mut db = ParityBackedDB::new(store);
+    let root = {
+        let mut trie = PatriciaTrieMut::new(&mut db);
+        for i in 0..100u32 {
+            trie.insert(&keccak256(&i.to_be_bytes()).0, i.to_be_bytes().to_vec())
+                .unwrap();
+        }
+        trie.root().unwrap()
+    };
+    let store = db.into_inner();
+    assert!(store.contains(&root, EMPTY_PREFIX));
+    let db = ParityBackedDB::new(store);
+    let trie = PatriciaTrie::from(&db, root).unwrap();
+    assert_eq!(
+        trie.get(&keccak256(&7u32.to_be_bytes()).0).unwrap(),
+        Some(7u32.to_be_bytes().to_vec())
+    );
+
+    // Nodes written through hash_db land in a db of this crate.
+    let mut shared = ParityHashDB::<_, Keccak>::new(MemoryDB::new(true));
+    let key = shared.insert(EMPTY_PREFIX, b"node");
+    assert_eq!(key, keccak256(b"node"));
+    assert_eq!(shared.inner().get(&key), Some(b"node".to_vec()));
+    assert_eq!(shared.get(&key, EMPTY_PREFIX), Some(b"node".to_vec()));
+    shared.remove(&key, EMPTY_PREFIX);
+    assert!(!shared.contains(&key, EMPTY_PREFIX));
+}
+
+#[test]
+fn test_db_hook() {
+    use ethtrie::{DbAccess, PatriciaTrie};
+    use std::sync::{Arc, Mutex};
+
+    let accesses = Arc::new(Mutex::new(Vec::new()));
+    let hook = {
+        let accesses = accesses.clone();
+        move |access: &DbAccess| accesses.lock().unwrap().push(*access)
+    };
+
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb).with_db_hook(hook.clone());
+    for i in 0..100u32 {
+        trie.insert(&keccak256(&i.to_be_bytes()).0, vec![i as u8; 40])
+            .unwrap();
+    }
+    let root = trie.root().unwrap();
+    let inserted: Vec<_> = accesses.lock().unwrap().drain(..).collect();
+    assert_eq!(inserted.len(), memdb.keys().len());
+    for access in inserted {
+        match access {
+            DbAccess::Insert { hash, len } => assert_eq!(memdb.get(&hash).unwrap().len(), len),
+            access => panic!("unexpected access {:?}", access),
+        }
+    }
+
+    let mut trie = PatriciaTrieMut::from(&mut memdb, root)
+        .unwrap()
+        .with_db_hook(hook.clone());
+    trie.remove(&keccak256(&0u32.to_be_bytes()).0).unwrap();
+    let new_root = trie.root().unwrap();
+    let accesses_after: Vec<_> = accesses.lock().unwrap().drain(..).collect();
+    let count = |f: fn(&DbAccess) -> bool| accesses_after.iter().filter(|a| f(a)).count();
+    assert!(count(|a| matches!(a, DbAccess::Get { len: Some(_), .. })) > 0);
+    assert!(count(|a| matches!(a, DbAccess::Insert { .. })) > 0);
+    assert!(count(|a| matches!(a, DbAccess::Remove { .. })) > 0);
+    for access in accesses_after.iter() {
+        if let DbAccess::Remove { hash } = access {
+            assert!(!memdb.contains(hash));
+        }
+    }
+
+    let trie = PatriciaTrie::from(&memdb, new_root)
+        .unwrap()
+        .with_db_hook(hook);
+    let key = keccak256(&1u32.to_be_bytes());
+    assert!(trie.get(&key.0).unwrap().is_some());
+    assert!(trie.get(&key.0).unwrap().is_some());
+    let reads: Vec<_> = accesses.lock().unwrap().drain(..).collect();
+    // Nodes read again come from the node cache.
+    assert!(!reads.is_empty());
+    let hashes: HashSet<_> = reads
+        .iter()
+        .map(|access| match access {
+            DbAccess::Get { hash, len } => {
+                assert_eq!(*len, memdb.get(hash).map(|data| data.len()));
+                *hash
+            }
+            access => panic!("unexpected access {:?}", access),
+        })
+        .collect();
+    assert_eq!(hashes.len(), reads.len());
+}
+
+#[test]
+fn test_blob_store() {
+    use ethtrie::{MemoryBlobStore, PatriciaTrie};
+
+    let code = |i: u8| vec![i; 2000];
+    let store = MemoryBlobStore::new();
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb)
+        .with_blob_store(store.clone(), 64)
+        .with_shadow_verification();
+    for i in 0..10u8 {
+        trie.insert(&[i], code(i)).unwrap();
+    }
+    trie.insert(b"small", b"inline".to_vec()).unwrap();
+    // Values as long as a hash go to the store too, so they read back as written.
+    let hash_long = keccak256(&code(1)).0.to_vec();
+    trie.insert(b"hash", hash_long.clone()).unwrap();
+    let root = trie.root().unwrap();
+    assert_eq!(store.len(), 11);
+    assert_eq!(trie.get(&[3]).unwrap(), Some(code(3)));
+    assert_eq!(trie.get(b"hash").unwrap(), Some(hash_long.clone()));
+    assert_eq!(trie.get(b"small").unwrap(), Some(b"inline".to_vec()));
+    let mut entries = 0;
+    trie.for_each_entry(|key, value| {
+        assert!(value == code(key[0]) || key == b"small" || key == b"hash");
+        entries += 1;
+    })
+    .unwrap();
+    assert_eq!(entries, 12);
+
+    // The nodes and proofs hold the hashes of the values.
+    let proof = trie.get_proof(&[3]).unwrap();
+    assert!(proof.iter().map(Vec::len).sum::<usize>() < 2000);
+    assert_eq!(
+        trie.verify_proof(root, &[3], proof).unwrap(),
+        Some(keccak256(&code(3)).0.to_vec())
+    );
+    drop(trie);
+
+    let trie = PatriciaTrie::from(&memdb, root)
+        .unwrap()
+        .with_blob_store(store.clone(), 64);
+    assert!(trie
+        .iter()
+        .all(|(key, value)| key == b"small" || key == b"hash" || value == code(key[0])));
+    let unresolved = PatriciaTrie::from(&memdb, root).unwrap();
+    assert_eq!(
+        unresolved.get(&[3]).unwrap(),
+        Some(keccak256(&code(3)).0.to_vec())
+    );
+
+    // A value missing from the store cannot be read.
+    let empty = PatriciaTrie::from(&memdb, root)
+        .unwrap()
+        .with_blob_store(MemoryBlobStore::new(), 64);
+    assert!(matches!(
+        empty.get(&[3]).unwrap_err().root_cause(),
+        TrieError::MissingBlob(hash) if *hash == keccak256(&code(3))
+    ));
+    assert_eq!(empty.get(b"small").unwrap(), Some(b"inline".to_vec()));
+    let first = empty.try_iter().next().unwrap().unwrap_err();
+    assert!(matches!(
+        first,
+        TrieError::AtKey { ref key, ref error } if key == &[0]
+            && matches!(**error, TrieError::MissingBlob(_))
+    ));
+    assert_eq!(empty.iter().count(), 0);
+}
+
+#[test]
+fn test_node_provider() {
+    use ethtrie::PatriciaTrie;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let mut remote = MemoryDB::new(true);
+    let root = {
+        let mut trie = PatriciaTrieMut::new(&mut remote);
+        for i in 0..100u32 {
+            trie.insert(&keccak256(&i.to_be_bytes()).0, i.to_be_bytes().to_vec())
+                .unwrap();
+        }
+        trie.root().unwrap()
+    };
+    let nodes: HashMap<H256, Vec<u8>> = remote
+        .keys()
+        .into_iter()
+        .map(|key| (key, remote.get(&key).unwrap()))
+        .collect();
+    let nodes = Arc::new(nodes);
+    let fetched = Arc::new(AtomicUsize::new(0));
+    let provider = {
+        let (nodes, fetched) = (nodes.clone(), fetched.clone());
+        move |hash: &H256| {
+            fetched.fetch_add(1, Ordering::Relaxed);
+            nodes.get(hash).cloned()
+        }
+    };
+
+    let key = |i: u32| keccak256(&i.to_be_bytes()).0;
+    let local = MemoryDB::new(true);
+    let trie = PatriciaTrie::<_>::from_with_provider(&local, root, provider.clone()).unwrap();
+    assert_eq!(
+        trie.get(&key(7)).unwrap(),
+        Some(7u32.to_be_bytes().to_vec())
+    );
+    assert!(fetched.load(Ordering::Relaxed) > 0);
+    assert!(local.keys().is_empty());
+
+    // A mutable trie writes the nodes it fetched to the db.
+    let mut local = MemoryDB::new(true);
+    let new_root = {
+        let mut trie =
+            PatriciaTrieMut::<_>::from_with_provider(&mut local, root, provider).unwrap();
+        assert_eq!(
+            trie.get(&key(7)).unwrap(),
+            Some(7u32.to_be_bytes().to_vec())
+        );
+        trie.insert(&key(1000), b"new".to_vec()).unwrap();
+        trie.root().unwrap()
+    };
+    let before = fetched.load(Ordering::Relaxed);
+    let trie = PatriciaTrie::from(&local, new_root).unwrap();
+    assert_eq!(
+        trie.get(&key(7)).unwrap(),
+        Some(7u32.to_be_bytes().to_vec())
+    );
+    assert_eq!(trie.get(&key(1000)).unwrap(), Some(b"new".to_vec()));
+    assert_eq!(fetched.load(Ordering::Relaxed), before);
+
+    // Nodes that do not hash to their key are rejected.
+    let forged = |_: &H256| Some(b"forged".to_vec());
+    let local = MemoryDB::new(true);
+    assert!(PatriciaTrie::<_>::from_with_provider(&local, root, forged).is_err());
+}
+
+#[test]
+fn test_path_storage() {
+    use ethtrie::{MemoryPathDB, PathHashDB};
+
+    let owner = H256::repeat_byte(1);
+    let mut pathdb = MemoryPathDB::new();
+    let mut memdb = MemoryDB::new(true);
+    let mut entries = HashMap::new();
+    let mut rng = thread_rng();
+    let mut root = None;
+    for round in 0..20 {
+        let mut db = PathHashDB::<_>::new(&mut pathdb, owner);
+        let mut trie = match root {
+            Some(root) => PatriciaTrieMut::from(&mut db, root).unwrap(),
+            None => PatriciaTrieMut::new(&mut db),
+        };
+        let mut expected = match root {
+            Some(root) => PatriciaTrieMut::from(&mut memdb, root).unwrap(),
+            None => PatriciaTrieMut::new(&mut memdb),
+        };
+        for _ in 0..50 {
+            let key = keccak256(&[rng.gen_range(0, 200u8)]).0;
+            if round > 10 && rng.gen_bool(0.6) {
+                trie.remove(&key).unwrap();
+                expected.remove(&key).unwrap();
+                entries.remove(&key);
+            } else {
+                let value = format!("value{}", rng.gen::<u32>()).into_bytes();
+                trie.insert(&key, value.clone()).unwrap();
+                expected.insert(&key, value.clone()).unwrap();
+                entries.insert(key, value);
+            }
+        }
+        let new_root = trie.root().unwrap();
+        assert_eq!(new_root, expected.root().unwrap());
+        root = Some(new_root);
+    }
+
+    // Reopened from its paths, the trie holds every entry and no stale node.
+    let root = root.unwrap();
+    let db = PathHashDB::<_>::new(&mut pathdb, owner);
+    let trie = ethtrie::PatriciaTrie::from(&db, root).unwrap();
+    assert_eq!(trie.iter().count(), entries.len());
+    for (key, value) in entries.iter() {
+        assert_eq!(trie.get(key).unwrap(), Some(value.clone()));
+    }
+    let mut fresh = MemoryDB::new(true);
+    let mut rebuilt = PatriciaTrieMut::new(&mut fresh);
+    for (key, value) in entries.iter() {
+        rebuilt.insert(key, value.clone()).unwrap();
+    }
+    assert_eq!(rebuilt.root().unwrap(), root);
+    assert_eq!(pathdb.len(&owner), fresh.keys().len());
+
+    // Owners do not share nodes, and an emptied trie keeps only its root.
+    assert!(pathdb.is_empty(&H256::zero()));
+    let mut db = PathHashDB::<_>::new(&mut pathdb, owner);
+    let mut trie = PatriciaTrieMut::from(&mut db, root).unwrap();
+    for key in entries.keys() {
+        trie.remove(key).unwrap();
+    }
+    trie.root().unwrap();
+    assert_eq!(pathdb.len(&owner), 1);
+}
+
+#[test]
+fn test_trie_healer() {
+    use ethtrie::TrieHealer;
+
+    let mut source = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut source);
+    for i in 0..500u32 {
+        trie.insert(&keccak256(&i.to_be_bytes()).0, i.to_be_bytes().to_vec())
+            .unwrap();
+    }
+    let root = trie.root().unwrap();
+
+    // The local db only has some of the nodes, the root excluded.
+    let mut local = MemoryDB::new(true);
+    for (i, key) in source.keys().into_iter().enumerate() {
+        if i % 3 == 0 && key != root {
+            local.insert(key, source.get(&key).unwrap());
+        }
+    }
+
+    let mut healer = TrieHealer::new(&mut local, root);
+    let mut rounds = 0;
+    while !healer.is_complete() {
+        let missing = healer.missing(16).unwrap();
+        if rounds == 0 {
+            assert_eq!(missing, vec![root]);
+            // A failed request is retried.
+            healer.retry();
+        } else {
+            let nodes = missing
+                .iter()
+                .map(|hash| source.get(hash).unwrap())
+                .collect();
+            assert_eq!(healer.process(nodes).unwrap(), missing.len());
+        }
+        rounds += 1;
+    }
+    assert_eq!(healer.pending(), 0);
+    assert!(healer.process(vec![b"unexpected".to_vec()]).is_err());
+
+    assert_eq!(local.keys().len(), source.keys().len());
+    let trie = PatriciaTrieMut::from(&mut local, root).unwrap();
+    assert_eq!(trie.iter().count(), 500);
+
+    let empty_root = keccak256(&rlp::NULL_RLP);
+    let mut empty = MemoryDB::new(true);
+    let mut healer = TrieHealer::new(&mut empty, empty_root);
+    assert!(healer.missing(16).unwrap().is_empty());
+    assert!(healer.is_complete());
+}
+
+#[test]
+fn test_snapshot_stream() {
+    use ethtrie::{export_snapshot, import_snapshot, PatriciaTrie, SnapshotImporter};
+
+    // Nodes of older roots stay in the source db but are not part of the stream.
+    let mut source = MemoryDB::new(false);
+    let mut trie = PatriciaTrieMut::new(&mut source);
+    for i in 0..500u32 {
+        trie.insert(&keccak256(&i.to_be_bytes()).0, vec![i as u8; 40])
+            .unwrap();
+    }
+    trie.root().unwrap();
+    trie.remove(&keccak256(&0u32.to_be_bytes()).0).unwrap();
+    let root = trie.root().unwrap();
+
+    let mut items = Vec::new();
+    let written = export_snapshot(&source, root, |item| items.push(item.to_vec())).unwrap();
+    assert_eq!(items.len(), written + 1);
+    let stream = items.concat();
+
+    let mut local = MemoryDB::new(true);
+    assert_eq!(import_snapshot(stream.chunks(7), &mut local).unwrap(), root);
+    assert_eq!(local.keys().len(), written);
+    assert!(local.keys().len() < source.keys().len());
+    let trie = PatriciaTrie::from(&local, root).unwrap();
+    assert_eq!(trie.iter().count(), 499);
+
+    // A node altered on the way is not one the trie refers to.
+    let mut altered = items.clone();
+    let last = altered[2].len() - 1;
+    altered[2][last] ^= 1;
+    let mut local = MemoryDB::new(true);
+    let result = import_snapshot(altered.iter(), &mut local);
+    assert!(matches!(result, Err(TrieError::InNode { .. })));
+
+    // A stream cut short misses nodes, or the end of an item.
+    let mut local = MemoryDB::new(true);
+    let result = import_snapshot(items[..items.len() - 1].iter(), &mut local);
+    assert!(matches!(result, Err(TrieError::MissingNode(_))));
+    let mut local = MemoryDB::new(true);
+    let mut importer = SnapshotImporter::new(&mut local);
+    assert_eq!(
+        importer.feed(&stream[..stream.len() - 1]).unwrap(),
+        written - 1
+    );
+    assert_eq!(importer.root(), Some(root));
+    assert!(importer.finish().is_err());
+
+    let empty_root = keccak256(&rlp::NULL_RLP);
+    let mut items = Vec::new();
+    let empty = MemoryDB::new(true);
+    assert_eq!(
+        export_snapshot(&empty, empty_root, |item| items.push(item.to_vec())).unwrap(),
+        1
+    );
+    let mut local = MemoryDB::new(true);
+    assert_eq!(import_snapshot(items, &mut local).unwrap(), empty_root);
+}
+
+#[test]
+fn test_snapshot_container() {
+    use ethtrie::{
+        export_snapshot_with_hasher, read_snapshot, write_snapshot, write_snapshot_with_hasher,
+        PatriciaTrie, RlpNodeCodec, SnapshotImporter, SnapshotReader,
+    };
+
+    // Keccak truncated to 20 bytes, a hasher the container must tell from keccak.
+    struct ShortKeccak;
+
+    impl Hasher for ShortKeccak {
+        const LENGTH: usize = 20;
+
+        fn hash(data: &[u8]) -> H256 {
+            let mut hash = keccak256(data);
+            hash.0[20..].fill(0);
+            hash
+        }
+    }
+
+    let mut source = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut source);
+    for i in 0..500u32 {
+        trie.insert(&keccak256(&i.to_be_bytes()).0, vec![i as u8; 40])
+            .unwrap();
+    }
+    let root = trie.root().unwrap();
+    let mut snapshot = Vec::new();
+    let written = write_snapshot(&source, root, |bytes| snapshot.extend_from_slice(bytes)).unwrap();
+    assert_eq!(written, source.keys().len());
+
+    let mut local = MemoryDB::new(true);
+    assert_eq!(read_snapshot(snapshot.chunks(5), &mut local).unwrap(), root);
+    let trie = PatriciaTrie::from(&local, root).unwrap();
+    assert_eq!(trie.iter().count(), 500);
+
+    // The format is stable: a container written now must read the same later.
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    trie.insert(b"doe", b"reindeer".to_vec()).unwrap();
+    let doe_root = trie.root().unwrap();
+    let mut doe = Vec::new();
+    write_snapshot(&memdb, doe_root, |bytes| doe.extend_from_slice(bytes)).unwrap();
+    assert_eq!(
+        hex::encode(&doe),
+        "6574687472696500000156e81f172011a0327cfcc5b7689b6b6d727e1f5f8846c1137caaa9fc871ba31b7c\
+         ce1b703ec2f8d3870000000fce8420646f65887265696e64656572f79d67ce00000000"
+    );
+
+    let read = |bytes: &[u8]| root_cause(read_snapshot(Some(bytes), &mut MemoryDB::new(true)));
+    let mut later = doe.clone();
+    later[9] = 2;
+    assert!(matches!(
+        read(&later),
+        Err(TrieError::UnsupportedSnapshotVersion(2))
+    ));
+    let mut corrupt = doe.clone();
+    corrupt[12] ^= 1;
+    assert!(matches!(
+        read(&corrupt),
+        Err(TrieError::SnapshotChecksumMismatch(0))
+    ));
+    let mut corrupt = doe.clone();
+    corrupt[60] ^= 1;
+    assert!(matches!(
+        read(&corrupt),
+        Err(TrieError::SnapshotChecksumMismatch(1))
+    ));
+    assert!(matches!(read(&doe[1..]), Err(TrieError::InvalidData)));
+    assert!(matches!(
+        read(&doe[..doe.len() - 1]),
+        Err(TrieError::InvalidData)
+    ));
+    assert!(matches!(
+        read(&[&doe[..], &[0]].concat()),
+        Err(TrieError::InvalidData)
+    ));
+
+    // Containers only read back with the hasher they were written for.
+    let mut short_db = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::<_, ShortKeccak>::new_with_hasher(&mut short_db);
+    for i in 0..100u32 {
+        trie.insert(&i.to_be_bytes(), vec![i as u8]).unwrap();
+    }
+    let short_root = trie.root().unwrap();
+    let mut short = Vec::new();
+    write_snapshot_with_hasher::<ShortKeccak, RlpNodeCodec, _>(&short_db, short_root, |bytes| {
+        short.extend_from_slice(bytes)
+    })
+    .unwrap();
+    assert!(matches!(
+        read(&short),
+        Err(TrieError::SnapshotHasherMismatch)
+    ));
+    let mut local = MemoryDB::new(true);
+    let mut reader = SnapshotReader::<_, ShortKeccak>::new_with_hasher(&mut local);
+    assert_eq!(reader.feed(&short).unwrap(), short_db.keys().len());
+    assert_eq!(reader.finish().unwrap(), short_root);
+
+    // So does the stream of nodes.
+    let mut stream = Vec::new();
+    export_snapshot_with_hasher::<ShortKeccak, RlpNodeCodec, _>(&short_db, short_root, |bytes| {
+        stream.extend_from_slice(bytes)
+    })
+    .unwrap();
+    let mut local = MemoryDB::new(true);
+    let mut importer = SnapshotImporter::<_, ShortKeccak>::new_with_hasher(&mut local);
+    assert_eq!(importer.feed(&stream).unwrap(), short_db.keys().len());
+    assert_eq!(importer.finish().unwrap(), short_root);
+}
+
+#[test]
+fn test_state_sync() {
+    use ethtrie::{PatriciaTrie, SyncClient, SyncRequest, SyncResponse, SyncServer};
+
+    let mut source = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut source);
+    for i in 0..1000u32 {
+        trie.insert(&keccak256(&i.to_be_bytes()).0, vec![i as u8; 40])
+            .unwrap();
+    }
+    let root = trie.root().unwrap();
+    let server = SyncServer::new(&source).with_max_nodes(50);
+
+    // The local db has some of the nodes already, which are not fetched again.
+    let mut local = MemoryDB::new(true);
+    for (i, key) in source.keys().into_iter().enumerate() {
+        if i % 4 == 0 && key != root {
+            local.insert(key, source.get(&key).unwrap());
+        }
+    }
+
+    let mut client = SyncClient::new(&mut local, root).with_max_nodes(64);
+    let mut fetched = 0;
+    let mut rounds = 0;
+    while !client.is_complete() {
+        let requests = client.requests(4).unwrap();
+        assert_eq!(client.pending(), requests.len());
+        for request in requests.iter() {
+            let response = server.serve(request).unwrap();
+            assert!(response.nodes.len() <= 50);
+            if rounds == 1 && response.nodes.len() > 1 {
+                // A response made of nodes from elsewhere is rejected, and the
+                // subtrie requested again.
+                let wrong = SyncResponse {
+                    nodes: response.nodes[1..].to_vec(),
+                };
+                assert!(client.process(request, wrong).is_err());
+            } else {
+                fetched += client.process(request, response).unwrap();
+            }
+        }
+        rounds += 1;
+    }
+    assert!(fetched < source.keys().len());
+    assert_eq!(local.keys().len(), source.keys().len());
+    let trie = PatriciaTrie::from(&local, root).unwrap();
+    assert_eq!(trie.iter().count(), 1000);
+
+    // Paths must lead to a node stored by hash.
+    let request = |path: Vec<u8>| SyncRequest {
+        root,
+        path,
+        max_nodes: 10,
+    };
+    assert_eq!(server.serve(&request(vec![])).unwrap().nodes.len(), 10);
+    assert!(server.serve(&request(vec![16])).is_err());
+    assert!(server.serve(&request(vec![0; 64])).is_err());
+    let unknown = SyncRequest {
+        root: keccak256(b"unknown"),
+        ..request(vec![])
+    };
+    assert!(matches!(
+        server.serve(&unknown),
+        Err(TrieError::MissingNode(_))
+    ));
+
+    let empty_root = keccak256(&rlp::NULL_RLP);
+    let mut empty = MemoryDB::new(true);
+    let mut client = SyncClient::new(&mut empty, empty_root);
+    assert!(client.requests(4).unwrap().is_empty());
+    assert!(client.is_complete());
+}
+
+#[test]
+fn test_missing_node() {
+    use ethtrie::PatriciaTrie;
+
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    let keys: Vec<_> = (0..100u32).map(|i| keccak256(&i.to_be_bytes())).collect();
+    for key in &keys {
+        trie.insert(key.as_bytes(), key.as_bytes().to_vec())
+            .unwrap();
+    }
+    let root = trie.root().unwrap();
+    let proof = trie.get_proof(keys[0].as_bytes()).unwrap();
+    let lost = keccak256(&proof[1]);
+    memdb.remove(&lost);
+
+    // Lookups, proofs and changes below the lost node fail instead of finding nothing.
+    let trie = PatriciaTrie::from(&memdb, root).unwrap();
+    assert!(matches!(
+        root_cause(trie.get(keys[0].as_bytes())),
+        Err(TrieError::MissingNode(hash)) if hash == lost
+    ));
+    assert!(matches!(
+        root_cause(trie.get_proof(keys[0].as_bytes())),
+        Err(TrieError::MissingNode(_))
+    ));
+    let mut trie = PatriciaTrieMut::from(&mut memdb, root).unwrap();
+    assert!(matches!(
+        root_cause(trie.insert(keys[0].as_bytes(), b"new".to_vec())),
+        Err(TrieError::MissingNode(_))
+    ));
+    let found = keys
+        .iter()
+        .filter(|key| trie.get(key.as_bytes()).is_ok())
+        .count();
+    assert!(found > 0 && found < keys.len());
+
+    // `try_iter` yields the node it fails to read and ends there, where `iter` ends.
+    let trie = PatriciaTrie::from(&memdb, root).unwrap();
+    let mut iter = trie.try_iter();
+    let listed = iter.by_ref().take_while(Result::is_ok).count();
+    assert!(listed < found);
+    assert!(iter.next().is_none());
+    assert_eq!(trie.iter().count(), listed);
+    assert!(matches!(
+        trie.try_iter().find_map(Result::err),
+        Some(TrieError::MissingNode(hash)) if hash == lost
+    ));
+
+    // A proof missing a node is not a proof of absence.
+    let trie = PatriciaTrie::new(&memdb);
+    assert!(trie
+        .verify_proof(root, keys[0].as_bytes(), proof[..1].to_vec())
+        .is_err());
+
+    let trie = PatriciaTrie::from(&memdb, root)
+        .unwrap()
+        .with_lenient_mode();
+    assert_eq!(trie.get(keys[0].as_bytes()).unwrap(), None);
+    let mut trie = PatriciaTrieMut::from(&mut memdb, root)
+        .unwrap()
+        .with_lenient_mode();
+    trie.insert(keys[0].as_bytes(), b"new".to_vec()).unwrap();
+    assert_eq!(trie.get(keys[0].as_bytes()).unwrap(), Some(b"new".to_vec()));
+}
+
+#[test]
+fn test_interleaved_views() {
+    use ethtrie::PatriciaTrie;
+
+    let mut memdb = MemoryDB::new(true);
+    let keys: Vec<_> = (0..300u32).map(|i| keccak256(&i.to_be_bytes())).collect();
+    let root = {
+        let mut trie = PatriciaTrieMut::new(&mut memdb);
+        for key in keys.iter() {
+            trie.insert(key.as_bytes(), key.as_bytes().to_vec())
+                .unwrap();
+        }
+        trie.root().unwrap()
+    };
+
+    // Iterators, clones and forks of one trie share its caches, kept small so that
+    // every step evicts nodes another view is about to read.
+    let trie = PatriciaTrie::from(&memdb, root)
+        .unwrap()
+        .with_node_cache_capacity(4);
+    let clone = trie.clone();
+    let mut fork = trie.fork();
+    let mut iters = [trie.iter(), clone.iter(), trie.iter()];
+    let mut counts = [0; 3];
+    for (i, key) in keys.iter().enumerate() {
+        for (iter, count) in iters.iter_mut().zip(counts.iter_mut()).take(i % 3 + 1) {
+            *count += iter.next().is_some() as usize;
+        }
+        assert_eq!(
+            clone.get(key.as_bytes()).unwrap(),
+            Some(key.as_bytes().to_vec())
+        );
+        let proof = trie.get_proof(key.as_bytes()).unwrap();
+        assert!(trie.verify_proof(root, key.as_bytes(), proof).is_ok());
+        fork.insert(key.as_bytes(), vec![1; 40]).unwrap();
+        if i % 50 == 0 {
+            fork.root_hash();
+        }
+    }
+    for (iter, count) in iters.iter_mut().zip(counts.iter_mut()) {
+        *count += iter.count();
+    }
+    assert_eq!(counts, [300; 3]);
+}
+
+#[test]
+fn test_snapshots_survive_mutation() {
+    use ethtrie::PatriciaTrie;
+
+    let mut rng = thread_rng();
+    let mut memdb = MemoryDB::new(false);
+    let mut original = HashMap::new();
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    for _ in 0..300 {
+        let key: Vec<u8> = (0..rng.gen_range(1, 6))
+            .map(|_| rng.gen_range(0, 8))
+            .collect();
+        let value = vec![rng.gen::<u8>(); rng.gen_range(1, 40)];
+        trie.insert(&key, value.clone()).unwrap();
+        original.insert(key, value);
+    }
+    let root = trie.root().unwrap();
+    let mut sorted: Vec<_> = original.clone().into_iter().collect();
+    sorted.sort();
+
+    {
+        // Every node of the old root is decoded and shared through the node cache.
+        let old = PatriciaTrie::from(&memdb, root).unwrap();
+        for key in original.keys() {
+            old.get(key).unwrap();
+        }
+        let mut iter = old.iter();
+        let head: Vec<_> = iter.by_ref().take(10).collect();
+
+        let mut forks = [old.fork(), old.fork()];
+        let mut contents = [original.clone(), original.clone()];
+        for _ in 0..500 {
+            let i = rng.gen_range(0, 2);
+            let key: Vec<u8> = (0..rng.gen_range(1, 6))
+                .map(|_| rng.gen_range(0, 8))
+                .collect();
+            if rng.gen() {
+                let value = vec![rng.gen::<u8>(); rng.gen_range(1, 40)];
+                forks[i].insert(&key, value.clone()).unwrap();
+                contents[i].insert(key, value);
+            } else {
+                forks[i].remove(&key).unwrap();
+                contents[i].remove(&key);
+            }
+        }
+        for (fork, contents) in forks.iter().zip(&contents) {
+            for (key, value) in contents {
+                assert_eq!(fork.get(key).unwrap().as_ref(), Some(value));
+            }
+            assert_eq!(fork.iter().count(), contents.len());
+        }
+
+        assert_eq!(head.into_iter().chain(iter).collect::<Vec<_>>(), sorted);
+        for (key, value) in &original {
+            assert_eq!(old.get(key).unwrap().as_ref(), Some(value));
+        }
+        assert_eq!(old.fork().root_hash(), root);
+    }
+
+    // Committing changes leaves the nodes of the old root alone in a db keeping them.
+    let mut trie = PatriciaTrieMut::from(&mut memdb, root).unwrap();
+    for key in original.keys().take(100) {
+        trie.remove(key).unwrap();
+    }
+    trie.insert(b"new", b"value".to_vec()).unwrap();
+    assert_ne!(trie.root().unwrap(), root);
+    let old = PatriciaTrie::from(&memdb, root).unwrap();
+    assert_eq!(old.iter().collect::<Vec<_>>(), sorted);
+}
+
+#[test]
+fn test_invalid_compact_encoding() {
+    use ethtrie::{PatriciaTrie, RlpNodeCodec};
+
+    let leaf = |compact: &[u8]| {
+        let mut s = rlp::RlpStream::new_list(2);
+        s.append(&compact);
+        s.append(&b"value".as_ref());
+        s.out().to_vec()
+    };
+    let valid = leaf(&[0x20, 0xab]);
+    match RlpNodeCodec::decode(&valid).unwrap() {
+        DecodedNode::Leaf(path, value) => {
+            assert_eq!((path.len(), path.at(0), path.at(1)), (2, 0xa, 0xb));
+            assert_eq!(value, b"value");
+        }
+        node => panic!("unexpected node {:?}", node),
+    }
+    assert!(RlpNodeCodec::decode(&leaf(&[0x3a, 0xbc])).is_ok());
+
+    // A padding nibble other than zero, a bad flag, or no flag at all.
+    for compact in [&[0x2f, 0xab][..], &[0x01, 0xab], &[0x4a], &[0xfa], &[]] {
+        assert!(matches!(
+            root_cause(RlpNodeCodec::decode(&leaf(compact))),
+            Err(TrieError::InvalidCompactEncoding)
+        ));
+    }
+
+    let mut memdb = MemoryDB::new(true);
+    let data = leaf(&[0x2f, 0xab]);
+    let root = keccak256(&data);
+    memdb.insert(root, data);
+    assert!(matches!(
+        root_cause(PatriciaTrie::from(&memdb, root)),
+        Err(TrieError::InvalidCompactEncoding)
+    ));
+}
+
+#[test]
+fn test_non_canonical_nodes() {
+    use ethtrie::{PatriciaTrie, RlpNodeCodec};
+
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    trie.insert(b"k", vec![5]).unwrap();
+    let root = trie.root().unwrap();
+    let proof = trie.get_proof(b"k").unwrap();
+    let leaf = proof[0].clone();
+    assert_eq!(leaf, [0xc4, 0x82, 0x20, 0x6b, 0x05]);
+    assert!(RlpNodeCodec::decode(&leaf).is_ok());
+
+    // The same leaf with its value behind a needless header, or followed by a
+    // trailing byte, hashes differently and must not verify.
+    for data in [
+        vec![0xc5, 0x82, 0x20, 0x6b, 0x81, 0x05],
+        [&leaf[..], &[0]].concat(),
+    ] {
+        assert!(matches!(
+            RlpNodeCodec::decode(&data),
+            Err(TrieError::NonCanonicalNode)
+        ));
+        let root = keccak256(&data);
+        assert!(trie.verify_proof(root, b"k", vec![data]).is_err());
+    }
+    assert!(RlpNodeCodec::decode(&[0xc4, 0x82, 0x20, 0x6b, 0x80]).is_err());
+    assert_eq!(trie.verify_proof(root, b"k", proof).unwrap(), Some(vec![5]));
+
+    // A child of a digest's length or more is hashed, never embedded.
+    let mut inline = rlp::RlpStream::new_list(2);
+    inline
+        .append(&[0x3bu8].as_ref())
+        .append(&[7u8; 30].as_ref());
+    let inline = inline.out().to_vec();
+    assert!(inline.len() >= 32);
+    let mut s = rlp::RlpStream::new_list(17);
+    for i in 0..16 {
+        if i == 6 {
+            s.append_raw(&inline, 1);
+        } else {
+            s.append_empty_data();
+        }
+    }
+    s.append(&[1u8].as_ref());
+    let data = s.out().to_vec();
+    let root = keccak256(&data);
+    memdb.insert(root, data);
+    assert!(matches!(
+        root_cause(PatriciaTrie::from(&memdb, root)),
+        Err(TrieError::NonCanonicalNode)
+    ));
+}
+
+#[test]
+fn test_non_canonical_structure() {
+    use ethtrie::RlpNodeCodec;
+    use rlp::RlpStream;
+
+    fn node(items: &[&[u8]], raw: &[bool]) -> Vec<u8> {
+        let mut s = RlpStream::new_list(items.len());
+        for (item, raw) in items.iter().zip(raw) {
+            if *raw {
+                s.append_raw(item, 1);
+            } else {
+                s.append(item);
+            }
+        }
+        s.out().to_vec()
+    }
+    fn branch(children: &[(usize, &[u8])], value: &[u8]) -> Vec<u8> {
+        let mut s = RlpStream::new_list(17);
+        for i in 0..16 {
+            match children.iter().find(|(at, _)| *at == i) {
+                Some((_, child)) => s.append_raw(child, 1),
+                None => s.append_empty_data(),
+            };
+        }
+        s.append(&value);
+        s.out().to_vec()
+    }
+    fn rejected(data: &[u8]) -> bool {
+        matches!(RlpNodeCodec::decode(data), Err(TrieError::NonCanonicalNode))
+    }
+
+    let hash = keccak256(b"child");
+    let leaf = node(&[&[0x20], &[1]], &[false, false]);
+    let small_branch = branch(&[(1, &leaf), (2, &leaf)], &[]);
+    assert!(small_branch.len() < 32);
+    assert!(RlpNodeCodec::decode(&small_branch).is_ok());
+    assert!(RlpNodeCodec::decode(&branch(&[(1, &leaf)], &[5])).is_ok());
+    assert!(RlpNodeCodec::decode(&node(&[&[0x11], hash.as_bytes()], &[false, false])).is_ok());
+    assert!(RlpNodeCodec::decode(&node(&[&[0x11], &small_branch], &[false, true])).is_ok());
+
+    // An extension with an empty path.
+    assert!(rejected(&node(
+        &[&[0x00], hash.as_bytes()],
+        &[false, false]
+    )));
+    assert!(rejected(&node(&[&[0x00], &small_branch], &[false, true])));
+    // An extension above an empty node, an inline leaf or an inline extension.
+    assert!(rejected(&node(&[&[0x11], &[]], &[false, false])));
+    assert!(rejected(&node(&[&[0x11], &leaf], &[false, true])));
+    let extension = node(&[&[0x12], &small_branch], &[false, true]);
+    assert!(extension.len() < 32);
+    assert!(rejected(&node(&[&[0x11], &extension], &[false, true])));
+    // A branch with fewer than two children and values.
+    assert!(rejected(&branch(&[], &[])));
+    assert!(rejected(&branch(&[], &[5])));
+    assert!(rejected(&branch(&[(3, &leaf)], &[])));
+}
+
+#[test]
+fn test_decode_limits() {
+    use ethtrie::{DecodeLimits, PatriciaTrie, TrieHealer};
+
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    for i in 0..64u8 {
+        trie.insert(&[i, i], vec![i]).unwrap();
+    }
+    trie.insert(&[0xff; 100], vec![1; 40]).unwrap();
+    trie.insert(&[0xfe], vec![2; 2000]).unwrap();
+    let root = trie.root().unwrap();
+
+    let open = |limits: DecodeLimits| {
+        PatriciaTrie::from(&memdb, root)
+            .unwrap()
+            .with_decode_limits(limits)
+    };
+    let limited = |limits: DecodeLimits, key: &[u8]| {
+        matches!(
+            root_cause(open(limits).get(key)),
+            Err(TrieError::LimitExceeded)
+        )
+    };
+    let paths = DecodeLimits {
+        max_path_len: 64,
+        ..Default::default()
+    };
+    let sizes = DecodeLimits {
+        max_node_size: 1024,
+        ..Default::default()
+    };
+    let depths = DecodeLimits {
+        max_depth: 0,
+        ..Default::default()
+    };
+    assert_eq!(
+        open(Default::default()).get(&[0xff; 100]).unwrap(),
+        Some(vec![1; 40])
+    );
+    assert!(limited(paths, &[0xff; 100]));
+    assert!(limited(sizes, &[0xfe]));
+    // Short entries are embedded in their parent.
+    assert!(limited(depths, &[5, 5]));
+    for limits in [paths, sizes] {
+        assert_eq!(open(limits).get(&[5, 5]).unwrap(), Some(vec![5]));
+    }
+
+    let verify = |limits: DecodeLimits, key: &[u8]| {
+        let proof = open(Default::default()).get_proof(key).unwrap();
+        open(limits).verify_proof(root, key, proof)
+    };
+    assert!(verify(Default::default(), &[0xfe]).is_ok());
+    assert!(matches!(
+        root_cause(verify(sizes, &[0xfe])),
+        Err(TrieError::LimitExceeded)
+    ));
+    let short = DecodeLimits {
+        max_depth: 1,
+        ..Default::default()
+    };
+    assert!(matches!(
+        root_cause(verify(short, &[0xff; 100])),
+        Err(TrieError::LimitExceeded)
+    ));
+
+    // The healer leaves the nodes it rejects missing.
+    let mut local = MemoryDB::new(true);
+    let mut healer = TrieHealer::new(&mut local, root).with_decode_limits(sizes);
+    while !healer.is_complete() {
+        let missing = healer.missing(16).unwrap();
+        if missing.is_empty() {
+            break;
+        }
+        for hash in missing {
+            let _ = healer.process(vec![memdb.get(&hash).unwrap()]);
+        }
+    }
+    assert_eq!(healer.pending(), 1);
+}
+
+#[test]
+fn test_trie_builder() {
+    use ethtrie::{DecodeLimits, EmptyValues, PatriciaTrie, RlpNodeCodec};
+
+    struct ReversedKeccak;
+
+    impl Hasher for ReversedKeccak {
+        fn hash(data: &[u8]) -> H256 {
+            let reversed: Vec<u8> = data.iter().rev().copied().collect();
+            keccak256(&reversed)
+        }
+    }
+
+    let builder = PatriciaTrieMut::builder()
+        .with_node_cache_capacity(0)
+        .with_pruning(false)
+        .with_empty_values(EmptyValues::Reject);
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = builder.build_mut(&mut memdb);
+    for i in 0..32u8 {
+        trie.insert(&[i], vec![i; 40]).unwrap();
+    }
+    let old_root = trie.root().unwrap();
+    assert!(matches!(
+        root_cause(trie.insert(&[1], Vec::new())),
+        Err(TrieError::InvalidData)
+    ));
+    trie.insert(&[1], vec![7; 40]).unwrap();
+    let root = trie.root().unwrap();
+
+    // Without pruning, both roots stay readable.
+    let open = |root| builder.open(&memdb, root).unwrap();
+    assert_eq!(open(old_root).get(&[1]).unwrap(), Some(vec![1; 40]));
+    assert_eq!(open(root).get(&[1]).unwrap(), Some(vec![7; 40]));
+    assert!(matches!(
+        builder.open(&memdb, H256::zero()),
+        Err(TrieError::InvalidStateRoot)
+    ));
+
+    // The default trie prunes and removes keys set to empty values.
+    let mut trie = PatriciaTrie::builder().open_mut(&mut memdb, root).unwrap();
+    trie.insert(&[1], Vec::new()).unwrap();
+    trie.root().unwrap();
+    assert!(PatriciaTrie::from(&memdb, root).is_err());
+
+    let limited = PatriciaTrie::builder().with_decode_limits(DecodeLimits {
+        max_node_size: 16,
+        ..Default::default()
+    });
+    assert!(matches!(
+        root_cause(limited.open(&memdb, old_root)),
+        Err(TrieError::LimitExceeded)
+    ));
+
+    let reversed = PatriciaTrie::builder().with_hasher::<ReversedKeccak, RlpNodeCodec>();
+    let mut reversed_db = MemoryDB::new(true);
+    let mut trie = reversed.build_mut(&mut reversed_db);
+    let mut expected_db = MemoryDB::new(true);
+    let mut expected = PatriciaTrieMut::<_, ReversedKeccak>::new_with_hasher(&mut expected_db);
+    for i in 0..32u8 {
+        trie.insert(&[i], vec![i; 40]).unwrap();
+        expected.insert(&[i], vec![i; 40]).unwrap();
+    }
+    let root = trie.root().unwrap();
+    assert_eq!(root, expected.root().unwrap());
+    assert_eq!(
+        reversed
+            .open(&reversed_db, root)
+            .unwrap()
+            .get(&[3])
+            .unwrap(),
+        Some(vec![3; 40])
+    );
+}
+
+#[test]
+fn test_corrupt_nodes_do_not_panic() {
+    use ethtrie::{PatriciaTrie, RlpNodeCodec, TrieHealer};
+
+    let mut rng = thread_rng();
+    let mut memdb = MemoryDB::new(false);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    let keys: Vec<Vec<u8>> = (0..200u32)
+        .map(|i| keccak256(&i.to_be_bytes()).0[..(i % 5) as usize].to_vec())
+        .collect();
+    for (i, key) in keys.iter().enumerate() {
+        trie.insert(key, vec![i as u8; i % 50 + 1]).unwrap();
+    }
+    let root = trie.root().unwrap();
+    let hashes = memdb.keys();
+
+    for round in 0..500 {
+        // Truncated, altered, extended or garbage bytes under a node of the trie.
+        let victim = hashes[rng.gen_range(0, hashes.len())];
+        let good = memdb.get(&victim).unwrap();
+        let mut node = good.clone();
+        match round % 4 {
+            0 => node.truncate(rng.gen_range(0, good.len())),
+            1 => node[rng.gen_range(0, good.len())] = rng.gen(),
+            2 => node.insert(rng.gen_range(0, good.len()), rng.gen()),
+            _ => node = (0..rng.gen_range(0, 80)).map(|_| rng.gen()).collect(),
+        }
+        let _ = RlpNodeCodec::decode(&node);
+        memdb.insert(victim, node);
+
+        if let Ok(trie) = PatriciaTrie::from(&memdb, root) {
+            for key in keys.iter().step_by(10) {
+                let _ = trie.get(key);
+                let _ = trie.get_proof(key);
+            }
+            let _ = trie.iter().count();
+            let _ = trie.for_each_entry(|_, _| {});
+        }
+        if let Ok(mut trie) = PatriciaTrieMut::from(&mut memdb, root) {
+            for key in keys.iter().step_by(20) {
+                let _ = trie.insert(key, vec![1]);
+                let _ = trie.remove(key);
+            }
+        }
+        memdb.insert(victim, good);
+    }
+
+    // A branch referring to a child by a digest longer than a hash.
+    let mut s = rlp::RlpStream::new_list(17);
+    s.append(&vec![0xaa; 40]);
+    s.append(&vec![0xbb; 32]);
+    for _ in 2..17 {
+        s.append_empty_data();
+    }
+    let branch = s.out().to_vec();
+    let root = keccak256(&branch);
+    let mut memdb = MemoryDB::new(true);
+    memdb.insert(root, branch.clone());
+    assert!(matches!(
+        root_cause(PatriciaTrie::from(&memdb, root)),
+        Err(TrieError::InvalidData)
+    ));
+    let trie = PatriciaTrie::new(&memdb);
+    assert!(trie.verify_proof(root, &[0x01], vec![branch]).is_err());
+    let mut local = MemoryDB::new(true);
+    let mut healer = TrieHealer::new(&mut local, root);
+    healer.missing(1).unwrap();
+    assert!(matches!(
+        root_cause(healer.process(vec![memdb.get(&root).unwrap()])),
+        Err(TrieError::InvalidData)
+    ));
+
+    // A key of the wrong length in a trie keyed by hashes.
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    trie.insert(&[1; 32], b"value".to_vec()).unwrap();
+    trie.insert(b"short", b"value".to_vec()).unwrap();
+    let root = trie.root().unwrap();
+    let trie = ethtrie::TrieDB::from(&memdb, root).unwrap();
+    assert_eq!(trie.iter().count(), 1);
+    let entries: Vec<_> = trie.try_iter().collect();
+    assert!(matches!(
+        entries[..],
+        [Ok(_), Err(ref e)] if matches!(e.root_cause(), TrieError::InvalidData)
+    ));
+    assert!(matches!(
+        root_cause(trie.for_each_entry(|_, _| {})),
+        Err(TrieError::InvalidData)
+    ));
+}
+
+#[test]
+fn test_error_trait() {
+    use std::error::Error;
+
+    let err: Box<dyn Error + Send + Sync> = Box::new(TrieError::MissingNode(H256::zero()));
+    assert!(err.to_string().starts_with("trie error: missing node"));
+    assert!(err.source().is_none());
+
+    let err = TrieError::from(rlp::DecoderError::RlpIsTooShort);
+    #[cfg(feature = "std")]
+    assert_eq!(err.source().unwrap().to_string(), "RlpIsTooShort");
+    #[cfg(not(feature = "std"))]
+    assert!(err.source().is_none());
+}
+
+#[test]
+fn test_error_context() {
+    use ethtrie::PatriciaTrie;
+    use std::error::Error;
+
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    let keys: Vec<_> = (0..100u32).map(|i| keccak256(&i.to_be_bytes())).collect();
+    for key in &keys {
+        trie.insert(key.as_bytes(), vec![1; 40]).unwrap();
+    }
+    let root = trie.root().unwrap();
+    let key = keys[0].as_bytes();
+    let proof = trie.get_proof(key).unwrap();
+    let corrupt = keccak256(&proof[1]);
+    memdb.insert(corrupt, b"garbage".to_vec());
+
+    let trie = PatriciaTrie::from(&memdb, root).unwrap();
+    let err = trie.get(key).unwrap_err();
+    match &err {
+        TrieError::AtKey { key: at, error } => {
+            assert_eq!(at.as_slice(), key);
+            assert!(matches!(**error, TrieError::InNode { hash, .. } if hash == corrupt));
+        }
+        err => panic!("unexpected error {:?}", err),
+    }
+    assert!(matches!(err.root_cause(), TrieError::NonCanonicalNode));
+    assert!(matches!(
+        err.source().unwrap().downcast_ref::<TrieError>(),
+        Some(TrieError::InNode { .. })
+    ));
+    let message = err.to_string();
+    assert!(message.contains(&format!("in node {:?}", corrupt)));
+    assert!(message.ends_with(&format!("at key 0x{}", hex::encode(key))));
+
+    // Proof failures tell which item is wrong.
+    let mut forged = proof.clone();
+    forged[2][5] ^= 1;
+    let err = trie.verify_proof(root, key, forged).unwrap_err();
+    assert!(matches!(err, TrieError::AtProofItem { index: 2, .. }));
+    assert!(matches!(err.root_cause(), TrieError::InvalidProof));
+    let err = trie
+        .verify_proof(root, key, proof[..2].to_vec())
+        .unwrap_err();
+    assert!(matches!(err, TrieError::AtProofItem { index: 2, .. }));
+    assert!(err.to_string().ends_with("invalid proof at proof item 2"));
+}
+
+// Strips the context from the error of `result`.
+fn root_cause<T>(result: Result<T, TrieError>) -> Result<T, TrieError> {
+    result.map_err(|mut error| loop {
+        error = match error {
+            TrieError::InNode { error, .. }
+            | TrieError::AtKey { error, .. }
+            | TrieError::AtProofItem { error, .. } => *error,
+            error => break error,
+        }
+    })
 }
@@ -1,7 +1,7 @@
 use hex::FromHex;
 use rand::Rng;
 
-use ethtrie::{MemoryDB, PatriciaTrieMut};
+use ethtrie::{keccak256, MemoryDB, PatriciaTrieMut};
 
 fn assert_root(data: Vec<(&[u8], &[u8])>, hash: &str) {
     let mut memdb = MemoryDB::new(true);
@@ -10,7 +10,7 @@ fn assert_root(data: Vec<(&[u8], &[u8])>, hash: &str) {
         trie.insert(k, v.to_vec()).unwrap();
     }
     let r = trie.root().unwrap();
-    let rs = format!("0x{}", hex::encode(r.clone()));
+    let rs = format!("0x{}", hex::encode(r));
     assert_eq!(rs.as_str(), hash);
     let mut trie = PatriciaTrieMut::from(&mut memdb, r).unwrap();
     let r2 = trie.root().unwrap();
@@ -540,7 +540,7 @@ fn test_proof_basic() {
             .collect::<Vec<_>>(),
         expected
     );
-    let value = trie.verify_proof(root.clone(), b"doe", proof).unwrap();
+    let value = trie.verify_proof(root, b"doe", proof).unwrap();
     assert_eq!(value, Some(b"reindeer".to_vec()));
 
     // proof of key not exist
@@ -558,18 +558,18 @@ fn test_proof_basic() {
             .collect::<Vec<_>>(),
         expected
     );
-    let value = trie.verify_proof(root.clone(), b"dogg", proof).unwrap();
+    let value = trie.verify_proof(root, b"dogg", proof).unwrap();
     assert_eq!(value, None);
 
     // empty proof
     let proof = vec![];
-    let value = trie.verify_proof(root.clone(), b"doe", proof);
-    assert_eq!(value.is_err(), true);
+    let value = trie.verify_proof(root, b"doe", proof);
+    assert!(value.is_err());
 
     // bad proof
     let proof = vec![b"aaa".to_vec(), b"ccc".to_vec()];
-    let value = trie.verify_proof(root.clone(), b"doe", proof);
-    assert_eq!(value.is_err(), true);
+    let value = trie.verify_proof(root, b"doe", proof);
+    assert!(value.is_err());
 }
 
 #[test]
@@ -591,11 +591,41 @@ fn test_proof_random() {
     let root = trie.root().unwrap();
     for k in keys.into_iter() {
         let proof = trie.get_proof(&k).unwrap();
-        let value = trie.verify_proof(root.clone(), &k, proof).unwrap().unwrap();
+        let value = trie.verify_proof(root, &k, proof).unwrap().unwrap();
         assert_eq!(value, k);
     }
 }
 
+#[test]
+fn test_proof_shared_with_retained_levels() {
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb).with_retained_levels(2);
+    let keys: Vec<_> = (0..100u32).map(|i| keccak256(&i.to_be_bytes())).collect();
+    for key in keys.iter() {
+        trie.insert(key.as_bytes(), key.as_bytes().to_vec())
+            .unwrap();
+    }
+    let root = trie.root().unwrap();
+
+    let trie = PatriciaTrieMut::from(&mut memdb, root).unwrap();
+    let mut retained_db = MemoryDB::new(true);
+    let mut retained = PatriciaTrieMut::new(&mut retained_db).with_retained_levels(2);
+    for key in keys.iter() {
+        retained
+            .insert(key.as_bytes(), key.as_bytes().to_vec())
+            .unwrap();
+    }
+    retained.root().unwrap();
+
+    for key in keys.iter() {
+        let proof = trie.get_proof(key.as_bytes()).unwrap();
+        let shared = retained.get_proof_shared(key.as_bytes()).unwrap();
+        assert_eq!(shared.iter().map(|n| n.to_vec()).collect::<Vec<_>>(), proof);
+        let value = trie.verify_proof(root, key.as_bytes(), proof).unwrap();
+        assert_eq!(value, Some(key.as_bytes().to_vec()));
+    }
+}
+
 #[test]
 fn test_proof_empty_trie() {
     let mut memdb = MemoryDB::new(true);
@@ -613,16 +643,68 @@ fn test_proof_one_element() {
     let root = trie.root().unwrap();
     let proof = trie.get_proof(b"k").unwrap();
     assert_eq!(proof.len(), 1);
-    let value = trie
-        .verify_proof(root.clone(), b"k", proof.clone())
-        .unwrap();
+    let value = trie.verify_proof(root, b"k", proof.clone()).unwrap();
     assert_eq!(value, Some(b"v".to_vec()));
 
     // remove key does not affect the verify process
     trie.remove(b"k").unwrap();
     let _root = trie.root().unwrap();
-    let value = trie
-        .verify_proof(root.clone(), b"k", proof.clone())
-        .unwrap();
+    let value = trie.verify_proof(root, b"k", proof.clone()).unwrap();
     assert_eq!(value, Some(b"v".to_vec()));
 }
+
+#[test]
+fn test_proof_strict() {
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    let keys: Vec<_> = (0..200u32).map(|i| keccak256(&i.to_be_bytes())).collect();
+    for key in keys.iter() {
+        trie.insert(key.as_bytes(), vec![1; 40]).unwrap();
+    }
+    let root = trie.root().unwrap();
+    let key = keys[0].as_bytes();
+    let proof = trie.get_proof(key).unwrap();
+    let other = trie.get_proof(keys[1].as_bytes()).unwrap();
+    assert!(proof.len() > 2);
+    assert_eq!(
+        trie.verify_proof(root, key, proof.clone()).unwrap(),
+        Some(vec![1; 40])
+    );
+
+    // An extra node, even a valid one of the trie.
+    let mut extra = proof.clone();
+    extra.push(other.last().unwrap().clone());
+    assert!(trie.verify_proof(root, key, extra).is_err());
+    let mut extra = proof.clone();
+    extra.insert(1, other[1].clone());
+    if other[1] != proof[1] {
+        assert!(trie.verify_proof(root, key, extra).is_err());
+    }
+
+    // Nodes out of order or off the path of the key.
+    let mut reordered = proof.clone();
+    reordered.swap(1, 2);
+    assert!(trie.verify_proof(root, key, reordered).is_err());
+    let mut unchained = proof.clone();
+    let last = unchained.len() - 1;
+    unchained[last] = other[other.len() - 1].clone();
+    assert!(trie.verify_proof(root, key, unchained).is_err());
+
+    // A truncated proof proves nothing, not even absence.
+    let absent = trie.get_proof(b"absent").unwrap();
+    assert_eq!(
+        trie.verify_proof(root, b"absent", absent.clone()).unwrap(),
+        None
+    );
+    assert!(trie
+        .verify_proof(root, b"absent", absent[..absent.len() - 1].to_vec())
+        .is_err());
+    assert!(trie
+        .verify_proof(root, key, proof[..proof.len() - 1].to_vec())
+        .is_err());
+
+    // The empty trie needs no proof.
+    let empty_root = keccak256(&rlp::NULL_RLP);
+    assert_eq!(trie.verify_proof(empty_root, key, vec![]).unwrap(), None);
+    assert!(trie.verify_proof(root, key, vec![]).is_err());
+}
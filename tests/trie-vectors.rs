@@ -0,0 +1,150 @@
+use std::collections::BTreeMap;
+
+use hex::FromHex;
+use serde_json::Value;
+
+use ethtrie::{keccak256, MemoryDB, PatriciaTrie, PatriciaTrieMut, SecTrieDBMut, H256};
+
+// The TrieTests vectors of https://github.com/ethereum/tests/tree/develop/TrieTests,
+// kept in their JSON format under tests/fixtures/TrieTests. The files there are
+// still a transcribed part of the upstream ones; `update.sh` in that directory
+// replaces them with the whole upstream files and records their commit in
+// `UPSTREAM`, and the vectors below run unchanged against them.
+const TRIETEST: &str = include_str!("fixtures/TrieTests/trietest.json");
+const TRIEANYORDER: &str = include_str!("fixtures/TrieTests/trieanyorder.json");
+const SECURETRIE: &str = include_str!("fixtures/TrieTests/hex_encoded_securetrie_test.json");
+
+struct Vector {
+    name: String,
+    // Updates in order, a `None` value removing the key.
+    updates: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    root: H256,
+}
+
+// Reads the vectors of a fixture file. Their input is either a list of updates to
+// apply in order or a map of entries to insert in any order.
+fn load(fixture: &str) -> Vec<Vector> {
+    let tests: BTreeMap<String, Value> = serde_json::from_str(fixture).unwrap();
+    tests
+        .into_iter()
+        .map(|(name, test)| {
+            let updates = match &test["in"] {
+                Value::Array(updates) => updates
+                    .iter()
+                    .map(|update| (bytes(&update[0]), value(&update[1])))
+                    .collect(),
+                Value::Object(entries) => entries
+                    .iter()
+                    .map(|(key, entry)| (text_bytes(key), value(entry)))
+                    .collect(),
+                input => panic!("{}: unexpected input {}", name, input),
+            };
+            let root = H256::from_slice(&bytes(&test["root"]));
+            Vector {
+                name,
+                updates,
+                root,
+            }
+        })
+        .collect()
+}
+
+// Strings starting with `0x` are hex, others stand for their own bytes.
+fn text_bytes(text: &str) -> Vec<u8> {
+    match text.strip_prefix("0x") {
+        Some(hex) => Vec::from_hex(hex).unwrap(),
+        None => text.as_bytes().to_vec(),
+    }
+}
+
+fn bytes(value: &Value) -> Vec<u8> {
+    text_bytes(value.as_str().unwrap())
+}
+
+fn value(value: &Value) -> Option<Vec<u8>> {
+    match value {
+        Value::Null => None,
+        value => Some(bytes(value)).filter(|value| !value.is_empty()),
+    }
+}
+
+// Applies the updates to a new trie, with keys hashed first for a secure trie, and
+// checks its root and its entries once reopened.
+fn check_vector(vector: &Vector, updates: &[(Vec<u8>, Option<Vec<u8>>)], secure: bool) {
+    let mut memdb = MemoryDB::new(true);
+    let mut trie = PatriciaTrieMut::new(&mut memdb);
+    let mut expected = BTreeMap::new();
+    for (key, value) in updates {
+        let key = if secure {
+            keccak256(key).as_bytes().to_vec()
+        } else {
+            key.clone()
+        };
+        match value {
+            Some(value) => {
+                trie.insert(&key, value.clone()).unwrap();
+                expected.insert(key, value.clone());
+            }
+            None => {
+                trie.remove(&key).unwrap();
+                expected.remove(&key);
+            }
+        }
+    }
+    let root = trie.root().unwrap();
+    assert_eq!(root, vector.root, "{}", vector.name);
+
+    let trie = PatriciaTrie::from(&memdb, root).unwrap();
+    for (key, value) in expected.iter() {
+        assert_eq!(
+            trie.get(key).unwrap().as_ref(),
+            Some(value),
+            "{}",
+            vector.name
+        );
+    }
+    assert_eq!(
+        trie.iter().collect::<BTreeMap<_, _>>(),
+        expected,
+        "{}",
+        vector.name
+    );
+}
+
+#[test]
+fn test_trietest() {
+    for vector in load(TRIETEST) {
+        check_vector(&vector, &vector.updates, false);
+    }
+}
+
+#[test]
+fn test_trieanyorder() {
+    for vector in load(TRIEANYORDER) {
+        check_vector(&vector, &vector.updates, false);
+        let mut reversed = vector.updates.clone();
+        reversed.reverse();
+        check_vector(&vector, &reversed, false);
+    }
+}
+
+#[test]
+fn test_hex_encoded_securetrie() {
+    for vector in load(SECURETRIE) {
+        check_vector(&vector, &vector.updates, true);
+
+        // A `SecTrieDBMut` hashes keys of 32 bytes itself, shorter ones such as
+        // addresses are hashed before reaching its inner trie.
+        let mut memdb = MemoryDB::new(true);
+        let mut trie = SecTrieDBMut::new(&mut memdb);
+        for (key, value) in vector.updates.iter() {
+            let value = value.clone().unwrap_or_default();
+            if key.len() == 32 {
+                trie.insert(&H256::from_slice(key), value).unwrap();
+            } else {
+                trie.trie_mut().insert(&keccak256(key), value).unwrap();
+            }
+        }
+        assert_eq!(trie.root().unwrap(), vector.root, "{}", vector.name);
+    }
+}